@@ -0,0 +1,76 @@
+use concurrent_queue as cq;
+
+/// Lock-free single-slot hand-off of the latest processed block from the audio thread
+/// to a polling consumer (e.g. a spectrum view). The audio thread never blocks or allocates:
+/// it recycles buffers from a small preallocated pool and drops blocks if none is free.
+pub struct AnalysisTap {
+    free: cq::ConcurrentQueue<Vec<f32>>,
+    latest: cq::ConcurrentQueue<Vec<f32>>,
+}
+
+impl AnalysisTap {
+    pub fn new(block_len: usize) -> Self {
+        let free = cq::ConcurrentQueue::bounded(2);
+        for _ in 0..free.capacity().unwrap() {
+            free.push(Vec::with_capacity(block_len)).unwrap();
+        }
+
+        Self {
+            free,
+            latest: cq::ConcurrentQueue::bounded(1),
+        }
+    }
+
+    /// Publishes `data`, replacing the previous block if it hasn't been taken yet.
+    pub fn publish(&self, data: &[f32]) {
+        let Some(mut buf) = self.free.pop().or_else(|_| self.latest.pop()).ok() else {
+            return;
+        };
+
+        buf.clear();
+        buf.extend_from_slice(data);
+
+        if let Err(err) = self.latest.push(buf) {
+            // Replace the block that hasn't been taken yet
+            if let Ok(old) = self.latest.pop() {
+                let _ = self.free.push(old);
+            }
+            if let Err(err) = self.latest.push(err.into_inner()) {
+                let _ = self.free.push(err.into_inner());
+            }
+        }
+    }
+
+    pub fn take_latest(&self) -> Option<Vec<f32>> {
+        let buf = self.latest.pop().ok()?;
+        let block = buf.clone();
+        let _ = self.free.push(buf);
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_latest_block() {
+        let tap = AnalysisTap::new(4);
+        assert!(tap.take_latest().is_none());
+
+        tap.publish(&[1.0, 2.0]);
+        tap.publish(&[3.0, 4.0]);
+        assert_eq!(tap.take_latest(), Some(vec![3.0, 4.0]));
+        assert!(tap.take_latest().is_none());
+    }
+
+    #[test]
+    fn recycles_buffers() {
+        let tap = AnalysisTap::new(4);
+        for i in 0..10 {
+            tap.publish(&[i as f32]);
+            assert_eq!(tap.take_latest(), Some(vec![i as f32]));
+        }
+        assert_eq!(tap.free.len(), 2);
+    }
+}
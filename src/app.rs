@@ -1,28 +1,216 @@
 use crate::{
     backend,
-    config::{self, AppConfig, AudioSourceMode, EqualizerProfile},
+    config::{self, AppConfig, AudioSourceMode, ConfigWatcher, EqualizerProfile, OutputMode},
+    diagnostics, execute_sampled, logging,
+    runtime_params::RuntimeParams,
+    settings_window::{SettingsChange, SettingsControl, SettingsWindow},
+    surround_virtualizer::{HEIGHT_CHANNELS_OFFSET, HEIGHT_LABELS, SPEAKER_LABELS},
 };
+use log::{info, warn};
 use std::collections::HashMap;
 use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use strum::IntoEnumIterator;
 use tray_icon::{
     Icon, TrayIcon, TrayIconBuilder, TrayIconEvent,
-    menu::{self, CheckMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu},
+    menu::{self, CheckMenuItem, IsMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu},
 };
 use winit::application::ApplicationHandler;
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoopProxy};
 
 const ICON: &'static [u8] = include_bytes!("../res/icon.png");
+/// Tray icon files looked up in `resource_dirs`. The state variants are derived from the main
+/// icon when missing.
+const ICON_FILE: &str = "icon.png";
+const BYPASSED_ICON_FILE: &str = "icon_bypassed.png";
+const CLIPPING_ICON_FILE: &str = "icon_clipping.png";
+/// Folder next to the executable searched for tray icon files.
+const RESOURCE_DIR_NAME: &str = "resources";
+/// Bounds for the width and height of a tray icon file, in pixels.
+const MIN_ICON_SIZE: u32 = 16;
+const MAX_ICON_SIZE: u32 = 1024;
+/// How often the tray icon reflects the processing and clipping state.
+const ICON_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+const STATUS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+const CLIPPING_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+const INPUT_RECORDING_DURATION: Duration = Duration::from_secs(10);
+/// `[left, right]` trims offered in the tray. The other ear is attenuated
+/// rather than the favored one boosted, so that a louder ear can't clip.
+const BALANCE_STEPS: [(&str, [f32; 2]); 5] = [
+    ("Left Ear +6 dB", [0.0, -6.0]),
+    ("Left Ear +3 dB", [0.0, -3.0]),
+    ("Centered", [0.0, 0.0]),
+    ("Right Ear +3 dB", [-3.0, 0.0]),
+    ("Right Ear +6 dB", [-6.0, 0.0]),
+];
+const VIRTUALIZATION_STRENGTH_STEPS: [f32; 5] = [0.0, 0.25, 0.5, 0.75, 1.0];
 
 pub enum AppUserEvent {
     MenuEvent(tray_icon::menu::MenuEvent),
     TrayIconEvent(tray_icon::TrayIconEvent),
+    SettingsEvent(SettingsControl),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum IconState {
+    Active,
+    /// The output is the unprocessed input, either by choice or because the HRIRs failed to load.
+    Bypassed,
+    Clipping,
+}
+
+struct IconImage {
+    rgba: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl IconImage {
+    fn from_png(data: &[u8]) -> Result<Self, String> {
+        let mut reader = png::Decoder::new(Cursor::new(data))
+            .read_info()
+            .map_err(|e| e.to_string())?;
+        let buf_size = reader
+            .output_buffer_size()
+            .ok_or_else(|| "the image is too large".to_string())?;
+        let mut rgba = vec![0; buf_size];
+        let info = reader.next_frame(&mut rgba).map_err(|e| e.to_string())?;
+        if info.color_type != png::ColorType::Rgba || info.bit_depth != png::BitDepth::Eight {
+            return Err("the image isn't an 8-bit RGBA PNG".to_string());
+        }
+        rgba.truncate(info.buffer_size());
+        Ok(Self {
+            rgba,
+            width: info.width,
+            height: info.height,
+        })
+    }
+
+    /// Reads a user icon file and checks that it's usable as a tray icon.
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let icon = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| Self::from_png(&data))?;
+        let size_range = MIN_ICON_SIZE..=MAX_ICON_SIZE;
+        if !size_range.contains(&icon.width) || !size_range.contains(&icon.height) {
+            return Err(format!(
+                "the image is {}x{}, expected {} to {} pixels per side",
+                icon.width, icon.height, MIN_ICON_SIZE, MAX_ICON_SIZE
+            ));
+        }
+        Ok(icon)
+    }
+
+    /// Loads the icon from `path`, or else from `ICON_FILE` in the resource folders,
+    /// falling back to the built-in one.
+    fn load(path: Option<&Path>) -> Self {
+        if let Some(path) = path {
+            match Self::from_file(path) {
+                Ok(icon) => return icon,
+                Err(err) => warn!(
+                    "Failed to load tray icon '{}': {}, using the default",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+        Self::find(ICON_FILE).unwrap_or_else(|| {
+            Self::from_png(ICON).expect("the built-in icon is an 8-bit RGBA PNG")
+        })
+    }
+
+    /// Loads the first valid `file_name` of the resource folders.
+    fn find(file_name: &str) -> Option<Self> {
+        resource_dirs()
+            .into_iter()
+            .map(|dir| dir.join(file_name))
+            .filter(|path| path.exists())
+            .find_map(|path| match Self::from_file(&path) {
+                Ok(icon) => {
+                    info!("Using tray icon {}", path.display());
+                    Some(icon)
+                }
+                Err(err) => {
+                    warn!("Ignoring tray icon '{}': {}", path.display(), err);
+                    None
+                }
+            })
+    }
+
+    fn to_icon(&self) -> Icon {
+        Icon::from_rgba(self.rgba.clone(), self.width, self.height).unwrap()
+    }
+
+    /// Returns a copy with a red dot in the bottom right corner.
+    fn with_warning_badge(&self) -> Self {
+        let radius = self.width.min(self.height) as f32 / 4.0;
+        let (cx, cy) = (self.width as f32 - radius, self.height as f32 - radius);
+        let mut rgba = self.rgba.clone();
+        for (i, pixel) in rgba.chunks_exact_mut(4).enumerate() {
+            let x = (i as u32 % self.width) as f32 + 0.5;
+            let y = (i as u32 / self.width) as f32 + 0.5;
+            if (x - cx).hypot(y - cy) <= radius {
+                pixel.copy_from_slice(&[230, 40, 40, 255]);
+            }
+        }
+        Self {
+            rgba,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Returns a faded grayscale copy.
+    fn dimmed(&self) -> Self {
+        let mut rgba = self.rgba.clone();
+        for pixel in rgba.chunks_exact_mut(4) {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(f32::from);
+            let luma = (0.299 * r + 0.587 * g + 0.114 * b) as u8;
+            pixel[..3].fill(luma);
+            pixel[3] /= 2;
+        }
+        Self {
+            rgba,
+            width: self.width,
+            height: self.height,
+        }
+    }
 }
 
 pub struct App {
-    _tray_icon: TrayIcon,
+    tray_icon: TrayIcon,
+    icon: IconImage,
+    bypassed_icon: IconImage,
+    clipping_icon: IconImage,
+    icon_state: IconState,
+    /// The `backend::get_clipping_counts` already reported.
+    clipping_counts: (u64, u64),
+    log_status: bool,
+    /// Set between `begin_batch` and `end_batch`, see `request_reload`.
+    is_batching: bool,
+    reload_pending: bool,
     quit_menu_item: MenuItem,
+    settings_item: MenuItem,
+    settings_window: Option<SettingsWindow>,
+    /// Handed to the settings window for reporting its changes.
+    event_proxy: EventLoopProxy<AppUserEvent>,
+    config_watcher: ConfigWatcher,
+    copy_diagnostics_item: MenuItem,
+    record_input_item: MenuItem,
     eq_items: Vec<(EqualizerProfile, CheckMenuItem)>,
     source_items: Vec<(AudioSourceMode, CheckMenuItem)>,
+    output_mode_items: Vec<(OutputMode, CheckMenuItem)>,
+    mono_output_item: CheckMenuItem,
+    balance_items: Vec<([f32; 2], CheckMenuItem)>,
+    output_buffer_items: Vec<(usize, CheckMenuItem)>,
+    strength_items: Vec<(f32, CheckMenuItem)>,
+    dialog_boost_item: CheckMenuItem,
+    log_level_items: Vec<(&'static str, CheckMenuItem)>,
+    solo_items: Vec<(Option<usize>, CheckMenuItem)>,
+    mute_items: Vec<(usize, CheckMenuItem)>,
+    test_tone_items: Vec<(u32, MenuItem)>,
+    swap_channels_item: CheckMenuItem,
     input_device_submenu: Submenu,
     output_device_submenu: Submenu,
     input_device_items: HashMap<String, CheckMenuItem>,
@@ -30,8 +218,12 @@ pub struct App {
 }
 
 impl App {
-    pub fn new() -> Self {
+    pub fn new(event_proxy: EventLoopProxy<AppUserEvent>) -> Self {
+        let config = config::get_snapshot();
         let quit_menu_item = menu::MenuItem::new("Quit", true, None);
+        let settings_item = menu::MenuItem::new("Settings…", true, None);
+        let copy_diagnostics_item = menu::MenuItem::new("Copy Diagnostics", true, None);
+        let record_input_item = menu::MenuItem::new("Record Input (10s)", true, None);
 
         let mut eq_items = Vec::new();
         let eq_submenu = menu::Submenu::new("Equalizer Profile", true);
@@ -51,43 +243,191 @@ impl App {
             source_submenu.append(&item).unwrap();
             source_items.push((source, item));
         }
+        source_submenu
+            .append(&PredefinedMenuItem::separator())
+            .unwrap();
+        let dialog_boost_item = menu::CheckMenuItem::new("Dialog Boost", true, false, None);
+        source_submenu.append(&dialog_boost_item).unwrap();
+
+        let mut output_mode_items = Vec::new();
+        let output_mode_submenu = menu::Submenu::new("Output Mode", true);
+        for mode in OutputMode::iter() {
+            let checked = mode == OutputMode::Headphones;
+            let label: &str = mode.into();
+            let item = menu::CheckMenuItem::new(label, true, checked, None);
+            output_mode_submenu.append(&item).unwrap();
+            output_mode_items.push((mode, item));
+        }
+        output_mode_submenu
+            .append(&PredefinedMenuItem::separator())
+            .unwrap();
+        let mono_output_item = menu::CheckMenuItem::new("Mono Output", true, false, None);
+        output_mode_submenu.append(&mono_output_item).unwrap();
+
+        let mut strength_items = Vec::new();
+        let strength_submenu = menu::Submenu::new("Virtualization Strength", true);
+        for strength in VIRTUALIZATION_STRENGTH_STEPS {
+            let label = format!("{:.0}%", strength * 100.0);
+            let item = menu::CheckMenuItem::new(label, true, false, None);
+            strength_submenu.append(&item).unwrap();
+            strength_items.push((strength, item));
+        }
+
+        let mut balance_items = Vec::new();
+        let balance_submenu = menu::Submenu::new("Balance", true);
+        for (label, balance_db) in BALANCE_STEPS {
+            let checked = balance_db == [0.0; 2];
+            let item = menu::CheckMenuItem::new(label, true, checked, None);
+            balance_submenu.append(&item).unwrap();
+            balance_items.push((balance_db, item));
+        }
+
+        let mut log_level_items = Vec::new();
+        let log_level_submenu = menu::Submenu::new("Log Level", !logging::is_level_overridden());
+        for level in logging::LOG_LEVELS {
+            let checked = level == logging::DEFAULT_LOG_LEVEL;
+            let item = menu::CheckMenuItem::new(level, true, checked, None);
+            log_level_submenu.append(&item).unwrap();
+            log_level_items.push((level, item));
+        }
+
+        let debug_channels_submenu = menu::Submenu::new("Debug Channels", true);
+        let mut solo_items = Vec::new();
+        let all_channels_item = menu::CheckMenuItem::new("All Channels", true, true, None);
+        debug_channels_submenu.append(&all_channels_item).unwrap();
+        solo_items.push((None, all_channels_item));
+        for (ch, label) in debug_channels() {
+            let item = menu::CheckMenuItem::new(format!("Solo {label}"), true, false, None);
+            debug_channels_submenu.append(&item).unwrap();
+            solo_items.push((Some(ch), item));
+        }
+        debug_channels_submenu
+            .append(&PredefinedMenuItem::separator())
+            .unwrap();
+        let mut mute_items = Vec::new();
+        for (ch, label) in debug_channels() {
+            let item = menu::CheckMenuItem::new(format!("Mute {label}"), true, false, None);
+            debug_channels_submenu.append(&item).unwrap();
+            mute_items.push((ch, item));
+        }
+
+        let test_tones_submenu = menu::Submenu::new("Test Tones", true);
+        let mut test_tone_items = Vec::new();
+        let all_speakers_item = menu::MenuItem::new("All Speakers", true, None);
+        test_tones_submenu.append(&all_speakers_item).unwrap();
+        test_tone_items.push(((1 << SPEAKER_LABELS.len()) - 1, all_speakers_item));
+        test_tones_submenu
+            .append(&PredefinedMenuItem::separator())
+            .unwrap();
+        for (ch, label) in SPEAKER_LABELS.iter().enumerate() {
+            let item = menu::MenuItem::new(*label, true, None);
+            test_tones_submenu.append(&item).unwrap();
+            test_tone_items.push((1 << ch, item));
+        }
+        // Next to the tones, so that a swap heard on FL/FR can be fixed right away
+        test_tones_submenu
+            .append(&PredefinedMenuItem::separator())
+            .unwrap();
+        let swap_channels_item = menu::CheckMenuItem::new("Swap Left/Right", true, false, None);
+        test_tones_submenu.append(&swap_channels_item).unwrap();
 
         let input_device_submenu = menu::Submenu::new("Surround Audio Source", true);
         let output_device_submenu = menu::Submenu::new("Stereo Output Device", true);
 
+        let mut output_buffer_items = Vec::new();
+        let output_buffer_submenu = menu::Submenu::new("Output Buffer", true);
+        for num_packets in backend::MIN_OUTPUT_BUFFER_PACKETS..=backend::MAX_OUTPUT_BUFFER_PACKETS {
+            let item = menu::CheckMenuItem::new(format!("{num_packets} Blocks"), true, false, None);
+            output_buffer_submenu.append(&item).unwrap();
+            output_buffer_items.push((num_packets, item));
+        }
+
+        // Groups of the top-level menu, separated from each other. The entries marked as
+        // advanced are only shown with `AppConfig::advanced_menu`.
+        let layout: [&[(&dyn IsMenuItem, bool)]; 4] = [
+            &[
+                (&eq_submenu, false),
+                (&source_submenu, false),
+                (&output_mode_submenu, true),
+                (&strength_submenu, true),
+                (&balance_submenu, true),
+            ],
+            &[
+                (&input_device_submenu, false),
+                (&output_device_submenu, false),
+                (&output_buffer_submenu, true),
+            ],
+            &[
+                (&log_level_submenu, true),
+                (&debug_channels_submenu, true),
+                (&test_tones_submenu, true),
+                (&record_input_item, true),
+            ],
+            &[
+                (&copy_diagnostics_item, false),
+                (&settings_item, false),
+                (&quit_menu_item, false),
+            ],
+        ];
         let tray_menu = Menu::new();
-        tray_menu.append(&eq_submenu).unwrap();
-        tray_menu.append(&source_submenu).unwrap();
-        tray_menu.append(&PredefinedMenuItem::separator()).unwrap();
-        tray_menu.append(&input_device_submenu).unwrap();
-        tray_menu.append(&output_device_submenu).unwrap();
-        tray_menu.append(&PredefinedMenuItem::separator()).unwrap();
-        tray_menu.append(&quit_menu_item).unwrap();
-
-        let mut icon_reader = png::Decoder::new(Cursor::new(ICON)).read_info().unwrap();
-        let mut icon_buf = vec![0; icon_reader.output_buffer_size().unwrap()];
-        icon_reader.next_frame(&mut icon_buf).unwrap();
-        icon_reader.finish().unwrap();
+        let groups = layout.iter().map(|group| {
+            group
+                .iter()
+                .filter(|(_, is_advanced)| config.advanced_menu || !is_advanced)
+                .map(|(item, _)| *item)
+                .collect::<Vec<_>>()
+        });
+        for (i, group) in groups.filter(|group| !group.is_empty()).enumerate() {
+            if i > 0 {
+                tray_menu.append(&PredefinedMenuItem::separator()).unwrap();
+            }
+            for item in group {
+                tray_menu.append(item).unwrap();
+            }
+        }
+
+        let icon = IconImage::load(config.tray_icon_path.as_deref());
+        let bypassed_icon = IconImage::find(BYPASSED_ICON_FILE).unwrap_or_else(|| icon.dimmed());
+        let clipping_icon =
+            IconImage::find(CLIPPING_ICON_FILE).unwrap_or_else(|| icon.with_warning_badge());
 
         let tray_icon = TrayIconBuilder::new()
             .with_tooltip("Audio Virtualizer")
             .with_menu(Box::new(tray_menu))
-            .with_icon(
-                Icon::from_rgba(
-                    icon_buf,
-                    icon_reader.info().width,
-                    icon_reader.info().height,
-                )
-                .unwrap(),
-            )
+            .with_icon(icon.to_icon())
             .build()
             .unwrap();
 
         Self {
-            _tray_icon: tray_icon,
+            tray_icon,
+            icon,
+            bypassed_icon,
+            clipping_icon,
+            icon_state: IconState::Active,
+            clipping_counts: backend::get_clipping_counts(),
+            log_status: false,
+            is_batching: false,
+            reload_pending: false,
             quit_menu_item,
+            settings_item,
+            settings_window: None,
+            event_proxy,
+            config_watcher: ConfigWatcher::new(),
+            copy_diagnostics_item,
+            record_input_item,
             eq_items,
             source_items,
+            output_mode_items,
+            mono_output_item,
+            balance_items,
+            output_buffer_items,
+            strength_items,
+            dialog_boost_item,
+            log_level_items,
+            solo_items,
+            mute_items,
+            test_tone_items,
+            swap_channels_item,
             input_device_submenu,
             output_device_submenu,
             input_device_items: HashMap::new(),
@@ -95,6 +435,28 @@ impl App {
         }
     }
 
+    /// Defers backend reloads until `end_batch`, so that applying several settings at once
+    /// restarts the audio only once.
+    fn begin_batch(&mut self) {
+        self.is_batching = true;
+    }
+
+    fn end_batch(&mut self) {
+        self.is_batching = false;
+        if self.reload_pending {
+            self.reload_pending = false;
+            backend::reload_backend();
+        }
+    }
+
+    fn request_reload(&mut self) {
+        if self.is_batching {
+            self.reload_pending = true;
+        } else {
+            backend::reload_backend();
+        }
+    }
+
     fn select_eq_item(&mut self, profile: EqualizerProfile) {
         for (p, item) in &self.eq_items {
             item.set_checked(*p == profile);
@@ -102,6 +464,7 @@ impl App {
         backend::set_equalizer_profile(profile);
         config::update(|cfg| {
             cfg.equalizer_profile = profile;
+            cfg.store_device_settings();
         });
     }
 
@@ -115,41 +478,118 @@ impl App {
         });
     }
 
-    fn refresh_audio_device_lists(&mut self, config: &AppConfig) {
-        for item in self.input_device_items.values() {
-            self.input_device_submenu.remove(item).unwrap_or_default();
+    fn select_output_mode(&mut self, mode: OutputMode) {
+        for (m, item) in &self.output_mode_items {
+            item.set_checked(*m == mode);
         }
-        for item in self.output_device_items.values() {
-            self.output_device_submenu.remove(item).unwrap_or_default();
+        backend::set_output_mode(mode);
+        config::update(|cfg| {
+            cfg.output_mode = mode;
+        });
+    }
+
+    fn set_mono_output(&mut self, enabled: bool) {
+        self.mono_output_item.set_checked(enabled);
+        backend::set_mono_output(enabled);
+        config::update(|cfg| {
+            cfg.mono_output = enabled;
+        });
+    }
+
+    fn set_swap_output_channels(&mut self, enabled: bool) {
+        self.swap_channels_item.set_checked(enabled);
+        backend::set_swap_output_channels(enabled);
+        config::update(|cfg| {
+            cfg.swap_output_channels = enabled;
+        });
+    }
+
+    /// `None` keeps the individually configured parameters and leaves all steps unchecked.
+    fn select_virtualization_strength(&mut self, strength: Option<f32>) {
+        for (s, item) in &self.strength_items {
+            item.set_checked(Some(*s) == strength);
+        }
+        if let Some(strength) = strength {
+            backend::set_virtualization_strength(strength);
+        }
+        config::update(|cfg| {
+            cfg.virtualization_strength = strength;
+        });
+    }
+
+    /// Values from the config that don't match a step leave all steps unchecked.
+    fn select_balance(&mut self, balance_db: [f32; 2]) {
+        for (b, item) in &self.balance_items {
+            item.set_checked(*b == balance_db);
         }
+        backend::set_balance_db(balance_db);
+        config::update(|cfg| {
+            cfg.balance_db = balance_db;
+            cfg.store_device_settings();
+        });
+    }
+
+    fn set_master_gain_db(&mut self, gain_db: f32) {
+        backend::set_master_gain_db(gain_db);
+        config::update(|cfg| {
+            cfg.master_gain_db = gain_db;
+            cfg.store_device_settings();
+        });
+    }
 
-        self.input_device_items.clear();
-        self.output_device_items.clear();
+    fn set_dialog_boost(&mut self, enabled: bool) {
+        self.dialog_boost_item.set_checked(enabled);
+        let config = config::get_snapshot();
+        backend::set_center_boost_db(if enabled { config.center_boost_db } else { 0.0 });
+        config::update(|cfg| {
+            cfg.dialog_boost = enabled;
+        });
+    }
 
-        let input_devices = backend::get_input_device_names();
-        let selected_input_def = config
-            .input_device_name
-            .as_deref()
-            .unwrap_or(backend::DEFAULT_INPUT_DEVICE_NAME);
+    fn select_log_level(&mut self, level: &str) {
+        for (l, item) in &self.log_level_items {
+            item.set_checked(*l == level);
+        }
+        logging::set_log_level(level);
+        config::update(|cfg| {
+            cfg.log_level = level.to_string();
+        });
+    }
 
-        for device_name in input_devices {
-            let is_selected = device_name == selected_input_def;
-            let item = menu::CheckMenuItem::new(&device_name, true, is_selected, None);
-            self.input_device_submenu.append(&item).unwrap();
-            self.input_device_items.insert(device_name, item);
+    fn select_channel_solo(&mut self, channel: Option<usize>) {
+        for (ch, item) in &self.solo_items {
+            item.set_checked(*ch == channel);
         }
+        backend::set_channel_solo(channel);
+    }
 
-        let output_devices = backend::get_output_device_names();
-        let selected_output_def = config
-            .output_device_name
-            .as_deref()
-            .unwrap_or(backend::DEFAULT_OUTPUT_DEVICE_NAME);
+    fn update_channel_mute(&mut self) {
+        let mask = self
+            .mute_items
+            .iter()
+            .filter(|(_, item)| item.is_checked())
+            .fold(0, |mask, (ch, _)| mask | (1 << ch));
+        backend::set_channel_mute(mask);
+    }
 
-        for device_name in output_devices {
-            let is_selected = device_name == selected_output_def;
-            let item = menu::CheckMenuItem::new(&device_name, true, is_selected, None);
-            self.output_device_submenu.append(&item).unwrap();
-            self.output_device_items.insert(device_name, item);
+    fn refresh_audio_device_lists(&mut self, config: &AppConfig) {
+        let input_device_names = backend::get_input_device_names();
+        let output_device_names = backend::get_output_device_names();
+        sync_device_items(
+            &self.input_device_submenu,
+            &mut self.input_device_items,
+            &input_device_names,
+            config.selected_input_device_name(),
+        );
+        sync_device_items(
+            &self.output_device_submenu,
+            &mut self.output_device_items,
+            &output_device_names,
+            config.selected_output_device_name(),
+        );
+        if let Some(window) = &self.settings_window {
+            window.set_device_names(&input_device_names, &output_device_names);
+            window.sync(config);
         }
     }
 
@@ -160,7 +600,7 @@ impl App {
         config::update(|cfg| {
             cfg.input_device_name = Some(device_name.to_string());
         });
-        backend::reload_backend();
+        self.request_reload();
     }
 
     fn select_output_device(&mut self, device_name: &str) {
@@ -170,38 +610,325 @@ impl App {
         config::update(|cfg| {
             cfg.output_device_name = Some(device_name.to_string());
         });
-        backend::reload_backend();
+        self.request_reload();
+    }
+
+    /// Switches to the EQ profile and gains remembered for the new output device,
+    /// after remembering the ones of the previous device.
+    fn switch_output_device(&mut self, device_name: &str) {
+        config::update(AppConfig::store_device_settings);
+        self.select_output_device(device_name);
+
+        let settings = config::get_snapshot()
+            .device_settings
+            .get(device_name)
+            .copied()
+            .unwrap_or_default();
+        self.select_eq_item(settings.equalizer_profile);
+        self.select_balance(settings.balance_db);
+        self.set_master_gain_db(settings.master_gain_db);
+    }
+
+    /// Takes effect with the backend reload.
+    fn select_output_buffer_packets(&mut self, num_packets: usize) {
+        for (n, item) in &self.output_buffer_items {
+            item.set_checked(*n == num_packets);
+        }
+        config::update(|cfg| {
+            cfg.output_buffer_packets = num_packets;
+        });
+        self.request_reload();
+    }
+
+    fn open_settings_window(&mut self, event_loop: &ActiveEventLoop) {
+        if let Some(window) = &self.settings_window {
+            window.focus();
+            return;
+        }
+        match SettingsWindow::open(event_loop, self.event_proxy.clone()) {
+            Ok(window) => {
+                self.settings_window = Some(window);
+                self.refresh_audio_device_lists(&config::get_snapshot());
+            }
+            Err(err) => warn!("Failed to open the settings window: {}", err),
+        }
+    }
+
+    /// Shows the current config in the settings window, if it's open.
+    fn sync_settings_window(&self) {
+        if let Some(window) = &self.settings_window {
+            window.sync(&config::get_snapshot());
+        }
+    }
+
+    fn apply_settings_change(&mut self, change: SettingsChange) {
+        let config = config::get_snapshot();
+        match change {
+            // The popups report a reselected item too, which mustn't restart the audio
+            SettingsChange::InputDevice(name) => {
+                if name != config.selected_input_device_name() {
+                    self.select_input_device(&name);
+                }
+            }
+            SettingsChange::OutputDevice(name) => {
+                if name != config.selected_output_device_name() {
+                    self.switch_output_device(&name);
+                }
+            }
+            SettingsChange::EqualizerProfile(profile) => self.select_eq_item(profile),
+            SettingsChange::SourceMode(mode) => self.select_source_mode(mode),
+            SettingsChange::OutputMode(mode) => self.select_output_mode(mode),
+            SettingsChange::MasterGainDb(gain_db) => self.set_master_gain_db(gain_db),
+            SettingsChange::VirtualizationStrength(strength) => {
+                self.select_virtualization_strength(Some(strength))
+            }
+            SettingsChange::BalanceDb(balance_db) => self.select_balance(balance_db),
+            SettingsChange::MonoOutput(enabled) => self.set_mono_output(enabled),
+            SettingsChange::SwapOutputChannels(enabled) => self.set_swap_output_channels(enabled),
+            SettingsChange::DialogBoost(enabled) => self.set_dialog_boost(enabled),
+            SettingsChange::EditConfigFile => {
+                if let Err(err) = config::open_in_editor() {
+                    warn!("Failed to open the config file: {}", err);
+                }
+            }
+        }
+    }
+
+    fn update_tooltip(&self) {
+        let mut tooltip = format!(
+            "Audio Virtualizer\nDSP load: {:.1}%",
+            backend::get_dsp_load()
+        );
+        if backend::is_waiting_for_startup() {
+            tooltip += "\nWaiting for the audio devices to settle";
+        }
+        if let Some(info) = backend::get_session_info() {
+            tooltip += &format!("\nLatency: {:.0} ms", info.latency_ms);
+            let num_underruns = backend::get_underrun_count();
+            if num_underruns > 0 {
+                tooltip += &format!("\nUnderruns: {}", num_underruns);
+            }
+            if info.input_is_fallback {
+                tooltip += &format!("\nLoopback input missing, using '{}'", info.input_device);
+            }
+            if info.is_passthrough {
+                tooltip += "\nHRIRs failed to load, stereo passthrough";
+            }
+        }
+        let (short_term_lufs, integrated_lufs) = backend::get_output_loudness();
+        if short_term_lufs.is_finite() {
+            tooltip += &format!("\nLoudness: {:.1} LUFS", short_term_lufs);
+        }
+        if integrated_lufs.is_finite() {
+            tooltip += &format!("\nIntegrated: {:.1} LUFS", integrated_lufs);
+        }
+        if backend::is_clipping() {
+            tooltip += "\nOutput is clipping";
+        }
+        let num_non_finite = backend::get_non_finite_sample_count();
+        if num_non_finite > 0 {
+            tooltip += &format!("\nInvalid samples: {}", num_non_finite);
+        }
+        self.tray_icon
+            .set_tooltip(Some(tooltip))
+            .unwrap_or_default();
+    }
+
+    /// Badges the tray icon while the output is clipping and fades it while processing is bypassed.
+    fn update_status_icon(&mut self) {
+        let clipping = backend::is_clipping();
+        let bypassed = backend::get_session_info().is_some_and(|info| info.is_passthrough)
+            || backend::get_params().wet_mix == 0.0;
+        let state = if clipping {
+            IconState::Clipping
+        } else if bypassed {
+            IconState::Bypassed
+        } else {
+            IconState::Active
+        };
+        if state == self.icon_state {
+            return;
+        }
+        self.icon_state = state;
+
+        let icon = match state {
+            IconState::Active => &self.icon,
+            IconState::Bypassed => &self.bypassed_icon,
+            IconState::Clipping => &self.clipping_icon,
+        };
+        self.tray_icon
+            .set_icon(Some(icon.to_icon()))
+            .unwrap_or_default();
+    }
+
+    /// Warns about the clipping the audio thread detected since the last call, and keeps
+    /// a master gain that auto-protect lowered meanwhile.
+    fn report_clipping(&mut self) {
+        let counts = backend::get_clipping_counts();
+        let (reported_clippings, reported_auto_protections) =
+            std::mem::replace(&mut self.clipping_counts, counts);
+        let (clippings, auto_protections) = counts;
+
+        if auto_protections != reported_auto_protections {
+            let master_gain_db = backend::get_master_gain_db();
+            warn!(
+                "Warning: output is clipping, lowered master gain to {:.1} dB",
+                master_gain_db
+            );
+            config::update(|cfg| {
+                cfg.master_gain_db = master_gain_db;
+                cfg.store_device_settings();
+            });
+            self.sync_settings_window();
+        } else if clippings != reported_clippings {
+            execute_sampled!(CLIPPING_WARNING_INTERVAL, {
+                warn!(
+                    "Warning: output is clipping (peak {:.1} dBFS), consider lowering the gain",
+                    backend::get_last_clipping_peak_db()
+                );
+            });
+        }
     }
 
     pub fn update_from_config(&mut self, config: &AppConfig) {
+        self.begin_batch();
         self.refresh_audio_device_lists(config);
+        // Switch the audio over in one step; the menu updates below then set the same values
+        let mut params = RuntimeParams {
+            equalizer_profile: config.equalizer_profile,
+            source_mode: config.audio_source_mode,
+            output_mode: config.output_mode,
+            mono_output: config.mono_output,
+            swap_output_channels: config.swap_output_channels,
+            master_gain_db: config.master_gain_db,
+            balance_db: config.balance_db,
+            wet_mix: config.wet_mix,
+            center_boost_db: if config.dialog_boost {
+                config.center_boost_db
+            } else {
+                0.0
+            },
+            ..backend::get_params()
+        };
+        if let Some(strength) = config.virtualization_strength {
+            params.set_virtualization_strength(strength);
+        }
+        backend::apply_params(params);
         self.select_eq_item(config.equalizer_profile);
         self.select_source_mode(config.audio_source_mode);
-        self.select_input_device(
-            config
-                .input_device_name
-                .as_deref()
-                .unwrap_or(backend::DEFAULT_INPUT_DEVICE_NAME),
-        );
-        self.select_output_device(
-            config
-                .output_device_name
-                .as_deref()
-                .unwrap_or(backend::DEFAULT_OUTPUT_DEVICE_NAME),
-        );
+        self.select_output_mode(config.output_mode);
+        self.set_mono_output(config.mono_output);
+        self.set_swap_output_channels(config.swap_output_channels);
+        self.select_virtualization_strength(config.virtualization_strength);
+        self.select_balance(config.balance_db);
+        self.set_dialog_boost(config.dialog_boost);
+        self.log_status = config.log_status;
+        for (n, item) in &self.output_buffer_items {
+            item.set_checked(*n == config.output_buffer_packets);
+        }
+        self.select_log_level(&config.log_level);
+        // The device items were checked by `refresh_audio_device_lists`. The devices aren't
+        // selected through `select_input_device` here, which would turn the fallback names
+        // into explicit choices in the config.
+        self.request_reload();
+        self.end_batch();
+    }
+}
+
+/// `(input channel, label)` of the channels listed in the Debug Channels submenu.
+fn debug_channels() -> impl Iterator<Item = (usize, &'static str)> {
+    let heights = HEIGHT_LABELS
+        .into_iter()
+        .enumerate()
+        .map(|(h, label)| (HEIGHT_CHANNELS_OFFSET + h, label));
+    SPEAKER_LABELS.into_iter().enumerate().chain(heights)
+}
+
+/// Folders searched for tray icon files, in order: the user assets folder, then
+/// `RESOURCE_DIR_NAME` next to the executable.
+fn resource_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![config::get_assets_path()];
+    if let Ok(exe_path) = std::env::current_exe()
+        && let Some(exe_dir) = exe_path.parent()
+    {
+        dirs.push(exe_dir.join(RESOURCE_DIR_NAME));
+    }
+    dirs
+}
+
+/// Brings a device submenu in line with `device_names`, touching only the items that changed,
+/// so that an open menu doesn't flicker or lose its scroll position.
+fn sync_device_items(
+    submenu: &Submenu,
+    items: &mut HashMap<String, CheckMenuItem>,
+    device_names: &[String],
+    selected_name: &str,
+) {
+    items.retain(|name, item| {
+        let is_present = device_names.contains(name);
+        if !is_present {
+            submenu.remove(item).unwrap_or_default();
+        }
+        is_present
+    });
+
+    for (position, device_name) in device_names.iter().enumerate() {
+        let is_selected = *device_name == selected_name;
+        match items.get(device_name) {
+            Some(item) => {
+                if item.is_checked() != is_selected {
+                    item.set_checked(is_selected);
+                }
+            }
+            None => {
+                let item = menu::CheckMenuItem::new(device_name, true, is_selected, None);
+                submenu.insert(&item, position).unwrap();
+                items.insert(device_name.clone(), item);
+            }
+        }
     }
 }
 
 impl ApplicationHandler<AppUserEvent> for App {
-    fn resumed(&mut self, _: &winit::event_loop::ActiveEventLoop) {}
+    fn resumed(&mut self, _: &ActiveEventLoop) {}
 
-    fn user_event(&mut self, event_loop: &winit::event_loop::ActiveEventLoop, event: AppUserEvent) {
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        self.update_status_icon();
+        self.report_clipping();
+        if let Some(config) = self.config_watcher.poll() {
+            info!("Applying the edited config file");
+            // Most options only take effect on a new session, picked up by the batch
+            self.reload_pending = true;
+            self.update_from_config(&config);
+            self.sync_settings_window();
+        }
+        if self.log_status {
+            execute_sampled!(STATUS_LOG_INTERVAL, {
+                info!("{}", diagnostics::status_line());
+            });
+        }
+        event_loop.set_control_flow(ControlFlow::wait_duration(ICON_UPDATE_INTERVAL));
+    }
+
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, event: AppUserEvent) {
         match event {
             AppUserEvent::MenuEvent(menu_event) => {
                 let menu_id = menu_event.id();
 
                 if menu_id == self.quit_menu_item.id() {
                     event_loop.exit();
+                } else if menu_id == self.settings_item.id() {
+                    self.open_settings_window(event_loop);
+                } else if menu_id == self.copy_diagnostics_item.id() {
+                    match diagnostics::copy_to_clipboard(&diagnostics::collect()) {
+                        Ok(()) => info!("Diagnostics copied to clipboard"),
+                        Err(msg) => warn!("{}", msg),
+                    }
+                } else if menu_id == self.record_input_item.id() {
+                    match backend::record_input(INPUT_RECORDING_DURATION) {
+                        Ok(path) => info!("Recording the input to '{}'", path.display()),
+                        Err(err) => warn!("{}", err),
+                    }
                 } else if let Some((profile, _)) =
                     self.eq_items.iter().find(|(_, item)| item.id() == menu_id)
                 {
@@ -212,6 +939,56 @@ impl ApplicationHandler<AppUserEvent> for App {
                     .find(|(_, item)| item.id() == menu_id)
                 {
                     self.select_source_mode(*source);
+                } else if let Some((mode, _)) = self
+                    .output_mode_items
+                    .iter()
+                    .find(|(_, item)| item.id() == menu_id)
+                {
+                    self.select_output_mode(*mode);
+                } else if menu_id == self.mono_output_item.id() {
+                    self.set_mono_output(self.mono_output_item.is_checked());
+                } else if let Some((strength, _)) = self
+                    .strength_items
+                    .iter()
+                    .find(|(_, item)| item.id() == menu_id)
+                {
+                    self.select_virtualization_strength(Some(*strength));
+                } else if let Some((num_packets, _)) = self
+                    .output_buffer_items
+                    .iter()
+                    .find(|(_, item)| item.id() == menu_id)
+                {
+                    self.select_output_buffer_packets(*num_packets);
+                } else if let Some((balance_db, _)) = self
+                    .balance_items
+                    .iter()
+                    .find(|(_, item)| item.id() == menu_id)
+                {
+                    self.select_balance(*balance_db);
+                } else if menu_id == self.dialog_boost_item.id() {
+                    self.set_dialog_boost(self.dialog_boost_item.is_checked());
+                } else if let Some((level, _)) = self
+                    .log_level_items
+                    .iter()
+                    .find(|(_, item)| item.id() == menu_id)
+                {
+                    self.select_log_level(*level);
+                } else if let Some((channel, _)) = self
+                    .solo_items
+                    .iter()
+                    .find(|(_, item)| item.id() == menu_id)
+                {
+                    self.select_channel_solo(*channel);
+                } else if self.mute_items.iter().any(|(_, item)| item.id() == menu_id) {
+                    self.update_channel_mute();
+                } else if let Some((speaker_mask, _)) = self
+                    .test_tone_items
+                    .iter()
+                    .find(|(_, item)| item.id() == menu_id)
+                {
+                    backend::play_test_tone(*speaker_mask);
+                } else if menu_id == self.swap_channels_item.id() {
+                    self.set_swap_output_channels(self.swap_channels_item.is_checked());
                 } else if let Some((device_name, _)) = self
                     .input_device_items
                     .iter()
@@ -223,26 +1000,44 @@ impl ApplicationHandler<AppUserEvent> for App {
                     .iter()
                     .find(|(_, item)| item.id() == menu_id)
                 {
-                    self.select_output_device(&device_name.clone());
+                    self.switch_output_device(&device_name.clone());
                 }
+                self.sync_settings_window();
             }
             AppUserEvent::TrayIconEvent(tray_icon_event) => {
+                self.update_tooltip();
                 if let TrayIconEvent::Click { .. } = tray_icon_event {
                     let config = config::get_snapshot();
                     self.refresh_audio_device_lists(&config);
                 }
             }
+            AppUserEvent::SettingsEvent(control) => {
+                let change = self
+                    .settings_window
+                    .as_ref()
+                    .and_then(|window| window.read(control));
+                if let Some(change) = change {
+                    self.apply_settings_change(change);
+                }
+                self.sync_settings_window();
+            }
         }
     }
 
     fn window_event(
         &mut self,
-        event_loop: &winit::event_loop::ActiveEventLoop,
-        _: winit::window::WindowId,
+        _: &ActiveEventLoop,
+        window_id: winit::window::WindowId,
         event: winit::event::WindowEvent,
     ) {
-        if let winit::event::WindowEvent::CloseRequested = event {
-            event_loop.exit();
+        // The settings window is the only one; closing it leaves the tray running
+        if let winit::event::WindowEvent::CloseRequested = event
+            && self
+                .settings_window
+                .as_ref()
+                .is_some_and(|window| window.id() == window_id)
+        {
+            self.settings_window = None;
         }
     }
 }
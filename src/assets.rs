@@ -0,0 +1,211 @@
+use crate::config::get_assets_path;
+use crate::error::Error;
+use crate::surround_virtualizer::{HEIGHT_LABELS, SPEAKER_LABELS};
+use log::{info, warn};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+pub const HRIR_MANIFEST_FILE: &str = "hrir.json";
+
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+/// Files given on the command line, used for this run instead of the assets folder
+/// and the embedded defaults.
+#[derive(Default)]
+pub struct Overrides {
+    /// Folder containing the HRIR files under their default names (`FL.wav`, `FR.wav`, ...),
+    /// and optionally the height ones (`TFL.wav`, ...).
+    pub hrir_dir: Option<PathBuf>,
+    /// Equalizer WAV used in place of the one of the selected profile.
+    pub eq_path: Option<PathBuf>,
+}
+
+/// Must be called before any asset is loaded.
+pub fn set_overrides(overrides: Overrides) {
+    if OVERRIDES.set(overrides).is_err() {
+        warn!("Asset overrides are already set, ignoring the new ones");
+    }
+}
+
+pub fn get_overrides() -> &'static Overrides {
+    OVERRIDES.get_or_init(Overrides::default)
+}
+
+/// Describes a third-party HRIR set placed in the user assets folder.
+#[derive(Deserialize)]
+pub struct HrirManifest {
+    pub name: String,
+    pub sample_rate: u32,
+    /// WAV file per speaker label (`FL`, `FR`, ...) or height label (`TFL`, ...), relative to
+    /// the assets folder. Speakers not listed use the default file name.
+    #[serde(default)]
+    pub files: HashMap<String, String>,
+    /// Recommended `[left, right]` gain per speaker, in `SPEAKER_LABELS` order.
+    pub gains: Option<Vec<[f32; 2]>>,
+}
+
+/// Reads `file_name` from the user assets folder, falling back to the embedded copy.
+pub fn load(file_name: &str, embedded: &'static [u8]) -> Cow<'static, [u8]> {
+    let path = get_assets_path().join(file_name);
+    if !path.exists() {
+        return Cow::Borrowed(embedded);
+    }
+    load_path(&path, embedded)
+}
+
+/// Reads the file at `path`, falling back to the embedded copy.
+pub fn load_path(path: &Path, embedded: &'static [u8]) -> Cow<'static, [u8]> {
+    match std::fs::read(path) {
+        Ok(data) => {
+            info!("Using user asset {}", path.display());
+            Cow::Owned(data)
+        }
+        Err(err) => {
+            warn!(
+                "Failed to read {}: {}, using the embedded default",
+                path.display(),
+                err
+            );
+            Cow::Borrowed(embedded)
+        }
+    }
+}
+
+/// Reads `file_name` from the user assets folder, for the assets that have no embedded copy.
+pub fn load_optional(file_name: &str) -> Option<Vec<u8>> {
+    load_optional_path(&get_assets_path().join(file_name))
+}
+
+/// Reads the file at `path`. `None` if there is none or it can't be read.
+pub fn load_optional_path(path: &Path) -> Option<Vec<u8>> {
+    if !path.exists() {
+        return None;
+    }
+    match std::fs::read(path) {
+        Ok(data) => {
+            info!("Using user asset {}", path.display());
+            Some(data)
+        }
+        Err(err) => {
+            warn!("Failed to read {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Checks that `path` is a float WAV file with the given sample rate and one of `channel_counts`.
+pub fn validate_wav(path: &Path, sample_rate: u32, channel_counts: &[u16]) -> Result<(), Error> {
+    let data = std::fs::read(path)
+        .map_err(|err| Error::from(err).context(&format!("Failed to read {}", path.display())))?;
+    let reader = hound::WavReader::new(Cursor::new(data)).map_err(|err| {
+        Error::Wav(format!(
+            "{} is not a valid WAV file: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    let spec = reader.spec();
+
+    if spec.sample_format != hound::SampleFormat::Float {
+        return Err(Error::Wav(format!(
+            "{} must contain float samples",
+            path.display()
+        )));
+    }
+    if spec.sample_rate != sample_rate {
+        return Err(Error::Wav(format!(
+            "{} is sampled at {} Hz, but {} Hz is required",
+            path.display(),
+            spec.sample_rate,
+            sample_rate
+        )));
+    }
+    if !channel_counts.contains(&spec.channels) {
+        return Err(Error::Wav(format!(
+            "{} has {} channels, expected {:?}",
+            path.display(),
+            spec.channels,
+            channel_counts
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the HRIR manifest from the user assets folder. Returns `None` if there is none
+/// or it doesn't describe a usable set.
+pub fn load_hrir_manifest(expected_sample_rate: u32) -> Option<HrirManifest> {
+    let path = get_assets_path().join(HRIR_MANIFEST_FILE);
+    let file = File::open(&path).ok()?;
+    let manifest: HrirManifest = match serde_json::from_reader(file) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            warn!("Failed to parse {}: {}", path.display(), err);
+            return None;
+        }
+    };
+
+    if manifest.sample_rate != expected_sample_rate {
+        warn!(
+            "HRIR set '{}' is sampled at {} Hz, but {} Hz is required, ignoring it",
+            manifest.name, manifest.sample_rate, expected_sample_rate
+        );
+        return None;
+    }
+    for (label, file_name) in &manifest.files {
+        if !SPEAKER_LABELS.contains(&label.as_str()) && !HEIGHT_LABELS.contains(&label.as_str()) {
+            warn!(
+                "HRIR set '{}' lists unknown speaker '{}', ignoring it",
+                manifest.name, label
+            );
+            return None;
+        }
+        if !get_assets_path().join(file_name).exists() {
+            warn!(
+                "HRIR set '{}' references missing file '{}', ignoring it",
+                manifest.name, file_name
+            );
+            return None;
+        }
+    }
+
+    info!("Using HRIR set '{}'", manifest.name);
+    Some(manifest)
+}
+
+/// Detects user asset files being added, removed or modified by polling their modification times.
+pub struct AssetsWatcher {
+    file_names: Vec<&'static str>,
+    mtimes: Vec<Option<SystemTime>>,
+}
+
+impl AssetsWatcher {
+    pub fn new(file_names: Vec<&'static str>) -> Self {
+        let mtimes = file_names.iter().map(|name| get_mtime(name)).collect();
+        Self { file_names, mtimes }
+    }
+
+    /// Returns the names of the files that changed since the previous call.
+    pub fn poll(&mut self) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        for (name, mtime) in self.file_names.iter().zip(&mut self.mtimes) {
+            let new_mtime = get_mtime(name);
+            if new_mtime != *mtime {
+                *mtime = new_mtime;
+                changed.push(*name);
+            }
+        }
+        changed
+    }
+}
+
+fn get_mtime(file_name: &str) -> Option<SystemTime> {
+    std::fs::metadata(get_assets_path().join(file_name))
+        .and_then(|meta| meta.modified())
+        .ok()
+}
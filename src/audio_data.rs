@@ -5,6 +5,10 @@ pub struct AudioDataRef<'a> {
 
 impl<'a> AudioDataRef<'a> {
     pub fn new(data: &'a [f32], num_channels: usize) -> Self {
+        assert!(
+            num_channels > 0,
+            "Audio data must have at least one channel."
+        );
         assert!(
             data.len() % num_channels == 0,
             "Data length must be a multiple of the number of channels."
@@ -16,6 +20,10 @@ impl<'a> AudioDataRef<'a> {
         self.num_channels
     }
 
+    pub fn num_frames(&self) -> usize {
+        self.data.len() / self.num_channels
+    }
+
     pub fn select_channel(&self, ch_idx: usize) -> impl Iterator<Item = f32> + 'a {
         assert!(ch_idx < self.num_channels, "channel index out of bounds");
         self.data
@@ -33,6 +41,10 @@ pub struct AudioDataMut<'a> {
 
 impl<'a> AudioDataMut<'a> {
     pub fn new(data: &'a mut [f32], num_channels: usize) -> Self {
+        assert!(
+            num_channels > 0,
+            "Audio data must have at least one channel."
+        );
         assert!(
             data.len() % num_channels == 0,
             "Data length must be a multiple of the number of channels."
@@ -40,7 +52,16 @@ impl<'a> AudioDataMut<'a> {
         Self { data, num_channels }
     }
 
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.data.len() / self.num_channels
+    }
+
     pub fn select_channel(&self, ch_idx: usize) -> impl Iterator<Item = f32> {
+        assert!(ch_idx < self.num_channels, "channel index out of bounds");
         self.data
             .iter()
             .skip(ch_idx)
@@ -49,6 +70,7 @@ impl<'a> AudioDataMut<'a> {
     }
 
     pub fn select_channel_mut(&mut self, ch_idx: usize) -> impl Iterator<Item = &mut f32> {
+        assert!(ch_idx < self.num_channels, "channel index out of bounds");
         self.data.iter_mut().skip(ch_idx).step_by(self.num_channels)
     }
 
@@ -65,4 +87,122 @@ impl<'a> AudioDataMut<'a> {
     }
 }
 
-pub type AFrame<const CH: usize> = [f32; CH];
+/// Block size used while `AppConfig::block_size` is unset, and the largest one it may be set to.
+pub const CH_BUF_SIZE: usize = 2048;
+/// Smallest accepted `AppConfig::block_size`.
+pub const MIN_BLOCK_SIZE: usize = 256;
+pub const NUM_OUT_CHANNELS: usize = 2;
+pub const HRIR_SAMPLE_RATE: u32 = 48000;
+
+/// Block size and sample rate shared by the block processors of a session. Processors built
+/// from the same `EngineConfig` agree on the block length, which each of them checks on entry
+/// with `assert_block_size`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EngineConfig {
+    pub block_size: usize,
+    pub sample_rate: u32,
+}
+
+/// Panics unless a block of `num_frames` frames matches a processor built for `block_size`.
+/// A mismatch would otherwise leave stale samples in the processor's buffers or overrun them.
+pub fn assert_block_size(num_frames: usize, block_size: usize) {
+    assert_eq!(
+        num_frames, block_size,
+        "audio block of {} frames passed to a processor built for blocks of {} frames",
+        num_frames, block_size
+    );
+}
+
+/// Deterministic white noise in [-1, 1] from a xorshift generator, the same for the same `seed`.
+pub fn white_noise(len: usize, seed: u32) -> impl Iterator<Item = f32> {
+    let mut state = seed.max(1);
+    (0..len).map(move |_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    })
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+pub fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NUM_CHANNELS: usize = 8;
+    const NUM_FRAMES: usize = 16;
+
+    /// An interleaved block in which every sample holds its channel index.
+    fn channel_indices() -> Vec<f32> {
+        (0..NUM_FRAMES * NUM_CHANNELS)
+            .map(|i| (i % NUM_CHANNELS) as f32)
+            .collect()
+    }
+
+    #[test]
+    fn selects_only_the_requested_channel() {
+        let data = channel_indices();
+        let adata = AudioDataRef::new(&data, NUM_CHANNELS);
+        assert_eq!(adata.num_frames(), NUM_FRAMES);
+        for ch in 0..NUM_CHANNELS {
+            let samples: Vec<f32> = adata.select_channel(ch).collect();
+            assert_eq!(samples, [ch as f32; NUM_FRAMES]);
+        }
+    }
+
+    #[test]
+    fn channel_copies_round_trip() {
+        let mut data = channel_indices();
+        let mut adata = AudioDataMut::new(&mut data, NUM_CHANNELS);
+        let ramp: Vec<f32> = (0..NUM_FRAMES).map(|i| 100.0 + i as f32).collect();
+        adata.copy_channel_from_slice(3, &ramp);
+
+        let mut copy = vec![0.0; NUM_FRAMES];
+        adata.copy_channel_to_slice(3, &mut copy);
+        assert_eq!(copy, ramp);
+        // The other channels are left alone
+        for ch in (0..NUM_CHANNELS).filter(|&ch| ch != 3) {
+            assert!(adata.select_channel(ch).all(|v| v == ch as f32));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "channel index out of bounds")]
+    fn selecting_a_channel_out_of_range_panics() {
+        let data = channel_indices();
+        let _ = AudioDataRef::new(&data, NUM_CHANNELS).select_channel(NUM_CHANNELS);
+    }
+
+    #[test]
+    #[should_panic(expected = "multiple of the number of channels")]
+    fn partial_frames_are_rejected() {
+        let data = vec![0.0; NUM_CHANNELS * 2 + 1];
+        AudioDataRef::new(&data, NUM_CHANNELS);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one channel")]
+    fn zero_channels_are_rejected() {
+        AudioDataRef::new(&[], 0);
+    }
+
+    #[test]
+    fn matching_block_size_passes() {
+        assert_block_size(MIN_BLOCK_SIZE, MIN_BLOCK_SIZE);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "audio block of 512 frames passed to a processor built for blocks of 1024 frames"
+    )]
+    fn mismatched_block_size_names_both_sizes() {
+        assert_block_size(512, 1024);
+    }
+}
@@ -1,10 +1,13 @@
-use crate::audio_data::AFrame;
 use concurrent_queue as cq;
 use ringbuf::traits::{Consumer, Observer, Producer};
 
-pub struct AudioSwapchain<const NUM_CHANNELS: usize> {
+/// Exchanges interleaved audio of `num_channels` channels between a ring buffer of samples
+/// and a pool of fixed-size buffers. The ring buffer is only ever pushed to and popped from
+/// in whole frames, so its contents always start on a frame boundary.
+pub struct AudioSwapchain {
     bufs: cq::ConcurrentQueue<Vec<f32>>,
     desired_rb_size: usize,
+    num_channels: usize,
 }
 
 pub struct AudioBuffer<'a> {
@@ -30,41 +33,50 @@ impl Drop for AudioBuffer<'_> {
     }
 }
 
-impl<const NUM_CHANNELS: usize> AudioSwapchain<NUM_CHANNELS> {
-    pub fn new(pool_buf_size: usize, peer_buf_size: usize, min_num_packets: usize) -> Self {
-        let rb_size = pool_buf_size.max(peer_buf_size) * min_num_packets;
+impl AudioSwapchain {
+    /// Creates a swapchain of `num_channels`-channel audio whose pool holds at least
+    /// `min_num_packets` buffers of `pool_buf_size` samples, exchanging data with a peer that
+    /// works in `peer_buf_size` chunks. Both sizes must be multiples of `num_channels`.
+    pub fn new(
+        num_channels: usize,
+        pool_buf_size: usize,
+        peer_buf_size: usize,
+        min_num_packets: usize,
+    ) -> Self {
+        assert_eq!(pool_buf_size % num_channels, 0);
+        assert_eq!(peer_buf_size % num_channels, 0);
 
-        let bufs =
-            cq::ConcurrentQueue::bounded(rb_size.next_multiple_of(pool_buf_size) / pool_buf_size);
-        for _ in 0..bufs.capacity().unwrap() {
+        // When the sizes aren't multiples of each other, the ring buffer may need to hold
+        // up to `pool + peer - gcd` samples before the pool side can make progress.
+        let max_pending = pool_buf_size + peer_buf_size - gcd(pool_buf_size, peer_buf_size);
+        let rb_size = (pool_buf_size.max(peer_buf_size) * min_num_packets).max(max_pending);
+
+        let num_bufs = rb_size.div_ceil(pool_buf_size).max(min_num_packets);
+        let bufs = cq::ConcurrentQueue::bounded(num_bufs);
+        for _ in 0..num_bufs {
             bufs.push(vec![0.0; pool_buf_size]).unwrap();
         }
 
         Self {
             bufs,
             desired_rb_size: rb_size,
+            num_channels,
         }
     }
 
     pub fn acquire_ready_output_buf(
         &self,
-        cons: &mut ringbuf::HeapCons<AFrame<NUM_CHANNELS>>,
+        cons: &mut ringbuf::HeapCons<f32>,
     ) -> Option<AudioBuffer<'_>> {
         let mut buf = AudioBuffer {
             data: self.bufs.pop().ok()?,
             free_queue: &self.bufs,
         };
 
-        let num_frames = buf.data.len() / NUM_CHANNELS;
-        if cons.occupied_len() < num_frames {
+        if cons.occupied_len() < buf.data.len() {
             return None;
         }
-
-        for (idx, frame) in cons.pop_iter().enumerate().take(num_frames) {
-            for ch in 0..NUM_CHANNELS {
-                buf.data[idx * NUM_CHANNELS + ch] = frame[ch];
-            }
-        }
+        cons.pop_slice(&mut buf.data);
 
         Some(buf)
     }
@@ -78,41 +90,172 @@ impl<const NUM_CHANNELS: usize> AudioSwapchain<NUM_CHANNELS> {
 
     /// Submits input audio data into the ring buffer producer.
     /// Returns the number of frames successfully pushed.
-    pub fn submit_input(data: &[f32], prod: &mut ringbuf::HeapProd<AFrame<NUM_CHANNELS>>) -> usize {
-        let num_frames = data.len() / NUM_CHANNELS;
-
-        let num_pushed_frames = prod.push_iter((0..num_frames).map(|idx| {
-            let mut frame = [0.0; NUM_CHANNELS];
-            for ch in 0..NUM_CHANNELS {
-                frame[ch] = data[idx * NUM_CHANNELS + ch];
-            }
-            frame
-        }));
+    ///
+    /// `data` must hold whole frames. When the ring buffer is full, the frames that don't fit
+    /// are dropped, so that the stored ones stay aligned.
+    pub fn submit_input(&self, data: &[f32], prod: &mut ringbuf::HeapProd<f32>) -> usize {
+        assert_eq!(
+            data.len() % self.num_channels,
+            0,
+            "Data length must be a multiple of the number of channels."
+        );
+        let num_frames =
+            (data.len() / self.num_channels).min(prod.vacant_len() / self.num_channels);
 
-        num_pushed_frames
+        prod.push_slice(&data[..num_frames * self.num_channels]);
+        num_frames
     }
 
-    /// Drains `output.len() / NUM_CHANNELS` frames from the ring buffer consumer into
+    /// Drains `output.len() / num_channels` frames from the ring buffer consumer into
     /// the interleaved `output` slice. Returns `false` if fewer frames are available.
-    pub fn drain_output(
-        cons: &mut ringbuf::HeapCons<AFrame<NUM_CHANNELS>>,
-        output: &mut [f32],
-    ) -> bool {
-        let num_frames = output.len() / NUM_CHANNELS;
-        if cons.occupied_len() < num_frames {
+    pub fn drain_output(&self, cons: &mut ringbuf::HeapCons<f32>, output: &mut [f32]) -> bool {
+        assert_eq!(output.len() % self.num_channels, 0);
+        if cons.occupied_len() < output.len() {
             return false;
         }
 
-        for (idx, frame) in cons.pop_iter().enumerate().take(num_frames) {
-            for ch in 0..NUM_CHANNELS {
-                output[idx * NUM_CHANNELS + ch] = frame[ch];
-            }
-        }
-
+        cons.pop_slice(output);
         true
     }
 
+    /// Ring buffer capacity in samples that covers the exchange between the pool and the peer.
     pub fn desired_rb_size(&self) -> usize {
         self.desired_rb_size
     }
+
+    /// Number of whole frames stored in the ring buffer.
+    pub fn occupied_frames(&self, rb: &impl Observer) -> usize {
+        rb.occupied_len() / self.num_channels
+    }
+}
+
+fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ringbuf::traits::Split;
+    use std::iter;
+
+    /// Pool and peer sizes in frames: co-prime, and multiples of each other either way.
+    const SIZE_PAIRS: [(usize, usize); 4] = [(256, 441), (441, 256), (2048, 512), (512, 2048)];
+    const NUM_CHANNELS: usize = 2;
+
+    fn new_swapchain(
+        pool_frames: usize,
+        peer_frames: usize,
+        min_num_packets: usize,
+    ) -> AudioSwapchain {
+        AudioSwapchain::new(
+            NUM_CHANNELS,
+            pool_frames * NUM_CHANNELS,
+            peer_frames * NUM_CHANNELS,
+            min_num_packets,
+        )
+    }
+
+    #[test]
+    fn capacity_holds_the_packets_of_either_side() {
+        for (pool_frames, peer_frames) in SIZE_PAIRS {
+            for min_num_packets in 1..=4 {
+                let sw = new_swapchain(pool_frames, peer_frames, min_num_packets);
+                let max_frames = pool_frames.max(peer_frames);
+                assert!(sw.desired_rb_size() >= max_frames * NUM_CHANNELS * min_num_packets);
+
+                let bufs: Vec<_> = iter::from_fn(|| sw.acquire_free_input_buf()).collect();
+                assert!(bufs.len() >= min_num_packets);
+            }
+        }
+    }
+
+    #[test]
+    fn in_sync_input_is_never_dropped() {
+        for (pool_frames, peer_frames) in SIZE_PAIRS {
+            let sw = new_swapchain(pool_frames, peer_frames, 1);
+            let (mut prod, mut cons) = ringbuf::HeapRb::<f32>::new(sw.desired_rb_size()).split();
+            let chunk = vec![0.0; peer_frames * NUM_CHANNELS];
+
+            // The pool side takes every block as soon as it's complete
+            for _ in 0..100 {
+                assert_eq!(sw.submit_input(&chunk, &mut prod), peer_frames);
+                while sw.acquire_ready_output_buf(&mut cons).is_some() {}
+            }
+        }
+    }
+
+    #[test]
+    fn in_sync_output_never_underruns() {
+        for (pool_frames, peer_frames) in SIZE_PAIRS {
+            let sw = new_swapchain(pool_frames, peer_frames, 1);
+            let (mut prod, mut cons) = ringbuf::HeapRb::<f32>::new(sw.desired_rb_size()).split();
+            let mut chunk = vec![0.0; peer_frames * NUM_CHANNELS];
+
+            // The pool side queues blocks whenever there is room for one, the peer drains its
+            // chunks from what is queued
+            for _ in 0..100 {
+                while prod.vacant_len() >= pool_frames * NUM_CHANNELS {
+                    let buf = sw.acquire_free_input_buf().unwrap();
+                    assert_eq!(sw.submit_input(buf.data(), &mut prod), pool_frames);
+                }
+                assert!(sw.drain_output(&mut cons, &mut chunk));
+            }
+        }
+    }
+
+    #[test]
+    fn overflow_drops_whole_frames() {
+        use ringbuf::traits::{Consumer, Observer};
+
+        let sw = new_swapchain(256, 256, 1);
+        let (mut prod, mut cons) = ringbuf::HeapRb::<f32>::new(sw.desired_rb_size() + 1).split();
+        let frame_capacity = (sw.desired_rb_size() + 1) / NUM_CHANNELS;
+
+        // More than fits, with room for half a frame left over at the end
+        let chunk: Vec<f32> = (0..frame_capacity + 10)
+            .flat_map(|i| [i as f32, -(i as f32)])
+            .collect();
+        assert_eq!(sw.submit_input(&chunk, &mut prod), frame_capacity);
+        assert_eq!(prod.vacant_len(), 1);
+        let stored: Vec<f32> = cons.pop_iter().collect();
+        assert_eq!(stored, chunk[..frame_capacity * NUM_CHANNELS]);
+    }
+
+    #[test]
+    #[should_panic(expected = "multiple of the number of channels")]
+    fn partial_frames_are_rejected() {
+        let sw = new_swapchain(256, 256, 1);
+        let (mut prod, _cons) = ringbuf::HeapRb::<f32>::new(sw.desired_rb_size()).split();
+        sw.submit_input(&[0.0; 2 * NUM_CHANNELS + 1], &mut prod);
+    }
+
+    #[test]
+    fn irregular_chunks_are_reblocked_without_gaps() {
+        let block_frames = 512;
+        let sw = new_swapchain(block_frames, 1300, 1);
+        let (mut prod, mut cons) = ringbuf::HeapRb::<f32>::new(sw.desired_rb_size()).split();
+
+        // Each frame holds its index, negated on the right channel
+        let mut next_in = 0;
+        let mut next_out = 0;
+        for chunk_frames in [700, 1300, 48].repeat(10) {
+            let chunk: Vec<f32> = (next_in..next_in + chunk_frames)
+                .flat_map(|i| [i as f32, -(i as f32)])
+                .collect();
+            assert_eq!(sw.submit_input(&chunk, &mut prod), chunk_frames);
+            next_in += chunk_frames;
+
+            while let Some(block) = sw.acquire_ready_output_buf(&mut cons) {
+                for frame in block.data().chunks_exact(NUM_CHANNELS) {
+                    assert_eq!(frame, [next_out as f32, -(next_out as f32)]);
+                    next_out += 1;
+                }
+            }
+            assert!(next_in - next_out < block_frames);
+        }
+    }
 }
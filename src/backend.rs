@@ -1,19 +1,60 @@
 use crate::{
-    audio_data::{AFrame, AudioDataMut, AudioDataRef},
+    analysis_tap::AnalysisTap,
+    assets::{self, AssetsWatcher},
+    audio_data::{
+        AudioDataMut, AudioDataRef, CH_BUF_SIZE, EngineConfig, HRIR_SAMPLE_RATE, MIN_BLOCK_SIZE,
+        NUM_OUT_CHANNELS, db_to_gain, gain_to_db,
+    },
     audio_swapchain::AudioSwapchain,
-    config::{self, AppConfig, AudioSourceMode, EqualizerProfile},
-    coreaudio, execute_sampled,
-    surround_virtualizer::{Equalizer, SurroundVirtualizer, SurroundVirtualizerConfig, wav_to_pcm},
+    config::{
+        self, AppConfig, AudioSourceMode, EqChannelMode, EqPosition, EqualizerProfile,
+        FftPrecision, InputSource, OutputMode, OutputStage, SpeakerLayout, StreamCreationOrder,
+        UnderrunStrategy,
+    },
+    coreaudio,
+    crosstalk_canceller::CrosstalkCanceller,
+    dither::{self, Dither},
+    dsp_core::{DspCore, DspCoreOptions},
+    equalizer_bank::{EqualizerBank, EqualizerSender, equalizer_bank},
+    error::Error,
+    execute_sampled,
+    idle_gate::IdleGate,
+    input_recorder::InputRecorder,
+    loudness::LoudnessMeter,
+    network_input::NetworkReceiver,
+    noise_gate::NoiseGate,
+    offline,
+    processing_chain::{
+        AutoGain, Balance, MasterGain, MonoOutput, ProcessingChain, StereoStage, SubsonicFilter,
+    },
+    runtime_params::RuntimeParams,
+    seqlock::SeqLock,
+    smoother::Smoother,
+    stream_config::{ChosenConfig, SupportedConfig, select_stream_config},
+    surround_virtualizer::{
+        DEFAULT_DOWNMIX_MATRIX, DownmixMatrix, Equalizer, HEIGHT_LABELS, NUM_HEIGHT_CHANNELS,
+        NUM_SPEAKERS, SPEAKER_LABELS, SurroundVirtualizer, SurroundVirtualizerConfig,
+        wav_to_equalizer,
+    },
+    test_tone::TestTone,
 };
+use concurrent_queue as cq;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use lazy_static::lazy_static;
 use log::{info, warn};
-use num_traits::FromPrimitive;
 use ringbuf::traits::Split;
+use std::array;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::path::PathBuf;
 use std::sync::{
     Arc, Condvar, Mutex,
-    atomic::{self, AtomicU32},
+    atomic::{self, AtomicBool, AtomicU32, AtomicU64},
 };
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use strum::IntoEnumIterator;
 
 const FC_WAV: &[u8] = include_bytes!("../res/hrir/1/FC.wav");
 const BL_WAV: &[u8] = include_bytes!("../res/hrir/1/BL.wav");
@@ -29,13 +70,66 @@ const AIRPODS4_EQ: &[u8] = include_bytes!("../res/eq/airpods4.wav");
 const K702_EQ: &[u8] = include_bytes!("../res/eq/k702.wav");
 const DT770PRO_EQ: &[u8] = include_bytes!("../res/eq/dt770pro.wav");
 
-const CH_BUF_SIZE: usize = 2048;
-const NUM_SURROUND_CHANNELS: usize = 8;
-const NUM_OUT_CHANNELS: usize = 2;
-const HRIR_SAMPLE_RATE: u32 = 48000;
+/// File names looked up in the user assets folder, in `SPEAKER_LABELS` order.
+const HRIR_ASSETS: [(&str, &[u8]); NUM_SPEAKERS] = [
+    ("FL.wav", FL_WAV),
+    ("FR.wav", FR_WAV),
+    ("FC.wav", FC_WAV),
+    ("LFE.wav", LFE_WAV),
+    ("SL.wav", SL_WAV),
+    ("SR.wav", SR_WAV),
+    ("BL.wav", BL_WAV),
+    ("BR.wav", BR_WAV),
+];
+/// Height HRIRs looked up next to the speaker ones, in `HEIGHT_LABELS` order. They have no
+/// embedded copies; height channels without one are folded down into the horizontal speakers.
+const HEIGHT_HRIR_ASSETS: [&str; NUM_HEIGHT_CHANNELS] =
+    ["TFL.wav", "TFR.wav", "TBL.wav", "TBR.wav"];
+
+pub const NUM_SURROUND_CHANNELS: usize = 8;
 const AUDIO_BACKEND_TIMEOUT_MS: u64 = 1000;
-pub const DEFAULT_INPUT_DEVICE_NAME: &str = "BlackHole 16ch";
-pub const DEFAULT_OUTPUT_DEVICE_NAME: &str = "External Headphones";
+const STARTUP_FADE_IN_MS: usize = 100;
+/// Fade-in when processing resumes after `AppConfig::idle_gate` skipped it.
+const IDLE_RESUME_FADE_IN_MS: usize = 5;
+/// Silent blocks run through the convolvers at startup when `AppConfig::prewarm_convolvers` is set.
+const PREWARM_BLOCKS: usize = 4;
+/// Weight of the newest block in the rolling DSP load average.
+const DSP_LOAD_SMOOTHING: f32 = 0.05;
+/// The session is restarted when no input callback has arrived for this long.
+const INPUT_STALL_TIMEOUT: Duration = Duration::from_secs(2);
+/// Bounds for `AppConfig::watchdog_interval_ms`.
+const MIN_WATCHDOG_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+/// Sessions ending sooner than this after their start count as failed and delay the next
+/// attempt, doubling the delay from `MIN_RETRY_DELAY` up to `MAX_RETRY_DELAY`.
+const STABLE_SESSION_DURATION: Duration = Duration::from_secs(5);
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How often the startup delay and deferred reloads check for a shutdown.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Up to this fraction of the retry delay is added at random.
+const RETRY_JITTER: f32 = 0.25;
+/// Weight of the newest measurement in the rolling output backlog average.
+const BACKLOG_SMOOTHING: f32 = 0.02;
+/// Width of the backlog band within which drift compensation leaves the blocks alone, as a
+/// fraction of the backlog range, and at least `MIN_DRIFT_BAND_FRAMES`.
+const DRIFT_BAND_FRACTION: f32 = 0.5;
+const MIN_DRIFT_BAND_FRAMES: usize = 64;
+/// Output clipping counts as sustained once this many consecutive blocks reach full scale.
+const SUSTAINED_CLIPPING_BLOCKS: u32 = 4;
+/// How long `is_clipping` keeps reporting after the last sustained clipping.
+const CLIPPING_HOLD: Duration = Duration::from_secs(3);
+/// Master gain reduction per sustained clipping when `AppConfig::auto_protect` is enabled,
+/// down to `MIN_AUTO_PROTECT_GAIN_DB`.
+const AUTO_PROTECT_STEP_DB: f32 = 3.0;
+const MIN_AUTO_PROTECT_GAIN_DB: f32 = -24.0;
+const NO_AUTO_PROTECT_GAIN: u32 = f32::NAN.to_bits();
+/// Bounds for `AppConfig::output_buffer_packets`.
+pub const MIN_OUTPUT_BUFFER_PACKETS: usize = 2;
+pub const MAX_OUTPUT_BUFFER_PACKETS: usize = 6;
+/// How long the output waits for the first processed block before it starts regardless.
+const FIRST_BLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+const FIRST_BLOCK_POLL_INTERVAL: Duration = Duration::from_millis(2);
 
 struct Signal(Mutex<bool>, Condvar);
 
@@ -56,17 +150,188 @@ impl Signal {
         }
         *signaled = false;
     }
+
+    /// Returns `false` if the timeout elapsed without a notification.
+    fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (mut signaled, _) = self
+            .1
+            .wait_timeout_while(self.0.lock().unwrap(), timeout, |signaled| !*signaled)
+            .unwrap();
+        std::mem::replace(&mut *signaled, false)
+    }
 }
 
-static CURRENT_SOURCE_MODE: AtomicU32 = AtomicU32::new(0);
-static CURRENT_EQ_PROFILE: AtomicU32 = AtomicU32::new(0);
+/// Read by the processing callback without locking, see `LocalParams`.
+static RUNTIME_PARAMS: SeqLock<RuntimeParams> = SeqLock::new(RuntimeParams::DEFAULT);
+/// Master gain in dB (as f32 bits) lowered by auto-protect in the processing callback,
+/// `NO_AUTO_PROTECT_GAIN` if there is none. The callback can't write `RUNTIME_PARAMS`,
+/// so the next parameter change folds it in.
+static AUTO_PROTECT_GAIN_DB: AtomicU32 = AtomicU32::new(NO_AUTO_PROTECT_GAIN);
 static CURRENT_CONTEXT: Mutex<Option<SessionContext>> = Mutex::new(None);
 static DEVICES_CHANGE_WAITER: Signal = Signal::new();
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static WAITING_FOR_STARTUP: AtomicBool = AtomicBool::new(false);
+static DSP_LOAD: AtomicU32 = AtomicU32::new(0);
+static NON_FINITE_SAMPLES: AtomicU64 = AtomicU64::new(0);
+static UNDERRUN_COUNT: AtomicU64 = AtomicU64::new(0);
+static REPEATED_BLOCK_COUNT: AtomicU64 = AtomicU64::new(0);
+static OVERFLOW_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Speakers of a test tone requested by the UI, picked up by the processing callback.
+static TEST_TONE_REQUEST: AtomicU32 = AtomicU32::new(0);
+/// Milliseconds since `BACKEND_EPOCH` at which the last input block was received.
+static LAST_INPUT_MS: AtomicU64 = AtomicU64::new(0);
+static BACKLOG_FRAMES: AtomicU64 = AtomicU64::new(0);
+/// Milliseconds since `BACKEND_EPOCH` at which sustained clipping was last detected.
+static LAST_CLIPPING_MS: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Sustained clippings detected so far, reported by the UI rather than the audio thread.
+static CLIPPING_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Sustained clippings that auto-protect answered by lowering the master gain.
+static AUTO_PROTECT_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Peak in dBFS (as f32 bits) of the block that completed the last sustained clipping.
+static LAST_CLIPPING_PEAK_DB: AtomicU32 = AtomicU32::new(0);
+static OUTPUT_PEAK_DB: AtomicU32 = AtomicU32::new(0);
+static OUTPUT_RMS_DB: AtomicU32 = AtomicU32::new(0);
+static SHORT_TERM_LUFS: AtomicU32 = AtomicU32::new(0);
+static INTEGRATED_LUFS: AtomicU32 = AtomicU32::new(0);
+static ANALYSIS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref BACKEND_EPOCH: Instant = Instant::now();
+    static ref OUTPUT_ANALYSIS_TAP: AnalysisTap = AnalysisTap::new(CH_BUF_SIZE * NUM_OUT_CHANNELS);
+    /// Input recording requested by the UI, picked up by the input callback.
+    static ref INPUT_RECORDER_REQUEST: cq::ConcurrentQueue<InputRecorder> =
+        cq::ConcurrentQueue::bounded(1);
+}
 
+// Fields are dropped in declaration order: the input stream goes first
+// so that nothing is fed into the output stream while it is being torn down.
 struct SessionContext {
-    _in_stream: cpal::Stream,
+    _in_stream: InputStream,
+    _dsp_thread: Option<DspThread>,
     _out_stream: cpal::Stream,
+    // Declared after the stream, so that it's released once the stream is closed
+    _hog_mode: Option<coreaudio::HogMode>,
     reload_signal: Arc<Signal>,
+    eq_loader: Arc<EqualizerLoader>,
+    info: SessionInfo,
+}
+
+/// Source of a session's input.
+enum InputStream {
+    Device(cpal::Stream),
+    Network(NetworkReceiver),
+}
+
+impl InputStream {
+    /// The network receiver delivers as soon as it is started.
+    fn play(&self) -> Result<(), cpal::Error> {
+        match self {
+            InputStream::Device(stream) => stream.play(),
+            InputStream::Network(_) => Ok(()),
+        }
+    }
+}
+
+/// Runs block processing outside the device callbacks. Stopped and joined on drop.
+struct DspThread {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl DspThread {
+    fn spawn<F>(mut process_block: F, poll_interval: Duration) -> Self
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = Arc::clone(&stop);
+        let handle = thread::Builder::new()
+            .name("dsp".to_string())
+            .spawn(move || {
+                while !stop2.load(atomic::Ordering::Relaxed) {
+                    // Drain everything that is ready, then sleep until the input callback wakes us
+                    if !process_block() {
+                        thread::park_timeout(poll_interval);
+                    }
+                }
+            })
+            .unwrap();
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    fn waker(&self) -> thread::Thread {
+        self.handle.as_ref().unwrap().thread().clone()
+    }
+}
+
+impl Drop for DspThread {
+    fn drop(&mut self) {
+        self.stop.store(true, atomic::Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            handle.join().unwrap_or_default();
+        }
+    }
+}
+
+/// Stream parameters chosen for the active session.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub input_device: String,
+    /// The configured input device is missing and the system default one is used instead.
+    pub input_is_fallback: bool,
+    /// The HRIRs failed to load and the input is downmixed to stereo without virtualization.
+    pub is_passthrough: bool,
+    pub output_device: String,
+    pub input_channels: u16,
+    pub input_sample_rate: u32,
+    pub input_sample_format: cpal::SampleFormat,
+    pub input_buffer_size: usize,
+    pub output_sample_rate: u32,
+    pub output_buffer_size: usize,
+    pub output_sample_format: cpal::SampleFormat,
+    /// Estimated end-to-end latency from input capture to output playback.
+    pub latency_ms: f32,
+}
+
+pub fn get_session_info() -> Option<SessionInfo> {
+    CURRENT_CONTEXT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|ctx| ctx.info.clone())
+}
+
+/// Returns the number of output callbacks that found no processed audio to play.
+pub fn get_underrun_count() -> u64 {
+    UNDERRUN_COUNT.load(atomic::Ordering::Relaxed)
+}
+
+/// Returns the number of underruns covered by repeating the previous output block.
+pub fn get_repeated_block_count() -> u64 {
+    REPEATED_BLOCK_COUNT.load(atomic::Ordering::Relaxed)
+}
+
+/// Returns the number of times frames were dropped because a ring buffer was full.
+pub fn get_overflow_count() -> u64 {
+    OVERFLOW_COUNT.load(atomic::Ordering::Relaxed)
+}
+
+/// Frames queued in the output ringbuffer, sampled before each processed block is submitted.
+pub fn get_backlog_frames() -> u64 {
+    BACKLOG_FRAMES.load(atomic::Ordering::Relaxed)
+}
+
+/// Returns the short-term and integrated loudness of the output in LUFS, `-inf` if not yet known.
+pub fn get_output_loudness() -> (f32, f32) {
+    (
+        f32::from_bits(SHORT_TERM_LUFS.load(atomic::Ordering::Relaxed)),
+        f32::from_bits(INTEGRATED_LUFS.load(atomic::Ordering::Relaxed)),
+    )
 }
 
 pub fn get_input_device_names() -> Vec<String> {
@@ -103,9 +368,13 @@ pub fn get_output_device_names() -> Vec<String> {
 
 fn notify_devices_change() {
     info!("Audio devices changed");
-    if CURRENT_CONTEXT.lock().unwrap().is_none() {
+    let ctx = CURRENT_CONTEXT.lock().unwrap();
+    match ctx.as_ref() {
         // Reload only if there are no healthy active session
-        DEVICES_CHANGE_WAITER.notify();
+        None => DEVICES_CHANGE_WAITER.notify(),
+        // The configured input device may have appeared
+        Some(ctx) if ctx.info.input_is_fallback => ctx.reload_signal.notify(),
+        Some(_) => {}
     }
 }
 
@@ -117,293 +386,1662 @@ pub fn reload_backend() {
     DEVICES_CHANGE_WAITER.notify();
 }
 
+/// Stops the active streams and makes `run` return.
+pub fn shutdown() {
+    SHUTDOWN.store(true, atomic::Ordering::Relaxed);
+    reload_backend();
+}
+
+/// Returns the rolling average processing time of one block
+/// as a percentage of its real-time duration.
+pub fn get_dsp_load() -> f32 {
+    f32::from_bits(DSP_LOAD.load(atomic::Ordering::Relaxed))
+}
+
+/// Enables copying each processed output block for `take_latest_output_block`.
+pub fn set_analysis_enabled(enabled: bool) {
+    ANALYSIS_ENABLED.store(enabled, atomic::Ordering::Relaxed);
+}
+
+/// Returns the most recent interleaved stereo output block, if a new one is available.
+/// Samples are at `get_output_sample_rate()`.
+pub fn take_latest_output_block() -> Option<Vec<f32>> {
+    OUTPUT_ANALYSIS_TAP.take_latest()
+}
+
+pub fn get_output_sample_rate() -> u32 {
+    HRIR_SAMPLE_RATE
+}
+
+/// Returns the parameters the processing callback currently runs with.
+pub fn get_params() -> RuntimeParams {
+    let mut params = RUNTIME_PARAMS.read();
+    apply_auto_protect_gain(&mut params, pending_auto_protect_gain_db());
+    params
+}
+
+/// Replaces all runtime parameters at once, so that the processing callback
+/// never sees a mix of old and new values (e.g. when switching presets).
+pub fn apply_params(params: RuntimeParams) {
+    update_params(|p| *p = params);
+    request_equalizer(params.equalizer_profile);
+}
+
+fn update_params(f: impl FnOnce(&mut RuntimeParams)) {
+    RUNTIME_PARAMS.write(|params| {
+        // Ahead of `f`, so that a gain set by the user replaces the lowered one
+        let auto_protect_gain_db =
+            AUTO_PROTECT_GAIN_DB.swap(NO_AUTO_PROTECT_GAIN, atomic::Ordering::AcqRel);
+        apply_auto_protect_gain(params, Some(f32::from_bits(auto_protect_gain_db)));
+        f(params);
+        params.wet_mix = params.wet_mix.clamp(0.0, 1.0);
+    });
+}
+
+fn pending_auto_protect_gain_db() -> Option<f32> {
+    let gain_db = f32::from_bits(AUTO_PROTECT_GAIN_DB.load(atomic::Ordering::Acquire));
+    (!gain_db.is_nan()).then_some(gain_db)
+}
+
+/// Auto-protect only ever lowers the gain, so that a gain the user lowered further stays.
+fn apply_auto_protect_gain(params: &mut RuntimeParams, gain_db: Option<f32>) {
+    if let Some(gain_db) = gain_db.filter(|gain_db| !gain_db.is_nan()) {
+        params.master_gain_db = params.master_gain_db.min(gain_db);
+    }
+}
+
+/// The processing callback's copy of `RUNTIME_PARAMS`.
+struct LocalParams {
+    params: RuntimeParams,
+    version: u64,
+}
+
+impl LocalParams {
+    fn new() -> Self {
+        let version = RUNTIME_PARAMS.version();
+        Self {
+            params: get_params(),
+            version,
+        }
+    }
+
+    /// Picks up the latest parameters if they changed. Never blocks: if a setter
+    /// is writing them right now, the previous snapshot is used for one more block.
+    fn refresh(&mut self) -> &RuntimeParams {
+        if RUNTIME_PARAMS.version() != self.version
+            && let Some((params, version)) = RUNTIME_PARAMS.try_read()
+        {
+            self.params = params;
+            self.version = version;
+            // Not folded in yet if auto-protect acted while the snapshot was written
+            apply_auto_protect_gain(&mut self.params, pending_auto_protect_gain_db());
+        }
+        &self.params
+    }
+
+    /// Lowers the master gain for this callback right away, and for everyone else through
+    /// `AUTO_PROTECT_GAIN_DB`.
+    fn lower_master_gain_db(&mut self, gain_db: f32) {
+        self.params.master_gain_db = gain_db;
+        AUTO_PROTECT_GAIN_DB.store(gain_db.to_bits(), atomic::Ordering::Release);
+    }
+}
+
+pub fn set_channel_solo(channel: Option<usize>) {
+    update_params(|p| p.channel_solo = channel);
+}
+
+pub fn set_channel_mute(mask: u32) {
+    update_params(|p| p.channel_mute_mask = mask);
+}
+
+/// Plays a noise burst through each speaker whose bit is set in `speaker_mask`, one after
+/// another, in place of the input. Restarts a test tone that is already playing.
+pub fn play_test_tone(speaker_mask: u32) {
+    TEST_TONE_REQUEST.store(speaker_mask, atomic::Ordering::Relaxed);
+}
+
+/// Starts copying the raw input of the active session to a WAV file in the cache folder
+/// for `duration`, and returns the path of that file.
+pub fn record_input(duration: Duration) -> Result<PathBuf, Error> {
+    let info = get_session_info()
+        .ok_or_else(|| Error::Device("No audio session is active".to_string()))?;
+
+    let cache_dir = config::get_cache_path();
+    std::fs::create_dir_all(&cache_dir).map_err(|e| {
+        Error::from(e).context(&format!("Failed to create '{}'", cache_dir.display()))
+    })?;
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = cache_dir.join(format!("input_{}.wav", timestamp));
+
+    let recorder = InputRecorder::start(
+        path.clone(),
+        info.input_channels,
+        HRIR_SAMPLE_RATE,
+        duration,
+        info.input_buffer_size * info.input_channels as usize,
+    )?;
+    // A request that hasn't been picked up yet is replaced
+    let _ = INPUT_RECORDER_REQUEST.force_push(recorder);
+    Ok(path)
+}
+
+/// Returns the number of NaN/Inf output samples replaced with silence since startup.
+pub fn get_non_finite_sample_count() -> u64 {
+    NON_FINITE_SAMPLES.load(atomic::Ordering::Relaxed)
+}
+
 pub fn set_equalizer_profile(profile: EqualizerProfile) {
-    CURRENT_EQ_PROFILE.store(profile as u32, atomic::Ordering::Relaxed);
+    update_params(|p| p.equalizer_profile = profile);
+    request_equalizer(profile);
 }
 
 pub fn set_source_mode(source_mode: AudioSourceMode) {
-    CURRENT_SOURCE_MODE.store(source_mode as u32, atomic::Ordering::Relaxed);
+    update_params(|p| p.source_mode = source_mode);
+}
+
+pub fn set_output_mode(output_mode: OutputMode) {
+    update_params(|p| p.output_mode = output_mode);
+}
+
+pub fn set_mono_output(enabled: bool) {
+    update_params(|p| p.mono_output = enabled);
 }
 
+pub fn set_swap_output_channels(enabled: bool) {
+    update_params(|p| p.swap_output_channels = enabled);
+}
+
+pub fn set_center_boost_db(boost_db: f32) {
+    update_params(|p| p.center_boost_db = boost_db);
+}
+
+pub fn set_virtualization_strength(strength: f32) {
+    update_params(|p| p.set_virtualization_strength(strength));
+}
+
+pub fn set_balance_db(balance_db: [f32; 2]) {
+    update_params(|p| p.balance_db = balance_db);
+}
+
+/// Sets the master gain, which is ramped in over `gain_smoothing_ms`.
+pub fn set_master_gain_db(gain_db: f32) {
+    update_params(|p| p.master_gain_db = gain_db);
+}
+
+/// Returns the master gain, which auto-protect may have lowered since it was set.
+pub fn get_master_gain_db() -> f32 {
+    get_params().master_gain_db
+}
+
+/// Returns the peak and RMS level of the output in dBFS over the last half second.
+pub fn get_output_levels() -> (f32, f32) {
+    (
+        f32::from_bits(OUTPUT_PEAK_DB.load(atomic::Ordering::Relaxed)),
+        f32::from_bits(OUTPUT_RMS_DB.load(atomic::Ordering::Relaxed)),
+    )
+}
+
+/// Whether `run` is still in the startup delay, before the first session.
+pub fn is_waiting_for_startup() -> bool {
+    WAITING_FOR_STARTUP.load(atomic::Ordering::Relaxed)
+}
+
+/// Whether the output has been clipping for several blocks within the last few seconds.
+pub fn is_clipping() -> bool {
+    let last_clipping_ms = LAST_CLIPPING_MS.load(atomic::Ordering::Relaxed);
+    let now_ms = BACKEND_EPOCH.elapsed().as_millis() as u64;
+    last_clipping_ms != u64::MAX
+        && now_ms.saturating_sub(last_clipping_ms) < CLIPPING_HOLD.as_millis() as u64
+}
+
+/// Returns how many sustained clippings were detected so far, and how many of them
+/// auto-protect answered by lowering the master gain.
+pub fn get_clipping_counts() -> (u64, u64) {
+    (
+        CLIPPING_COUNT.load(atomic::Ordering::Relaxed),
+        AUTO_PROTECT_COUNT.load(atomic::Ordering::Relaxed),
+    )
+}
+
+/// Returns the peak level in dBFS of the last sustained clipping.
+pub fn get_last_clipping_peak_db() -> f32 {
+    f32::from_bits(LAST_CLIPPING_PEAK_DB.load(atomic::Ordering::Relaxed))
+}
+
+/// The input device is `None` with `InputSource::Network`.
 fn get_devices(
     host: &cpal::Host,
     config: &AppConfig,
-) -> Result<(cpal::Device, cpal::Device), String> {
-    let input_device_name = config
-        .input_device_name
-        .as_deref()
-        .unwrap_or(DEFAULT_INPUT_DEVICE_NAME);
-    let output_device_name = config
-        .output_device_name
-        .as_deref()
-        .unwrap_or(DEFAULT_OUTPUT_DEVICE_NAME);
-
-    let input_dev = host.input_devices().unwrap().find(|dev| {
-        dev.description()
-            .map(|desc| desc.name() == input_device_name)
-            .unwrap_or(false)
-    });
-    let Some(input_dev) = input_dev else {
-        return Err(format!("Input device '{}' not found", input_device_name));
+) -> Result<(Option<cpal::Device>, cpal::Device, bool), Error> {
+    let output_device_name = config.selected_output_device_name();
+    let output_dev = host
+        .output_devices()
+        .map_err(|err| Error::Device(format!("Failed to list output devices: {err}")))?
+        .find(|dev| {
+            dev.description()
+                .map(|desc| desc.name() == output_device_name)
+                .unwrap_or(false)
+        });
+    let Some(output_dev) = output_dev else {
+        return Err(Error::Device(format!(
+            "Output device '{}' not found",
+            output_device_name
+        )));
+    };
+
+    if config.input_source == InputSource::Network {
+        return Ok((None, output_dev, false));
+    }
+
+    let input_device_name = config.selected_input_device_name();
+    let input_dev = host
+        .input_devices()
+        .map_err(|err| Error::Device(format!("Failed to list input devices: {err}")))?
+        .find(|dev| {
+            dev.description()
+                .map(|desc| desc.name() == input_device_name)
+                .unwrap_or(false)
+        });
+    // Without the loopback device installed, capture from the default input so that
+    // the app still runs. The configured device name is kept and retried on device changes.
+    let (input_dev, input_is_fallback) = match input_dev {
+        Some(dev) => (dev, false),
+        None => {
+            let Some(dev) = host.default_input_device() else {
+                return Err(Error::Device(format!(
+                    "Input device '{}' not found",
+                    input_device_name
+                )));
+            };
+            warn!(
+                "Input device '{}' not found, falling back to the default input device. \
+                 Install a loopback device (e.g. BlackHole) and route system audio to it \
+                 to virtualize the system output.",
+                input_device_name
+            );
+            (dev, true)
+        }
+    };
+
+    Ok((Some(input_dev), output_dev, input_is_fallback))
+}
+
+/// Returns the configured downmix matrix, else the one recommended by the HRIR set.
+fn get_downmix_matrix(config: &AppConfig, hrirs: &HrirSet) -> DownmixMatrix {
+    let Some(rows) = config
+        .downmix_matrix
+        .as_ref()
+        .or(hrirs.recommended_downmix.as_ref())
+    else {
+        return DEFAULT_DOWNMIX_MATRIX;
     };
 
-    let output_dev = host.output_devices().unwrap().find(|dev| {
-        dev.description()
-            .map(|desc| desc.name() == output_device_name)
-            .unwrap_or(false)
+    match DownmixMatrix::try_from(rows.as_slice()) {
+        Ok(matrix) if matrix.iter().flatten().all(|g| g.is_finite()) => matrix,
+        _ => {
+            warn!(
+                "Downmix matrix must have {} finite [left, right] rows (got {}), using the default one",
+                NUM_SPEAKERS,
+                rows.len()
+            );
+            DEFAULT_DOWNMIX_MATRIX
+        }
+    }
+}
+
+/// Speaker distances in meters of `SpeakerLayout::Room`, in `SPEAKER_LABELS` order.
+const ROOM_SPEAKER_DISTANCES_M: [f32; NUM_SPEAKERS] = [2.5, 2.5, 2.3, 2.5, 1.5, 1.5, 2.0, 2.0];
+
+fn get_speaker_distances(config: &AppConfig) -> Option<[f32; NUM_SPEAKERS]> {
+    let Some(distances) = &config.speaker_distances_m else {
+        return match config.speaker_layout {
+            SpeakerLayout::Equidistant => None,
+            SpeakerLayout::Room => Some(ROOM_SPEAKER_DISTANCES_M),
+        };
+    };
+
+    match <[f32; NUM_SPEAKERS]>::try_from(distances.as_slice()) {
+        Ok(distances) if distances.iter().all(|d| d.is_finite() && *d > 0.0) => Some(distances),
+        _ => {
+            warn!(
+                "Speaker distances must be {} positive values (got {}), ignoring them",
+                NUM_SPEAKERS,
+                distances.len()
+            );
+            None
+        }
+    }
+}
+
+/// Speaker HRIRs in `SPEAKER_LABELS` order along with the set's recommended speaker gains.
+pub struct HrirSet {
+    wavs: [Cow<'static, [u8]>; NUM_SPEAKERS],
+    /// In `HEIGHT_LABELS` order, `None` for the ones the set doesn't have.
+    height_wavs: [Option<Vec<u8>>; NUM_HEIGHT_CHANNELS],
+    recommended_downmix: Option<Vec<[f32; 2]>>,
+}
+
+/// Loads the speaker HRIRs, preferring the files of a user HRIR set (described by its
+/// manifest, if any) over the embedded ones.
+pub fn load_hrirs() -> HrirSet {
+    if let Some(hrir_dir) = &assets::get_overrides().hrir_dir {
+        return HrirSet {
+            wavs: HRIR_ASSETS.map(|(file_name, embedded)| {
+                assets::load_path(&hrir_dir.join(file_name), embedded)
+            }),
+            height_wavs: HEIGHT_HRIR_ASSETS
+                .map(|file_name| assets::load_optional_path(&hrir_dir.join(file_name))),
+            recommended_downmix: None,
+        };
+    }
+
+    let manifest = assets::load_hrir_manifest(HRIR_SAMPLE_RATE);
+    let get_file_name = |label: &str, default_file_name: &'static str| {
+        manifest
+            .as_ref()
+            .and_then(|manifest| manifest.files.get(label))
+            .map_or(default_file_name, String::as_str)
+    };
+    let wavs = array::from_fn(|i| {
+        let (default_file_name, embedded) = HRIR_ASSETS[i];
+        assets::load(
+            get_file_name(SPEAKER_LABELS[i], default_file_name),
+            embedded,
+        )
     });
-    let Some(output_dev) = output_dev else {
-        return Err(format!("Output device '{}' not found", output_device_name));
+    let height_wavs = array::from_fn(|h| {
+        assets::load_optional(get_file_name(HEIGHT_LABELS[h], HEIGHT_HRIR_ASSETS[h]))
+    });
+
+    HrirSet {
+        wavs,
+        height_wavs,
+        recommended_downmix: manifest.and_then(|manifest| manifest.gains),
+    }
+}
+
+/// Returns the engine config of a session, shared by every block processor so that their
+/// block sizes can't diverge. A configured block size out of range is clamped.
+pub fn get_engine_config(config: &AppConfig) -> EngineConfig {
+    EngineConfig {
+        block_size: config
+            .block_size
+            .map_or(CH_BUF_SIZE, |size| size.clamp(MIN_BLOCK_SIZE, CH_BUF_SIZE)),
+        sample_rate: HRIR_SAMPLE_RATE,
+    }
+}
+
+fn get_dsp_core_options(config: &AppConfig) -> DspCoreOptions {
+    DspCoreOptions {
+        stereo_upmix: config.stereo_upmix,
+        crossfeed_level_db: config.crossfeed_level_db,
+        crossfeed_cutoff_hz: config.crossfeed_cutoff_hz,
+        gain_smoothing_ms: config.gain_smoothing_ms,
+    }
+}
+
+pub fn get_virtualizer_config<'a>(
+    config: &AppConfig,
+    hrirs: &'a HrirSet,
+) -> SurroundVirtualizerConfig<'a> {
+    let [
+        fl_wav,
+        fr_wav,
+        fc_wav,
+        lfe_wav,
+        sl_wav,
+        sr_wav,
+        bl_wav,
+        br_wav,
+    ] = hrirs.wavs.each_ref().map(|wav| &**wav);
+    let [tfl_wav, tfr_wav, tbl_wav, tbr_wav] = hrirs.height_wavs.each_ref().map(Option::as_deref);
+    SurroundVirtualizerConfig {
+        fc_wav,
+        bl_wav,
+        br_wav,
+        fl_wav,
+        fr_wav,
+        sl_wav,
+        sr_wav,
+        lfe_wav,
+        tfl_wav,
+        tfr_wav,
+        tbl_wav,
+        tbr_wav,
+        engine: get_engine_config(config),
+        downmix: get_downmix_matrix(config, hrirs),
+        lfe_gain: config.lfe_gain.filter(|gain| gain.is_finite()),
+        lfe_hrir: config.lfe_hrir,
+        max_hrir_len: config
+            .max_hrir_ms
+            .map(|ms| (ms.max(0.0) * HRIR_SAMPLE_RATE as f32 / 1000.0) as usize),
+        align_onsets: config.align_hrir_onsets,
+        mode_gain_compensation: config.mode_gain_compensation,
+        headroom_db: config.hrir_headroom_db,
+        remove_dc: config.remove_hrir_dc,
+        double_precision: config.fft_precision == FftPrecision::Double,
+        decorrelation: config.surround_decorrelation,
+        speaker_distances: get_speaker_distances(config),
+    }
+}
+
+/// Returns, for each device output channel, the stereo channel feeding it (`None` for silence).
+fn get_output_routing(config: &AppConfig) -> Vec<Option<usize>> {
+    let num_channels = match config.output_channels {
+        2 | 4 => config.output_channels as usize,
+        n => {
+            warn!("Unsupported output channel count {}, using stereo", n);
+            NUM_OUT_CHANNELS
+        }
+    };
+
+    match &config.output_routing {
+        Some(routing)
+            if routing.len() == num_channels
+                && routing.iter().flatten().all(|ch| *ch < NUM_OUT_CHANNELS) =>
+        {
+            routing.clone()
+        }
+        routing => {
+            if routing.is_some() {
+                warn!(
+                    "Output routing must list {} entries of 0, 1 or null, duplicating stereo instead",
+                    num_channels
+                );
+            }
+            (0..num_channels)
+                .map(|ch| Some(ch % NUM_OUT_CHANNELS))
+                .collect()
+        }
+    }
+}
+
+/// Queues a device input buffer of `num_channels`-channel frames and returns the number of
+/// frames dropped because the ring buffer is full. A buffer holding a partial frame would
+/// shift all following samples into the wrong channels, so it's dropped whole and `None`
+/// is returned.
+fn submit_device_input(
+    swapchain: &AudioSwapchain,
+    prod: &mut ringbuf::HeapProd<f32>,
+    input: &[f32],
+    num_channels: usize,
+) -> Option<usize> {
+    if !input.len().is_multiple_of(num_channels) {
+        return None;
+    }
+    Some(input.len() / num_channels - swapchain.submit_input(input, prod))
+}
+
+/// Expands interleaved stereo frames into the interleaved device `output` according to `routing`.
+fn route_stereo(stereo: &[f32], output: &mut [f32], routing: &[Option<usize>]) {
+    for (out_frame, stereo_frame) in output
+        .chunks_exact_mut(routing.len())
+        .zip(stereo.chunks_exact(NUM_OUT_CHANNELS))
+    {
+        for (v, src) in out_frame.iter_mut().zip(routing) {
+            *v = src.map_or(0.0, |ch| stereo_frame[ch]);
+        }
+    }
+}
+
+/// The stereo frames played by the output callback, drained from the output ring buffer.
+/// On an underrun, the previous block is either replaced with silence or, with
+/// `UnderrunStrategy::RepeatLast`, played once more while fading out.
+struct StereoOutput {
+    /// Preallocated for as many frames as the ring buffer holds, so that the output callback
+    /// never allocates. Requests are never longer, see `max_frames`.
+    buf: Vec<f32>,
+    /// Frames of the last block.
+    num_frames: usize,
+    strategy: UnderrunStrategy,
+    /// Whether the last block was a repeat.
+    repeated: bool,
+}
+
+impl StereoOutput {
+    fn new(swapchain: &AudioSwapchain, strategy: UnderrunStrategy) -> Self {
+        Self {
+            buf: vec![0.0; swapchain.desired_rb_size()],
+            num_frames: 0,
+            strategy,
+            repeated: false,
+        }
+    }
+
+    /// Frames per `fill`, longer device buffers are filled in several parts. More could never
+    /// be drained from the ring buffer at once.
+    fn max_frames(&self) -> usize {
+        self.buf.len() / NUM_OUT_CHANNELS
+    }
+
+    /// Drains the next `num_frames` (at most `max_frames`) stereo frames and returns them.
+    fn fill(
+        &mut self,
+        swapchain: &AudioSwapchain,
+        cons: &mut ringbuf::HeapCons<f32>,
+        num_frames: usize,
+    ) -> &[f32] {
+        let prev_frames = std::mem::replace(&mut self.num_frames, num_frames);
+        let block = &mut self.buf[..num_frames * NUM_OUT_CHANNELS];
+        if swapchain.drain_output(cons, block) {
+            self.repeated = false;
+            return block;
+        }
+        UNDERRUN_COUNT.fetch_add(1, atomic::Ordering::Relaxed);
+
+        // A repeat is only seamless with a block of the same length, and repeating twice
+        // would turn a dropout into an audible loop
+        if self.strategy == UnderrunStrategy::RepeatLast
+            && !self.repeated
+            && prev_frames == num_frames
+        {
+            for (i, frame) in block.chunks_exact_mut(NUM_OUT_CHANNELS).enumerate() {
+                let gain = 1.0 - i as f32 / num_frames as f32;
+                frame.iter_mut().for_each(|v| *v *= gain);
+            }
+            REPEATED_BLOCK_COUNT.fetch_add(1, atomic::Ordering::Relaxed);
+            self.repeated = true;
+        } else {
+            block.fill(cpal::Sample::EQUILIBRIUM);
+        }
+        block
+    }
+}
+
+/// Returns the assets file name and the embedded data of the profile's EQ.
+fn get_equalizer_asset(profile: EqualizerProfile) -> Option<(&'static str, &'static [u8])> {
+    match profile {
+        EqualizerProfile::None => None,
+        EqualizerProfile::EarPods => Some(("earpods.wav", EARPODS_EQ)),
+        EqualizerProfile::AirPods4 => Some(("airpods4.wav", AIRPODS4_EQ)),
+        EqualizerProfile::K702 => Some(("k702.wav", K702_EQ)),
+        EqualizerProfile::DT770Pro => Some(("dt770pro.wav", DT770PRO_EQ)),
+    }
+}
+
+fn get_equalizer_wav(profile: EqualizerProfile) -> Option<Cow<'static, [u8]>> {
+    let eq_path = &assets::get_overrides().eq_path;
+    get_equalizer_asset(profile).map(|(file_name, embedded)| match eq_path {
+        Some(path) => assets::load_path(path, embedded),
+        None => assets::load(file_name, embedded),
+    })
+}
+
+/// Checks the files given on the command line, so that a bad one is reported at startup
+/// instead of silently falling back to the defaults.
+pub fn validate_asset_overrides(overrides: &assets::Overrides) -> Result<(), Error> {
+    if let Some(hrir_dir) = &overrides.hrir_dir {
+        if !hrir_dir.is_dir() {
+            return Err(Error::Config(format!(
+                "{} is not a directory",
+                hrir_dir.display()
+            )));
+        }
+        for (file_name, _) in HRIR_ASSETS {
+            assets::validate_wav(&hrir_dir.join(file_name), HRIR_SAMPLE_RATE, &[1, 2])?;
+        }
+        for file_name in HEIGHT_HRIR_ASSETS {
+            let path = hrir_dir.join(file_name);
+            if path.exists() {
+                assets::validate_wav(&path, HRIR_SAMPLE_RATE, &[1, 2])?;
+            }
+        }
+    }
+    if let Some(eq_path) = &overrides.eq_path {
+        assets::validate_wav(eq_path, HRIR_SAMPLE_RATE, &[1, 2])?;
+    }
+    Ok(())
+}
+
+fn load_equalizer(
+    profile: EqualizerProfile,
+    wav_data: &[u8],
+    engine: EngineConfig,
+    preamp_overrides: &HashMap<EqualizerProfile, f32>,
+    channel_mode: EqChannelMode,
+) -> Result<Equalizer, Error> {
+    let mut eq = wav_to_equalizer(wav_data, engine, channel_mode)
+        .map_err(|err| err.context(&format!("{} equalizer", profile.label())))?;
+    if let Some(preamp_db) = preamp_overrides.get(&profile) {
+        eq.set_preamp_db(*preamp_db);
+    }
+    info!(
+        "{} equalizer preamp: {:.1} dB",
+        profile.label(),
+        eq.preamp_db()
+    );
+    Ok(eq)
+}
+
+/// Builds each equalizer of a session when its profile is first selected, and again when
+/// its user asset changes, then hands it to the session's `EqualizerBank`. Runs on the
+/// thread that selects the profile or watches the assets, never on the audio thread.
+struct EqualizerLoader {
+    engine: EngineConfig,
+    preamp_overrides: HashMap<EqualizerProfile, f32>,
+    channel_mode: EqChannelMode,
+    /// Input channels equalized before virtualization, 0 if it's applied after.
+    num_channels: usize,
+    prewarm: bool,
+    /// The profiles built so far, including those that failed to load.
+    requested: Mutex<HashSet<EqualizerProfile>>,
+    sender: EqualizerSender,
+}
+
+impl EqualizerLoader {
+    fn new(config: &AppConfig, max_in_channels: usize, sender: EqualizerSender) -> Self {
+        Self {
+            engine: get_engine_config(config),
+            preamp_overrides: config.eq_preamp_db.clone(),
+            channel_mode: config.eq_channel_mode,
+            num_channels: match config.eq_position {
+                EqPosition::PreVirtualization => max_in_channels,
+                EqPosition::PostVirtualization => 0,
+            },
+            prewarm: config.prewarm_convolvers,
+            requested: Mutex::new(HashSet::new()),
+            sender,
+        }
+    }
+
+    /// Builds the equalizer of `profile` unless it was already built for this session.
+    fn request(&self, profile: EqualizerProfile) {
+        if self.requested.lock().unwrap().insert(profile) {
+            self.build(profile, "Failed to load an equalizer");
+        }
+    }
+
+    /// Rebuilds the equalizers whose user asset files changed. A broken file keeps
+    /// the previous equalizer until it's fixed.
+    fn reload(&self, changed_files: &[&str]) {
+        for profile in EqualizerProfile::iter() {
+            if let Some((file_name, _)) = get_equalizer_asset(profile)
+                && changed_files.contains(&file_name)
+            {
+                self.requested.lock().unwrap().insert(profile);
+                self.build(profile, "Failed to reload an equalizer");
+            }
+        }
+    }
+
+    fn build(&self, profile: EqualizerProfile, failure: &str) {
+        let Some(wav_data) = get_equalizer_wav(profile) else {
+            return;
+        };
+        match load_equalizer(
+            profile,
+            &wav_data,
+            self.engine,
+            &self.preamp_overrides,
+            self.channel_mode,
+        ) {
+            Ok(eq) => {
+                let mut eq = eq.with_channels(self.num_channels);
+                if self.prewarm {
+                    eq.prewarm(PREWARM_BLOCKS);
+                }
+                self.sender.send(profile, eq);
+            }
+            Err(err) => warn!("{}: {}", failure, err),
+        }
+    }
+}
+
+/// Stretches or squeezes the interleaved stereo `block` to the frame count of `out`,
+/// interpolating linearly. The first and the last frame stay in place, so that consecutive
+/// blocks join without a discontinuity, and a difference of a frame is spread over the block
+/// instead of being heard as a click.
+fn resample_block(block: &[f32], out: &mut [f32]) {
+    let in_frames = block.len() / NUM_OUT_CHANNELS;
+    let out_frames = out.len() / NUM_OUT_CHANNELS;
+    let step = if out_frames > 1 {
+        (in_frames - 1) as f64 / (out_frames - 1) as f64
+    } else {
+        0.0
     };
+    for (i, frame) in out.chunks_exact_mut(NUM_OUT_CHANNELS).enumerate() {
+        let pos = i as f64 * step;
+        let index = (pos as usize).min(in_frames - 1);
+        let next = (index + 1).min(in_frames - 1);
+        let t = (pos - index as f64) as f32;
+        for (ch, v) in frame.iter_mut().enumerate() {
+            let a = block[index * NUM_OUT_CHANNELS + ch];
+            let b = block[next * NUM_OUT_CHANNELS + ch];
+            *v = a + t * (b - a);
+        }
+    }
+}
 
-    Ok((input_dev, output_dev))
+/// Returns the highest output backlog in frames that still leaves room for a block.
+fn get_max_output_backlog(out_sw: &AudioSwapchain, block_size: usize) -> usize {
+    out_sw.desired_rb_size() / NUM_OUT_CHANNELS - block_size
 }
 
-fn start_backend(input_dev: &cpal::Device, output_dev: &cpal::Device) -> Option<SessionContext> {
+/// Returns the backlog in frames above which drift compensation drops a frame from each block,
+/// and below which it adds one, given the highest backlog that still leaves room for a block.
+/// The band is centered, so that the backlog can move as far either way before a correction,
+/// and is wide enough for the sawtooth of the device callbacks not to trigger one.
+/// `None` if `max_backlog` is too small for such a band.
+fn get_drift_band(max_backlog: usize) -> Option<(f32, f32)> {
+    let width = (max_backlog as f32 * DRIFT_BAND_FRACTION).max(MIN_DRIFT_BAND_FRAMES as f32);
+    if width >= max_backlog as f32 {
+        return None;
+    }
+    let center = max_backlog as f32 / 2.0;
+    Some((center - width / 2.0, center + width / 2.0))
+}
+
+/// Returns `AppConfig::output_stages` without repeats. The equalizer is left out with
+/// `EqPosition::PreVirtualization`, as it's applied to the input channels instead.
+fn output_stage_sequence(config: &AppConfig) -> Vec<OutputStage> {
+    let mut sequence = Vec::new();
+    for &stage in &config.output_stages {
+        if sequence.contains(&stage) {
+            warn!("Ignoring the repeated output stage {:?}", stage);
+            continue;
+        }
+        if stage == OutputStage::Equalizer && config.eq_position == EqPosition::PreVirtualization {
+            continue;
+        }
+        sequence.push(stage);
+    }
+    sequence
+}
+
+/// Builds the stages of `AppConfig::output_stages`, followed by the subsonic filter if enabled.
+/// The smoothed gains start from `params`.
+fn build_output_chain(
+    config: &AppConfig,
+    mut post_equalizer: Option<EqualizerBank>,
+    params: &RuntimeParams,
+) -> ProcessingChain {
+    let new_smoother = |initial| Smoother::new(HRIR_SAMPLE_RATE, config.gain_smoothing_ms, initial);
+    let mut stages: Vec<Box<dyn StereoStage>> = Vec::new();
+    // Ahead of the master gain, so that it doesn't undo the user's volume changes
+    let mut auto_gain = config.auto_gain.then(|| {
+        AutoGain::new(
+            HRIR_SAMPLE_RATE,
+            config.auto_gain_target_lufs,
+            config.auto_gain_max_db,
+            config.auto_gain_response_ms,
+            new_smoother(1.0),
+        )
+    });
+
+    for stage in output_stage_sequence(config) {
+        match stage {
+            OutputStage::Equalizer => {
+                if let Some(bank) = post_equalizer.take() {
+                    stages.push(Box::new(bank));
+                }
+            }
+            OutputStage::CrosstalkCanceller => {
+                match CrosstalkCanceller::new(get_engine_config(config), config.speaker_span_deg) {
+                    Ok(xtc) => stages.push(Box::new(xtc)),
+                    Err(err) => warn!("Skipping the crosstalk canceller: {}", err),
+                }
+            }
+            OutputStage::MonoOutput => stages.push(Box::new(MonoOutput)),
+            OutputStage::MasterGain => {
+                if let Some(auto_gain) = auto_gain.take() {
+                    stages.push(Box::new(auto_gain));
+                }
+                stages.push(Box::new(MasterGain::new(new_smoother(db_to_gain(
+                    params.master_gain_db,
+                )))));
+            }
+            OutputStage::Balance => stages.push(Box::new(Balance::new(
+                params.balance_db.map(|db| new_smoother(db_to_gain(db))),
+            ))),
+        }
+    }
+
+    if let Some(auto_gain) = auto_gain {
+        stages.push(Box::new(auto_gain));
+    }
+    // Last, so that it also catches offsets added by the earlier stages
+    if config.subsonic_filter {
+        stages.push(Box::new(SubsonicFilter::new(
+            HRIR_SAMPLE_RATE,
+            config.subsonic_cutoff_hz,
+        )));
+    }
+    ProcessingChain::new(stages)
+}
+
+/// Builds the equalizer of the configured profile, `None` for `EqualizerProfile::None`.
+pub fn build_equalizer(config: &AppConfig) -> Result<Option<Equalizer>, Error> {
+    let profile = config.equalizer_profile;
+    get_equalizer_wav(profile)
+        .map(|wav_data| {
+            load_equalizer(
+                profile,
+                &wav_data,
+                get_engine_config(config),
+                &config.eq_preamp_db,
+                config.eq_channel_mode,
+            )
+        })
+        .transpose()
+}
+
+fn start_backend(
+    input_dev: Option<&cpal::Device>,
+    output_dev: &cpal::Device,
+    input_is_fallback: bool,
+    config: &AppConfig,
+) -> Option<SessionContext> {
     let reload_signal = Arc::new(Signal::new());
+    let engine = get_engine_config(config);
 
-    let in_dev_name = input_dev
-        .description()
-        .map(|desc| desc.name().to_string())
-        .unwrap_or_default();
+    let in_dev_name = match input_dev {
+        Some(input_dev) => input_dev
+            .description()
+            .map(|desc| desc.name().to_string())
+            .unwrap_or_default(),
+        None => format!("Network {}", config.network_input_addr),
+    };
     let out_dev_name = output_dev
         .description()
         .map(|desc| desc.name().to_string())
         .unwrap_or_default();
 
-    let virt_config = SurroundVirtualizerConfig {
-        fc_wav: FC_WAV,
-        bl_wav: BL_WAV,
-        br_wav: BR_WAV,
-        fl_wav: FL_WAV,
-        fr_wav: FR_WAV,
-        sl_wav: SL_WAV,
-        sr_wav: SR_WAV,
-        lfe_wav: LFE_WAV,
-        block_size: CH_BUF_SIZE,
+    let hog_mode = config
+        .exclusive_output
+        .then(|| match coreaudio::HogMode::acquire(&out_dev_name) {
+            Ok(hog_mode) => {
+                info!("Output device opened in exclusive mode");
+                Some(hog_mode)
+            }
+            Err(err) => {
+                info!("Exclusive mode unavailable, using shared mode: {}", err);
+                None
+            }
+        })
+        .flatten();
+
+    let hrirs = load_hrirs();
+    // Keep audio flowing with a broken HRIR set, the user can fix it while listening
+    let mut sv = match SurroundVirtualizer::new(&get_virtualizer_config(config, &hrirs)) {
+        Ok(sv) => Some(sv),
+        Err(err) => {
+            warn!("Failed to load the HRIRs ({err}), falling back to a plain stereo downmix");
+            None
+        }
     };
-    let mut sv = SurroundVirtualizer::new(&virt_config);
+    if config.prewarm_convolvers
+        && let Some(sv) = &mut sv
+    {
+        sv.prewarm(PREWARM_BLOCKS);
+    }
+    let is_passthrough = sv.is_none();
 
-    let mut eq_earpods = Equalizer::new(CH_BUF_SIZE, wav_to_pcm(EARPODS_EQ));
-    let mut eq_airpods4 = Equalizer::new(CH_BUF_SIZE, wav_to_pcm(AIRPODS4_EQ));
-    let mut eq_k702 = Equalizer::new(CH_BUF_SIZE, wav_to_pcm(K702_EQ));
-    let mut eq_dt770pro = Equalizer::new(CH_BUF_SIZE, wav_to_pcm(DT770PRO_EQ));
+    let max_in_channels = if config.height_channels {
+        NUM_SURROUND_CHANNELS + NUM_HEIGHT_CHANNELS
+    } else {
+        NUM_SURROUND_CHANNELS
+    };
 
-    let input_selection = input_dev
-        .supported_input_configs()
-        .unwrap()
-        .filter(|conf| {
-            (conf.min_sample_rate() <= HRIR_SAMPLE_RATE)
-                && (conf.max_sample_rate() >= HRIR_SAMPLE_RATE)
-        })
-        .map(|conf| {
-            let buf_sz = match conf.buffer_size() {
-                cpal::SupportedBufferSize::Range { min, max } => {
-                    CH_BUF_SIZE.clamp(*min as usize, *max as usize)
+    let (eq_bank, eq_sender) = equalizer_bank();
+    let eq_loader = Arc::new(EqualizerLoader::new(config, max_in_channels, eq_sender));
+    // Upfront, so that the first blocks are already equalized
+    eq_loader.request(get_params().equalizer_profile);
+    let (pre_equalizer, post_equalizer) = match config.eq_position {
+        EqPosition::PreVirtualization => (Some(eq_bank), None),
+        EqPosition::PostVirtualization => (None, Some(eq_bank)),
+    };
+    let mut num_requested_in_channels = config
+        .input_channels
+        .map_or(max_in_channels, |n| (n as usize).clamp(1, max_in_channels));
+
+    let input_selection = match input_dev {
+        Some(input_dev) => {
+            // Flaky drivers may fail to report their configs,
+            // restart with backoff instead of panicking
+            let mut input_configs: Vec<SupportedConfig> = match input_dev.supported_input_configs()
+            {
+                Ok(configs) => configs.map(|conf| SupportedConfig::from(&conf)).collect(),
+                Err(err) => {
+                    warn!("Error: Failed to query configs of input device '{in_dev_name}': {err}");
+                    reload_signal.notify();
+                    return None;
                 }
-                _ => CH_BUF_SIZE,
             };
-            let ch = conf.channels();
-            (buf_sz, ch)
-        })
-        .min_by_key(|(buf_sz, ch)| {
-            let dist_ch = (*ch as isize - NUM_SURROUND_CHANNELS as isize).abs();
-            (dist_ch, (*buf_sz as isize - CH_BUF_SIZE as isize).abs())
-        });
+            if config.input_channels.is_some() {
+                let exact_configs: Vec<SupportedConfig> = input_configs
+                    .iter()
+                    .filter(|conf| conf.channels as usize == num_requested_in_channels)
+                    .copied()
+                    .collect();
+                if exact_configs.is_empty() {
+                    warn!(
+                        "Input device '{}' doesn't support {} channels, choosing automatically",
+                        in_dev_name, num_requested_in_channels
+                    );
+                    num_requested_in_channels = max_in_channels;
+                } else {
+                    input_configs = exact_configs;
+                }
+            }
+            select_stream_config(
+                &input_configs,
+                HRIR_SAMPLE_RATE,
+                engine.block_size,
+                num_requested_in_channels as u16,
+            )
+        }
+        // The sender's channels are mapped onto the requested ones, a block at a time
+        None => Some(ChosenConfig {
+            buffer_size: engine.block_size,
+            channels: num_requested_in_channels as u16,
+            sample_format: cpal::SampleFormat::F32,
+        }),
+    };
 
-    let output_buf_size = output_dev
-        .supported_output_configs()
-        .unwrap()
+    let output_routing = get_output_routing(config);
+    let output_configs = match output_dev.supported_output_configs() {
+        Ok(configs) => configs,
+        Err(err) => {
+            warn!("Error: Failed to query configs of output device '{out_dev_name}': {err}");
+            reload_signal.notify();
+            return None;
+        }
+    };
+    let output_configs: Vec<SupportedConfig> = output_configs
+        .map(|conf| SupportedConfig::from(&conf))
         .filter(|conf| {
-            conf.channels() >= NUM_OUT_CHANNELS as u16
-                && (conf.min_sample_rate() <= HRIR_SAMPLE_RATE)
-                && (conf.max_sample_rate() >= HRIR_SAMPLE_RATE)
-        })
-        .map(|conf| match conf.buffer_size() {
-            cpal::SupportedBufferSize::Range { min, max } => {
-                CH_BUF_SIZE.clamp(*min as usize, *max as usize)
-            }
-            _ => CH_BUF_SIZE,
+            conf.channels >= output_routing.len() as u16
+                && matches!(
+                    conf.sample_format,
+                    cpal::SampleFormat::F32 | cpal::SampleFormat::I16
+                )
         })
-        .min_by_key(|buf_size| (*buf_size as isize - CH_BUF_SIZE as isize).abs());
+        .collect();
+    let output_selection = select_stream_config(
+        &output_configs,
+        HRIR_SAMPLE_RATE,
+        engine.block_size,
+        output_routing.len() as u16,
+    );
 
-    let Some((input_buf_size, in_selected_channels)) = input_selection else {
+    let Some(input_selection) = input_selection else {
         warn!("Error: No supported input config found for device '{in_dev_name}'",);
         reload_signal.notify();
         return None;
     };
-    let Some(output_buf_size) = output_buf_size else {
+    let Some(output_selection) = output_selection else {
         warn!("Error: No supported output config found for device '{out_dev_name}'",);
         reload_signal.notify();
         return None;
     };
 
     let in_config = cpal::StreamConfig {
-        channels: in_selected_channels.min(NUM_SURROUND_CHANNELS as u16),
+        channels: input_selection
+            .channels
+            .min(num_requested_in_channels as u16),
         sample_rate: HRIR_SAMPLE_RATE,
-        buffer_size: cpal::BufferSize::Fixed(input_buf_size as u32),
+        buffer_size: cpal::BufferSize::Fixed(input_selection.buffer_size as u32),
     };
+    info!(
+        "Input: {} of the device's {} channels",
+        in_config.channels, input_selection.channels
+    );
 
     let out_config = cpal::StreamConfig {
-        channels: NUM_OUT_CHANNELS as u16,
+        channels: output_routing.len() as u16,
         sample_rate: HRIR_SAMPLE_RATE,
-        buffer_size: cpal::BufferSize::Fixed(output_buf_size as u32),
+        buffer_size: cpal::BufferSize::Fixed(output_selection.buffer_size as u32),
     };
 
-    let in_sw = Arc::new(AudioSwapchain::<NUM_SURROUND_CHANNELS>::new(
-        CH_BUF_SIZE * in_config.channels as usize,
-        input_buf_size * in_config.channels as usize,
-        1,
+    let in_channels = in_config.channels as usize;
+    let in_sw = Arc::new(AudioSwapchain::new(
+        in_channels,
+        engine.block_size * in_config.channels as usize,
+        input_selection.buffer_size * in_config.channels as usize,
+        // The DSP thread needs slack to pick up a block while the next one is captured
+        if config.dsp_thread { 2 } else { 1 },
     ));
     let (mut in_rb_prod, mut in_rb_cons) =
-        ringbuf::HeapRb::<AFrame<{ NUM_SURROUND_CHANNELS as usize }>>::new(
-            in_sw.desired_rb_size() / in_config.channels as usize,
-        )
-        .split();
+        ringbuf::HeapRb::<f32>::new(in_sw.desired_rb_size()).split();
 
-    let out_sw = Arc::new(AudioSwapchain::<NUM_OUT_CHANNELS>::new(
-        CH_BUF_SIZE * NUM_OUT_CHANNELS as usize,
-        output_buf_size * NUM_OUT_CHANNELS as usize,
-        3,
+    let out_sw = Arc::new(AudioSwapchain::new(
+        NUM_OUT_CHANNELS,
+        engine.block_size * NUM_OUT_CHANNELS as usize,
+        output_selection.buffer_size * NUM_OUT_CHANNELS as usize,
+        config
+            .output_buffer_packets
+            .clamp(MIN_OUTPUT_BUFFER_PACKETS, MAX_OUTPUT_BUFFER_PACKETS),
     ));
-    let (mut out_rb_prod, mut out_rb_cons) = ringbuf::HeapRb::<AFrame<NUM_OUT_CHANNELS>>::new(
-        out_sw.desired_rb_size() / NUM_OUT_CHANNELS,
-    )
-    .split();
+    let (mut out_rb_prod, mut out_rb_cons) =
+        ringbuf::HeapRb::<f32>::new(out_sw.desired_rb_size()).split();
 
-    // first create the output stream to reduce glitches at startup
+    let underrun_strategy = config.underrun_strategy;
     let reload_sig2 = Arc::clone(&reload_signal);
-    let out_stream = output_dev
-        .build_output_stream(
-            out_config,
-            move |output: &mut [f32], _| {
-                // CoreAudio may hand us a buffer whose length differs from the requested
-                // size (e.g. when it resamples between the device's native rate and our
-                // stream rate), so drain to fit whatever length it actually asks for.
-                if !AudioSwapchain::drain_output(&mut out_rb_cons, output) {
-                    output.fill(cpal::Sample::EQUILIBRIUM);
-                }
-            },
-            move |err| {
-                warn!("Output error: {}", err);
-                reload_sig2.notify();
-            },
-            Some(Duration::from_millis(AUDIO_BACKEND_TIMEOUT_MS)),
-        )
-        .unwrap();
+    let out_sw2 = Arc::clone(&out_sw);
+    let build_out_stream = move || match output_selection.sample_format {
+        cpal::SampleFormat::I16 => {
+            let mut dither = config.dither_output.then(Dither::new);
+            let mut stereo_output = StereoOutput::new(&out_sw2, underrun_strategy);
+            let mut output_f32 = vec![0.0; stereo_output.max_frames() * output_routing.len()];
+            output_dev.build_output_stream(
+                out_config,
+                move |output: &mut [i16], _| {
+                    let max_samples = stereo_output.max_frames() * output_routing.len();
+                    for output in output.chunks_mut(max_samples) {
+                        let num_frames = output.len() / output_routing.len();
+                        let stereo = stereo_output.fill(&out_sw2, &mut out_rb_cons, num_frames);
+                        let output_f32 = &mut output_f32[..output.len()];
+                        route_stereo(stereo, output_f32, &output_routing);
+                        dither::quantize_i16(output_f32, output, dither.as_mut());
+                    }
+                },
+                move |err| {
+                    warn!("Output error: {}", err);
+                    reload_sig2.notify();
+                },
+                Some(Duration::from_millis(AUDIO_BACKEND_TIMEOUT_MS)),
+            )
+        }
+        _ => {
+            let mut stereo_output = StereoOutput::new(&out_sw2, underrun_strategy);
+            output_dev.build_output_stream(
+                out_config,
+                move |output: &mut [f32], _| {
+                    // CoreAudio may hand us a buffer whose length differs from the requested
+                    // size (e.g. when it resamples between the device's native rate and our
+                    // stream rate), so drain to fit whatever length it actually asks for.
+                    let max_samples = stereo_output.max_frames() * output_routing.len();
+                    for output in output.chunks_mut(max_samples) {
+                        let num_frames = output.len() / output_routing.len();
+                        let stereo = stereo_output.fill(&out_sw2, &mut out_rb_cons, num_frames);
+                        route_stereo(stereo, output, &output_routing);
+                    }
+                },
+                move |err| {
+                    warn!("Output error: {}", err);
+                    reload_sig2.notify();
+                },
+                Some(Duration::from_millis(AUDIO_BACKEND_TIMEOUT_MS)),
+            )
+        }
+    };
 
     let aq = Arc::clone(&out_sw);
+    let in_sw2 = Arc::clone(&in_sw);
+    let first_block_ready = Arc::new(AtomicBool::new(false));
+    let first_block_ready2 = Arc::clone(&first_block_ready);
     let reload_sig1 = Arc::clone(&reload_signal);
     let reload_sig2 = Arc::clone(&reload_signal);
     let mut consecutive_output_drops: u32 = 0;
-    let in_stream = input_dev
-        .build_input_stream(
-            in_config,
-            move |input: &[f32], _| {
-                let num_frames_pushed = AudioSwapchain::submit_input(input, &mut in_rb_prod);
-                if num_frames_pushed < input.len() / in_config.channels as usize {
-                    execute_sampled!(Duration::from_secs(5), {
-                        warn!(
-                            "Warning: dropped {} frames due to full input ringbuffer",
-                            (input.len() / in_config.channels as usize) - num_frames_pushed
-                        );
-                    });
-                }
+    let block_duration =
+        Duration::from_secs_f64(engine.block_size as f64 / HRIR_SAMPLE_RATE as f64);
+    let mut dsp_load_avg: f32 = 0.0;
+    let mut fade_in_frames = HRIR_SAMPLE_RATE as usize * STARTUP_FADE_IN_MS / 1000;
+    let resume_fade_in_frames = HRIR_SAMPLE_RATE as usize * IDLE_RESUME_FADE_IN_MS / 1000;
+    let mut fade_in_frames_left = fade_in_frames;
+    let mut is_first_block = true;
+    let mut local_params = LocalParams::new();
+    // Start from the current values so that a restart doesn't ramp them in again
+    let output_chain = build_output_chain(config, post_equalizer, &local_params.params);
+    let mut dsp_core = DspCore::new(
+        engine,
+        &get_dsp_core_options(config),
+        in_config.channels as usize,
+        sv,
+        pre_equalizer,
+        output_chain,
+        &local_params.params,
+    );
+    let max_backlog = get_max_output_backlog(&out_sw, engine.block_size);
+    let drift_band = if config.drift_compensation {
+        let band = get_drift_band(max_backlog);
+        if band.is_none() {
+            warn!("The output buffer is too small for drift compensation, which stays disabled");
+        }
+        band
+    } else {
+        None
+    };
+    let mut drift_buf = vec![0.0; (engine.block_size + 1) * NUM_OUT_CHANNELS];
+    let mut backlog_avg = max_backlog as f32 / 2.0;
+    let auto_protect = config.auto_protect;
+    let mut test_tone = TestTone::new(HRIR_SAMPLE_RATE);
+    let mut test_tone_buf = vec![0.0; engine.block_size * NUM_SURROUND_CHANNELS];
+    let mut consecutive_clipping_blocks: u32 = 0;
+    let mut idle_gate = config.idle_gate.then(|| {
+        IdleGate::new(
+            HRIR_SAMPLE_RATE,
+            config.idle_threshold_db,
+            config.idle_timeout_ms,
+        )
+    });
+    let mut is_idle = false;
+    let mut noise_gate = config.noise_gate.then(|| {
+        NoiseGate::new(
+            HRIR_SAMPLE_RATE,
+            in_channels,
+            config.noise_gate_threshold_db,
+            config.noise_gate_attack_ms,
+            config.noise_gate_hold_ms,
+            config.noise_gate_release_ms,
+            config.noise_gate_per_channel,
+        )
+    });
+    BACKLOG_FRAMES.store(0, atomic::Ordering::Relaxed);
+    DSP_LOAD.store(0.0_f32.to_bits(), atomic::Ordering::Relaxed);
+    let mut loudness_meter = LoudnessMeter::new(HRIR_SAMPLE_RATE);
+    let mut level_peak: f32 = 0.0;
+    let mut level_power: f64 = 0.0;
+    let mut level_num_samples: usize = 0;
+    OUTPUT_PEAK_DB.store(f32::NEG_INFINITY.to_bits(), atomic::Ordering::Relaxed);
+    OUTPUT_RMS_DB.store(f32::NEG_INFINITY.to_bits(), atomic::Ordering::Relaxed);
+    SHORT_TERM_LUFS.store(f32::NEG_INFINITY.to_bits(), atomic::Ordering::Relaxed);
+    INTEGRATED_LUFS.store(f32::NEG_INFINITY.to_bits(), atomic::Ordering::Relaxed);
 
-                let Some(input) = in_sw.acquire_ready_output_buf(&mut in_rb_cons) else {
-                    return;
-                };
-
-                let Some(mut buf) = aq.acquire_free_input_buf() else {
-                    return;
-                };
-
-                let in_ch = in_config.channels as usize;
-                let input_adata = AudioDataRef::new(input.data(), in_ch);
-                let mut stereo_adata =
-                    AudioDataMut::new(buf.data_mut(), out_config.channels as usize);
-
-                let current_source_mode = CURRENT_SOURCE_MODE.load(atomic::Ordering::Relaxed);
-                match AudioSourceMode::from_u32(current_source_mode)
-                    .unwrap_or(AudioSourceMode::Universal)
-                {
-                    AudioSourceMode::Universal => {
-                        if in_ch >= NUM_SURROUND_CHANNELS as usize {
-                            sv.process_ch8(&input_adata, &mut stereo_adata);
-                        } else if in_ch >= 2 {
-                            sv.process_ch2(&input_adata, &mut stereo_adata);
-                        } else {
-                            sv.process_mono(&input_adata, &mut stereo_adata);
-                        }
-                    }
-                    AudioSourceMode::Stereo => {
-                        if in_ch >= 2 {
-                            sv.process_ch2(&input_adata, &mut stereo_adata);
-                        } else {
-                            sv.process_mono(&input_adata, &mut stereo_adata);
-                        }
-                    }
-                    AudioSourceMode::Mono => {
-                        sv.process_mono(&input_adata, &mut stereo_adata);
-                    }
-                }
+    // Processes one block if a full input block is available, returns whether it did.
+    let process_block = move || -> bool {
+        let Some(mut input) = in_sw.acquire_ready_output_buf(&mut in_rb_cons) else {
+            return false;
+        };
 
-                let current_profile = CURRENT_EQ_PROFILE.load(atomic::Ordering::Relaxed);
-                match EqualizerProfile::from_u32(current_profile).unwrap_or(EqualizerProfile::None)
-                {
-                    EqualizerProfile::EarPods => eq_earpods.process(&mut stereo_adata),
-                    EqualizerProfile::AirPods4 => eq_airpods4.process(&mut stereo_adata),
-                    EqualizerProfile::K702 => eq_k702.process(&mut stereo_adata),
-                    EqualizerProfile::DT770Pro => eq_dt770pro.process(&mut stereo_adata),
-                    _ => {}
-                }
+        let Some(mut buf) = aq.acquire_free_input_buf() else {
+            return false;
+        };
+
+        // Before the idle gate, so that gated hiss also counts as silence
+        if let Some(gate) = &mut noise_gate {
+            gate.process(input.data_mut());
+        }
+
+        let process_start = Instant::now();
+
+        // One coherent snapshot for the whole block
+        let params = *local_params.refresh();
 
-                let num_frames_pushed = AudioSwapchain::submit_input(buf.data(), &mut out_rb_prod);
-                if num_frames_pushed < buf.data().len() / NUM_OUT_CHANNELS {
-                    consecutive_output_drops += 1;
-                    execute_sampled!(Duration::from_secs(5), {
-                        warn!(
-                            "Warning: dropped {} frames due to full output ringbuffer ({} consecutive)",
-                            (buf.data().len() / NUM_OUT_CHANNELS) - num_frames_pushed,
-                            consecutive_output_drops
-                        );
-                    });
-                    if consecutive_output_drops >= 10 {
-                        warn!("Output ringbuffer consistently full, likely no audio output available, reloading backend");
-                        consecutive_output_drops = 0;
-                        reload_sig1.notify();
+        let was_idle = is_idle;
+        // The gate sees every block, so that it knows how long the input has been silent
+        is_idle = idle_gate
+            .as_mut()
+            .is_some_and(|gate| gate.update(input.data(), engine.block_size))
+            && !test_tone.is_active()
+            && TEST_TONE_REQUEST.load(atomic::Ordering::Relaxed) == 0;
+        if was_idle && !is_idle {
+            fade_in_frames = resume_fade_in_frames;
+            fade_in_frames_left = fade_in_frames;
+        }
+
+        if is_idle {
+            // Silent input renders to (near) silence, so the convolutions can be skipped
+            buf.data_mut().fill(0.0);
+        } else {
+            let input_adata = AudioDataRef::new(input.data(), in_config.channels as usize);
+            let mut stereo_adata = AudioDataMut::new(buf.data_mut(), NUM_OUT_CHANNELS);
+
+            let test_tone_request = TEST_TONE_REQUEST.swap(0, atomic::Ordering::Relaxed);
+            if test_tone_request != 0 {
+                test_tone.start(test_tone_request);
+            }
+            if test_tone.is_active() {
+                // The input block is still consumed to keep the input ringbuffer flowing
+                test_tone.fill(&mut test_tone_buf, NUM_SURROUND_CHANNELS);
+                let tone_adata = AudioDataRef::new(&test_tone_buf, NUM_SURROUND_CHANNELS);
+                dsp_core.process_test_tone(&tone_adata, &mut stereo_adata, &params);
+            } else {
+                dsp_core.process_block(&input_adata, &mut stereo_adata, &params);
+            }
+
+            // Ramp the output up after each (re)start to avoid a thump
+            if fade_in_frames_left > 0 {
+                for frame in stereo_adata.data.chunks_exact_mut(NUM_OUT_CHANNELS) {
+                    let gain = 1.0 - fade_in_frames_left as f32 / fade_in_frames as f32;
+                    for v in frame {
+                        *v *= gain;
                     }
-                } else {
-                    consecutive_output_drops = 0;
+                    fade_in_frames_left = fade_in_frames_left.saturating_sub(1);
                 }
-            },
-            move |err| {
-                warn!("Input error: {}", err);
-                reload_sig2.notify();
-            },
-            Some(Duration::from_millis(AUDIO_BACKEND_TIMEOUT_MS)),
+            }
+        }
+
+        let load = process_start.elapsed().as_secs_f32() / block_duration.as_secs_f32();
+        dsp_load_avg += (load * 100.0 - dsp_load_avg) * DSP_LOAD_SMOOTHING;
+        if is_first_block {
+            is_first_block = false;
+            info!(
+                "First block processed in {:.2} ms ({:.0}% of the block duration)",
+                process_start.elapsed().as_secs_f64() * 1000.0,
+                load * 100.0
+            );
+        }
+        execute_sampled!(Duration::from_millis(500), {
+            DSP_LOAD.store(dsp_load_avg.to_bits(), atomic::Ordering::Relaxed);
+        });
+
+        // Never let a corrupt impulse or a numerical blow-up reach the device
+        let mut num_non_finite = 0;
+        for v in buf.data_mut() {
+            if !v.is_finite() {
+                *v = 0.0;
+                num_non_finite += 1;
+            }
+        }
+        if num_non_finite > 0 {
+            NON_FINITE_SAMPLES.fetch_add(num_non_finite, atomic::Ordering::Relaxed);
+            execute_sampled!(Duration::from_secs(5), {
+                warn!(
+                    "Warning: replaced {} non-finite output samples",
+                    num_non_finite
+                );
+            });
+        }
+
+        let peak = buf.data().iter().fold(0.0_f32, |peak, v| peak.max(v.abs()));
+        if peak >= 1.0 {
+            consecutive_clipping_blocks += 1;
+        } else {
+            consecutive_clipping_blocks = 0;
+        }
+        level_peak = level_peak.max(peak);
+        level_power += buf.data().iter().map(|v| (v * v) as f64).sum::<f64>();
+        level_num_samples += buf.data().len();
+        execute_sampled!(Duration::from_millis(500), {
+            let rms = (level_power / level_num_samples as f64).sqrt() as f32;
+            OUTPUT_PEAK_DB.store(gain_to_db(level_peak).to_bits(), atomic::Ordering::Relaxed);
+            OUTPUT_RMS_DB.store(gain_to_db(rms).to_bits(), atomic::Ordering::Relaxed);
+            level_peak = 0.0;
+            level_power = 0.0;
+            level_num_samples = 0;
+        });
+
+        if consecutive_clipping_blocks == SUSTAINED_CLIPPING_BLOCKS {
+            let now_ms = BACKEND_EPOCH.elapsed().as_millis() as u64;
+            LAST_CLIPPING_MS.store(now_ms, atomic::Ordering::Relaxed);
+            LAST_CLIPPING_PEAK_DB.store(gain_to_db(peak).to_bits(), atomic::Ordering::Relaxed);
+            if auto_protect && local_params.params.master_gain_db > MIN_AUTO_PROTECT_GAIN_DB {
+                let new_gain_db = (local_params.params.master_gain_db - AUTO_PROTECT_STEP_DB)
+                    .max(MIN_AUTO_PROTECT_GAIN_DB);
+                local_params.lower_master_gain_db(new_gain_db);
+                AUTO_PROTECT_COUNT.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            CLIPPING_COUNT.fetch_add(1, atomic::Ordering::Relaxed);
+            consecutive_clipping_blocks = 0;
+        }
+
+        loudness_meter.process(buf.data());
+        execute_sampled!(Duration::from_millis(500), {
+            let short_term = loudness_meter.short_term_lufs();
+            SHORT_TERM_LUFS.store(short_term.to_bits(), atomic::Ordering::Relaxed);
+            let integrated = loudness_meter.integrated_lufs();
+            INTEGRATED_LUFS.store(integrated.to_bits(), atomic::Ordering::Relaxed);
+        });
+
+        if ANALYSIS_ENABLED.load(atomic::Ordering::Relaxed) {
+            OUTPUT_ANALYSIS_TAP.publish(buf.data());
+        }
+
+        let backlog_frames = aq.occupied_frames(&out_rb_prod);
+        BACKLOG_FRAMES.store(backlog_frames as u64, atomic::Ordering::Relaxed);
+        backlog_avg += BACKLOG_SMOOTHING * (backlog_frames as f32 - backlog_avg);
+        execute_sampled!(Duration::from_secs(60), {
+            info!(
+                "Output backlog: {} frames (average {:.0})",
+                backlog_frames, backlog_avg
+            );
+        });
+
+        // The input and output clocks drift apart over long sessions: resample the block
+        // to one frame less or more to pull the backlog back between the thresholds.
+        let mut block = buf.data();
+        if let Some((backlog_low, backlog_high)) = drift_band {
+            let num_frames = block.len() / NUM_OUT_CHANNELS;
+            let adjusted_frames = if backlog_avg > backlog_high {
+                Some(num_frames - 1)
+            } else if backlog_avg < backlog_low {
+                Some(num_frames + 1)
+            } else {
+                None
+            };
+            if let Some(adjusted_frames) = adjusted_frames {
+                let adjusted = &mut drift_buf[..adjusted_frames * NUM_OUT_CHANNELS];
+                resample_block(block, adjusted);
+                block = adjusted;
+            }
+        }
+
+        let num_frames = block.len() / NUM_OUT_CHANNELS;
+        let num_frames_pushed = aq.submit_input(block, &mut out_rb_prod);
+        if num_frames_pushed < num_frames {
+            consecutive_output_drops += 1;
+            OVERFLOW_COUNT.fetch_add(1, atomic::Ordering::Relaxed);
+            execute_sampled!(Duration::from_secs(5), {
+                warn!(
+                    "Warning: dropped {} frames due to full output ringbuffer ({} consecutive)",
+                    num_frames - num_frames_pushed,
+                    consecutive_output_drops
+                );
+            });
+            if consecutive_output_drops >= 10 {
+                warn!(
+                    "Output ringbuffer consistently full, likely no audio output available, reloading backend"
+                );
+                consecutive_output_drops = 0;
+                reload_sig1.notify();
+            }
+        } else {
+            consecutive_output_drops = 0;
+        }
+        first_block_ready2.store(true, atomic::Ordering::Release);
+
+        true
+    };
+
+    let (mut inline_dsp, dsp_thread) = if config.dsp_thread {
+        (None, Some(DspThread::spawn(process_block, block_duration)))
+    } else {
+        (Some(process_block), None)
+    };
+    let dsp_waker = dsp_thread.as_ref().map(DspThread::waker);
+    let reload_sig3 = Arc::clone(&reload_signal);
+    let mut input_recorder: Option<InputRecorder> = None;
+
+    let mut on_input = move |input: &[f32]| {
+        mark_input_received();
+        // Let the reload negotiate the stream again
+        let Some(num_frames_dropped) =
+            submit_device_input(&in_sw2, &mut in_rb_prod, input, in_channels)
+        else {
+            execute_sampled!(Duration::from_secs(5), {
+                warn!(
+                    "Warning: input buffer of {} samples doesn't hold whole {}-channel frames, reloading backend",
+                    input.len(),
+                    in_channels
+                );
+            });
+            reload_sig3.notify();
+            return;
+        };
+        if num_frames_dropped > 0 {
+            OVERFLOW_COUNT.fetch_add(1, atomic::Ordering::Relaxed);
+            execute_sampled!(Duration::from_secs(5), {
+                warn!(
+                    "Warning: dropped {} frames due to full input ringbuffer",
+                    num_frames_dropped
+                );
+            });
+        }
+
+        if let Ok(recorder) = INPUT_RECORDER_REQUEST.pop() {
+            // The session may have been reloaded with another layout since the request
+            if recorder.num_channels() == in_channels {
+                input_recorder = Some(recorder);
+            } else {
+                warn!("Discarding the input recording, the input channel count has changed");
+            }
+        }
+        if let Some(recorder) = &mut input_recorder
+            && !recorder.submit(input)
+        {
+            input_recorder = None;
+        }
+
+        if let Some(process_block) = &mut inline_dsp {
+            // A device buffer larger than the block may complete several blocks at once
+            while process_block() {}
+        } else if let Some(dsp_waker) = &dsp_waker {
+            dsp_waker.unpark();
+        }
+    };
+    let network_input_addr = config.network_input_addr.clone();
+    let build_in_stream = move || match input_dev {
+        Some(input_dev) => input_dev
+            .build_input_stream(
+                in_config,
+                move |input: &[f32], _| on_input(input),
+                move |err| {
+                    warn!("Input error: {}", err);
+                    reload_sig2.notify();
+                },
+                Some(Duration::from_millis(AUDIO_BACKEND_TIMEOUT_MS)),
+            )
+            .map(InputStream::Device)
+            .map_err(|err| err.to_string()),
+        None => NetworkReceiver::start(
+            &network_input_addr,
+            in_channels,
+            HRIR_SAMPLE_RATE,
+            engine.block_size,
+            on_input,
         )
-        .unwrap();
+        .map(InputStream::Network),
+    };
+
+    let (out_stream, in_stream) = match config.stream_creation_order {
+        StreamCreationOrder::OutputFirst => {
+            let out_stream = build_out_stream();
+            (out_stream, build_in_stream())
+        }
+        StreamCreationOrder::InputFirst => {
+            let in_stream = build_in_stream();
+            (build_out_stream(), in_stream)
+        }
+    };
+    let out_stream = match out_stream {
+        Ok(stream) => stream,
+        Err(err) => {
+            warn!("Error: Failed to open output device '{out_dev_name}': {err}");
+            reload_signal.notify();
+            return None;
+        }
+    };
+    let in_stream = match in_stream {
+        Ok(stream) => stream,
+        Err(err) => {
+            warn!("Error: Failed to open input device '{in_dev_name}': {err}");
+            reload_signal.notify();
+            return None;
+        }
+    };
 
-    if out_stream.play().is_err() {
-        warn!("Failed to play output stream");
-        reload_signal.notify();
-    }
     if in_stream.play().is_err() {
         warn!("Failed to play input stream");
         reload_signal.notify();
     }
+    // Hold the output back until it has something to play, so that it doesn't start
+    // with a burst of underruns. A dead input is left to the stall check.
+    let wait_start = Instant::now();
+    while !first_block_ready.load(atomic::Ordering::Acquire) {
+        if wait_start.elapsed() >= FIRST_BLOCK_TIMEOUT {
+            warn!(
+                "No input block processed after {FIRST_BLOCK_TIMEOUT:?}, starting the output anyway"
+            );
+            break;
+        }
+        thread::sleep(FIRST_BLOCK_POLL_INTERVAL);
+    }
+    if out_stream.play().is_err() {
+        warn!("Failed to play output stream");
+        reload_signal.notify();
+    }
+
+    let latency_frames = input_selection.buffer_size
+        + engine.block_size
+        + out_sw.desired_rb_size() / NUM_OUT_CHANNELS;
+    let info = SessionInfo {
+        input_device: in_dev_name,
+        input_is_fallback,
+        is_passthrough,
+        output_device: out_dev_name,
+        input_channels: in_config.channels,
+        input_sample_rate: in_config.sample_rate,
+        input_sample_format: input_selection.sample_format,
+        input_buffer_size: input_selection.buffer_size,
+        output_sample_rate: out_config.sample_rate,
+        output_buffer_size: output_selection.buffer_size,
+        output_sample_format: output_selection.sample_format,
+        latency_ms: latency_frames as f32 * 1000.0 / HRIR_SAMPLE_RATE as f32,
+    };
+    info!("Session started: {:?}", info);
 
     Some(SessionContext {
         _in_stream: in_stream,
+        _dsp_thread: dsp_thread,
         _out_stream: out_stream,
+        _hog_mode: hog_mode,
         reload_signal,
+        eq_loader,
+        info,
     })
 }
 
+fn mark_input_received() {
+    let now_ms = BACKEND_EPOCH.elapsed().as_millis() as u64;
+    LAST_INPUT_MS.store(now_ms, atomic::Ordering::Relaxed);
+}
+
+fn is_input_stalled() -> bool {
+    let now_ms = BACKEND_EPOCH.elapsed().as_millis() as u64;
+    let last_ms = LAST_INPUT_MS.load(atomic::Ordering::Relaxed);
+    now_ms.saturating_sub(last_ms) > INPUT_STALL_TIMEOUT.as_millis() as u64
+}
+
+/// Builds the equalizer of `profile` for the running session, unless it already has it.
+fn request_equalizer(profile: EqualizerProfile) {
+    if let Some(eq_loader) = get_equalizer_loader() {
+        eq_loader.request(profile);
+    }
+}
+
+/// Rebuilds the equalizers whose user asset files changed and hands them to the running session.
+fn update_equalizers(changed_files: &[&str]) {
+    if let Some(eq_loader) = get_equalizer_loader() {
+        eq_loader.reload(changed_files);
+    }
+}
+
+/// Returns the equalizer loader of the running session. Cloned out, so that the equalizers
+/// are built without holding the session lock.
+fn get_equalizer_loader() -> Option<Arc<EqualizerLoader>> {
+    CURRENT_CONTEXT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|ctx| Arc::clone(&ctx.eq_loader))
+}
+
+/// Blocks until a reload is requested or, while a session is running, its input stalls
+/// or the HRIR assets change. Changed EQ assets are applied without a reload.
+/// These checks run every `watchdog_interval`. A network sender may pause at any time,
+/// so the stall check is skipped without `check_input_stall`.
+fn wait_for_reload(
+    reload_signal: &Signal,
+    watchdog_interval: Duration,
+    check_input_stall: bool,
+    assets_watcher: &mut AssetsWatcher,
+) {
+    while !reload_signal.wait_timeout(watchdog_interval) {
+        if SHUTDOWN.load(atomic::Ordering::Relaxed) {
+            return;
+        }
+        if check_input_stall && is_input_stalled() {
+            warn!(
+                "No input received for {:?}, restarting the audio pipeline",
+                INPUT_STALL_TIMEOUT
+            );
+            return;
+        }
+
+        let changed_files = assets_watcher.poll();
+        if changed_files.is_empty() {
+            continue;
+        }
+        info!("User assets changed: {}", changed_files.join(", "));
+        if changed_files.contains(&assets::HRIR_MANIFEST_FILE)
+            || HRIR_ASSETS
+                .iter()
+                .any(|(file_name, _)| changed_files.contains(file_name))
+            || HEIGHT_HRIR_ASSETS
+                .iter()
+                .any(|file_name| changed_files.contains(file_name))
+        {
+            return;
+        }
+        update_equalizers(&changed_files);
+    }
+}
+
+/// Picks `AppConfig::block_size` by measuring the DSP load and saves it, so that this only
+/// runs once. On failure, the default block size is used and the next launch tries again.
+fn calibrate_block_size() {
+    info!("No block size configured, measuring the DSP load to pick one...");
+    match offline::calibrate_block_size(&config::get_snapshot(), || {
+        SHUTDOWN.load(atomic::Ordering::Relaxed)
+    }) {
+        Ok(Some(block_size)) => {
+            info!("Using block size {}", block_size);
+            config::update(|cfg| cfg.block_size = Some(block_size));
+        }
+        Ok(None) => info!("Block size calibration interrupted by shutdown"),
+        Err(err) => warn!(
+            "Block size calibration failed ({}), using {}",
+            err, CH_BUF_SIZE
+        ),
+    }
+}
+
+/// Sleeps for `duration`, returning early on shutdown.
+fn sleep_unless_shutdown(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !SHUTDOWN.load(atomic::Ordering::Relaxed) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(left.min(SHUTDOWN_POLL_INTERVAL));
+    }
+}
+
+fn with_jitter(delay: Duration) -> Duration {
+    // Clock-derived randomness is enough to keep simultaneous failures from retrying in lockstep
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    delay + delay.mul_f32(RETRY_JITTER * (nanos % 1000) as f32 / 1000.0)
+}
+
 pub fn run() {
     let host = cpal::default_host();
     coreaudio::on_devices_change(notify_devices_change);
+    let mut assets_watcher = AssetsWatcher::new(
+        HRIR_ASSETS
+            .iter()
+            .map(|(file_name, _)| *file_name)
+            .chain(HEIGHT_HRIR_ASSETS)
+            .chain(iter::once(assets::HRIR_MANIFEST_FILE))
+            .chain(EqualizerProfile::iter().filter_map(|p| Some(get_equalizer_asset(p)?.0)))
+            .collect(),
+    );
+    let mut last_session_start: Option<Instant> = None;
+    let mut retry_delay: Option<Duration> = None;
+
+    let startup_delay = Duration::from_millis(config::get_snapshot().startup_delay_ms);
+    if !startup_delay.is_zero() {
+        info!(
+            "Waiting {:?} for the audio devices to settle",
+            startup_delay
+        );
+        WAITING_FOR_STARTUP.store(true, atomic::Ordering::Relaxed);
+        // Device changes don't cut the wait short, they are expected while devices come up
+        sleep_unless_shutdown(startup_delay);
+        WAITING_FOR_STARTUP.store(false, atomic::Ordering::Relaxed);
+    }
+
+    if config::get_snapshot().block_size.is_none() && !SHUTDOWN.load(atomic::Ordering::Relaxed) {
+        calibrate_block_size();
+    }
 
     loop {
         let reload_signal = CURRENT_CONTEXT
@@ -411,23 +2049,367 @@ pub fn run() {
             .unwrap()
             .as_ref()
             .map(|ctx| Arc::clone(&ctx.reload_signal));
-        if let Some(reload_signal) = reload_signal {
-            reload_signal.wait();
+        if let Some(reload_signal) = reload_signal
+            && !SHUTDOWN.load(atomic::Ordering::Relaxed)
+        {
+            let conf = config::get_snapshot();
+            let watchdog_interval = Duration::from_millis(conf.watchdog_interval_ms)
+                .clamp(MIN_WATCHDOG_INTERVAL, MAX_WATCHDOG_INTERVAL);
+            wait_for_reload(
+                &reload_signal,
+                watchdog_interval,
+                conf.input_source == InputSource::Device,
+                &mut assets_watcher,
+            );
+
+            // Keep the current session playing until the minimum interval has passed.
+            // Requests arriving meanwhile notify the session that is about to be dropped,
+            // so they are all served by this one reload.
+            let min_interval = Duration::from_millis(conf.min_reload_interval_ms);
+            let since_last = last_session_start.map_or(Duration::MAX, |start| start.elapsed());
+            if since_last < min_interval && !SHUTDOWN.load(atomic::Ordering::Relaxed) {
+                let delay = min_interval - since_last;
+                info!(
+                    "Reload requested {:?} after the previous one, deferring it by {:?}",
+                    since_last, delay
+                );
+                sleep_unless_shutdown(delay);
+            }
         }
 
         drop(CURRENT_CONTEXT.lock().unwrap().take());
 
+        if last_session_start.is_some_and(|start| start.elapsed() < STABLE_SESSION_DURATION) {
+            let delay =
+                retry_delay.map_or(MIN_RETRY_DELAY, |delay| (delay * 2).min(MAX_RETRY_DELAY));
+            retry_delay = Some(delay);
+            let delay = with_jitter(delay);
+            warn!(
+                "Audio session ended shortly after start, retrying in {:?}",
+                delay
+            );
+            // Device changes, explicit reloads and shutdown cut the wait short
+            DEVICES_CHANGE_WAITER.wait_timeout(delay);
+        } else {
+            retry_delay = None;
+        }
+
+        if SHUTDOWN.load(atomic::Ordering::Relaxed) {
+            info!("Backend stopped");
+            break;
+        }
+
         let conf = config::get_snapshot();
         match get_devices(&host, &conf) {
-            Ok((input_dev, output_dev)) => {
+            Ok((input_dev, output_dev, input_is_fallback)) => {
                 info!("Starting backend...");
-                let ctx = start_backend(&input_dev, &output_dev);
+                // Give the new session a full stall timeout to deliver its first input.
+                mark_input_received();
+                last_session_start = Some(Instant::now());
+                let ctx = start_backend(input_dev.as_ref(), &output_dev, input_is_fallback, &conf);
                 *CURRENT_CONTEXT.lock().unwrap() = ctx;
+                // In case the profile changed while the session was starting
+                request_equalizer(get_params().equalizer_profile);
             }
-            Err(msg) => {
-                warn!("{}. Waiting for devices to be available...", msg);
+            Err(err) => {
+                last_session_start = None;
+                warn!("{}. Waiting for devices to be available...", err);
                 DEVICES_CHANGE_WAITER.wait();
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An interleaved stereo ramp, falling on the right channel.
+    fn stereo_ramp(num_frames: usize) -> Vec<f32> {
+        (0..num_frames)
+            .flat_map(|i| [i as f32, -(i as f32)])
+            .collect()
+    }
+
+    #[test]
+    fn engine_block_size_is_clamped() {
+        let config = |block_size| AppConfig {
+            block_size,
+            ..Default::default()
+        };
+        assert_eq!(get_engine_config(&config(None)).block_size, CH_BUF_SIZE);
+        assert_eq!(
+            get_engine_config(&config(Some(1))).block_size,
+            MIN_BLOCK_SIZE
+        );
+        assert_eq!(
+            get_engine_config(&config(Some(1 << 20))).block_size,
+            CH_BUF_SIZE
+        );
+        assert_eq!(get_engine_config(&config(Some(1024))).block_size, 1024);
+    }
+
+    #[test]
+    fn invalid_downmix_matrix_and_distances_fall_back() {
+        let hrirs = HrirSet {
+            wavs: std::array::from_fn(|_| Cow::Borrowed(&[][..])),
+            height_wavs: Default::default(),
+            recommended_downmix: None,
+        };
+        let mut config = AppConfig {
+            downmix_matrix: Some(vec![[1.0, 0.0]; NUM_SPEAKERS - 1]),
+            speaker_distances_m: Some(vec![-1.0; NUM_SPEAKERS]),
+            ..Default::default()
+        };
+        assert_eq!(get_downmix_matrix(&config, &hrirs), DEFAULT_DOWNMIX_MATRIX);
+        assert_eq!(get_speaker_distances(&config), None);
+
+        config.downmix_matrix = Some(vec![[f32::NAN, 0.0]; NUM_SPEAKERS]);
+        config.speaker_distances_m = Some(vec![1.0; NUM_SPEAKERS + 1]);
+        assert_eq!(get_downmix_matrix(&config, &hrirs), DEFAULT_DOWNMIX_MATRIX);
+        assert_eq!(get_speaker_distances(&config), None);
+
+        config.downmix_matrix = Some(vec![[0.5, 0.5]; NUM_SPEAKERS]);
+        config.speaker_distances_m = Some(vec![2.0; NUM_SPEAKERS]);
+        assert_eq!(
+            get_downmix_matrix(&config, &hrirs),
+            [[0.5, 0.5]; NUM_SPEAKERS]
+        );
+        assert_eq!(get_speaker_distances(&config), Some([2.0; NUM_SPEAKERS]));
+    }
+
+    #[test]
+    fn resampled_block_keeps_its_first_and_last_frame() {
+        let block = stereo_ramp(256);
+        for out_frames in [255, 256, 257] {
+            let mut out = vec![0.0; out_frames * NUM_OUT_CHANNELS];
+            resample_block(&block, &mut out);
+
+            assert_eq!(out[..2], block[..2]);
+            assert_eq!(out[out.len() - 2..], block[block.len() - 2..]);
+        }
+    }
+
+    #[test]
+    fn resampled_block_has_no_jumps() {
+        let block = stereo_ramp(256);
+        for out_frames in [255, 257] {
+            let mut out = vec![0.0; out_frames * NUM_OUT_CHANNELS];
+            resample_block(&block, &mut out);
+
+            // A ramp stays a ramp: the frame the block lost or gained is spread over all steps
+            let expected_step = 255.0 / (out_frames - 1) as f32;
+            for (i, frame) in out.chunks_exact(NUM_OUT_CHANNELS).enumerate() {
+                let expected = i as f32 * expected_step;
+                assert!(
+                    (frame[0] - expected).abs() < 1e-3,
+                    "frame {}: {}",
+                    i,
+                    frame[0]
+                );
+                assert!(
+                    (frame[1] + expected).abs() < 1e-3,
+                    "frame {}: {}",
+                    i,
+                    frame[1]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn drift_band_is_centered_in_the_backlog_range() {
+        for max_backlog in [256, 1024, 5000] {
+            let (low, high) = get_drift_band(max_backlog).unwrap();
+            assert!(low > 0.0 && high < max_backlog as f32, "{} {}", low, high);
+            assert!(high - low >= MIN_DRIFT_BAND_FRAMES as f32);
+            assert_eq!(low + high, max_backlog as f32);
+        }
+    }
+
+    #[test]
+    fn no_drift_band_without_room_for_one() {
+        assert_eq!(get_drift_band(0), None);
+        assert_eq!(get_drift_band(MIN_DRIFT_BAND_FRAMES), None);
+    }
+
+    #[test]
+    fn every_output_buffer_size_leaves_a_drift_band() {
+        for num_packets in MIN_OUTPUT_BUFFER_PACKETS..=MAX_OUTPUT_BUFFER_PACKETS {
+            for block_size in [MIN_BLOCK_SIZE, 512, 1024, CH_BUF_SIZE] {
+                for device_buffer_size in [64, 256, 441, 2048, 4096] {
+                    let out_sw = AudioSwapchain::new(
+                        NUM_OUT_CHANNELS,
+                        block_size * NUM_OUT_CHANNELS,
+                        device_buffer_size * NUM_OUT_CHANNELS,
+                        num_packets,
+                    );
+                    let max_backlog = get_max_output_backlog(&out_sw, block_size);
+                    assert!(
+                        get_drift_band(max_backlog).is_some(),
+                        "{} packets, block {}, device buffer {}",
+                        num_packets,
+                        block_size,
+                        device_buffer_size
+                    );
+                }
+            }
+        }
+    }
+
+    /// A stereo output swapchain of 256-frame blocks and 256-frame device buffers.
+    fn new_output_swapchain() -> (
+        AudioSwapchain,
+        ringbuf::HeapProd<f32>,
+        ringbuf::HeapCons<f32>,
+    ) {
+        use ringbuf::traits::Split;
+
+        let sw = AudioSwapchain::new(
+            NUM_OUT_CHANNELS,
+            256 * NUM_OUT_CHANNELS,
+            256 * NUM_OUT_CHANNELS,
+            2,
+        );
+        let (prod, cons) = ringbuf::HeapRb::<f32>::new(sw.desired_rb_size()).split();
+        (sw, prod, cons)
+    }
+
+    #[test]
+    fn underrun_plays_silence() {
+        let (sw, mut prod, mut cons) = new_output_swapchain();
+        let mut stereo_output = StereoOutput::new(&sw, UnderrunStrategy::Silence);
+        let block = stereo_ramp(256);
+        sw.submit_input(&block, &mut prod);
+
+        assert_eq!(stereo_output.fill(&sw, &mut cons, 256), block);
+        assert!(
+            stereo_output
+                .fill(&sw, &mut cons, 256)
+                .iter()
+                .all(|v| *v == 0.0)
+        );
+    }
+
+    #[test]
+    fn underrun_repeats_the_last_block_once_fading_out() {
+        let (sw, mut prod, mut cons) = new_output_swapchain();
+        let mut stereo_output = StereoOutput::new(&sw, UnderrunStrategy::RepeatLast);
+        let block = vec![1.0; 256 * NUM_OUT_CHANNELS];
+        sw.submit_input(&block, &mut prod);
+        stereo_output.fill(&sw, &mut cons, 256);
+
+        let repeated = stereo_output.fill(&sw, &mut cons, 256);
+        assert_eq!(repeated[..2], [1.0, 1.0]);
+        assert!(
+            repeated[repeated.len() - 2..]
+                .iter()
+                .all(|v| *v > 0.0 && *v < 0.01)
+        );
+        assert!(repeated.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+        assert!(repeated.windows(3).step_by(2).all(|w| w[2] < w[0]));
+
+        // Not twice in a row
+        assert!(
+            stereo_output
+                .fill(&sw, &mut cons, 256)
+                .iter()
+                .all(|v| *v == 0.0)
+        );
+
+        // Nor over a block of another length, which would not line up
+        sw.submit_input(&block, &mut prod);
+        stereo_output.fill(&sw, &mut cons, 256);
+        assert!(
+            stereo_output
+                .fill(&sw, &mut cons, 128)
+                .iter()
+                .all(|v| *v == 0.0)
+        );
+
+        // Data arriving again ends the repeat
+        sw.submit_input(&block, &mut prod);
+        assert_eq!(
+            stereo_output.fill(&sw, &mut cons, 128),
+            [1.0; 128 * NUM_OUT_CHANNELS]
+        );
+    }
+
+    #[test]
+    fn partial_input_frames_are_dropped_whole() {
+        use ringbuf::traits::{Consumer, Observer, Split};
+
+        let num_channels = 8;
+        let sw = AudioSwapchain::new(num_channels, 256 * num_channels, 100 * num_channels, 2);
+        let (mut prod, mut cons) = ringbuf::HeapRb::<f32>::new(sw.desired_rb_size()).split();
+        // Each sample holds its channel index
+        let frames = |num_frames: usize| -> Vec<f32> {
+            (0..num_frames * num_channels)
+                .map(|i| (i % num_channels) as f32)
+                .collect()
+        };
+
+        // A driver delivering one channel more than negotiated
+        let extra_channel = vec![0.0; 100 * (num_channels + 1)];
+        assert_eq!(
+            submit_device_input(&sw, &mut prod, &extra_channel, num_channels),
+            None
+        );
+        assert_eq!(cons.occupied_len(), 0);
+
+        assert_eq!(
+            submit_device_input(&sw, &mut prod, &frames(100), num_channels),
+            Some(0)
+        );
+        assert_eq!(
+            submit_device_input(&sw, &mut prod, &frames(100)[1..], num_channels),
+            None
+        );
+        assert_eq!(
+            submit_device_input(&sw, &mut prod, &frames(100), num_channels),
+            Some(0)
+        );
+        let stored: Vec<f32> = cons.pop_iter().collect();
+        assert_eq!(stored, frames(200));
+    }
+
+    #[test]
+    fn post_virtualization_eq_keeps_its_output_stage() {
+        let config = AppConfig {
+            eq_position: EqPosition::PostVirtualization,
+            ..AppConfig::default()
+        };
+        assert_eq!(output_stage_sequence(&config), config.output_stages);
+    }
+
+    #[test]
+    fn pre_virtualization_eq_leaves_the_output_stages() {
+        let config = AppConfig {
+            eq_position: EqPosition::PreVirtualization,
+            ..AppConfig::default()
+        };
+        let expected: Vec<_> = config
+            .output_stages
+            .iter()
+            .copied()
+            .filter(|&stage| stage != OutputStage::Equalizer)
+            .collect();
+        assert_eq!(output_stage_sequence(&config), expected);
+    }
+
+    #[test]
+    fn repeated_output_stages_are_ignored() {
+        let config = AppConfig {
+            output_stages: vec![
+                OutputStage::MasterGain,
+                OutputStage::Equalizer,
+                OutputStage::MasterGain,
+            ],
+            ..AppConfig::default()
+        };
+        assert_eq!(
+            output_stage_sequence(&config),
+            [OutputStage::MasterGain, OutputStage::Equalizer]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+/// Second-order IIR section in transposed direct form II.
+#[derive(Clone)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    /// Creates a filter from coefficients normalized so that `a0 == 1`.
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b0: b[0] as f32,
+            b1: b[1] as f32,
+            b2: b[2] as f32,
+            a1: a[0] as f32,
+            a2: a[1] as f32,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /// Second-order low pass (RBJ cookbook).
+    pub fn low_pass(sample_rate: u32, freq: f32, q: f32) -> Self {
+        let (cos_w0, alpha) = rbj_params(sample_rate, freq, q as f64);
+        let a0 = 1.0 + alpha;
+
+        Self::new(
+            [
+                (1.0 - cos_w0) / 2.0 / a0,
+                (1.0 - cos_w0) / a0,
+                (1.0 - cos_w0) / 2.0 / a0,
+            ],
+            [-2.0 * cos_w0 / a0, (1.0 - alpha) / a0],
+        )
+    }
+
+    /// Second-order high pass (RBJ cookbook).
+    pub fn high_pass(sample_rate: u32, freq: f32, q: f32) -> Self {
+        let (cos_w0, alpha) = rbj_params(sample_rate, freq, q as f64);
+        let a0 = 1.0 + alpha;
+
+        Self::new(
+            [
+                (1.0 + cos_w0) / 2.0 / a0,
+                -(1.0 + cos_w0) / a0,
+                (1.0 + cos_w0) / 2.0 / a0,
+            ],
+            [-2.0 * cos_w0 / a0, (1.0 - alpha) / a0],
+        )
+    }
+
+    /// Low shelf with a shelf slope of 1 (RBJ cookbook).
+    pub fn low_shelf(sample_rate: u32, freq: f32, gain_db: f32) -> Self {
+        let a = 10.0_f64.powf(gain_db as f64 / 40.0);
+        let (cos_w0, alpha) = rbj_params(sample_rate, freq, std::f64::consts::FRAC_1_SQRT_2);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+        let a0 = (a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha;
+
+        Self::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha) / a0,
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0) / a0,
+                a * ((a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha) / a0,
+            ],
+            [
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0) / a0,
+                ((a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha) / a0,
+            ],
+        )
+    }
+
+    /// Second-order all pass (RBJ cookbook): unity magnitude, with a phase that turns by 360°
+    /// around `freq`, more steeply for a higher `q`.
+    pub fn all_pass(sample_rate: u32, freq: f32, q: f32) -> Self {
+        let (cos_w0, alpha) = rbj_params(sample_rate, freq, q as f64);
+        let a0 = 1.0 + alpha;
+
+        Self::new(
+            [(1.0 - alpha) / a0, -2.0 * cos_w0 / a0, 1.0],
+            [-2.0 * cos_w0 / a0, (1.0 - alpha) / a0],
+        )
+    }
+
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Returns `cos(w0)` and `alpha` of the RBJ cookbook formulas.
+fn rbj_params(sample_rate: u32, freq: f32, q: f64) -> (f64, f64) {
+    let w0 = 2.0 * std::f64::consts::PI * freq as f64 / sample_rate as f64;
+    (w0.cos(), w0.sin() / (2.0 * q))
+}
@@ -1,28 +1,307 @@
+use crate::error::Error;
+use crate::logging;
 use lazy_static::lazy_static;
 use log::warn;
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
-use std::{fs::File, path::PathBuf, sync::Mutex};
+use std::{
+    collections::HashMap, fs::File, path::PathBuf, process::Command, sync::Mutex, time::SystemTime,
+};
 use strum_macros::{EnumIter, IntoStaticStr};
 
+const DEFAULT_INPUT_DEVICE_NAME: &str = "BlackHole 16ch";
+const DEFAULT_OUTPUT_DEVICE_NAME: &str = "External Headphones";
+const DEFAULT_NETWORK_INPUT_ADDR: &str = "0.0.0.0:4010";
+
 lazy_static! {
-    static ref APP_CONFIG: Mutex<AppConfig> = Mutex::new(AppConfig {
-        equalizer_profile: EqualizerProfile::None,
-        input_device_name: None,
-        output_device_name: None,
-        audio_source_mode: AudioSourceMode::Universal,
-    });
+    static ref APP_CONFIG: Mutex<AppConfig> = Mutex::new(AppConfig::default());
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct AppConfig {
     pub equalizer_profile: EqualizerProfile,
+    /// Preamp overrides in dB, replacing the automatically computed safe value.
+    pub eq_preamp_db: HashMap<EqualizerProfile, f32>,
+    pub eq_position: EqPosition,
+    pub eq_channel_mode: EqChannelMode,
+    pub input_source: InputSource,
+    /// Address the `InputSource::Network` receiver listens on.
+    pub network_input_addr: String,
     pub input_device_name: Option<String>,
     pub output_device_name: Option<String>,
+    /// Device used when no input device has been selected explicitly.
+    pub default_input_device_name: String,
+    /// Device used when no output device has been selected explicitly.
+    pub default_output_device_name: String,
     pub audio_source_mode: AudioSourceMode,
+    pub align_hrir_onsets: bool,
+    /// Truncate HRIRs to this length to bound CPU usage; `None` keeps the full length.
+    pub max_hrir_ms: Option<f32>,
+    /// Request up to four extra height channels (TFL, TFR, TBL, TBR) after the 7.1 ones.
+    pub height_channels: bool,
+    /// Request exactly this many input channels instead of choosing the closest supported count.
+    pub input_channels: Option<u16>,
+    /// Attenuation before the HRIR convolution, made up after it, to keep the convolution in range.
+    pub hrir_headroom_db: f32,
+    /// Remove the DC component of the HRIRs, which would offset the output and waste headroom.
+    pub remove_hrir_dc: bool,
+    pub fft_precision: FftPrecision,
+    /// Amount of all-pass phase decorrelation between the 7.1 speakers before they are summed
+    /// to stereo, from 0 (off) to 1. Reduces comb filtering of content shared by several speakers.
+    pub surround_decorrelation: f32,
+    /// Match the level of stereo and mono sources to that of 7.1 sources.
+    pub mode_gain_compensation: bool,
+    /// Attenuation of the opposite-channel bleed in `AudioSourceMode::Crossfeed`.
+    pub crossfeed_level_db: f32,
+    /// Low-pass cutoff of the opposite-channel bleed in `AudioSourceMode::Crossfeed`.
+    pub crossfeed_cutoff_hz: f32,
+    /// Raise the center speaker by `center_boost_db` to make dialog clearer.
+    pub dialog_boost: bool,
+    pub center_boost_db: f32,
+    /// Upmix stereo sources to center and surround speakers instead of rendering them as a plain pair.
+    pub stereo_upmix: bool,
+    /// EQ profile and gains remembered per output device name, applied when switching to it.
+    pub device_settings: HashMap<String, DeviceSettings>,
+    /// Custom `[left, right]` gain per 7.1 speaker (FL, FR, FC, LFE, SL, SR, BL, BR).
+    pub downmix_matrix: Option<Vec<[f32; 2]>>,
+    /// LFE gain applied to both ears, overriding the downmix matrix.
+    pub lfe_gain: Option<f32>,
+    /// Render the LFE through its HRIR instead of adding it flat to both ears.
+    pub lfe_hrir: bool,
+    pub speaker_layout: SpeakerLayout,
+    /// Distance of each 7.1 speaker (FL, FR, FC, LFE, SL, SR, BL, BR) from the listener in
+    /// meters, overriding the one of `speaker_layout`.
+    pub speaker_distances_m: Option<Vec<f32>>,
+    pub output_mode: OutputMode,
+    /// Stereo stages applied after the virtualizer, in this order. Stages left out are disabled.
+    pub output_stages: Vec<OutputStage>,
+    /// High-pass the final output at `subsonic_cutoff_hz` to remove DC and subsonic rumble.
+    pub subsonic_filter: bool,
+    pub subsonic_cutoff_hz: f32,
+    /// Steer the output toward `auto_gain_target_lufs` by up to `auto_gain_max_db` either way,
+    /// following the loudness of the material over `auto_gain_response_ms`. Applied just before
+    /// the master gain, or last if that stage is disabled.
+    pub auto_gain: bool,
+    pub auto_gain_target_lufs: f32,
+    pub auto_gain_max_db: f32,
+    pub auto_gain_response_ms: f32,
+    /// Send the sum of both channels to each ear.
+    pub mono_output: bool,
+    /// Exchange the left and right output channels, for devices or cables that swap them.
+    pub swap_output_channels: bool,
+    /// Gain applied to the final output.
+    pub master_gain_db: f32,
+    /// Gain applied to the `[left, right]` ear of the final output, to compensate for hearing asymmetry.
+    pub balance_db: [f32; 2],
+    /// Share of the virtualized signal mixed with the unprocessed input, from 0 (dry) to 1 (wet).
+    pub wet_mix: f32,
+    /// Single 0–1 control from which the virtualization parameters are derived, overriding
+    /// them when set. See `RuntimeParams::set_virtualization_strength`.
+    pub virtualization_strength: Option<f32>,
+    /// Time in which master gain, balance and wet mix changes reach 99% of their target.
+    pub gain_smoothing_ms: f32,
+    /// Lower the master gain by a few dB whenever the output clips for several blocks in a row.
+    pub auto_protect: bool,
+    /// Output silence without processing while the input stays below `idle_threshold_db`
+    /// for `idle_timeout_ms`, to save power.
+    pub idle_gate: bool,
+    pub idle_threshold_db: f32,
+    pub idle_timeout_ms: f32,
+    /// Mute the input while it stays below `noise_gate_threshold_db`, e.g. to suppress
+    /// the hiss of a loopback driver. The gate stays open for the hold time after the level
+    /// drops, then closes over the release time.
+    pub noise_gate: bool,
+    pub noise_gate_threshold_db: f32,
+    pub noise_gate_attack_ms: f32,
+    pub noise_gate_hold_ms: f32,
+    pub noise_gate_release_ms: f32,
+    /// Gate each input channel on its own level instead of all of them on the loudest one.
+    pub noise_gate_per_channel: bool,
+    /// Angle between the two speakers as seen from the listener, used in `OutputMode::Speakers`.
+    pub speaker_span_deg: f32,
+    /// Number of device output channels: 2 or 4.
+    pub output_channels: u16,
+    /// Stereo channel (0 = left, 1 = right, `null` = silence) for each device output channel.
+    /// Defaults to repeating the stereo pair across all output channels.
+    pub output_routing: Option<Vec<Option<usize>>>,
+    /// Process audio on a dedicated thread instead of inside the input device callback.
+    pub dsp_thread: bool,
+    /// Run a few silent blocks through the convolvers before the streams start, so that the
+    /// first real block isn't slowed down by cold caches.
+    pub prewarm_convolvers: bool,
+    /// Resample an occasional block by a frame to keep the output backlog bounded despite
+    /// clock drift.
+    pub drift_compensation: bool,
+    /// Processed blocks the output buffer holds at least: fewer lowers the latency,
+    /// more rides out scheduling hiccups (e.g. of Bluetooth devices). Clamped to 2–6.
+    pub output_buffer_packets: usize,
+    /// Open the output device exclusively (CoreAudio hog mode), bypassing the system mixer.
+    /// Falls back to shared access when the device can't be taken.
+    pub exclusive_output: bool,
+    pub underrun_strategy: UnderrunStrategy,
+    pub stream_creation_order: StreamCreationOrder,
+    /// How often the input stall check and the user assets poll run while a session is active.
+    pub watchdog_interval_ms: u64,
+    /// Wait this long before looking up the devices for the first time, e.g. so that a
+    /// loopback driver or headphones are ready when the app is started on login.
+    pub startup_delay_ms: u64,
+    /// Frames processed per block, from `MIN_BLOCK_SIZE` to `CH_BUF_SIZE`. Smaller blocks
+    /// lower the latency but cost more processing time. Picked by measuring the DSP load
+    /// on the first run while unset.
+    pub block_size: Option<usize>,
+    /// Reloads requested sooner than this after the previous one are deferred until this
+    /// much time has passed, with any further requests in between merged into one reload.
+    pub min_reload_interval_ms: u64,
+    /// Apply TPDF dither when the output device only accepts 16-bit integer samples.
+    pub dither_output: bool,
+    pub log_level: String,
+    /// 8-bit RGBA PNG shown in the tray instead of the built-in icon and the `icon.png`
+    /// of the resource folders.
+    pub tray_icon_path: Option<PathBuf>,
+    /// Show every submenu of the tray menu. Without it, only the equalizer, source mode
+    /// and device submenus are shown. Takes effect on the next launch.
+    pub advanced_menu: bool,
+    /// Log a summary of the runtime state every 10 seconds.
+    pub log_status: bool,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            equalizer_profile: EqualizerProfile::None,
+            eq_preamp_db: HashMap::new(),
+            eq_position: EqPosition::PostVirtualization,
+            eq_channel_mode: EqChannelMode::LeftRight,
+            input_source: InputSource::Device,
+            network_input_addr: DEFAULT_NETWORK_INPUT_ADDR.to_string(),
+            input_device_name: None,
+            output_device_name: None,
+            default_input_device_name: DEFAULT_INPUT_DEVICE_NAME.to_string(),
+            default_output_device_name: DEFAULT_OUTPUT_DEVICE_NAME.to_string(),
+            audio_source_mode: AudioSourceMode::Universal,
+            align_hrir_onsets: false,
+            max_hrir_ms: None,
+            height_channels: false,
+            input_channels: None,
+            hrir_headroom_db: 6.0,
+            remove_hrir_dc: true,
+            fft_precision: FftPrecision::Single,
+            surround_decorrelation: 0.0,
+            mode_gain_compensation: true,
+            crossfeed_level_db: 4.5,
+            crossfeed_cutoff_hz: 700.0,
+            dialog_boost: false,
+            center_boost_db: 3.0,
+            stereo_upmix: false,
+            device_settings: HashMap::new(),
+            downmix_matrix: None,
+            lfe_gain: None,
+            lfe_hrir: false,
+            speaker_layout: SpeakerLayout::Equidistant,
+            speaker_distances_m: None,
+            output_mode: OutputMode::Headphones,
+            output_stages: vec![
+                OutputStage::Equalizer,
+                OutputStage::CrosstalkCanceller,
+                OutputStage::MonoOutput,
+                OutputStage::MasterGain,
+                OutputStage::Balance,
+            ],
+            subsonic_filter: true,
+            subsonic_cutoff_hz: 15.0,
+            auto_gain: false,
+            auto_gain_target_lufs: -18.0,
+            auto_gain_max_db: 9.0,
+            auto_gain_response_ms: 5000.0,
+            mono_output: false,
+            swap_output_channels: false,
+            master_gain_db: 0.0,
+            balance_db: [0.0; 2],
+            wet_mix: 1.0,
+            virtualization_strength: None,
+            gain_smoothing_ms: 30.0,
+            auto_protect: false,
+            idle_gate: false,
+            idle_threshold_db: -90.0,
+            idle_timeout_ms: 1000.0,
+            noise_gate: false,
+            noise_gate_threshold_db: -70.0,
+            noise_gate_attack_ms: 1.0,
+            noise_gate_hold_ms: 100.0,
+            noise_gate_release_ms: 150.0,
+            noise_gate_per_channel: true,
+            speaker_span_deg: 60.0,
+            output_channels: 2,
+            output_routing: None,
+            dsp_thread: false,
+            prewarm_convolvers: true,
+            drift_compensation: false,
+            output_buffer_packets: 3,
+            exclusive_output: false,
+            underrun_strategy: UnderrunStrategy::Silence,
+            stream_creation_order: StreamCreationOrder::OutputFirst,
+            watchdog_interval_ms: 500,
+            startup_delay_ms: 0,
+            block_size: None,
+            min_reload_interval_ms: 500,
+            dither_output: true,
+            log_level: logging::DEFAULT_LOG_LEVEL.to_string(),
+            tray_icon_path: None,
+            advanced_menu: true,
+            log_status: false,
+        }
+    }
+}
+
+impl AppConfig {
+    pub fn selected_input_device_name(&self) -> &str {
+        self.input_device_name
+            .as_deref()
+            .unwrap_or(&self.default_input_device_name)
+    }
+
+    pub fn selected_output_device_name(&self) -> &str {
+        self.output_device_name
+            .as_deref()
+            .unwrap_or(&self.default_output_device_name)
+    }
+
+    /// Remembers the current EQ profile and gains for the selected output device.
+    pub fn store_device_settings(&mut self) {
+        let settings = DeviceSettings {
+            equalizer_profile: self.equalizer_profile,
+            master_gain_db: self.master_gain_db,
+            balance_db: self.balance_db,
+        };
+        self.device_settings
+            .insert(self.selected_output_device_name().to_string(), settings);
+    }
+}
+
+/// The settings that follow the output device, so that each pair of headphones keeps its own.
+/// A device without remembered settings starts from the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceSettings {
+    pub equalizer_profile: EqualizerProfile,
+    pub master_gain_db: f32,
+    pub balance_db: [f32; 2],
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive, Serialize, Deserialize, EnumIter)]
+impl Default for DeviceSettings {
+    fn default() -> Self {
+        Self {
+            equalizer_profile: EqualizerProfile::None,
+            master_gain_db: 0.0,
+            balance_db: [0.0; 2],
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, FromPrimitive, Serialize, Deserialize, EnumIter,
+)]
 pub enum EqualizerProfile {
     None,
     EarPods,
@@ -50,6 +329,94 @@ pub enum AudioSourceMode {
     Universal,
     Stereo,
     Mono,
+    /// Plain stereo with headphone crossfeed instead of HRIR virtualization.
+    Crossfeed,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, FromPrimitive, Serialize, Deserialize, EnumIter, IntoStaticStr,
+)]
+pub enum OutputMode {
+    Headphones,
+    Speakers,
+}
+
+/// Where the surround input comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum InputSource {
+    /// The selected input device, typically a loopback device.
+    Device,
+    /// Frames sent over TCP by another machine, in the format described in `network_input`.
+    Network,
+}
+
+/// A stage of `AppConfig::output_stages`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum OutputStage {
+    /// Only applies with `EqPosition::PostVirtualization`.
+    Equalizer,
+    /// Only applies in `OutputMode::Speakers`.
+    CrosstalkCanceller,
+    /// Only applies when `mono_output` is set.
+    MonoOutput,
+    MasterGain,
+    Balance,
+}
+
+/// What the output plays when no processed audio is ready in time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum UnderrunStrategy {
+    Silence,
+    /// Repeat the previous block once with a fade-out, which masks short gaps better.
+    RepeatLast,
+}
+
+/// Preset distances of the virtual speakers from the listener.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SpeakerLayout {
+    /// All speakers at the same distance, as the HRIRs were measured.
+    Equidistant,
+    /// A typical living room, where the center is a bit closer than the fronts
+    /// and the listener sits near the side and back speakers.
+    Room,
+}
+
+/// Which device stream is created first when a session starts. Either way, the output
+/// only starts playing once the first input block has been processed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StreamCreationOrder {
+    /// Reduces glitches at startup on most devices.
+    OutputFirst,
+    /// For devices that misbehave when their output is opened before the input.
+    InputFirst,
+}
+
+/// Float precision of the HRIR convolutions.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FftPrecision {
+    Single,
+    /// Keeps the rounding error accumulated over many partitions of long HRIRs lower,
+    /// at about twice the CPU cost.
+    Double,
+}
+
+/// Where the equalizer sits in the processing chain.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EqPosition {
+    /// On each input channel: shapes the content before the HRIR convolution.
+    PreVirtualization,
+    /// On the binaural result: corrects the headphones, as their response affects every direction.
+    PostVirtualization,
+}
+
+/// What the two curves of a stereo EQ WAV apply to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EqChannelMode {
+    /// The left and the right ear.
+    LeftRight,
+    /// The mid (L+R) and the side (L-R) signal, e.g. to tame a harsh stereo width
+    /// at some frequencies without touching centered content.
+    MidSide,
 }
 
 fn get_project_dirs() -> directories::ProjectDirs {
@@ -61,6 +428,12 @@ fn get_config_path() -> PathBuf {
     path.config_dir().join("config.json")
 }
 
+/// User folder whose HRIR and EQ WAVs and tray icons override the embedded ones.
+pub fn get_assets_path() -> PathBuf {
+    let path = get_project_dirs();
+    path.config_dir().join("assets")
+}
+
 pub fn get_cache_path() -> PathBuf {
     let path = get_project_dirs();
     path.cache_dir().to_path_buf()
@@ -86,18 +459,158 @@ pub fn get_snapshot() -> AppConfig {
     APP_CONFIG.lock().unwrap().clone()
 }
 
-fn save() {
+pub fn save() -> Result<(), Error> {
     let config_path = get_config_path();
-    std::fs::create_dir_all(config_path.parent().unwrap()).unwrap();
+    if let Some(config_dir) = config_path.parent() {
+        std::fs::create_dir_all(config_dir)?;
+    }
 
+    // Written next to the config and renamed over it, so that neither `ConfigWatcher` nor a
+    // crash mid-write leaves a half-written file behind
+    let temp_path = config_path.with_extension("json.tmp");
     let app_config = APP_CONFIG.lock().unwrap();
-    let config_file = File::create(config_path).unwrap();
-    serde_json::to_writer_pretty(config_file, &*app_config).unwrap();
+    let temp_file = File::create(&temp_path)?;
+    serde_json::to_writer_pretty(temp_file, &*app_config)?;
+    std::fs::rename(temp_path, config_path)?;
+    Ok(())
+}
+
+/// Opens the config file in the default text editor, for the options that neither the tray
+/// nor the settings window offer.
+/// `ConfigWatcher` applies the edits once they are saved.
+pub fn open_in_editor() -> Result<(), Error> {
+    // Write it out first, so that there is a file even before the first change
+    save()?;
+    Command::new("open")
+        .arg("-t")
+        .arg(get_config_path())
+        .spawn()?;
+    Ok(())
+}
+
+/// Picks up edits of the config file made outside the app, see `open_in_editor`.
+pub struct ConfigWatcher {
+    mtime: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new() -> Self {
+        Self {
+            mtime: get_config_mtime(),
+        }
+    }
+
+    /// Returns the edited config if the file changed since the previous call, after making it
+    /// the current one. An edit that fails to parse is logged and leaves the config alone.
+    pub fn poll(&mut self) -> Option<AppConfig> {
+        let mtime = get_config_mtime();
+        if mtime == self.mtime {
+            return None;
+        }
+        self.mtime = mtime;
+
+        let contents = std::fs::read_to_string(get_config_path()).ok()?;
+        let mut app_config = APP_CONFIG.lock().unwrap();
+        match parse_edited(&contents, &app_config) {
+            Ok(Some(config)) => {
+                *app_config = config.clone();
+                Some(config)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                warn!("Ignoring the edited config file: {}", err);
+                None
+            }
+        }
+    }
 }
 
+fn get_config_mtime() -> Option<SystemTime> {
+    std::fs::metadata(get_config_path())
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Parses the contents of an edited config file. `None` if they hold the `current` config,
+/// as after the app's own saves.
+fn parse_edited(contents: &str, current: &AppConfig) -> Result<Option<AppConfig>, Error> {
+    let edited: AppConfig = serde_json::from_str(contents)?;
+    let is_changed = serde_json::to_value(&edited)? != serde_json::to_value(current)?;
+    Ok(is_changed.then_some(edited))
+}
+
+/// Applies `f` to the configuration and saves it. A failed save only loses the change
+/// on the next launch, so it's logged instead of returned.
 pub fn update<F: FnOnce(&mut AppConfig)>(f: F) {
     let mut config = APP_CONFIG.lock().unwrap();
     f(&mut config);
     drop(config);
-    save();
+    if let Err(err) = save() {
+        warn!("Failed to save the config: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_config_file_is_no_edit() {
+        let config = AppConfig::default();
+        let contents = serde_json::to_string_pretty(&config).unwrap();
+        assert!(parse_edited(&contents, &config).unwrap().is_none());
+    }
+
+    #[test]
+    fn edited_config_file_is_picked_up() {
+        let config = AppConfig::default();
+        let edited = AppConfig {
+            master_gain_db: -6.0,
+            ..AppConfig::default()
+        };
+        let contents = serde_json::to_string_pretty(&edited).unwrap();
+
+        let picked_up = parse_edited(&contents, &config).unwrap().unwrap();
+        assert_eq!(picked_up.master_gain_db, -6.0);
+    }
+
+    #[test]
+    fn reloaded_config_keeps_the_devices_unset() {
+        let config = AppConfig::default();
+        let edited = AppConfig {
+            default_input_device_name: "Loopback Audio".to_string(),
+            default_output_device_name: "External Headphones".to_string(),
+            ..AppConfig::default()
+        };
+        let contents = serde_json::to_string_pretty(&edited).unwrap();
+
+        let picked_up = parse_edited(&contents, &config).unwrap().unwrap();
+        assert_eq!(picked_up.input_device_name, None);
+        assert_eq!(picked_up.output_device_name, None);
+        assert_eq!(picked_up.selected_input_device_name(), "Loopback Audio");
+        assert_eq!(
+            picked_up.selected_output_device_name(),
+            "External Headphones"
+        );
+    }
+
+    #[test]
+    fn broken_config_file_is_rejected() {
+        let config = AppConfig::default();
+        assert!(parse_edited("{ \"master_gain_db\": ", &config).is_err());
+    }
+
+    #[test]
+    fn malformed_values_are_rejected() {
+        let config = AppConfig::default();
+        // A downmix row must be a [left, right] pair
+        let bad_matrix = r#"{ "downmix_matrix": [[1.0, 0.0, 0.5]] }"#;
+        assert!(parse_edited(bad_matrix, &config).is_err());
+        let negative_block_size = r#"{ "block_size": -256 }"#;
+        assert!(parse_edited(negative_block_size, &config).is_err());
+        let negative_packets = r#"{ "output_buffer_packets": -1 }"#;
+        assert!(parse_edited(negative_packets, &config).is_err());
+        let bad_channel_count = r#"{ "input_channels": 70000 }"#;
+        assert!(parse_edited(bad_channel_count, &config).is_err());
+    }
 }
@@ -1,8 +1,12 @@
+use crate::error::Error;
 use objc2_core_audio::{
-    AudioObjectAddPropertyListener, AudioObjectPropertyAddress, kAudioHardwareNoError,
-    kAudioHardwarePropertyDevices, kAudioObjectPropertyElementMain,
+    AudioObjectAddPropertyListener, AudioObjectGetPropertyData, AudioObjectGetPropertyDataSize,
+    AudioObjectID, AudioObjectPropertyAddress, AudioObjectPropertySelector,
+    AudioObjectSetPropertyData, kAudioDevicePropertyHogMode, kAudioHardwareNoError,
+    kAudioHardwarePropertyDevices, kAudioObjectPropertyElementMain, kAudioObjectPropertyName,
     kAudioObjectPropertyScopeGlobal, kAudioObjectSystemObject,
 };
+use std::ffi::{c_char, c_void};
 use std::ptr::NonNull;
 use std::sync::Mutex;
 
@@ -39,3 +43,169 @@ pub fn on_devices_change(listener: impl Fn() + Send + 'static) {
     });
     LISTENERS.lock().unwrap().push(Box::new(listener));
 }
+
+const K_CF_STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+const DEVICE_NAME_BUF_LEN: usize = 256;
+/// Hog mode owner of a device that nobody holds.
+const NO_HOG_OWNER: i32 = -1;
+
+#[link(name = "CoreFoundation", kind = "framework")]
+unsafe extern "C" {
+    fn CFStringGetCString(
+        string: *const c_void,
+        buffer: *mut c_char,
+        buffer_size: isize,
+        encoding: u32,
+    ) -> u8;
+    fn CFRelease(object: *const c_void);
+}
+
+/// Exclusive access to an output device ("hog mode"): other processes can't play through it,
+/// and the system mixer and its sample rate conversion are bypassed. Released on drop.
+pub struct HogMode {
+    device_id: AudioObjectID,
+}
+
+impl HogMode {
+    /// Takes exclusive access to the device named `device_name`.
+    pub fn acquire(device_name: &str) -> Result<Self, Error> {
+        let device_id = find_device(device_name).ok_or_else(|| {
+            Error::Device(format!("no CoreAudio device named \"{}\"", device_name))
+        })?;
+
+        let pid = std::process::id() as i32;
+        let mut owner = NO_HOG_OWNER;
+        if !get_property(device_id, kAudioDevicePropertyHogMode, &mut owner) {
+            return Err(Error::Device(
+                "the device doesn't support hog mode".to_string(),
+            ));
+        }
+        if owner != NO_HOG_OWNER && owner != pid {
+            return Err(Error::Device(format!(
+                "the device is held by process {}",
+                owner
+            )));
+        }
+        if owner != pid && !set_property(device_id, kAudioDevicePropertyHogMode, pid) {
+            return Err(Error::Device("the device refused hog mode".to_string()));
+        }
+        Ok(Self { device_id })
+    }
+}
+
+impl Drop for HogMode {
+    fn drop(&mut self) {
+        set_property(self.device_id, kAudioDevicePropertyHogMode, NO_HOG_OWNER);
+    }
+}
+
+fn find_device(name: &str) -> Option<AudioObjectID> {
+    let addr = global_address(kAudioHardwarePropertyDevices);
+    let system = kAudioObjectSystemObject as u32;
+    let mut size = 0u32;
+    let status = unsafe {
+        AudioObjectGetPropertyDataSize(
+            system,
+            NonNull::from(&addr),
+            0,
+            std::ptr::null(),
+            NonNull::from(&mut size),
+        )
+    };
+    if status != kAudioHardwareNoError {
+        return None;
+    }
+
+    let mut device_ids: Vec<AudioObjectID> = vec![0; size as usize / size_of::<AudioObjectID>()];
+    let status = unsafe {
+        AudioObjectGetPropertyData(
+            system,
+            NonNull::from(&addr),
+            0,
+            std::ptr::null(),
+            NonNull::from(&mut size),
+            NonNull::new(device_ids.as_mut_ptr().cast()).unwrap(),
+        )
+    };
+    if status != kAudioHardwareNoError {
+        return None;
+    }
+    device_ids.truncate(size as usize / size_of::<AudioObjectID>());
+
+    device_ids
+        .into_iter()
+        .find(|&id| device_name(id).is_some_and(|dev_name| dev_name == name))
+}
+
+fn device_name(device_id: AudioObjectID) -> Option<String> {
+    let mut cf_name: *const c_void = std::ptr::null();
+    if !get_property(device_id, kAudioObjectPropertyName, &mut cf_name) || cf_name.is_null() {
+        return None;
+    }
+
+    let mut buf = [0 as c_char; DEVICE_NAME_BUF_LEN];
+    let ok = unsafe {
+        let ok = CFStringGetCString(
+            cf_name,
+            buf.as_mut_ptr(),
+            buf.len() as isize,
+            K_CF_STRING_ENCODING_UTF8,
+        );
+        CFRelease(cf_name);
+        ok != 0
+    };
+    ok.then(|| {
+        unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+fn global_address(selector: AudioObjectPropertySelector) -> AudioObjectPropertyAddress {
+    AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMain,
+    }
+}
+
+/// Reads a fixed-size property of the global scope, returns whether it succeeded.
+fn get_property<T: Copy>(
+    object_id: AudioObjectID,
+    selector: AudioObjectPropertySelector,
+    value: &mut T,
+) -> bool {
+    let addr = global_address(selector);
+    let mut size = size_of::<T>() as u32;
+    let status = unsafe {
+        AudioObjectGetPropertyData(
+            object_id,
+            NonNull::from(&addr),
+            0,
+            std::ptr::null(),
+            NonNull::from(&mut size),
+            NonNull::from(value).cast(),
+        )
+    };
+    status == kAudioHardwareNoError && size as usize == size_of::<T>()
+}
+
+/// Writes a fixed-size property of the global scope, returns whether it succeeded.
+fn set_property<T: Copy>(
+    object_id: AudioObjectID,
+    selector: AudioObjectPropertySelector,
+    value: T,
+) -> bool {
+    let addr = global_address(selector);
+    let status = unsafe {
+        AudioObjectSetPropertyData(
+            object_id,
+            NonNull::from(&addr),
+            0,
+            std::ptr::null(),
+            size_of::<T>() as u32,
+            NonNull::from(&value).cast(),
+        )
+    };
+    status == kAudioHardwareNoError
+}
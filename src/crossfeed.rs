@@ -0,0 +1,61 @@
+use crate::audio_data::{AudioDataMut, AudioDataRef, db_to_gain};
+use crate::biquad::Biquad;
+
+/// Interaural delay of the crossfed signal.
+const CROSSFEED_DELAY_US: u32 = 300;
+const CROSSFEED_Q: f32 = 0.5;
+
+/// Bauer-style headphone crossfeed: each ear also receives the opposite channel,
+/// delayed and low-passed like the sound of a speaker reaching the far ear. The direct
+/// path gets a matching low-shelf cut so that centered sources keep a flat response.
+pub struct Crossfeed {
+    direct: [Biquad; 2],
+    cross: [Biquad; 2],
+    cross_gain: f32,
+    delay_lines: [Vec<f32>; 2],
+    delay_pos: usize,
+}
+
+impl Crossfeed {
+    pub fn new(sample_rate: u32, level_db: f32, cutoff_hz: f32) -> Self {
+        let cross_gain = db_to_gain(-level_db);
+        let direct_cut_db = -20.0 * (1.0 + cross_gain).log10();
+        let delay_len = (sample_rate * CROSSFEED_DELAY_US / 1_000_000).max(1) as usize;
+
+        Self {
+            direct: [(); 2].map(|_| Biquad::low_shelf(sample_rate, cutoff_hz, direct_cut_db)),
+            cross: [(); 2].map(|_| Biquad::low_pass(sample_rate, cutoff_hz, CROSSFEED_Q)),
+            cross_gain,
+            delay_lines: [vec![0.0; delay_len], vec![0.0; delay_len]],
+            delay_pos: 0,
+        }
+    }
+
+    /// Renders the first two input channels (or a single mono channel) to stereo.
+    pub fn process(&mut self, input_block: &AudioDataRef, stereo_output: &mut AudioDataMut) {
+        let right_ch = if input_block.num_channels() >= 2 {
+            1
+        } else {
+            0
+        };
+        let inputs = input_block
+            .select_channel(0)
+            .zip(input_block.select_channel(right_ch));
+
+        for (frame, (l, r)) in stereo_output.data.chunks_exact_mut(2).zip(inputs) {
+            let delayed = [
+                std::mem::replace(&mut self.delay_lines[0][self.delay_pos], l),
+                std::mem::replace(&mut self.delay_lines[1][self.delay_pos], r),
+            ];
+            self.delay_pos = (self.delay_pos + 1) % self.delay_lines[0].len();
+
+            let direct_l = self.direct[0].process_sample(l);
+            let direct_r = self.direct[1].process_sample(r);
+            let cross_l = self.cross[0].process_sample(delayed[1]);
+            let cross_r = self.cross[1].process_sample(delayed[0]);
+
+            frame[0] = direct_l + self.cross_gain * cross_l;
+            frame[1] = direct_r + self.cross_gain * cross_r;
+        }
+    }
+}
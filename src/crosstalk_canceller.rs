@@ -0,0 +1,92 @@
+use crate::audio_data::{AudioDataMut, EngineConfig, assert_block_size};
+use crate::block_convolver::BlockConvolver;
+use crate::error::Error;
+
+const HEAD_RADIUS_M: f32 = 0.0875;
+const SPEED_OF_SOUND_M_S: f32 = 343.0;
+/// Level of the contralateral (crosstalk) path relative to the ipsilateral one.
+/// Kept below the physical value to regularize the low-frequency boost of the inverse.
+const CROSSTALK_GAIN: f32 = 0.7;
+/// Number of cancellation recursions unrolled into the FIR filters.
+const NUM_CANCELLATION_ORDERS: usize = 8;
+
+/// Stereo crosstalk cancellation (XTC) for listening to binaural output over a pair of speakers.
+///
+/// The head is modeled as a free-field path to the ipsilateral ear and a delayed, attenuated
+/// path to the contralateral ear. The inverse of that 2x2 system is expanded into a pair of
+/// short FIR filters: `same` (speaker to ipsilateral output) and `cross` (to contralateral output).
+pub struct CrosstalkCanceller {
+    left_same: BlockConvolver,
+    left_cross: BlockConvolver,
+    right_same: BlockConvolver,
+    right_cross: BlockConvolver,
+    left_same_out: Vec<f32>,
+    left_cross_out: Vec<f32>,
+    right_same_out: Vec<f32>,
+    right_cross_out: Vec<f32>,
+}
+
+impl CrosstalkCanceller {
+    pub fn new(engine: EngineConfig, speaker_span_deg: f32) -> Result<Self, Error> {
+        let (same_ir, cross_ir) = xtc_filters(engine.sample_rate, speaker_span_deg);
+        let block_size = engine.block_size;
+
+        let same = BlockConvolver::new(block_size, &same_ir)?;
+        let cross = BlockConvolver::new(block_size, &cross_ir)?;
+
+        Ok(Self {
+            left_same: same.clone(),
+            left_cross: cross.clone(),
+            right_same: same,
+            right_cross: cross,
+            left_same_out: vec![0.0; block_size],
+            left_cross_out: vec![0.0; block_size],
+            right_same_out: vec![0.0; block_size],
+            right_cross_out: vec![0.0; block_size],
+        })
+    }
+
+    pub fn process(&mut self, stereo_data: &mut AudioDataMut<'_>) {
+        assert_block_size(stereo_data.num_frames(), self.left_same_out.len());
+
+        stereo_data.copy_channel_to_slice(0, &mut self.left_same_out);
+        self.left_cross_out.copy_from_slice(&self.left_same_out);
+        stereo_data.copy_channel_to_slice(1, &mut self.right_same_out);
+        self.right_cross_out.copy_from_slice(&self.right_same_out);
+
+        self.left_same.process(&mut self.left_same_out);
+        self.left_cross.process(&mut self.left_cross_out);
+        self.right_same.process(&mut self.right_same_out);
+        self.right_cross.process(&mut self.right_cross_out);
+
+        let left_ch = stereo_data.select_channel_mut(0);
+        for (i, v) in left_ch.enumerate() {
+            *v = self.left_same_out[i] + self.right_cross_out[i];
+        }
+
+        let right_ch = stereo_data.select_channel_mut(1);
+        for (i, v) in right_ch.enumerate() {
+            *v = self.right_same_out[i] + self.left_cross_out[i];
+        }
+    }
+}
+
+/// Builds the `(same, cross)` impulse responses inverting the symmetric head model
+/// `[[1, g*z^-d], [g*z^-d, 1]]` via its truncated series expansion.
+fn xtc_filters(sample_rate: u32, speaker_span_deg: f32) -> (Vec<f32>, Vec<f32>) {
+    // Woodworth's spherical head model for the interaural time difference.
+    let half_span = (speaker_span_deg * 0.5).to_radians();
+    let itd = HEAD_RADIUS_M / SPEED_OF_SOUND_M_S * (half_span + half_span.sin());
+    let delay = ((itd * sample_rate as f32).round() as usize).max(1);
+
+    let len = (2 * NUM_CANCELLATION_ORDERS + 1) * delay + 1;
+    let mut same = vec![0.0; len];
+    let mut cross = vec![0.0; len];
+
+    for k in 0..NUM_CANCELLATION_ORDERS {
+        same[2 * k * delay] = CROSSTALK_GAIN.powi(2 * k as i32);
+        cross[(2 * k + 1) * delay] = -CROSSTALK_GAIN.powi(2 * k as i32 + 1);
+    }
+
+    (same, cross)
+}
@@ -0,0 +1,236 @@
+use crate::{
+    audio_data::NUM_OUT_CHANNELS,
+    backend::{self, get_engine_config},
+    config,
+    config::get_cache_path,
+};
+use realfft::RealFftPlanner;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const NUM_LOG_LINES: usize = 30;
+/// How long to wait for the audio thread to publish an output block for the spectrum.
+const SPECTRUM_TIMEOUT: Duration = Duration::from_millis(200);
+const SPECTRUM_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Center frequencies of the octave bands reported in the output spectrum.
+const OCTAVE_BANDS_HZ: [f32; 9] = [
+    63.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+/// Assembles a plain-text report for bug reports.
+pub fn collect() -> String {
+    let conf = config::get_snapshot();
+    let mut report = String::new();
+
+    let os_version = Command::new("sw_vers")
+        .arg("-productVersion")
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|v| v.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let _ = writeln!(report, "Audio Virtualizer {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(report, "OS: {} {}", std::env::consts::OS, os_version);
+    let _ = writeln!(
+        report,
+        "Block size: {}",
+        get_engine_config(&conf).block_size
+    );
+    let _ = writeln!(report, "Equalizer: {}", conf.equalizer_profile.label());
+    let _ = writeln!(report, "Source mode: {:?}", conf.audio_source_mode);
+    let _ = writeln!(report, "Output mode: {:?}", conf.output_mode);
+
+    match backend::get_session_info() {
+        Some(info) => {
+            let _ = writeln!(
+                report,
+                "Input: '{}', {} ch, {} Hz, {:?}, buffer {}",
+                info.input_device,
+                info.input_channels,
+                info.input_sample_rate,
+                info.input_sample_format,
+                info.input_buffer_size
+            );
+            if info.input_is_fallback {
+                let _ = writeln!(
+                    report,
+                    "Input is the system default (configured device missing)"
+                );
+            }
+            if info.is_passthrough {
+                let _ = writeln!(report, "HRIRs failed to load, stereo passthrough");
+            }
+            let _ = writeln!(
+                report,
+                "Output: '{}', {} Hz, {:?}, buffer {}",
+                info.output_device,
+                info.output_sample_rate,
+                info.output_sample_format,
+                info.output_buffer_size
+            );
+            let _ = writeln!(report, "Latency: {:.1} ms", info.latency_ms);
+        }
+        None => {
+            let _ = writeln!(report, "No active session");
+        }
+    }
+
+    let _ = writeln!(report, "DSP load: {:.1}%", backend::get_dsp_load());
+    let _ = writeln!(report, "Underruns: {}", backend::get_underrun_count());
+    let _ = writeln!(
+        report,
+        "Repeated blocks: {}",
+        backend::get_repeated_block_count()
+    );
+    let _ = writeln!(report, "Overflows: {}", backend::get_overflow_count());
+    let _ = writeln!(report, "Backlog: {} frames", backend::get_backlog_frames());
+    let (peak_db, rms_db) = backend::get_output_levels();
+    let _ = writeln!(
+        report,
+        "Output level: peak {:.1} dBFS, RMS {:.1} dBFS",
+        peak_db, rms_db
+    );
+    let _ = writeln!(
+        report,
+        "Non-finite samples: {}",
+        backend::get_non_finite_sample_count()
+    );
+    match capture_output_spectrum() {
+        Some(bands) => {
+            let _ = write!(report, "Output spectrum:");
+            for (freq, level_db) in bands {
+                let _ = write!(report, " {}Hz={:.1}dB", freq, level_db);
+            }
+            let _ = writeln!(report);
+        }
+        None => {
+            let _ = writeln!(report, "Output spectrum: no audio");
+        }
+    }
+
+    let _ = writeln!(report, "\nRecent log:");
+    for line in read_recent_log_lines() {
+        let _ = writeln!(report, "{}", line);
+    }
+
+    report
+}
+
+/// Summarizes the runtime state in a single line for periodic logging.
+pub fn status_line() -> String {
+    let conf = config::get_snapshot();
+    let (peak_db, rms_db) = backend::get_output_levels();
+    let devices = backend::get_session_info().map_or("no session".to_string(), |info| {
+        format!("'{}' -> '{}'", info.input_device, info.output_device)
+    });
+
+    format!(
+        "Status: peak={:.1}dBFS rms={:.1}dBFS dsp_load={:.1}% backlog={} underruns={} \
+         overflows={} eq={} source={:?} devices={}",
+        peak_db,
+        rms_db,
+        backend::get_dsp_load(),
+        backend::get_backlog_frames(),
+        backend::get_underrun_count(),
+        backend::get_overflow_count(),
+        conf.equalizer_profile.label(),
+        conf.audio_source_mode,
+        devices
+    )
+}
+
+/// Briefly enables the output analysis tap and returns the level of the mid signal
+/// in each of `OCTAVE_BANDS_HZ`, or `None` if no block was processed in time.
+fn capture_output_spectrum() -> Option<Vec<(f32, f32)>> {
+    // Discard a block left over from an earlier capture
+    let _ = backend::take_latest_output_block();
+    backend::set_analysis_enabled(true);
+    let deadline = Instant::now() + SPECTRUM_TIMEOUT;
+    let block = loop {
+        if let Some(block) = backend::take_latest_output_block() {
+            break Some(block);
+        }
+        if Instant::now() >= deadline {
+            break None;
+        }
+        std::thread::sleep(SPECTRUM_POLL_INTERVAL);
+    };
+    backend::set_analysis_enabled(false);
+
+    let block = block?;
+    let mut mid: Vec<f32> = block
+        .chunks_exact(NUM_OUT_CHANNELS)
+        .map(|frame| (frame[0] + frame[1]) * 0.5)
+        .collect();
+    let len = mid.len();
+    if len == 0 {
+        return None;
+    }
+    // Hann window against leakage from the block edges
+    for (i, v) in mid.iter_mut().enumerate() {
+        *v *= 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / len as f32).cos();
+    }
+
+    let fft = RealFftPlanner::<f32>::new().plan_fft_forward(len);
+    let mut spectrum = fft.make_output_vec();
+    fft.process(&mut mid, &mut spectrum).ok()?;
+
+    let bin_hz = backend::get_output_sample_rate() as f32 / len as f32;
+    let bands = OCTAVE_BANDS_HZ
+        .iter()
+        .map(|&center| {
+            // At least one bin per band, which small blocks can't resolve any finer
+            let lo = ((center / 2f32.sqrt() / bin_hz) as usize).max(1);
+            let hi = ((center * 2f32.sqrt() / bin_hz) as usize)
+                .max(lo + 1)
+                .min(spectrum.len());
+            let bins = spectrum.get(lo..hi).unwrap_or(&[]);
+            let power: f32 = bins.iter().map(|c| c.norm_sqr()).sum();
+            // Relative to a full-scale sine, whose windowed peak bin is len / 4
+            let level = power.sqrt() / (len as f32 / 4.0);
+            (center, 20.0 * level.max(1e-10).log10())
+        })
+        .collect();
+    Some(bands)
+}
+
+/// Returns the last lines of the most recently written log file.
+fn read_recent_log_lines() -> Vec<String> {
+    let latest_log = std::fs::read_dir(get_cache_path())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+        .max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok());
+
+    let Some(contents) = latest_log.and_then(|entry| std::fs::read_to_string(entry.path()).ok())
+    else {
+        return Vec::new();
+    };
+
+    let lines: Vec<&str> = contents.lines().collect();
+    lines[lines.len().saturating_sub(NUM_LOG_LINES)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+pub fn copy_to_clipboard(text: &str) -> Result<(), String> {
+    let mut child = Command::new("pbcopy")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run pbcopy: {}", e))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(text.as_bytes())
+        .map_err(|e| format!("Failed to write to pbcopy: {}", e))?;
+    child
+        .wait()
+        .map_err(|e| format!("Failed to wait for pbcopy: {}", e))?;
+    Ok(())
+}
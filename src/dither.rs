@@ -0,0 +1,91 @@
+/// TPDF (triangular probability density function) dither for quantizing f32 samples
+/// to integer formats. Decorrelates the quantization error from the signal so that
+/// it becomes a constant, benign noise floor instead of distortion in quiet passages.
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new() -> Self {
+        Self { state: 0x9E37_79B9 }
+    }
+
+    /// Xorshift32: cheap enough to be called for every output sample.
+    fn next_uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+
+    /// Returns triangular noise in the range (-1, 1) LSB.
+    pub fn next_noise(&mut self) -> f32 {
+        self.next_uniform() - self.next_uniform()
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts f32 samples to i16, adding dither noise before rounding if provided.
+pub fn quantize_i16(src: &[f32], dst: &mut [i16], mut dither: Option<&mut Dither>) {
+    const SCALE: f32 = i16::MAX as f32;
+
+    for (d, s) in dst.iter_mut().zip(src) {
+        let noise = dither.as_mut().map_or(0.0, |dither| dither.next_noise());
+        *d = (s * SCALE + noise)
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NUM_SAMPLES: usize = 200_000;
+
+    #[test]
+    fn noise_is_triangular() {
+        const NUM_BINS: usize = 20;
+        let mut dither = Dither::new();
+        let mut histogram = [0usize; NUM_BINS];
+        let mut sum = 0.0f64;
+        let mut sum_sq = 0.0f64;
+        for _ in 0..NUM_SAMPLES {
+            let v = dither.next_noise();
+            assert!(v > -1.0 && v < 1.0);
+            sum += v as f64;
+            sum_sq += (v * v) as f64;
+            histogram[((v + 1.0) / 2.0 * NUM_BINS as f32) as usize] += 1;
+        }
+
+        assert!((sum / NUM_SAMPLES as f64).abs() < 0.01);
+        // The variance of the difference of two uniform [0, 1) variables is 1/6
+        assert!((sum_sq / NUM_SAMPLES as f64 - 1.0 / 6.0).abs() < 0.01);
+        for (bin, &count) in histogram.iter().enumerate() {
+            let center = (bin as f32 + 0.5) / NUM_BINS as f32 * 2.0 - 1.0;
+            let expected = (1.0 - center.abs()) * 2.0 / NUM_BINS as f32;
+            let share = count as f32 / NUM_SAMPLES as f32;
+            assert!((share - expected).abs() < 0.005, "bin {}: {}", bin, share);
+        }
+    }
+
+    #[test]
+    fn dithered_quantization_error_averages_out() {
+        // 0.3 LSB, which plain rounding always turns into 0
+        let level = 0.3 / i16::MAX as f32;
+        let src = vec![level; NUM_SAMPLES];
+        let mut dst = vec![0i16; NUM_SAMPLES];
+
+        quantize_i16(&src, &mut dst, None);
+        assert!(dst.iter().all(|&v| v == 0));
+
+        quantize_i16(&src, &mut dst, Some(&mut Dither::new()));
+        let mean = dst.iter().map(|&v| v as f64).sum::<f64>() / NUM_SAMPLES as f64;
+        assert!((mean - 0.3).abs() < 0.01, "mean {}", mean);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::audio_data::{
+    AudioDataMut, AudioDataRef, EngineConfig, NUM_OUT_CHANNELS, assert_block_size,
+};
+use crate::config::AudioSourceMode;
+use crate::crossfeed::Crossfeed;
+use crate::equalizer_bank::EqualizerBank;
+use crate::processing_chain::ProcessingChain;
+use crate::runtime_params::RuntimeParams;
+use crate::smoother::Smoother;
+use crate::surround_virtualizer::{SurroundVirtualizer, downmix_to_stereo};
+
+/// The settings a `DspCore` is built with, taken from the `AppConfig` fields of the same names.
+#[derive(Clone, Copy)]
+pub struct DspCoreOptions {
+    /// Render stereo through `SurroundVirtualizer::process_ch2_upmix`.
+    pub stereo_upmix: bool,
+    pub crossfeed_level_db: f32,
+    pub crossfeed_cutoff_hz: f32,
+    pub gain_smoothing_ms: f32,
+}
+
+/// The virtualizer with the equalizers and the output chain around it, rendering one block
+/// of the configured engine block size per call.
+///
+/// It owns no streams or threads, so that any real-time render callback can drive it, not
+/// only the cpal streams of a session. Processing doesn't allocate or lock: the buffers are
+/// sized in `new`, and the equalizers are built elsewhere and only swapped in by the
+/// `EqualizerBank`.
+pub struct DspCore {
+    engine: EngineConfig,
+    /// `None` if the HRIRs failed to load, which leaves a plain stereo downmix.
+    sv: Option<SurroundVirtualizer>,
+    process_stereo: fn(&mut SurroundVirtualizer, &AudioDataRef, &mut AudioDataMut),
+    pre_equalizer: Option<EqualizerBank>,
+    crossfeed: Crossfeed,
+    wet_mix: Smoother,
+    output_chain: ProcessingChain,
+    eq_input_buf: Vec<f32>,
+}
+
+impl DspCore {
+    /// Blocks passed in later may have up to `max_in_channels` channels. The wet mix starts
+    /// from `params`, so that a restart doesn't ramp it in again.
+    pub fn new(
+        engine: EngineConfig,
+        options: &DspCoreOptions,
+        max_in_channels: usize,
+        sv: Option<SurroundVirtualizer>,
+        pre_equalizer: Option<EqualizerBank>,
+        output_chain: ProcessingChain,
+        params: &RuntimeParams,
+    ) -> Self {
+        Self {
+            engine,
+            sv,
+            process_stereo: if options.stereo_upmix {
+                SurroundVirtualizer::process_ch2_upmix
+            } else {
+                SurroundVirtualizer::process_ch2
+            },
+            pre_equalizer,
+            crossfeed: Crossfeed::new(
+                engine.sample_rate,
+                options.crossfeed_level_db,
+                options.crossfeed_cutoff_hz,
+            ),
+            wet_mix: Smoother::new(
+                engine.sample_rate,
+                options.gain_smoothing_ms,
+                params.wet_mix,
+            ),
+            output_chain,
+            eq_input_buf: vec![0.0; engine.block_size * max_in_channels],
+        }
+    }
+
+    /// Renders `input` the way `params.source_mode` asks for: inputs of more than two channels
+    /// are virtualized as surround in `AudioSourceMode::Universal`, anything else as stereo.
+    pub fn process_block(
+        &mut self,
+        input: &AudioDataRef,
+        output: &mut AudioDataMut,
+        params: &RuntimeParams,
+    ) {
+        if params.source_mode == AudioSourceMode::Universal && input.num_channels() > 2 {
+            self.process_surround_block(input, output, params);
+        } else {
+            self.process_stereo_block(input, output, params);
+        }
+    }
+
+    /// Virtualizes a 7.1 block, or a 7.1.4 one with height HRIRs, into `output`.
+    pub fn process_surround_block(
+        &mut self,
+        input: &AudioDataRef,
+        output: &mut AudioDataMut,
+        params: &RuntimeParams,
+    ) {
+        assert_block_size(input.num_frames(), self.engine.block_size);
+        self.apply_params(params);
+        let input = equalize_input(
+            &mut self.pre_equalizer,
+            &mut self.eq_input_buf,
+            input,
+            params,
+        );
+
+        match &mut self.sv {
+            Some(sv) => sv.process_ch8(&input, output),
+            None => downmix_to_stereo(&input, output),
+        }
+        Self::finish(
+            &mut self.wet_mix,
+            &mut self.output_chain,
+            Some(&input),
+            output,
+            params,
+        );
+    }
+
+    /// Renders a stereo or mono block, or the first two channels of a wider one, into `output`.
+    /// `AudioSourceMode::Mono` and `AudioSourceMode::Crossfeed` take precedence over stereo.
+    pub fn process_stereo_block(
+        &mut self,
+        input: &AudioDataRef,
+        output: &mut AudioDataMut,
+        params: &RuntimeParams,
+    ) {
+        assert_block_size(input.num_frames(), self.engine.block_size);
+        self.apply_params(params);
+        let input = equalize_input(
+            &mut self.pre_equalizer,
+            &mut self.eq_input_buf,
+            input,
+            params,
+        );
+
+        match (&mut self.sv, params.source_mode) {
+            (None, _) => downmix_to_stereo(&input, output),
+            (Some(_), AudioSourceMode::Crossfeed) => self.crossfeed.process(&input, output),
+            (Some(sv), AudioSourceMode::Mono) => sv.process_mono(&input, output),
+            (Some(sv), _) if input.num_channels() < 2 => sv.process_mono(&input, output),
+            (Some(sv), _) => (self.process_stereo)(sv, &input, output),
+        }
+        Self::finish(
+            &mut self.wet_mix,
+            &mut self.output_chain,
+            Some(&input),
+            output,
+            params,
+        );
+    }
+
+    /// Virtualizes a 7.1 test signal into `output`. Unlike the program input, it isn't
+    /// equalized beforehand or mixed with the dry signal.
+    pub fn process_test_tone(
+        &mut self,
+        tone: &AudioDataRef,
+        output: &mut AudioDataMut,
+        params: &RuntimeParams,
+    ) {
+        assert_block_size(tone.num_frames(), self.engine.block_size);
+        self.apply_params(params);
+
+        match &mut self.sv {
+            Some(sv) => sv.process_ch8(tone, output),
+            None => downmix_to_stereo(tone, output),
+        }
+        Self::finish(
+            &mut self.wet_mix,
+            &mut self.output_chain,
+            None,
+            output,
+            params,
+        );
+    }
+
+    fn apply_params(&mut self, params: &RuntimeParams) {
+        if let Some(sv) = &mut self.sv {
+            sv.set_speaker_mask(params.active_speaker_mask());
+            sv.set_center_boost_db(params.center_boost_db);
+        }
+    }
+
+    /// Mixes the dry input back in according to `RuntimeParams::wet_mix`, then runs the
+    /// output chain and swaps the channels if `RuntimeParams::swap_output_channels` is set.
+    fn finish(
+        wet_mix: &mut Smoother,
+        output_chain: &mut ProcessingChain,
+        dry: Option<&AudioDataRef>,
+        output: &mut AudioDataMut,
+        params: &RuntimeParams,
+    ) {
+        let wet_target = params.wet_mix;
+        if let Some(dry) = dry
+            && (wet_target != 1.0 || !wet_mix.is_settled(wet_target))
+        {
+            // The first two input channels (or the only one) make up the dry signal
+            let dry_l = dry.select_channel(0);
+            let dry_r = dry.select_channel(1.min(dry.num_channels() - 1));
+            for ((frame, l), r) in output
+                .data
+                .chunks_exact_mut(NUM_OUT_CHANNELS)
+                .zip(dry_l)
+                .zip(dry_r)
+            {
+                let wet = wet_mix.next(wet_target);
+                frame[0] = wet * frame[0] + (1.0 - wet) * l;
+                frame[1] = wet * frame[1] + (1.0 - wet) * r;
+            }
+        }
+
+        output_chain.process(output, params);
+
+        // After the chain, whose per-ear stages refer to the listener's ears
+        if params.swap_output_channels {
+            for frame in output.data.chunks_exact_mut(NUM_OUT_CHANNELS) {
+                frame.swap(0, 1);
+            }
+        }
+    }
+}
+
+/// Applies the equalizer to every input channel if it comes before virtualization.
+fn equalize_input<'a>(
+    pre_equalizer: &mut Option<EqualizerBank>,
+    eq_input_buf: &'a mut [f32],
+    input: &AudioDataRef<'a>,
+    params: &RuntimeParams,
+) -> AudioDataRef<'a> {
+    let in_ch = input.num_channels();
+    let pre_eq = pre_equalizer
+        .as_mut()
+        .and_then(|bank| bank.get(params.equalizer_profile));
+    match pre_eq {
+        Some(eq) => {
+            let eq_input = &mut eq_input_buf[..input.data.len()];
+            eq_input.copy_from_slice(input.data);
+            eq.process_channels(&mut AudioDataMut::new(eq_input, in_ch));
+            AudioDataRef::new(eq_input, in_ch)
+        }
+        None => AudioDataRef::new(input.data, in_ch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_data::{CH_BUF_SIZE, HRIR_SAMPLE_RATE, db_to_gain};
+    use crate::processing_chain::Balance;
+
+    const ENGINE: EngineConfig = EngineConfig {
+        block_size: CH_BUF_SIZE,
+        sample_rate: HRIR_SAMPLE_RATE,
+    };
+
+    /// A core without HRIRs, which downmixes a stereo input unchanged, followed by a balance
+    /// stage set to `balance_db`.
+    fn new_dsp_core(params: &RuntimeParams) -> DspCore {
+        let options = DspCoreOptions {
+            stereo_upmix: false,
+            crossfeed_level_db: 0.0,
+            crossfeed_cutoff_hz: 700.0,
+            gain_smoothing_ms: 0.0,
+        };
+        let balance = Balance::new(
+            params
+                .balance_db
+                .map(|db| Smoother::new(48000, 0.0, db_to_gain(db))),
+        );
+        DspCore::new(
+            ENGINE,
+            &options,
+            2,
+            None,
+            None,
+            ProcessingChain::new(vec![Box::new(balance)]),
+            params,
+        )
+    }
+
+    #[test]
+    fn swapped_channels_are_exchanged_after_the_chain() {
+        let mut params = RuntimeParams {
+            balance_db: [-6.0, 0.0],
+            ..RuntimeParams::DEFAULT
+        };
+        let block_size = ENGINE.block_size;
+        let input: Vec<f32> = (0..block_size).flat_map(|_| [1.0, 0.5]).collect();
+        let render = |params: &RuntimeParams| {
+            let mut dsp_core = new_dsp_core(params);
+            let mut output = vec![0.0; block_size * NUM_OUT_CHANNELS];
+            dsp_core.process_stereo_block(
+                &AudioDataRef::new(&input, 2),
+                &mut AudioDataMut::new(&mut output, NUM_OUT_CHANNELS),
+                params,
+            );
+            output
+        };
+
+        let straight = render(&params);
+        params.swap_output_channels = true;
+        let swapped = render(&params);
+
+        // The left ear is attenuated before the swap moves it to the right channel
+        let left_gain = db_to_gain(-6.0);
+        assert!((straight[0] - left_gain).abs() < 1e-6);
+        assert!((straight[1] - 0.5).abs() < 1e-6);
+        for (straight, swapped) in straight
+            .chunks_exact(NUM_OUT_CHANNELS)
+            .zip(swapped.chunks_exact(NUM_OUT_CHANNELS))
+        {
+            assert_eq!(swapped, [straight[1], straight[0]]);
+        }
+    }
+}
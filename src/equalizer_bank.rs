@@ -0,0 +1,78 @@
+use crate::audio_data::AudioDataMut;
+use crate::config::EqualizerProfile;
+use crate::processing_chain::StereoStage;
+use crate::runtime_params::RuntimeParams;
+use crate::surround_virtualizer::Equalizer;
+use concurrent_queue as cq;
+use log::warn;
+use std::iter;
+use std::sync::Arc;
+use strum::IntoEnumIterator;
+
+/// Returns an empty equalizer bank for the audio thread, and the sender that fills it.
+pub fn equalizer_bank() -> (EqualizerBank, EqualizerSender) {
+    let num_profiles = EqualizerProfile::iter().count();
+    let updates = Arc::new(cq::ConcurrentQueue::bounded(num_profiles));
+    // The sender drops the retired equalizers before each update, so that there is room
+    // for every equalizer that update can replace
+    let retired = Arc::new(cq::ConcurrentQueue::bounded(num_profiles * 2));
+    let bank = EqualizerBank {
+        slots: iter::repeat_with(|| None).take(num_profiles).collect(),
+        updates: Arc::clone(&updates),
+        retired: Arc::clone(&retired),
+    };
+    (bank, EqualizerSender { updates, retired })
+}
+
+/// The equalizers of a session, built off the audio thread and handed over by an
+/// `EqualizerSender`. The audio thread only swaps them in, without allocating or
+/// dropping any.
+pub struct EqualizerBank {
+    /// One slot per profile, indexed by `EqualizerProfile as usize`. The profiles without an
+    /// equalizer (not built yet, or failed to load) leave the signal alone.
+    slots: Vec<Option<Equalizer>>,
+    updates: Arc<cq::ConcurrentQueue<(EqualizerProfile, Equalizer)>>,
+    /// Equalizers replaced in `slots`, dropped by the sender.
+    retired: Arc<cq::ConcurrentQueue<Equalizer>>,
+}
+
+impl EqualizerBank {
+    /// Returns the equalizer of `profile`, if it has been built. `None` for `EqualizerProfile::None`.
+    pub fn get(&mut self, profile: EqualizerProfile) -> Option<&mut Equalizer> {
+        while let Ok((profile, eq)) = self.updates.pop() {
+            if let Some(old) = self.slots[profile as usize].replace(eq) {
+                // The queue only fills up if several threads send at once, which makes
+                // dropping the old one here a rare fallback
+                let _ = self.retired.push(old);
+            }
+        }
+        self.slots[profile as usize].as_mut()
+    }
+}
+
+/// The non-real-time end of an `EqualizerBank`.
+pub struct EqualizerSender {
+    updates: Arc<cq::ConcurrentQueue<(EqualizerProfile, Equalizer)>>,
+    retired: Arc<cq::ConcurrentQueue<Equalizer>>,
+}
+
+impl EqualizerSender {
+    /// Hands `eq` to the bank, replacing the equalizer of `profile` there.
+    pub fn send(&self, profile: EqualizerProfile, eq: Equalizer) {
+        while self.retired.pop().is_ok() {}
+        if self.updates.push((profile, eq)).is_err() {
+            warn!(
+                "Dropping the {} equalizer, too many are waiting for the audio thread",
+                profile.label()
+            );
+        }
+    }
+}
+
+impl StereoStage for EqualizerBank {
+    fn process(&mut self, stereo_data: &mut AudioDataMut, params: &RuntimeParams) {
+        if let Some(eq) = self.get(params.equalizer_profile) {
+            eq.process(stereo_data);
+        }
+    }
+}
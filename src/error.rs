@@ -0,0 +1,75 @@
+use std::fmt;
+
+/// Failure of loading or setting up one of the DSP components, the configuration or an
+/// audio device, or of an offline command.
+#[derive(Debug)]
+pub enum Error {
+    /// Malformed or unsupported WAV data, e.g. an HRIR with the wrong channel count.
+    Wav(String),
+    /// A transform couldn't be set up or run, e.g. for a zero block size.
+    Fft(String),
+    Io(std::io::Error),
+    /// The configuration couldn't be serialized or stored, or a setting or command line
+    /// option is unusable.
+    Config(String),
+    /// An audio device couldn't be found or opened the way it was asked for.
+    Device(String),
+    /// The self-test found a problem with the rendered output.
+    SelfTest(String),
+}
+
+impl Error {
+    /// Prefixes the message with what was being loaded, e.g. `FL HRIR`.
+    pub fn context(self, what: &str) -> Self {
+        match self {
+            Self::Wav(msg) => Self::Wav(format!("{}: {}", what, msg)),
+            Self::Fft(msg) => Self::Fft(format!("{}: {}", what, msg)),
+            Self::Io(err) => Self::Io(std::io::Error::new(
+                err.kind(),
+                format!("{}: {}", what, err),
+            )),
+            Self::Config(msg) => Self::Config(format!("{}: {}", what, msg)),
+            Self::Device(msg) => Self::Device(format!("{}: {}", what, msg)),
+            Self::SelfTest(msg) => Self::SelfTest(format!("{}: {}", what, msg)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Wav(msg)
+            | Self::Fft(msg)
+            | Self::Config(msg)
+            | Self::Device(msg)
+            | Self::SelfTest(msg) => f.write_str(msg),
+            Self::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<hound::Error> for Error {
+    fn from(err: hound::Error) -> Self {
+        Self::Wav(format!("invalid WAV data: {}", err))
+    }
+}
+
+impl From<realfft::FftError> for Error {
+    fn from(err: realfft::FftError) -> Self {
+        Self::Fft(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Config(err.to_string())
+    }
+}
@@ -0,0 +1,48 @@
+/// Number of input samples the interpolation of each output sample is based on.
+const NUM_TAPS: usize = 4;
+
+/// Delays a signal by a fractional number of samples, interpolating between the samples
+/// around the delay with a third-order Lagrange polynomial.
+pub struct FractionalDelay {
+    history: Vec<f32>,
+    pos: usize,
+    /// Delay of the first tap, chosen so that the delay falls between the middle taps.
+    first_tap: usize,
+    coeffs: [f32; NUM_TAPS],
+}
+
+impl FractionalDelay {
+    pub fn new(delay_samples: f32) -> Self {
+        let delay = delay_samples.max(0.0) as f64;
+        let first_tap = (delay.floor() as usize).saturating_sub(1);
+        let d = delay - first_tap as f64;
+
+        let coeffs = std::array::from_fn(|k| {
+            (0..NUM_TAPS)
+                .filter(|&j| j != k)
+                .map(|j| (d - j as f64) / (k as f64 - j as f64))
+                .product::<f64>() as f32
+        });
+
+        Self {
+            history: vec![0.0; first_tap + NUM_TAPS],
+            pos: 0,
+            first_tap,
+            coeffs,
+        }
+    }
+
+    pub fn process(&mut self, block: &mut [f32]) {
+        let len = self.history.len();
+        for v in block {
+            self.history[self.pos] = *v;
+            *v = self
+                .coeffs
+                .iter()
+                .enumerate()
+                .map(|(k, c)| c * self.history[(self.pos + len - self.first_tap - k) % len])
+                .sum();
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+}
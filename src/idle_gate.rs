@@ -0,0 +1,74 @@
+use crate::audio_data::db_to_gain;
+
+/// Detects sustained silence on the input, so that processing can be skipped to save power.
+pub struct IdleGate {
+    threshold: f32,
+    timeout_frames: usize,
+    silent_frames: usize,
+}
+
+impl IdleGate {
+    pub fn new(sample_rate: u32, threshold_db: f32, timeout_ms: f32) -> Self {
+        Self {
+            threshold: db_to_gain(threshold_db),
+            timeout_frames: (timeout_ms.max(0.0) * sample_rate as f32 / 1000.0) as usize,
+            silent_frames: 0,
+        }
+    }
+
+    /// Takes the next interleaved input block of `num_frames` frames and returns whether
+    /// the input has stayed below the threshold for the whole timeout. A single block
+    /// above the threshold ends the idle state.
+    pub fn update(&mut self, block: &[f32], num_frames: usize) -> bool {
+        let peak = block.iter().fold(0.0_f32, |peak, v| peak.max(v.abs()));
+        if peak < self.threshold {
+            self.silent_frames = self.silent_frames.saturating_add(num_frames);
+        } else {
+            self.silent_frames = 0;
+        }
+        self.silent_frames >= self.timeout_frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const NUM_FRAMES: usize = 480;
+
+    /// A stereo block of `NUM_FRAMES` frames at a constant level.
+    fn block(level: f32) -> Vec<f32> {
+        vec![level; NUM_FRAMES * 2]
+    }
+
+    #[test]
+    fn goes_idle_after_the_timeout_and_resumes_on_signal() {
+        // 100 ms, ten blocks
+        let mut gate = IdleGate::new(SAMPLE_RATE, -60.0, 100.0);
+        let quiet = block(db_to_gain(-70.0));
+
+        for _ in 0..9 {
+            assert!(!gate.update(&quiet, NUM_FRAMES));
+        }
+        assert!(gate.update(&quiet, NUM_FRAMES));
+        assert!(gate.update(&quiet, NUM_FRAMES));
+
+        // A single louder block ends it at once, and the timeout starts over
+        assert!(!gate.update(&block(db_to_gain(-50.0)), NUM_FRAMES));
+        for _ in 0..9 {
+            assert!(!gate.update(&quiet, NUM_FRAMES));
+        }
+        assert!(gate.update(&quiet, NUM_FRAMES));
+    }
+
+    #[test]
+    fn single_loud_sample_keeps_the_gate_open() {
+        let mut gate = IdleGate::new(SAMPLE_RATE, -60.0, 10.0);
+        let mut click = block(0.0);
+        click[NUM_FRAMES + 1] = -0.5;
+        for _ in 0..100 {
+            assert!(!gate.update(&click, NUM_FRAMES));
+        }
+    }
+}
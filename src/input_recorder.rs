@@ -0,0 +1,118 @@
+use crate::error::Error;
+use concurrent_queue as cq;
+use log::{info, warn};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Buffers in flight between the input callback and the writer thread.
+const NUM_BUFS: usize = 16;
+const WRITER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Copies the raw device input to a WAV file for a fixed duration.
+///
+/// The input callback only copies into preallocated buffers and hands them over to a writer
+/// thread, so it never waits for the file. If the writer falls behind, buffers are skipped.
+/// Dropping the recorder (when the duration is complete or the session ends) closes the file.
+pub struct InputRecorder {
+    num_channels: usize,
+    frames_left: usize,
+    free: Arc<cq::ConcurrentQueue<Vec<f32>>>,
+    filled: Arc<cq::ConcurrentQueue<Vec<f32>>>,
+    writer: thread::Thread,
+}
+
+impl InputRecorder {
+    /// Creates the file and starts the writer thread. `max_buffer_len` is the expected
+    /// device buffer length in samples, preallocated so that the callback doesn't allocate.
+    pub fn start(
+        path: PathBuf,
+        num_channels: u16,
+        sample_rate: u32,
+        duration: Duration,
+        max_buffer_len: usize,
+    ) -> Result<Self, Error> {
+        let spec = hound::WavSpec {
+            channels: num_channels,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to create '{}': {}",
+                path.display(),
+                e
+            )))
+        })?;
+
+        let free = Arc::new(cq::ConcurrentQueue::bounded(NUM_BUFS));
+        for _ in 0..NUM_BUFS {
+            free.push(Vec::with_capacity(max_buffer_len)).unwrap();
+        }
+        let filled = Arc::new(cq::ConcurrentQueue::<Vec<f32>>::bounded(NUM_BUFS));
+        let free2 = Arc::clone(&free);
+        let filled2 = Arc::clone(&filled);
+
+        let handle = thread::Builder::new()
+            .name("input-recorder".to_string())
+            .spawn(move || {
+                let mut write_result = Ok(());
+                loop {
+                    match filled2.pop() {
+                        Ok(buf) => {
+                            if write_result.is_ok() {
+                                write_result = buf.iter().try_for_each(|v| writer.write_sample(*v));
+                            }
+                            let _ = free2.push(buf);
+                        }
+                        Err(cq::PopError::Empty) => thread::park_timeout(WRITER_POLL_INTERVAL),
+                        Err(cq::PopError::Closed) => break,
+                    }
+                }
+                match write_result.and_then(|()| writer.finalize()) {
+                    Ok(()) => info!("Recorded input to '{}'", path.display()),
+                    Err(e) => warn!("Failed to write '{}': {}", path.display(), e),
+                }
+            })
+            .map_err(|e| Error::from(e).context("Failed to start the input recorder"))?;
+
+        Ok(Self {
+            num_channels: num_channels as usize,
+            frames_left: (duration.as_secs_f64() * sample_rate as f64) as usize,
+            free,
+            filled,
+            writer: handle.thread().clone(),
+        })
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    /// Records the whole frames of the interleaved `data` that still fit the duration.
+    /// Returns `false` once the duration is complete.
+    pub fn submit(&mut self, data: &[f32]) -> bool {
+        let num_frames = (data.len() / self.num_channels).min(self.frames_left);
+        if num_frames > 0
+            && let Ok(mut buf) = self.free.pop()
+        {
+            buf.clear();
+            buf.extend_from_slice(&data[..num_frames * self.num_channels]);
+            // Every buffer is in one of the two queues, so there is always room
+            self.filled.push(buf).unwrap();
+            self.writer.unpark();
+        }
+        self.frames_left -= num_frames;
+        self.frames_left > 0
+    }
+}
+
+impl Drop for InputRecorder {
+    fn drop(&mut self) {
+        // The writer drains what is left, then finalizes the file
+        self.filled.close();
+        self.writer.unpark();
+    }
+}
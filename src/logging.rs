@@ -0,0 +1,56 @@
+use crate::config::get_cache_path;
+use flexi_logger::{Cleanup, Criterion, Duplicate, FileSpec, Logger, LoggerHandle, Naming};
+use log::warn;
+use std::sync::OnceLock;
+
+/// Environment variable that overrides the configured log level.
+const LOG_LEVEL_ENV: &str = "AUDIO_VIRTUALIZER_LOG";
+pub const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+static LOGGER: OnceLock<LoggerHandle> = OnceLock::new();
+
+pub fn setup_logging() {
+    let cache_dir = get_cache_path();
+    let _ = std::fs::create_dir_all(&cache_dir).ok();
+
+    let level = std::env::var(LOG_LEVEL_ENV).unwrap_or_else(|_| DEFAULT_LOG_LEVEL.to_string());
+
+    let handle = Logger::try_with_str(level)
+        .unwrap()
+        .log_to_file(
+            FileSpec::default()
+                .directory(cache_dir)
+                .basename("audio_virtualizer"),
+        )
+        .format(flexi_logger::detailed_format)
+        .rotate(
+            Criterion::Size(1_000_000),
+            Naming::Numbers,
+            Cleanup::KeepLogFiles(3),
+        )
+        .duplicate_to_stderr(Duplicate::Info)
+        .start()
+        .unwrap();
+    let _ = LOGGER.set(handle);
+
+    log_panics::init();
+}
+
+/// Returns `true` if the log level is forced through the environment.
+pub fn is_level_overridden() -> bool {
+    std::env::var_os(LOG_LEVEL_ENV).is_some()
+}
+
+/// Applies a new log level at runtime, unless it is overridden through the environment.
+pub fn set_log_level(level: &str) {
+    if is_level_overridden() {
+        return;
+    }
+    let Some(handle) = LOGGER.get() else {
+        return;
+    };
+    if let Err(e) = handle.parse_new_spec(level) {
+        warn!("Invalid log level '{}': {}", level, e);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::biquad::Biquad;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Measurements are accumulated in 100 ms steps: gating blocks are 4 steps long
+/// (400 ms with 75% overlap) and the short-term window is 30 steps (3 s).
+const STEP_MS: usize = 100;
+const STEPS_PER_BLOCK: usize = 4;
+const STEPS_PER_SHORT_TERM: usize = 30;
+const ABSOLUTE_GATE_LUFS: f32 = -70.0;
+const RELATIVE_GATE_LU: f32 = -10.0;
+/// Gated block loudness is kept as a histogram from the absolute gate up to +5 LUFS.
+const HISTOGRAM_RESOLUTION_LU: f32 = 0.1;
+const HISTOGRAM_MAX_LUFS: f32 = 5.0;
+
+/// ITU-R BS.1770 loudness meter for an interleaved stereo signal.
+pub struct LoudnessMeter {
+    filters: [[Biquad; 2]; 2],
+    step_len: usize,
+    step_frames: usize,
+    step_power: f64,
+    steps: VecDeque<f64>,
+    histogram: Vec<u64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32) -> Self {
+        let num_bins =
+            ((HISTOGRAM_MAX_LUFS - ABSOLUTE_GATE_LUFS) / HISTOGRAM_RESOLUTION_LU) as usize;
+
+        Self {
+            filters: [k_weighting(sample_rate), k_weighting(sample_rate)],
+            step_len: sample_rate as usize * STEP_MS / 1000,
+            step_frames: 0,
+            step_power: 0.0,
+            steps: VecDeque::with_capacity(STEPS_PER_SHORT_TERM),
+            histogram: vec![0; num_bins],
+        }
+    }
+
+    pub fn process(&mut self, stereo_data: &[f32]) {
+        for frame in stereo_data.chunks_exact(2) {
+            for (v, [shelf, high_pass]) in frame.iter().zip(&mut self.filters) {
+                let weighted = high_pass.process_sample(shelf.process_sample(*v)) as f64;
+                self.step_power += weighted * weighted;
+            }
+
+            self.step_frames += 1;
+            if self.step_frames == self.step_len {
+                self.finish_step();
+            }
+        }
+    }
+
+    fn finish_step(&mut self) {
+        if self.steps.len() == STEPS_PER_SHORT_TERM {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.step_power / self.step_len as f64);
+        self.step_power = 0.0;
+        self.step_frames = 0;
+
+        if self.steps.len() >= STEPS_PER_BLOCK {
+            let block_power =
+                self.steps.iter().rev().take(STEPS_PER_BLOCK).sum::<f64>() / STEPS_PER_BLOCK as f64;
+            let block_loudness = power_to_lufs(block_power);
+            if block_loudness > ABSOLUTE_GATE_LUFS {
+                let bin =
+                    ((block_loudness - ABSOLUTE_GATE_LUFS) / HISTOGRAM_RESOLUTION_LU) as usize;
+                let last_bin = self.histogram.len() - 1;
+                self.histogram[bin.min(last_bin)] += 1;
+            }
+        }
+    }
+
+    /// Loudness over the last 3 seconds, `-inf` until that much audio has been measured.
+    pub fn short_term_lufs(&self) -> f32 {
+        if self.steps.len() < STEPS_PER_SHORT_TERM {
+            return f32::NEG_INFINITY;
+        }
+        power_to_lufs(self.steps.iter().sum::<f64>() / self.steps.len() as f64)
+    }
+
+    /// Gated loudness since the meter was created, `-inf` if nothing passed the gates.
+    pub fn integrated_lufs(&self) -> f32 {
+        let bin_power = |bin: usize| {
+            lufs_to_power(ABSOLUTE_GATE_LUFS + (bin as f32 + 0.5) * HISTOGRAM_RESOLUTION_LU)
+        };
+        let gated_mean = |min_bin: usize| {
+            let (power, count) = self.histogram[min_bin..]
+                .iter()
+                .enumerate()
+                .fold((0.0, 0), |(power, count), (i, n)| {
+                    (power + bin_power(min_bin + i) * *n as f64, count + n)
+                });
+            (count > 0).then(|| power / count as f64)
+        };
+
+        let Some(absolute_gated) = gated_mean(0) else {
+            return f32::NEG_INFINITY;
+        };
+        let relative_gate = power_to_lufs(absolute_gated) + RELATIVE_GATE_LU;
+        let min_bin = ((relative_gate - ABSOLUTE_GATE_LUFS) / HISTOGRAM_RESOLUTION_LU)
+            .clamp(0.0, (self.histogram.len() - 1) as f32) as usize;
+
+        gated_mean(min_bin).map_or(f32::NEG_INFINITY, power_to_lufs)
+    }
+}
+
+fn power_to_lufs(power: f64) -> f32 {
+    (-0.691 + 10.0 * power.log10()) as f32
+}
+
+fn lufs_to_power(lufs: f32) -> f64 {
+    10.0_f64.powf((lufs as f64 + 0.691) / 10.0)
+}
+
+/// The two K-weighting stages (high shelf, then high pass) for the given sample rate.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.97445095553;
+    let gain_db = 3.99984385397335;
+    let q = 0.70717523695542;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10.0_f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.499666774154542);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.1354708760244;
+    let q = 0.500327037323877;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// A 1 kHz sine in both channels. At full scale it reads 0 LUFS by the BS.1770 calibration.
+    fn sine_1k(peak_dbfs: f32, secs: usize) -> Vec<f32> {
+        let amplitude = 10.0_f32.powf(peak_dbfs / 20.0);
+        (0..SAMPLE_RATE as usize * secs)
+            .flat_map(|i| {
+                let v =
+                    amplitude * (2.0 * PI * 1000.0 * i as f64 / SAMPLE_RATE as f64).sin() as f32;
+                [v, v]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn calibrated_sine_reads_its_level() {
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE);
+        assert_eq!(meter.short_term_lufs(), f32::NEG_INFINITY);
+        assert_eq!(meter.integrated_lufs(), f32::NEG_INFINITY);
+
+        // Fed in blocks of an odd length so that steps straddle the calls
+        for block in sine_1k(-23.0, 10).chunks(2 * 1001) {
+            meter.process(block);
+        }
+        let short_term = meter.short_term_lufs();
+        let integrated = meter.integrated_lufs();
+        assert!((short_term + 23.0).abs() < 0.5, "{}", short_term);
+        assert!((integrated + 23.0).abs() < 0.5, "{}", integrated);
+    }
+
+    #[test]
+    fn silence_is_gated_out() {
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE);
+        meter.process(&sine_1k(-23.0, 5));
+        meter.process(&vec![0.0; SAMPLE_RATE as usize * 2 * 5]);
+
+        // The silent half falls below the absolute gate and leaves the integrated value alone
+        assert!(meter.short_term_lufs() < ABSOLUTE_GATE_LUFS);
+        assert!((meter.integrated_lufs() + 23.0).abs() < 0.5);
+    }
+}
@@ -1,55 +1,135 @@
+mod analysis_tap;
 mod app;
+mod assets;
 mod audio_data;
 mod audio_swapchain;
 mod backend;
+mod biquad;
 mod block_convolver;
 mod config;
 mod coreaudio;
+mod crossfeed;
+mod crosstalk_canceller;
+mod diagnostics;
+mod dither;
+mod dsp_core;
+mod equalizer_bank;
+mod error;
+mod fractional_delay;
+mod idle_gate;
+mod input_recorder;
+mod logging;
+mod loudness;
 mod macros;
+mod network_input;
+mod noise_gate;
+mod offline;
+mod processing_chain;
+mod runtime_params;
+mod seqlock;
+mod settings_window;
+mod smoother;
+mod stream_config;
 mod surround_virtualizer;
+mod test_tone;
 
 use crate::app::{App, AppUserEvent};
-use crate::config::get_cache_path;
-use flexi_logger::{Cleanup, Criterion, Duplicate, FileSpec, Logger, Naming};
-use log::error;
+use log::{error, info};
+use std::path::{Path, PathBuf};
 use winit::event_loop::EventLoop;
 
-fn setup_logging() {
-    let cache_dir = get_cache_path();
-    let _ = std::fs::create_dir_all(&cache_dir).ok();
-
-    Logger::try_with_str("info")
-        .unwrap()
-        .log_to_file(
-            FileSpec::default()
-                .directory(cache_dir)
-                .basename("audio_virtualizer"),
-        )
-        .format(flexi_logger::detailed_format)
-        .rotate(
-            Criterion::Size(1_000_000),
-            Naming::Numbers,
-            Cleanup::KeepLogFiles(3),
-        )
-        .duplicate_to_stderr(Duplicate::Info)
-        .start()
-        .unwrap();
-
-    log_panics::init();
+/// Returns the value following `flag` on the command line, if present.
+fn get_arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let pos = args.iter().position(|arg| arg == flag)?;
+    args.get(pos + 1).map(String::as_str)
 }
 
 fn main() {
-    setup_logging();
+    logging::setup_logging();
     config::load();
+    logging::set_log_level(&config::get_snapshot().log_level);
+
+    let args: Vec<String> = std::env::args().collect();
+    for flag in ["--hrir-dir", "--eq"] {
+        if args.iter().any(|arg| arg == flag) && get_arg_value(&args, flag).is_none() {
+            error!("Usage: {} <path>", flag);
+            std::process::exit(1);
+        }
+    }
+    let asset_overrides = assets::Overrides {
+        hrir_dir: get_arg_value(&args, "--hrir-dir").map(PathBuf::from),
+        eq_path: get_arg_value(&args, "--eq").map(PathBuf::from),
+    };
+    if let Err(err) = backend::validate_asset_overrides(&asset_overrides) {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if asset_overrides.eq_path.is_some()
+        && config::get_snapshot().equalizer_profile == config::EqualizerProfile::None
+    {
+        info!("The --eq file is used once an equalizer profile is selected");
+    }
+    assets::set_overrides(asset_overrides);
+    if args.iter().any(|arg| arg == "--dump-ir") {
+        let Some(path) = get_arg_value(&args, "--dump-ir") else {
+            error!("Usage: --dump-ir <out.wav>");
+            std::process::exit(1);
+        };
+        match offline::dump_ir(Path::new(path), &config::get_snapshot()) {
+            Ok(()) => info!("Wrote binaural impulse response to '{}'", path),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--dump-eq-response") {
+        let Some(path) = get_arg_value(&args, "--dump-eq-response") else {
+            error!("Usage: --dump-eq-response <out.csv>");
+            std::process::exit(1);
+        };
+        match offline::dump_eq_response(Path::new(path), &config::get_snapshot()) {
+            Ok(()) => info!("Wrote equalizer frequency response to '{}'", path),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--selftest") {
+        match offline::self_test(&config::get_snapshot()) {
+            Ok(summary) => info!("Self-test passed: {}", summary),
+            Err(err) => {
+                error!("Self-test failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--bench-dsp") {
+        match offline::bench_dsp(&config::get_snapshot()) {
+            Ok(report) => info!("DSP benchmark:\n{}", report),
+            Err(err) => {
+                error!("DSP benchmark failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
     let mut event_loop_builder = EventLoop::<AppUserEvent>::with_user_event();
 
     #[cfg(target_os = "macos")]
     {
-        // hide the app from the dock
+        // hide the app from the dock, while still letting the settings window take focus
         use winit::platform::macos::EventLoopBuilderExtMacOS;
         event_loop_builder
-            .with_activation_policy(winit::platform::macos::ActivationPolicy::Prohibited);
+            .with_activation_policy(winit::platform::macos::ActivationPolicy::Accessory);
     }
 
     let event_loop = event_loop_builder.build().unwrap();
@@ -68,12 +148,20 @@ fn main() {
         }
     }));
 
-    let mut app = App::new();
+    let mut app = App::new(event_loop.create_proxy());
     app.update_from_config(&config::get_snapshot());
 
-    std::thread::spawn(|| {
+    let backend_thread = std::thread::spawn(|| {
         backend::run();
     });
 
     event_loop.run_app(&mut app).unwrap();
+
+    backend::shutdown();
+    if backend_thread.join().is_err() {
+        error!("Backend thread panicked during shutdown");
+    }
+    if let Err(err) = config::save() {
+        error!("Failed to save the config: {}", err);
+    }
 }
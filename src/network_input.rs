@@ -0,0 +1,190 @@
+use log::{info, warn};
+use std::io::{self, Read};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicBool};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Start of every connection: these four bytes, then the channel count (`u16`) and the
+/// sample rate (`u32`), both little endian. Interleaved little-endian `f32` frames follow
+/// for as long as the connection stays open.
+const HEADER_MAGIC: [u8; 4] = *b"AVIN";
+const HEADER_LEN: usize = 10;
+const SAMPLE_LEN: usize = size_of::<f32>();
+/// How often a blocked read or accept checks whether the receiver is stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const READ_BUF_LEN: usize = 16384;
+
+/// Receives the surround input over TCP in place of an input device, from one sender at a time.
+///
+/// The sender's channels are mapped onto the session's: extra ones are dropped and missing
+/// ones are silent. While no sender is connected, silence is delivered in real time so that
+/// the output keeps running. Stopped and joined on drop.
+pub struct NetworkReceiver {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl NetworkReceiver {
+    /// Listens on `addr` and passes the received interleaved frames to `on_input`,
+    /// at most `chunk_frames` at once.
+    pub fn start<F>(
+        addr: &str,
+        num_channels: usize,
+        sample_rate: u32,
+        chunk_frames: usize,
+        mut on_input: F,
+    ) -> Result<Self, String>
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        let listener = TcpListener::bind(addr)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+            .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+        info!("Listening for network input on {}", addr);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = Arc::clone(&stop);
+        let handle = thread::Builder::new()
+            .name("network-input".to_string())
+            .spawn(move || {
+                let silence = vec![0.0; chunk_frames * num_channels];
+                let chunk_duration =
+                    Duration::from_secs_f64(chunk_frames as f64 / sample_rate as f64);
+                let mut next_chunk = Instant::now();
+
+                while !stop2.load(atomic::Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, peer)) => {
+                            info!("Network input connected from {}", peer);
+                            match receive(
+                                stream,
+                                num_channels,
+                                sample_rate,
+                                chunk_frames,
+                                &stop2,
+                                &mut on_input,
+                            ) {
+                                Ok(()) => info!("Network input from {} disconnected", peer),
+                                Err(err) => warn!("Network input from {} failed: {}", peer, err),
+                            }
+                            next_chunk = Instant::now();
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            on_input(&silence);
+                            next_chunk += chunk_duration;
+                            thread::sleep(next_chunk.saturating_duration_since(Instant::now()));
+                        }
+                        Err(err) => {
+                            warn!("Failed to accept a network input connection: {}", err);
+                            thread::sleep(POLL_INTERVAL);
+                            next_chunk = Instant::now();
+                        }
+                    }
+                }
+            })
+            .map_err(|e| format!("Failed to start the network input: {}", e))?;
+
+        Ok(Self {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for NetworkReceiver {
+    fn drop(&mut self) {
+        self.stop.store(true, atomic::Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Reads one connection until the sender closes it or the receiver is stopped.
+fn receive(
+    mut stream: TcpStream,
+    num_channels: usize,
+    sample_rate: u32,
+    chunk_frames: usize,
+    stop: &AtomicBool,
+    on_input: &mut impl FnMut(&[f32]),
+) -> Result<(), String> {
+    // Accepted streams may inherit the non-blocking mode of the listener
+    stream
+        .set_nonblocking(false)
+        .and_then(|()| stream.set_read_timeout(Some(POLL_INTERVAL)))
+        .map_err(|e| e.to_string())?;
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut read_buf = vec![0; READ_BUF_LEN];
+    let mut samples = Vec::with_capacity(chunk_frames * num_channels);
+    let mut sender_channels = None;
+
+    while !stop.load(atomic::Ordering::Relaxed) {
+        match stream.read(&mut read_buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => pending.extend_from_slice(&read_buf[..len]),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => return Err(err.to_string()),
+        }
+
+        let channels = match sender_channels {
+            Some(channels) => channels,
+            None if pending.len() < HEADER_LEN => continue,
+            None => {
+                let channels = parse_header(&pending[..HEADER_LEN], sample_rate)?;
+                pending.drain(..HEADER_LEN);
+                info!("Network input: {} channels at {} Hz", channels, sample_rate);
+                *sender_channels.insert(channels)
+            }
+        };
+
+        let frame_len = channels * SAMPLE_LEN;
+        let num_bytes = pending.len() / frame_len * frame_len;
+        for chunk in pending[..num_bytes].chunks(chunk_frames * frame_len) {
+            samples.clear();
+            for frame in chunk.chunks_exact(frame_len) {
+                samples.extend((0..num_channels).map(|ch| {
+                    frame
+                        .get(ch * SAMPLE_LEN..(ch + 1) * SAMPLE_LEN)
+                        .map_or(0.0, |bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                }));
+            }
+            on_input(&samples);
+        }
+        pending.drain(..num_bytes);
+    }
+    Ok(())
+}
+
+/// Returns the sender's channel count.
+fn parse_header(header: &[u8], sample_rate: u32) -> Result<usize, String> {
+    if header[..4] != HEADER_MAGIC {
+        return Err("invalid header".to_string());
+    }
+    let num_channels = u16::from_le_bytes([header[4], header[5]]) as usize;
+    let sender_rate = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+
+    if num_channels == 0 {
+        return Err("the sender announced no channels".to_string());
+    }
+    // There is no resampler, the sender has to match the processing rate
+    if sender_rate != sample_rate {
+        return Err(format!(
+            "the sender's sample rate of {} Hz differs from the required {} Hz",
+            sender_rate, sample_rate
+        ));
+    }
+    Ok(num_channels)
+}
@@ -0,0 +1,159 @@
+use crate::audio_data::db_to_gain;
+
+/// Mutes the input while it stays below a threshold, so that the hiss some loopback drivers
+/// produce during silence isn't spatialized into ambient noise.
+///
+/// The gate opens with the attack time as soon as the level exceeds the threshold, stays open
+/// for the hold time after the level falls below it, then closes with the release time.
+pub struct NoiseGate {
+    timing: GateTiming,
+    num_channels: usize,
+    per_channel: bool,
+    /// One state per channel, or a single one shared by all channels.
+    states: Vec<GateState>,
+}
+
+struct GateTiming {
+    threshold: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    hold_frames: usize,
+}
+
+#[derive(Clone, Copy)]
+struct GateState {
+    gain: f32,
+    hold_frames_left: usize,
+}
+
+impl NoiseGate {
+    /// Blocks passed to `process` are interleaved with `num_channels` channels.
+    pub fn new(
+        sample_rate: u32,
+        num_channels: usize,
+        threshold_db: f32,
+        attack_ms: f32,
+        hold_ms: f32,
+        release_ms: f32,
+        per_channel: bool,
+    ) -> Self {
+        Self {
+            timing: GateTiming {
+                threshold: db_to_gain(threshold_db),
+                attack_coeff: time_coeff(sample_rate, attack_ms),
+                release_coeff: time_coeff(sample_rate, release_ms),
+                hold_frames: (hold_ms.max(0.0) * sample_rate as f32 / 1000.0) as usize,
+            },
+            num_channels,
+            per_channel,
+            // Start closed, the first loud frame opens the gate within the attack time
+            states: vec![
+                GateState {
+                    gain: 0.0,
+                    hold_frames_left: 0,
+                };
+                if per_channel { num_channels } else { 1 }
+            ],
+        }
+    }
+
+    /// Gates the interleaved `block` in place.
+    pub fn process(&mut self, block: &mut [f32]) {
+        for frame in block.chunks_exact_mut(self.num_channels) {
+            if self.per_channel {
+                for (v, state) in frame.iter_mut().zip(&mut self.states) {
+                    let gain = state.advance(&self.timing, v.abs());
+                    *v *= gain;
+                }
+            } else {
+                let level = frame.iter().fold(0.0_f32, |peak, v| peak.max(v.abs()));
+                let gain = self.states[0].advance(&self.timing, level);
+                frame.iter_mut().for_each(|v| *v *= gain);
+            }
+        }
+    }
+}
+
+impl GateState {
+    /// Advances by one frame of the given level and returns the gain to apply.
+    fn advance(&mut self, timing: &GateTiming, level: f32) -> f32 {
+        let is_open = if level >= timing.threshold {
+            self.hold_frames_left = timing.hold_frames;
+            true
+        } else if self.hold_frames_left > 0 {
+            self.hold_frames_left -= 1;
+            true
+        } else {
+            false
+        };
+
+        let (target, coeff) = if is_open {
+            (1.0, timing.attack_coeff)
+        } else {
+            (0.0, timing.release_coeff)
+        };
+        self.gain = target + coeff * (self.gain - target);
+        self.gain
+    }
+}
+
+/// One-pole coefficient that covers ~63% of a step in `time_ms`.
+fn time_coeff(sample_rate: u32, time_ms: f32) -> f32 {
+    let time_frames = time_ms * sample_rate as f32 / 1000.0;
+    if time_frames > 0.0 {
+        (-1.0 / time_frames).exp()
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn gate(per_channel: bool) -> NoiseGate {
+        NoiseGate::new(SAMPLE_RATE, 2, -60.0, 0.0, 10.0, 0.0, per_channel)
+    }
+
+    #[test]
+    fn mutes_sub_threshold_input() {
+        let mut gate = gate(false);
+        let mut block = vec![1e-4; 2 * 4800];
+        gate.process(&mut block);
+        assert!(block.iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn passes_loud_input_through() {
+        let mut gate = gate(false);
+        let mut block = vec![0.5; 2 * 256];
+        gate.process(&mut block);
+        assert!(block.iter().all(|v| *v == 0.5));
+    }
+
+    #[test]
+    fn gates_channels_independently() {
+        let mut gate = gate(true);
+        let mut block: Vec<f32> = [0.5, 1e-4].repeat(256);
+        gate.process(&mut block);
+        for frame in block.chunks_exact(2) {
+            assert_eq!(frame, [0.5, 0.0]);
+        }
+    }
+
+    #[test]
+    fn holds_then_releases() {
+        let hold_frames = 480;
+        let mut gate = NoiseGate::new(SAMPLE_RATE, 1, -60.0, 0.0, 10.0, 5.0, false);
+        let mut block = vec![0.5; 256];
+        gate.process(&mut block);
+
+        let mut tail = vec![1e-4; hold_frames + 4800];
+        gate.process(&mut tail);
+        assert!(tail[..hold_frames].iter().all(|v| *v == 1e-4));
+        assert!(tail[hold_frames + 1] < 1e-4);
+        assert!(tail.last().unwrap().abs() < 1e-8);
+    }
+}
@@ -0,0 +1,452 @@
+use crate::{
+    audio_data::{
+        AudioDataMut, AudioDataRef, CH_BUF_SIZE, EngineConfig, HRIR_SAMPLE_RATE, NUM_OUT_CHANNELS,
+        gain_to_db, white_noise,
+    },
+    backend::{self, NUM_SURROUND_CHANNELS, get_engine_config},
+    block_convolver::new_convolver,
+    config::{AppConfig, FftPrecision},
+    error::Error,
+    surround_virtualizer::{SurroundVirtualizer, SurroundVirtualizerConfig},
+};
+use log::{info, warn};
+use realfft::RealFftPlanner;
+use std::fmt::Write;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Distance in frames between the impulses of consecutive input channels, a multiple of
+/// every block size.
+const IR_SLOT_FRAMES: usize = CH_BUF_SIZE * 2;
+/// Peak level below which the self-test considers the output silent (-120 dBFS).
+const SILENCE_THRESHOLD: f32 = 1e-6;
+/// Upper bound on the rendered equalizer response, in case its tail never decays.
+const MAX_EQ_RESPONSE_FRAMES: usize = HRIR_SAMPLE_RATE as usize;
+/// Smallest FFT used for the equalizer response, for a resolution of about 6 Hz.
+const MIN_EQ_FFT_SIZE: usize = 8192;
+/// Block sizes and HRIR lengths (in frames) that `bench_dsp` runs every combination of.
+const BENCH_BLOCK_SIZES: [usize; 5] = [256, 512, 1024, 2048, 4096];
+const BENCH_HRIR_LENS: [usize; 3] = [512, 4096, 16384];
+/// Duration of the audio timed per combination, after `BENCH_WARMUP_BLOCKS` untimed blocks.
+const BENCH_AUDIO_SECS: f64 = 5.0;
+/// Like `BENCH_AUDIO_SECS` for each size of the calibration, which delays the first session.
+const CALIBRATION_AUDIO_SECS: f64 = 0.5;
+const BENCH_WARMUP_BLOCKS: usize = 8;
+/// Block sizes `calibrate_block_size` tries, from the safest down to the lowest latency.
+const CALIBRATION_BLOCK_SIZES: [usize; 4] = [2048, 1024, 512, 256];
+/// Highest processing time per block, relative to the block duration, that the calibration
+/// accepts. The rest is headroom for other load on the machine.
+const CALIBRATION_MAX_LOAD: f64 = 0.5;
+
+/// Renders a delta in each surround input channel, one after another `IR_SLOT_FRAMES` apart,
+/// through the virtualizer and returns the interleaved stereo response.
+pub fn render_surround_ir(config: &AppConfig) -> Result<Vec<f32>, Error> {
+    Ok(render_impulses(config, |_| {})?.0)
+}
+
+/// Like `render_surround_ir`, but passes each stereo block through `post_process` and also
+/// returns the longest time spent on a single block.
+fn render_impulses(
+    config: &AppConfig,
+    mut post_process: impl FnMut(&mut AudioDataMut),
+) -> Result<(Vec<f32>, Duration), Error> {
+    let hrirs = backend::load_hrirs();
+    let mut sv = SurroundVirtualizer::new(&backend::get_virtualizer_config(config, &hrirs))?;
+    let block_size = get_engine_config(config).block_size;
+
+    // one extra slot lets the tail of the last response ring out
+    let total_frames = IR_SLOT_FRAMES * (NUM_SURROUND_CHANNELS + 1);
+    let mut input = vec![0.0_f32; block_size * NUM_SURROUND_CHANNELS];
+    let mut output = vec![0.0_f32; total_frames * NUM_OUT_CHANNELS];
+    let mut max_block_time = Duration::ZERO;
+
+    for (block_idx, out_block) in output
+        .chunks_exact_mut(block_size * NUM_OUT_CHANNELS)
+        .enumerate()
+    {
+        input.fill(0.0);
+        for ch in 0..NUM_SURROUND_CHANNELS {
+            let impulse_frame = ch * IR_SLOT_FRAMES;
+            if impulse_frame / block_size == block_idx {
+                input[(impulse_frame % block_size) * NUM_SURROUND_CHANNELS + ch] = 1.0;
+            }
+        }
+
+        let block_start = Instant::now();
+        let input_adata = AudioDataRef::new(&input, NUM_SURROUND_CHANNELS);
+        let mut stereo_adata = AudioDataMut::new(out_block, NUM_OUT_CHANNELS);
+        sv.process_ch8(&input_adata, &mut stereo_adata);
+        post_process(&mut stereo_adata);
+        max_block_time = max_block_time.max(block_start.elapsed());
+    }
+
+    Ok((output, max_block_time))
+}
+
+/// Runs impulses through the virtualizer and the selected equalizer without touching any device.
+/// Checks that the output is finite and audible and that the slowest block fits the real-time
+/// budget. Returns a summary of the measurements, or the reason of the failure.
+pub fn self_test(config: &AppConfig) -> Result<String, Error> {
+    let mut eq = backend::build_equalizer(config)?;
+    let (output, max_block_time) = render_impulses(config, |block| {
+        if let Some(eq) = &mut eq {
+            eq.process(block);
+        }
+    })?;
+
+    let num_non_finite = output.iter().filter(|v| !v.is_finite()).count();
+    if num_non_finite > 0 {
+        return Err(Error::SelfTest(format!(
+            "{} output samples are not finite",
+            num_non_finite
+        )));
+    }
+
+    let peak = output.iter().fold(0.0_f32, |peak, v| peak.max(v.abs()));
+    if peak < SILENCE_THRESHOLD {
+        return Err(Error::SelfTest("the output is silent".to_string()));
+    }
+
+    let block_size = get_engine_config(config).block_size;
+    let budget = Duration::from_secs_f64(block_size as f64 / HRIR_SAMPLE_RATE as f64);
+    let summary = format!(
+        "block size {}, equalizer {}, output peak {:.1} dB, slowest block {:.2} ms of {:.2} ms",
+        block_size,
+        config.equalizer_profile.label(),
+        gain_to_db(peak),
+        max_block_time.as_secs_f64() * 1000.0,
+        budget.as_secs_f64() * 1000.0
+    );
+    if max_block_time > budget {
+        return Err(Error::SelfTest(format!(
+            "processing is slower than real time: {}",
+            summary
+        )));
+    }
+
+    Ok(summary)
+}
+
+/// Writes the combined binaural impulse response of the current configuration to a WAV file.
+pub fn dump_ir(path: &Path, config: &AppConfig) -> Result<(), Error> {
+    let response = render_surround_ir(config)?;
+
+    let spec = hound::WavSpec {
+        channels: NUM_OUT_CHANNELS as u16,
+        sample_rate: HRIR_SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer =
+        hound::WavWriter::create(path, spec).map_err(|e| file_error("create", path, e))?;
+    for sample in response {
+        writer
+            .write_sample(sample)
+            .map_err(|e| file_error("write", path, e))?;
+    }
+    writer
+        .finalize()
+        .map_err(|e| file_error("finalize", path, e))
+}
+
+/// Writes the magnitude response (in dB, per FFT bin) of the configured equalizer to a CSV
+/// file with `frequency_hz,left_db,right_db` rows. The response includes the preamp.
+pub fn dump_eq_response(path: &Path, config: &AppConfig) -> Result<(), Error> {
+    let mut eq = backend::build_equalizer(config)?
+        .ok_or_else(|| Error::Config("No equalizer profile is selected".to_string()))?;
+
+    // Render an impulse block by block until the response has decayed
+    let mut response: [Vec<f32>; 2] = [Vec::new(), Vec::new()];
+    let block_size = get_engine_config(config).block_size;
+    let mut block = vec![0.0_f32; block_size * NUM_OUT_CHANNELS];
+    block[..NUM_OUT_CHANNELS].fill(1.0);
+    while response[0].len() < MAX_EQ_RESPONSE_FRAMES {
+        let mut stereo_adata = AudioDataMut::new(&mut block, NUM_OUT_CHANNELS);
+        eq.process(&mut stereo_adata);
+        for (ch, channel_response) in response.iter_mut().enumerate() {
+            channel_response.extend(block.iter().skip(ch).step_by(NUM_OUT_CHANNELS));
+        }
+
+        let peak = block.iter().fold(0.0_f32, |peak, v| peak.max(v.abs()));
+        if peak < SILENCE_THRESHOLD {
+            break;
+        }
+        block.fill(0.0);
+    }
+
+    let fft_size = response[0].len().max(MIN_EQ_FFT_SIZE).next_power_of_two();
+    let fft = RealFftPlanner::<f32>::new().plan_fft_forward(fft_size);
+    let spectra = response.map(|mut ir| {
+        ir.resize(fft_size, 0.0);
+        let mut spectrum = fft.make_output_vec();
+        fft.process(&mut ir, &mut spectrum).unwrap();
+        spectrum
+    });
+
+    let mut csv = String::from("frequency_hz,left_db,right_db\n");
+    for (k, (l, r)) in spectra[0].iter().zip(&spectra[1]).enumerate() {
+        let freq = k as f64 * HRIR_SAMPLE_RATE as f64 / fft_size as f64;
+        writeln!(
+            csv,
+            "{:.2},{:.2},{:.2}",
+            freq,
+            gain_to_db(l.norm()),
+            gain_to_db(r.norm())
+        )
+        .unwrap();
+    }
+
+    std::fs::write(path, csv)
+        .map_err(|e| Error::from(e).context(&format!("Failed to write '{}'", path.display())))
+}
+
+/// Times `BlockConvolver::process` and `SurroundVirtualizer::process_ch8` on synthetic HRIRs
+/// for every block size and HRIR length of the benchmark, with the configured precision and
+/// virtualizer options. Returns one line per measurement with the mean time per block and the
+/// real-time headroom factor (block duration divided by processing time).
+pub fn bench_dsp(config: &AppConfig) -> Result<String, Error> {
+    let hrirs = backend::load_hrirs();
+    let double_precision = config.fft_precision == FftPrecision::Double;
+    let mut report = String::new();
+
+    for hrir_len in BENCH_HRIR_LENS {
+        let wavs: [Vec<u8>; NUM_SURROUND_CHANNELS] =
+            std::array::from_fn(|i| synthetic_hrir_wav(hrir_len, i as u32 + 1));
+        let ir: Vec<f32> = white_noise(hrir_len, 1).collect();
+
+        for block_size in BENCH_BLOCK_SIZES {
+            let input: Vec<f32> = white_noise(block_size * NUM_SURROUND_CHANNELS, 2).collect();
+
+            let mut conv = new_convolver(block_size, &ir, double_precision)?;
+            let mut block = vec![0.0; block_size];
+            let conv_time = time_per_block(block_size, BENCH_AUDIO_SECS, || {
+                // Fresh input every time, feeding the output back could blow up into denormals
+                block.copy_from_slice(&input[..block_size]);
+                conv.process(&mut block);
+            });
+            write_bench_line(
+                &mut report,
+                "BlockConvolver",
+                block_size,
+                hrir_len,
+                conv_time,
+            );
+
+            let mut sv = SurroundVirtualizer::new(&SurroundVirtualizerConfig {
+                fl_wav: &wavs[0],
+                fr_wav: &wavs[1],
+                fc_wav: &wavs[2],
+                lfe_wav: &wavs[3],
+                sl_wav: &wavs[4],
+                sr_wav: &wavs[5],
+                bl_wav: &wavs[6],
+                br_wav: &wavs[7],
+                tfl_wav: None,
+                tfr_wav: None,
+                tbl_wav: None,
+                tbr_wav: None,
+                engine: EngineConfig {
+                    block_size,
+                    ..get_engine_config(config)
+                },
+                max_hrir_len: None,
+                ..backend::get_virtualizer_config(config, &hrirs)
+            })?;
+            let input_adata = AudioDataRef::new(&input, NUM_SURROUND_CHANNELS);
+            let mut output = vec![0.0; block_size * NUM_OUT_CHANNELS];
+            let sv_time = time_per_block(block_size, BENCH_AUDIO_SECS, || {
+                sv.process_ch8(
+                    &input_adata,
+                    &mut AudioDataMut::new(&mut output, NUM_OUT_CHANNELS),
+                );
+            });
+            write_bench_line(&mut report, "process_ch8", block_size, hrir_len, sv_time);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Times the virtualizer and the selected equalizer of the current configuration at each
+/// of `CALIBRATION_BLOCK_SIZES` and returns the smallest block size whose load stays under
+/// `CALIBRATION_MAX_LOAD`, or the largest one if none does. Logs the load of each size.
+/// `None` if `is_stopped` returned `true` before a size was picked.
+pub fn calibrate_block_size(
+    config: &AppConfig,
+    is_stopped: impl Fn() -> bool,
+) -> Result<Option<usize>, Error> {
+    let hrirs = backend::load_hrirs();
+
+    pick_block_size(is_stopped, |block_size| {
+        let config = AppConfig {
+            block_size: Some(block_size),
+            ..config.clone()
+        };
+        let mut sv = SurroundVirtualizer::new(&backend::get_virtualizer_config(&config, &hrirs))?;
+        let mut eq = backend::build_equalizer(&config)?;
+        let input: Vec<f32> = white_noise(block_size * NUM_SURROUND_CHANNELS, 2).collect();
+        let input_adata = AudioDataRef::new(&input, NUM_SURROUND_CHANNELS);
+        let mut output = vec![0.0; block_size * NUM_OUT_CHANNELS];
+
+        let block_time = time_per_block(block_size, CALIBRATION_AUDIO_SECS, || {
+            let mut stereo_adata = AudioDataMut::new(&mut output, NUM_OUT_CHANNELS);
+            sv.process_ch8(&input_adata, &mut stereo_adata);
+            if let Some(eq) = &mut eq {
+                eq.process(&mut stereo_adata);
+            }
+        });
+        let load = block_time.as_secs_f64() * HRIR_SAMPLE_RATE as f64 / block_size as f64;
+        info!("Block size {}: {:.0}% DSP load", block_size, load * 100.0);
+        Ok(load)
+    })
+}
+
+/// Returns the smallest of `CALIBRATION_BLOCK_SIZES` whose load, as returned by
+/// `measure_load`, stays under `CALIBRATION_MAX_LOAD`, else the largest one.
+/// `is_stopped` is checked before each size, `None` is returned once it's `true`.
+fn pick_block_size(
+    is_stopped: impl Fn() -> bool,
+    mut measure_load: impl FnMut(usize) -> Result<f64, Error>,
+) -> Result<Option<usize>, Error> {
+    let mut chosen = None;
+    for block_size in CALIBRATION_BLOCK_SIZES {
+        if is_stopped() {
+            return Ok(None);
+        }
+        // Smaller blocks only add overhead, so they won't do better
+        if measure_load(block_size)? >= CALIBRATION_MAX_LOAD {
+            break;
+        }
+        chosen = Some(block_size);
+    }
+
+    Ok(Some(chosen.unwrap_or_else(|| {
+        warn!(
+            "No block size stays under {:.0}% DSP load, using the largest one",
+            CALIBRATION_MAX_LOAD * 100.0
+        );
+        CALIBRATION_BLOCK_SIZES[0]
+    })))
+}
+
+/// An I/O error for failing to `action` the WAV file at `path`, e.g. `file_error("write", ..)`.
+fn file_error(action: &str, path: &Path, err: hound::Error) -> Error {
+    Error::Io(std::io::Error::other(format!(
+        "Failed to {} '{}': {}",
+        action,
+        path.display(),
+        err
+    )))
+}
+
+/// Runs `process` for `audio_secs` worth of blocks and returns the mean time per block.
+fn time_per_block(block_size: usize, audio_secs: f64, mut process: impl FnMut()) -> Duration {
+    for _ in 0..BENCH_WARMUP_BLOCKS {
+        process();
+    }
+    let num_blocks = (audio_secs * HRIR_SAMPLE_RATE as f64 / block_size as f64).ceil() as u32;
+    let start = Instant::now();
+    for _ in 0..num_blocks {
+        process();
+    }
+    start.elapsed() / num_blocks
+}
+
+fn write_bench_line(
+    report: &mut String,
+    name: &str,
+    block_size: usize,
+    hrir_len: usize,
+    block_time: Duration,
+) {
+    let block_duration = block_size as f64 / HRIR_SAMPLE_RATE as f64;
+    writeln!(
+        report,
+        "{:<14} block {:>4}, HRIR {:>5}: {:>10} ns/block, {:>7.1}x real time",
+        name,
+        block_size,
+        hrir_len,
+        block_time.as_nanos(),
+        block_duration / block_time.as_secs_f64().max(f64::MIN_POSITIVE)
+    )
+    .unwrap();
+}
+
+/// Stereo WAV of exponentially decaying noise, standing in for an HRIR of `len` frames.
+fn synthetic_hrir_wav(len: usize, seed: u32) -> Vec<u8> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: HRIR_SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut wav = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+    for (i, v) in white_noise(len * 2, seed).enumerate() {
+        let decay = (-6.0 * (i / 2) as f32 / len as f32).exp();
+        writer.write_sample(v * decay).unwrap();
+    }
+    writer.finalize().unwrap();
+    wav.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Returns the block sizes `pick_block_size` measured and the size it picked, when
+    /// processing a block costs a fixed overhead plus a time proportional to its length.
+    fn pick_with_cost(overhead: f64, per_frame: f64) -> (Vec<usize>, usize) {
+        let mut measured = Vec::new();
+        let block_size = pick_block_size(
+            || false,
+            |block_size| {
+                measured.push(block_size);
+                Ok(overhead / block_size as f64 + per_frame)
+            },
+        )
+        .unwrap();
+        (measured, block_size.unwrap())
+    }
+
+    #[test]
+    fn picks_the_smallest_block_size_under_the_load_limit() {
+        // Still at 0.4 with 256 frames
+        assert_eq!(pick_with_cost(51.2, 0.2), (vec![2048, 1024, 512, 256], 256));
+        // Crosses the limit between 1024 (0.4) and 512 (0.6) frames
+        assert_eq!(pick_with_cost(204.8, 0.2), (vec![2048, 1024, 512], 1024));
+    }
+
+    #[test]
+    fn falls_back_to_the_largest_block_size() {
+        assert_eq!(pick_with_cost(0.0, 0.8), (vec![2048], 2048));
+    }
+
+    #[test]
+    fn measurement_errors_are_returned() {
+        let result = pick_block_size(
+            || false,
+            |block_size| match block_size {
+                2048 => Ok(0.1),
+                _ => Err(Error::Fft("broken".to_string())),
+            },
+        );
+        assert_eq!(result.unwrap_err().to_string(), "broken");
+    }
+
+    #[test]
+    fn stops_between_block_sizes() {
+        let measured = RefCell::new(Vec::new());
+        let result = pick_block_size(
+            || measured.borrow().len() == 2,
+            |block_size| {
+                measured.borrow_mut().push(block_size);
+                Ok(0.1)
+            },
+        );
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(*measured.borrow(), [2048, 1024]);
+    }
+}
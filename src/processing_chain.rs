@@ -0,0 +1,364 @@
+use crate::audio_data::{AudioDataMut, NUM_OUT_CHANNELS, db_to_gain};
+use crate::biquad::Biquad;
+use crate::config::OutputMode;
+use crate::crosstalk_canceller::CrosstalkCanceller;
+use crate::loudness::LoudnessMeter;
+use crate::runtime_params::RuntimeParams;
+use crate::smoother::Smoother;
+
+/// Below this short-term loudness the auto gain holds its adjustment, so that silence and
+/// fade-outs aren't boosted.
+const AUTO_GAIN_GATE_LUFS: f32 = -50.0;
+
+/// A step applied in place to the stereo output of the virtualizer.
+/// It runs on the audio thread, so it must not allocate, lock or block while processing.
+/// Whatever is costly to build, like an equalizer, is built beforehand and handed over.
+pub trait StereoStage: Send {
+    fn process(&mut self, stereo_data: &mut AudioDataMut, params: &RuntimeParams);
+}
+
+/// The stereo stages following the virtualizer, in the order of `AppConfig::output_stages`.
+/// Built once per session, so that processing a block doesn't allocate.
+pub struct ProcessingChain {
+    stages: Vec<Box<dyn StereoStage>>,
+}
+
+impl ProcessingChain {
+    pub fn new(stages: Vec<Box<dyn StereoStage>>) -> Self {
+        Self { stages }
+    }
+
+    pub fn process(&mut self, stereo_data: &mut AudioDataMut, params: &RuntimeParams) {
+        for stage in &mut self.stages {
+            stage.process(stereo_data, params);
+        }
+    }
+}
+
+/// Only active in `OutputMode::Speakers`.
+impl StereoStage for CrosstalkCanceller {
+    fn process(&mut self, stereo_data: &mut AudioDataMut, params: &RuntimeParams) {
+        if params.output_mode == OutputMode::Speakers {
+            CrosstalkCanceller::process(self, stereo_data);
+        }
+    }
+}
+
+/// Sends the sum of both channels to each ear when `RuntimeParams::mono_output` is set.
+pub struct MonoOutput;
+
+impl StereoStage for MonoOutput {
+    fn process(&mut self, stereo_data: &mut AudioDataMut, params: &RuntimeParams) {
+        if !params.mono_output {
+            return;
+        }
+        // Halving each channel keeps the sum of two full-scale channels in range
+        for frame in stereo_data.data.chunks_exact_mut(NUM_OUT_CHANNELS) {
+            let mono = 0.5 * (frame[0] + frame[1]);
+            frame.fill(mono);
+        }
+    }
+}
+
+pub struct MasterGain {
+    smoother: Smoother,
+}
+
+impl MasterGain {
+    pub fn new(smoother: Smoother) -> Self {
+        Self { smoother }
+    }
+}
+
+impl StereoStage for MasterGain {
+    fn process(&mut self, stereo_data: &mut AudioDataMut, params: &RuntimeParams) {
+        let target = db_to_gain(params.master_gain_db);
+        if target == 1.0 && self.smoother.is_settled(target) {
+            return;
+        }
+        for frame in stereo_data.data.chunks_exact_mut(NUM_OUT_CHANNELS) {
+            let gain = self.smoother.next(target);
+            for v in frame {
+                *v *= gain;
+            }
+        }
+    }
+}
+
+/// Slowly steers the short-term loudness of its input toward a target by up to a maximum
+/// adjustment, so that quiet and loud material play at a similar level. The adjustment follows
+/// the measured error with a long time constant, so that it doesn't pump with the music.
+pub struct AutoGain {
+    meter: LoudnessMeter,
+    target_lufs: f32,
+    max_adjust_db: f32,
+    response_frames: f32,
+    adjust_db: f32,
+    smoother: Smoother,
+}
+
+impl AutoGain {
+    pub fn new(
+        sample_rate: u32,
+        target_lufs: f32,
+        max_adjust_db: f32,
+        response_ms: f32,
+        smoother: Smoother,
+    ) -> Self {
+        Self {
+            meter: LoudnessMeter::new(sample_rate),
+            target_lufs,
+            max_adjust_db: max_adjust_db.max(0.0),
+            response_frames: response_ms.max(0.0) * sample_rate as f32 / 1000.0,
+            adjust_db: 0.0,
+            smoother,
+        }
+    }
+}
+
+impl StereoStage for AutoGain {
+    fn process(&mut self, stereo_data: &mut AudioDataMut, _: &RuntimeParams) {
+        // Measured before the adjustment, which would otherwise feed back into the measurement
+        self.meter.process(stereo_data.data);
+        let lufs = self.meter.short_term_lufs();
+        if lufs > AUTO_GAIN_GATE_LUFS {
+            let desired_db =
+                (self.target_lufs - lufs).clamp(-self.max_adjust_db, self.max_adjust_db);
+            let num_frames = stereo_data.num_frames() as f32;
+            let coeff = if self.response_frames > 0.0 {
+                (-num_frames / self.response_frames).exp()
+            } else {
+                0.0
+            };
+            self.adjust_db = desired_db + coeff * (self.adjust_db - desired_db);
+        }
+
+        let target = db_to_gain(self.adjust_db);
+        if target == 1.0 && self.smoother.is_settled(target) {
+            return;
+        }
+        for frame in stereo_data.data.chunks_exact_mut(NUM_OUT_CHANNELS) {
+            let gain = self.smoother.next(target);
+            for v in frame {
+                *v *= gain;
+            }
+        }
+    }
+}
+
+/// Butterworth high pass that keeps DC and subsonic rumble from wasting headroom
+/// and straining the drivers.
+pub struct SubsonicFilter {
+    filters: [Biquad; 2],
+}
+
+impl SubsonicFilter {
+    pub fn new(sample_rate: u32, cutoff_hz: f32) -> Self {
+        Self {
+            filters: [(); 2].map(|_| {
+                Biquad::high_pass(sample_rate, cutoff_hz, std::f32::consts::FRAC_1_SQRT_2)
+            }),
+        }
+    }
+}
+
+impl StereoStage for SubsonicFilter {
+    fn process(&mut self, stereo_data: &mut AudioDataMut, _: &RuntimeParams) {
+        for frame in stereo_data.data.chunks_exact_mut(NUM_OUT_CHANNELS) {
+            for (v, filter) in frame.iter_mut().zip(&mut self.filters) {
+                *v = filter.process_sample(*v);
+            }
+        }
+    }
+}
+
+/// Per-ear gain of `RuntimeParams::balance_db`.
+pub struct Balance {
+    smoothers: [Smoother; 2],
+}
+
+impl Balance {
+    pub fn new(smoothers: [Smoother; 2]) -> Self {
+        Self { smoothers }
+    }
+}
+
+impl StereoStage for Balance {
+    fn process(&mut self, stereo_data: &mut AudioDataMut, params: &RuntimeParams) {
+        let targets = params.balance_db.map(db_to_gain);
+        if targets == [1.0; 2]
+            && self.smoothers[0].is_settled(targets[0])
+            && self.smoothers[1].is_settled(targets[1])
+        {
+            return;
+        }
+        for frame in stereo_data.data.chunks_exact_mut(NUM_OUT_CHANNELS) {
+            for ((v, smoother), target) in frame.iter_mut().zip(&mut self.smoothers).zip(targets) {
+                *v *= smoother.next(target);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_data::gain_to_db;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const BLOCK_SIZE: usize = 480;
+
+    /// Interleaved stereo blocks of a sine of `freq` at `amplitude` in both channels.
+    fn sine_blocks(freq: f32, amplitude: f32, num_blocks: usize) -> Vec<Vec<f32>> {
+        (0..num_blocks)
+            .map(|block| {
+                (block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE)
+                    .flat_map(|i| {
+                        let v = amplitude
+                            * (2.0 * std::f32::consts::PI * freq * i as f32 / SAMPLE_RATE as f32)
+                                .sin();
+                        [v, v]
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Runs `blocks` through `stage` and returns the peak of the second half of them, after
+    /// the stage has settled.
+    fn output_peak(stage: &mut impl StereoStage, blocks: Vec<Vec<f32>>) -> f32 {
+        let num_blocks = blocks.len();
+        let mut peak = 0.0_f32;
+        for (i, mut block) in blocks.into_iter().enumerate() {
+            stage.process(
+                &mut AudioDataMut::new(&mut block, NUM_OUT_CHANNELS),
+                &RuntimeParams::DEFAULT,
+            );
+            if i >= num_blocks / 2 {
+                peak = block.iter().fold(peak, |peak, v| peak.max(v.abs()));
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn subsonic_filter_removes_rumble_and_passes_the_audible_range() {
+        // Two seconds, half of them for the filters to settle
+        let rumble = output_peak(
+            &mut SubsonicFilter::new(SAMPLE_RATE, 20.0),
+            sine_blocks(5.0, 1.0, 200),
+        );
+        let tone = output_peak(
+            &mut SubsonicFilter::new(SAMPLE_RATE, 20.0),
+            sine_blocks(1000.0, 1.0, 200),
+        );
+
+        // 2nd order: about 24 dB down two octaves below the cutoff
+        assert!(rumble < db_to_gain(-20.0), "{}", rumble);
+        assert!((tone - 1.0).abs() < 0.01, "{}", tone);
+    }
+
+    fn new_auto_gain() -> AutoGain {
+        AutoGain::new(
+            SAMPLE_RATE,
+            -23.0,
+            12.0,
+            1000.0,
+            Smoother::new(SAMPLE_RATE, 0.0, 1.0),
+        )
+    }
+
+    #[test]
+    fn auto_gain_converges_to_the_target() {
+        // A 1 kHz sine in both channels reads its peak level in LUFS
+        let mut auto_gain = new_auto_gain();
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE);
+        for mut block in sine_blocks(1000.0, db_to_gain(-33.0), 2000) {
+            auto_gain.process(
+                &mut AudioDataMut::new(&mut block, NUM_OUT_CHANNELS),
+                &RuntimeParams::DEFAULT,
+            );
+            meter.process(&block);
+        }
+        let lufs = meter.short_term_lufs();
+        assert!((lufs + 23.0).abs() < 0.5, "{}", lufs);
+
+        // Never more than the maximum adjustment
+        let mut auto_gain = new_auto_gain();
+        let peak = output_peak(&mut auto_gain, sine_blocks(1000.0, db_to_gain(-43.0), 2000));
+        assert!((peak - db_to_gain(-31.0)).abs() < 1e-3, "{}", peak);
+    }
+
+    #[test]
+    fn auto_gain_holds_below_the_gate() {
+        let mut auto_gain = new_auto_gain();
+        let peak = output_peak(&mut auto_gain, sine_blocks(1000.0, db_to_gain(-60.0), 1000));
+        assert!((peak - db_to_gain(-60.0)).abs() < 1e-6, "{}", peak);
+
+        // After a loud passage, the cut is frozen once the level has fallen below the gate
+        let mut auto_gain = new_auto_gain();
+        output_peak(&mut auto_gain, sine_blocks(1000.0, db_to_gain(-13.0), 1000));
+        // Long enough for the short-term window to forget the loud passage
+        output_peak(&mut auto_gain, sine_blocks(1000.0, db_to_gain(-60.0), 500));
+        let held = output_peak(&mut auto_gain, sine_blocks(1000.0, db_to_gain(-60.0), 500));
+        let later = output_peak(&mut auto_gain, sine_blocks(1000.0, db_to_gain(-60.0), 1000));
+        assert!(held < db_to_gain(-60.0), "{}", held);
+        assert_eq!(held, later);
+    }
+
+    #[test]
+    fn mono_output_sends_the_same_sum_to_both_ears() {
+        let mut block: Vec<f32> = (0..BLOCK_SIZE)
+            .flat_map(|i| [i as f32 / BLOCK_SIZE as f32, -0.25])
+            .collect();
+        let input = block.clone();
+        let mut params = RuntimeParams::DEFAULT;
+
+        // Off by default
+        MonoOutput.process(
+            &mut AudioDataMut::new(&mut block, NUM_OUT_CHANNELS),
+            &params,
+        );
+        assert_eq!(block, input);
+
+        params.mono_output = true;
+        MonoOutput.process(
+            &mut AudioDataMut::new(&mut block, NUM_OUT_CHANNELS),
+            &params,
+        );
+        for (out, frame) in block.chunks_exact(2).zip(input.chunks_exact(2)) {
+            assert_eq!(out[0], out[1]);
+            assert_eq!(out[0], 0.5 * (frame[0] + frame[1]));
+        }
+    }
+
+    #[test]
+    fn balance_sets_the_level_of_each_ear() {
+        for balance_db in [[-3.0, 0.0], [0.0, -3.0], [3.0, -3.0]] {
+            let params = RuntimeParams {
+                balance_db,
+                ..RuntimeParams::DEFAULT
+            };
+            // Starting from unity, so that the smoothers ramp to the targets
+            let mut balance = Balance::new([(); 2].map(|_| Smoother::new(SAMPLE_RATE, 10.0, 1.0)));
+            let mut block = Vec::new();
+            for _ in 0..10 {
+                block = vec![0.5; BLOCK_SIZE * NUM_OUT_CHANNELS];
+                balance.process(
+                    &mut AudioDataMut::new(&mut block, NUM_OUT_CHANNELS),
+                    &params,
+                );
+            }
+
+            let last = &block[block.len() - 2..];
+            for (v, db) in last.iter().zip(balance_db) {
+                assert!(
+                    (gain_to_db(v / 0.5) - db).abs() < 0.01,
+                    "{} != {} dB",
+                    v,
+                    db
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use crate::config::{AudioSourceMode, EqualizerProfile, OutputMode};
+
+/// Parameters that take effect on the next processed block, without rebuilding the processors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuntimeParams {
+    pub equalizer_profile: EqualizerProfile,
+    pub source_mode: AudioSourceMode,
+    pub output_mode: OutputMode,
+    /// Sums the final stereo result to both channels, for listening with a single ear.
+    pub mono_output: bool,
+    /// Exchanges the left and right channels as the very last step, to fix swapped wiring.
+    pub swap_output_channels: bool,
+    pub master_gain_db: f32,
+    /// Per-ear `[left, right]` trim applied after the master gain.
+    pub balance_db: [f32; 2],
+    /// Mixes the virtualized signal (1) with the unprocessed input (0).
+    pub wet_mix: f32,
+    /// Raises the center speaker relative to the others, for clearer dialog.
+    pub center_boost_db: f32,
+    /// Makes only the given surround input channel audible, or all of them if `None`.
+    /// The height channels are numbered after the 7.1 ones, from `HEIGHT_CHANNELS_OFFSET`.
+    pub channel_solo: Option<usize>,
+    /// Silences the surround input channels whose bits are set, height channels included.
+    pub channel_mute_mask: u32,
+}
+
+impl RuntimeParams {
+    pub const DEFAULT: Self = Self {
+        equalizer_profile: EqualizerProfile::None,
+        source_mode: AudioSourceMode::Universal,
+        output_mode: OutputMode::Headphones,
+        mono_output: false,
+        swap_output_channels: false,
+        master_gain_db: 0.0,
+        balance_db: [0.0; 2],
+        wet_mix: 1.0,
+        center_boost_db: 0.0,
+        channel_solo: None,
+        channel_mute_mask: 0,
+    };
+
+    /// Maps the single "virtualization strength" control (0 = off, 1 = full) onto the
+    /// parameters it drives. This is the one place to tune how they follow it.
+    pub fn set_virtualization_strength(&mut self, strength: f32) {
+        let strength = strength.clamp(0.0, 1.0);
+        // The virtualized signal takes over quickly, so that the lower steps are still audible
+        self.wet_mix = strength.sqrt();
+    }
+
+    /// Bit mask of the surround input channels, height channels included, left audible by
+    /// the solo and mute settings.
+    pub fn active_speaker_mask(&self) -> u32 {
+        match self.channel_solo {
+            Some(ch) => 1 << ch,
+            None => !self.channel_mute_mask,
+        }
+    }
+}
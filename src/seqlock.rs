@@ -0,0 +1,111 @@
+use std::cell::UnsafeCell;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering, fence};
+
+/// A `Copy` value written by one thread at a time and read by any thread without locking.
+/// Reads never block: one that overlaps a write fails, so that the audio thread can keep
+/// its previous copy for another block instead of waiting for the writer.
+pub struct SeqLock<T: Copy> {
+    /// Even while the value is stable, odd while a write is in progress.
+    seq: AtomicU64,
+    value: UnsafeCell<T>,
+    /// Serializes the writers, which never run on the audio thread.
+    writer: Mutex<()>,
+}
+
+// Readers only ever copy the value out and discard the copy if a write overlapped it
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            value: UnsafeCell::new(value),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Changes with every write, so that readers can tell whether their copy is current.
+    pub fn version(&self) -> u64 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    /// Returns the value and the version it belongs to, or `None` if a write is in progress.
+    pub fn try_read(&self) -> Option<(T, u64)> {
+        let seq = self.seq.load(Ordering::Acquire);
+        if seq & 1 == 1 {
+            return None;
+        }
+        // The copy may be torn by a concurrent write, in which case the check below discards it
+        let value = unsafe { std::ptr::read_volatile(self.value.get()) };
+        fence(Ordering::Acquire);
+        (self.seq.load(Ordering::Relaxed) == seq).then_some((value, seq))
+    }
+
+    /// Returns the value, waiting for a write in progress. Not for the audio thread.
+    pub fn read(&self) -> T {
+        let _writer = self.writer.lock().unwrap();
+        unsafe { *self.value.get() }
+    }
+
+    /// Applies `f` to the value, publishing the result to the readers at once.
+    pub fn write(&self, f: impl FnOnce(&mut T)) {
+        let _writer = self.writer.lock().unwrap();
+        let mut value = unsafe { *self.value.get() };
+        f(&mut value);
+
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { std::ptr::write_volatile(self.value.get(), value) };
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn reads_the_latest_write() {
+        let lock = SeqLock::new(1);
+        let version = lock.version();
+        lock.write(|v| *v += 1);
+
+        let (value, new_version) = lock.try_read().unwrap();
+        assert_eq!(value, 2);
+        assert_ne!(new_version, version);
+        assert_eq!(lock.read(), 2);
+    }
+
+    #[test]
+    fn never_returns_a_torn_value() {
+        let lock = Arc::new(SeqLock::new([0u64; 16]));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writer = {
+            let lock = Arc::clone(&lock);
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                for i in 1..=100_000 {
+                    lock.write(|v| *v = [i; 16]);
+                }
+                done.store(true, Ordering::Release);
+            })
+        };
+
+        while !done.load(Ordering::Acquire) {
+            if let Some((value, _)) = lock.try_read() {
+                assert!(
+                    value.iter().all(|&v| v == value[0]),
+                    "torn read {:?}",
+                    value
+                );
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(lock.try_read().unwrap().0, [100_000; 16]);
+    }
+}
@@ -0,0 +1,445 @@
+use crate::{
+    app::AppUserEvent,
+    config::{AppConfig, AudioSourceMode, EqualizerProfile, OutputMode},
+};
+use log::error;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive as _;
+use objc2::{
+    DefinedClass, MainThreadMarker, MainThreadOnly, define_class, msg_send,
+    rc::Retained,
+    runtime::{AnyObject, NSObject, NSObjectProtocol},
+    sel,
+};
+use objc2_app_kit::{
+    NSButton, NSControl, NSControlStateValueOff, NSControlStateValueOn, NSGridCell,
+    NSGridCellPlacement, NSGridRowAlignment, NSGridView, NSPopUpButton, NSSlider, NSTextField,
+    NSView,
+};
+use objc2_foundation::{NSArray, NSPoint, NSRect, NSString};
+use strum::IntoEnumIterator;
+use winit::{
+    dpi::LogicalSize,
+    event_loop::{ActiveEventLoop, EventLoopProxy},
+    raw_window_handle::{HasWindowHandle, RawWindowHandle},
+    window::{Window, WindowId},
+};
+
+const TITLE: &str = "Audio Virtualizer Settings";
+/// Space around the controls, in points.
+const MARGIN: f64 = 20.0;
+/// Width of the popups and sliders, in points. Long device names are truncated.
+const CONTROL_WIDTH: f64 = 280.0;
+const MIN_MASTER_GAIN_DB: f64 = -30.0;
+const MAX_MASTER_GAIN_DB: f64 = 6.0;
+/// Largest trim of the balance slider, the same as the last tray step.
+const MAX_BALANCE_DB: f64 = 6.0;
+/// Slider values are rounded to these steps, so that the config holds readable numbers.
+const GAIN_STEP_DB: f32 = 0.5;
+const STRENGTH_STEP: f32 = 0.01;
+
+/// The controls of the settings window, stored as their tag.
+#[derive(Clone, Copy, Debug, FromPrimitive)]
+pub enum SettingsControl {
+    InputDevice,
+    OutputDevice,
+    EqualizerProfile,
+    SourceMode,
+    OutputMode,
+    MasterGain,
+    VirtualizationStrength,
+    Balance,
+    MonoOutput,
+    SwapOutputChannels,
+    DialogBoost,
+    EditConfigFile,
+}
+
+/// The value a control was changed to, see `SettingsWindow::read`.
+pub enum SettingsChange {
+    InputDevice(String),
+    OutputDevice(String),
+    EqualizerProfile(EqualizerProfile),
+    SourceMode(AudioSourceMode),
+    OutputMode(OutputMode),
+    MasterGainDb(f32),
+    VirtualizationStrength(f32),
+    BalanceDb([f32; 2]),
+    MonoOutput(bool),
+    SwapOutputChannels(bool),
+    DialogBoost(bool),
+    EditConfigFile,
+}
+
+define_class!(
+    // SAFETY: NSObject has no subclassing requirements and `ControlTarget` doesn't implement Drop.
+    #[unsafe(super(NSObject))]
+    #[thread_kind = MainThreadOnly]
+    #[ivars = EventLoopProxy<AppUserEvent>]
+    struct ControlTarget;
+
+    impl ControlTarget {
+        #[unsafe(method(controlChanged:))]
+        fn control_changed(&self, sender: &NSControl) {
+            let Some(control) = SettingsControl::from_isize(sender.tag()) else {
+                return;
+            };
+            if let Err(e) = self.ivars().send_event(AppUserEvent::SettingsEvent(control)) {
+                error!("Failed to send settings event: {}", e);
+            }
+        }
+    }
+
+    unsafe impl NSObjectProtocol for ControlTarget {}
+);
+
+impl ControlTarget {
+    fn new(proxy: EventLoopProxy<AppUserEvent>, mtm: MainThreadMarker) -> Retained<Self> {
+        let this = Self::alloc(mtm).set_ivars(proxy);
+        unsafe { msg_send![super(this), init] }
+    }
+
+    /// Makes the control report its changes as `AppUserEvent::SettingsEvent(tag)`.
+    fn connect(&self, control: &NSControl, tag: SettingsControl) {
+        let target: &AnyObject = self;
+        control.setTag(tag as isize);
+        // SAFETY: `controlChanged:` takes the sender, and `SettingsWindow` keeps the target
+        // alive for as long as its controls.
+        unsafe {
+            control.setTarget(Some(target));
+            control.setAction(Some(sel!(controlChanged:)));
+        }
+    }
+}
+
+/// A native window with the everyday settings of the tray menu, as sliders and popups.
+/// The controls only report changes; `App` applies them and calls `sync` afterwards.
+pub struct SettingsWindow {
+    window: Window,
+    /// Controls don't retain their target.
+    _target: Retained<ControlTarget>,
+    input_device_popup: Retained<NSPopUpButton>,
+    output_device_popup: Retained<NSPopUpButton>,
+    eq_popup: Retained<NSPopUpButton>,
+    source_mode_popup: Retained<NSPopUpButton>,
+    output_mode_popup: Retained<NSPopUpButton>,
+    master_gain_slider: Retained<NSSlider>,
+    master_gain_label: Retained<NSTextField>,
+    strength_slider: Retained<NSSlider>,
+    strength_label: Retained<NSTextField>,
+    balance_slider: Retained<NSSlider>,
+    balance_label: Retained<NSTextField>,
+    mono_output_checkbox: Retained<NSButton>,
+    swap_channels_checkbox: Retained<NSButton>,
+    dialog_boost_checkbox: Retained<NSButton>,
+}
+
+impl SettingsWindow {
+    pub fn open(
+        event_loop: &ActiveEventLoop,
+        proxy: EventLoopProxy<AppUserEvent>,
+    ) -> Result<Self, String> {
+        let mtm = MainThreadMarker::new().ok_or("not on the main thread")?;
+        let target = ControlTarget::new(proxy, mtm);
+
+        let new_popup = |tag, titles: &[&str]| {
+            let popup = NSPopUpButton::initWithFrame_pullsDown(
+                NSPopUpButton::alloc(mtm),
+                NSRect::ZERO,
+                false,
+            );
+            for title in titles {
+                popup.addItemWithTitle(&NSString::from_str(title));
+            }
+            target.connect(&popup, tag);
+            popup
+        };
+        let new_slider = |tag, min_value, max_value| {
+            // SAFETY: no target or action is set here
+            let slider = unsafe {
+                NSSlider::sliderWithValue_minValue_maxValue_target_action(
+                    min_value, min_value, max_value, None, None, mtm,
+                )
+            };
+            // Only report the released value, every change saves the config
+            slider.setContinuous(false);
+            target.connect(&slider, tag);
+            slider
+        };
+        let new_checkbox = |tag, title| {
+            // SAFETY: no target or action is set here
+            let checkbox = unsafe {
+                NSButton::checkboxWithTitle_target_action(
+                    &NSString::from_str(title),
+                    None,
+                    None,
+                    mtm,
+                )
+            };
+            target.connect(&checkbox, tag);
+            checkbox
+        };
+        let new_label = |text| NSTextField::labelWithString(&NSString::from_str(text), mtm);
+
+        let eq_labels: Vec<_> = EqualizerProfile::iter().map(|p| p.label()).collect();
+        let source_labels: Vec<&str> = AudioSourceMode::iter().map(Into::into).collect();
+        let output_mode_labels: Vec<&str> = OutputMode::iter().map(Into::into).collect();
+
+        let input_device_popup = new_popup(SettingsControl::InputDevice, &[]);
+        let output_device_popup = new_popup(SettingsControl::OutputDevice, &[]);
+        let eq_popup = new_popup(SettingsControl::EqualizerProfile, &eq_labels);
+        let source_mode_popup = new_popup(SettingsControl::SourceMode, &source_labels);
+        let output_mode_popup = new_popup(SettingsControl::OutputMode, &output_mode_labels);
+        let master_gain_slider = new_slider(
+            SettingsControl::MasterGain,
+            MIN_MASTER_GAIN_DB,
+            MAX_MASTER_GAIN_DB,
+        );
+        let strength_slider = new_slider(SettingsControl::VirtualizationStrength, 0.0, 1.0);
+        let balance_slider = new_slider(SettingsControl::Balance, -MAX_BALANCE_DB, MAX_BALANCE_DB);
+        let mono_output_checkbox = new_checkbox(SettingsControl::MonoOutput, "Mono Output");
+        let swap_channels_checkbox =
+            new_checkbox(SettingsControl::SwapOutputChannels, "Swap Left/Right");
+        let dialog_boost_checkbox = new_checkbox(SettingsControl::DialogBoost, "Dialog Boost");
+        // SAFETY: no target or action is set here
+        let edit_config_button = unsafe {
+            NSButton::buttonWithTitle_target_action(
+                &NSString::from_str("Edit Config File…"),
+                None,
+                None,
+                mtm,
+            )
+        };
+        target.connect(&edit_config_button, SettingsControl::EditConfigFile);
+        let master_gain_label = new_label("");
+        let strength_label = new_label("");
+        let balance_label = new_label("");
+
+        let empty = NSGridCell::emptyContentView(mtm);
+        let rows = [
+            grid_row([
+                &new_label("Surround Audio Source"),
+                &input_device_popup,
+                &empty,
+            ]),
+            grid_row([
+                &new_label("Stereo Output Device"),
+                &output_device_popup,
+                &empty,
+            ]),
+            grid_row([&new_label("Equalizer Profile"), &eq_popup, &empty]),
+            grid_row([&new_label("Audio Source Mode"), &source_mode_popup, &empty]),
+            grid_row([&new_label("Output Mode"), &output_mode_popup, &empty]),
+            grid_row([
+                &new_label("Master Gain"),
+                &master_gain_slider,
+                &master_gain_label,
+            ]),
+            grid_row([
+                &new_label("Virtualization Strength"),
+                &strength_slider,
+                &strength_label,
+            ]),
+            grid_row([&new_label("Balance"), &balance_slider, &balance_label]),
+            grid_row([&empty, &mono_output_checkbox, &empty]),
+            grid_row([&empty, &swap_channels_checkbox, &empty]),
+            grid_row([&empty, &dialog_boost_checkbox, &empty]),
+            grid_row([&empty, &edit_config_button, &empty]),
+        ];
+        let grid = NSGridView::gridViewWithViews(&NSArray::from_retained_slice(&rows), mtm);
+        grid.setRowAlignment(NSGridRowAlignment::FirstBaseline);
+        grid.setRowSpacing(8.0);
+        grid.setColumnSpacing(12.0);
+        grid.columnAtIndex(0)
+            .setXPlacement(NSGridCellPlacement::Trailing);
+        grid.columnAtIndex(1).setWidth(CONTROL_WIDTH);
+        let grid_size = grid.fittingSize();
+
+        let attributes = Window::default_attributes()
+            .with_title(TITLE)
+            .with_inner_size(LogicalSize::new(
+                grid_size.width + 2.0 * MARGIN,
+                grid_size.height + 2.0 * MARGIN,
+            ))
+            .with_resizable(false);
+        let window = event_loop
+            .create_window(attributes)
+            .map_err(|e| e.to_string())?;
+        let handle = window.window_handle().map_err(|e| e.to_string())?;
+        let RawWindowHandle::AppKit(handle) = handle.as_raw() else {
+            return Err("the window isn't an AppKit window".to_string());
+        };
+        // SAFETY: the handle of an AppKit window points to its content view, which lives
+        // as long as the window
+        let content_view = unsafe { handle.ns_view.cast::<NSView>().as_ref() };
+        grid.setFrameOrigin(NSPoint::new(MARGIN, MARGIN));
+        grid.setFrameSize(grid_size);
+        content_view.addSubview(&grid);
+        // An accessory app isn't brought to the front by opening a window
+        window.focus_window();
+
+        Ok(Self {
+            window,
+            _target: target,
+            input_device_popup,
+            output_device_popup,
+            eq_popup,
+            source_mode_popup,
+            output_mode_popup,
+            master_gain_slider,
+            master_gain_label,
+            strength_slider,
+            strength_label,
+            balance_slider,
+            balance_label,
+            mono_output_checkbox,
+            swap_channels_checkbox,
+            dialog_boost_checkbox,
+        })
+    }
+
+    pub fn id(&self) -> WindowId {
+        self.window.id()
+    }
+
+    pub fn focus(&self) {
+        self.window.focus_window();
+    }
+
+    /// Replaces the entries of the device popups, see `sync` for the selection.
+    pub fn set_device_names(&self, input_device_names: &[String], output_device_names: &[String]) {
+        for (popup, names) in [
+            (&self.input_device_popup, input_device_names),
+            (&self.output_device_popup, output_device_names),
+        ] {
+            let titles: Vec<_> = names.iter().map(|name| NSString::from_str(name)).collect();
+            popup.removeAllItems();
+            popup.addItemsWithTitles(&NSArray::from_retained_slice(&titles));
+        }
+    }
+
+    /// Shows the values of `config`, after a change from the window, the tray or the config file.
+    pub fn sync(&self, config: &AppConfig) {
+        self.input_device_popup
+            .selectItemWithTitle(&NSString::from_str(config.selected_input_device_name()));
+        self.output_device_popup
+            .selectItemWithTitle(&NSString::from_str(config.selected_output_device_name()));
+        select_variant(&self.eq_popup, config.equalizer_profile);
+        select_variant(&self.source_mode_popup, config.audio_source_mode);
+        select_variant(&self.output_mode_popup, config.output_mode);
+
+        self.master_gain_slider
+            .setDoubleValue(config.master_gain_db as f64);
+        self.master_gain_label
+            .setStringValue(&NSString::from_str(&format!(
+                "{:+.1} dB",
+                config.master_gain_db
+            )));
+        // Without a strength the wet mix was set on its own; show the strength that yields it
+        let strength = config
+            .virtualization_strength
+            .unwrap_or(config.wet_mix * config.wet_mix);
+        self.strength_slider.setDoubleValue(strength as f64);
+        self.strength_label
+            .setStringValue(&NSString::from_str(&format!("{:.0}%", strength * 100.0)));
+        let balance = balance_db_to_slider(config.balance_db);
+        self.balance_slider.setDoubleValue(balance as f64);
+        let balance_text = if balance < 0.0 {
+            format!("Left Ear +{:.1} dB", -balance)
+        } else if balance > 0.0 {
+            format!("Right Ear +{:.1} dB", balance)
+        } else {
+            "Centered".to_string()
+        };
+        self.balance_label
+            .setStringValue(&NSString::from_str(&balance_text));
+
+        set_checked(&self.mono_output_checkbox, config.mono_output);
+        set_checked(&self.swap_channels_checkbox, config.swap_output_channels);
+        set_checked(&self.dialog_boost_checkbox, config.dialog_boost);
+    }
+
+    /// Reads the value of a control that reported a change.
+    pub fn read(&self, control: SettingsControl) -> Option<SettingsChange> {
+        let change = match control {
+            SettingsControl::InputDevice => SettingsChange::InputDevice(
+                self.input_device_popup.titleOfSelectedItem()?.to_string(),
+            ),
+            SettingsControl::OutputDevice => SettingsChange::OutputDevice(
+                self.output_device_popup.titleOfSelectedItem()?.to_string(),
+            ),
+            SettingsControl::EqualizerProfile => {
+                SettingsChange::EqualizerProfile(selected_variant(&self.eq_popup)?)
+            }
+            SettingsControl::SourceMode => {
+                SettingsChange::SourceMode(selected_variant(&self.source_mode_popup)?)
+            }
+            SettingsControl::OutputMode => {
+                SettingsChange::OutputMode(selected_variant(&self.output_mode_popup)?)
+            }
+            SettingsControl::MasterGain => SettingsChange::MasterGainDb(round_to_step(
+                self.master_gain_slider.doubleValue() as f32,
+                GAIN_STEP_DB,
+            )),
+            SettingsControl::VirtualizationStrength => SettingsChange::VirtualizationStrength(
+                round_to_step(self.strength_slider.doubleValue() as f32, STRENGTH_STEP),
+            ),
+            SettingsControl::Balance => SettingsChange::BalanceDb(slider_to_balance_db(
+                round_to_step(self.balance_slider.doubleValue() as f32, GAIN_STEP_DB),
+            )),
+            SettingsControl::MonoOutput => {
+                SettingsChange::MonoOutput(is_checked(&self.mono_output_checkbox))
+            }
+            SettingsControl::SwapOutputChannels => {
+                SettingsChange::SwapOutputChannels(is_checked(&self.swap_channels_checkbox))
+            }
+            SettingsControl::DialogBoost => {
+                SettingsChange::DialogBoost(is_checked(&self.dialog_boost_checkbox))
+            }
+            SettingsControl::EditConfigFile => SettingsChange::EditConfigFile,
+        };
+        Some(change)
+    }
+}
+
+fn grid_row(views: [&NSView; 3]) -> Retained<NSArray<NSView>> {
+    NSArray::from_slice(&views)
+}
+
+/// Selects the popup item of `value`, the items being in the order of `T::iter`.
+fn select_variant<T: IntoEnumIterator + PartialEq>(popup: &NSPopUpButton, value: T) {
+    if let Some(index) = T::iter().position(|v| v == value) {
+        popup.selectItemAtIndex(index as isize);
+    }
+}
+
+fn selected_variant<T: IntoEnumIterator>(popup: &NSPopUpButton) -> Option<T> {
+    let index = usize::try_from(popup.indexOfSelectedItem()).ok()?;
+    T::iter().nth(index)
+}
+
+fn set_checked(checkbox: &NSButton, checked: bool) {
+    checkbox.setState(if checked {
+        NSControlStateValueOn
+    } else {
+        NSControlStateValueOff
+    });
+}
+
+fn is_checked(checkbox: &NSButton) -> bool {
+    checkbox.state() == NSControlStateValueOn
+}
+
+fn round_to_step(value: f32, step: f32) -> f32 {
+    (value / step).round() * step
+}
+
+/// Maps the `[left, right]` trims onto a single slider: negative values favor the left ear.
+fn balance_db_to_slider(balance_db: [f32; 2]) -> f32 {
+    balance_db[1] - balance_db[0]
+}
+
+/// Attenuates the other ear, like the tray steps, so that the favored one can't clip.
+fn slider_to_balance_db(value: f32) -> [f32; 2] {
+    [(-value).min(0.0), value.min(0.0)]
+}
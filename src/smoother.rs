@@ -0,0 +1,92 @@
+/// One-pole low pass that moves a control value towards its target,
+/// so that gain changes don't produce zipper noise.
+pub struct Smoother {
+    value: f32,
+    coeff: f32,
+}
+
+impl Smoother {
+    /// A step is 99% complete after `time_ms`.
+    /// A non-positive time makes changes instant.
+    pub fn new(sample_rate: u32, time_ms: f32, initial: f32) -> Self {
+        let time_frames = time_ms * sample_rate as f32 / 1000.0;
+        let coeff = if time_frames > 0.0 {
+            (-(100f32).ln() / time_frames).exp()
+        } else {
+            0.0
+        };
+        Self {
+            value: initial,
+            coeff,
+        }
+    }
+
+    /// Advances by one frame and returns the smoothed value.
+    pub fn next(&mut self, target: f32) -> f32 {
+        self.value = target + self.coeff * (self.value - target);
+        self.value
+    }
+
+    /// Whether the value has settled on `target` so the per-frame work can be skipped.
+    pub fn is_settled(&mut self, target: f32) -> bool {
+        if (self.value - target).abs() < 1e-6 {
+            self.value = target;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_settles_to_99_percent_within_time() {
+        let sample_rate = 48000;
+        let time_ms = 10.0;
+        let mut smoother = Smoother::new(sample_rate, time_ms, 0.0);
+        let frames = (time_ms * sample_rate as f32 / 1000.0) as usize;
+
+        let mut value = 0.0;
+        for _ in 0..frames - 1 {
+            value = smoother.next(1.0);
+        }
+        assert!(value < 0.99, "settled too early: {}", value);
+        value = smoother.next(1.0);
+        assert!(value >= 0.99 - 1e-4, "not settled: {}", value);
+    }
+
+    #[test]
+    fn ramp_has_no_jumps() {
+        let sample_rate = 48000;
+        let time_ms = 10.0;
+        let mut smoother = Smoother::new(sample_rate, time_ms, 0.0);
+        let frames = (time_ms * sample_rate as f32 / 1000.0) as usize;
+        // Largest step per frame towards a target at distance 1, taken right after the change
+        let max_step = 100f32.ln() / frames as f32;
+
+        // The target is changed again halfway through the ramp
+        let mut prev = 0.0;
+        for i in 0..2 * frames {
+            let target = if i < frames / 2 { 1.0 } else { -1.0 };
+            let value = smoother.next(target);
+            let step = (value - prev).abs();
+            assert!(
+                step <= max_step * (target - prev).abs() + 1e-6,
+                "frame {}: jumped by {}",
+                i,
+                step
+            );
+            prev = value;
+        }
+    }
+
+    #[test]
+    fn zero_time_is_instant() {
+        let mut smoother = Smoother::new(48000, 0.0, 0.0);
+        assert_eq!(smoother.next(0.5), 0.5);
+        assert!(smoother.is_settled(0.5));
+    }
+}
@@ -0,0 +1,118 @@
+/// Plain-data view of a device's supported stream config range,
+/// decoupled from cpal so that the selection heuristics can be reasoned about in isolation.
+#[derive(Debug, Clone, Copy)]
+pub struct SupportedConfig {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    /// Supported `(min, max)` buffer size in frames, `None` if unknown.
+    pub buffer_size_range: Option<(u32, u32)>,
+    pub sample_format: cpal::SampleFormat,
+}
+
+impl From<&cpal::SupportedStreamConfigRange> for SupportedConfig {
+    fn from(conf: &cpal::SupportedStreamConfigRange) -> Self {
+        let buffer_size_range = match conf.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } => Some((*min, *max)),
+            _ => None,
+        };
+        Self {
+            channels: conf.channels(),
+            min_sample_rate: conf.min_sample_rate(),
+            max_sample_rate: conf.max_sample_rate(),
+            buffer_size_range,
+            sample_format: conf.sample_format(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChosenConfig {
+    pub buffer_size: usize,
+    pub channels: u16,
+    pub sample_format: cpal::SampleFormat,
+}
+
+/// Picks the config supporting `target_rate` that is closest to the targets.
+///
+/// Candidates are ranked by, in order: float sample format, distance to `target_channels`,
+/// distance of the buffer size (clamped to the supported range) to `target_buf`.
+pub fn select_stream_config(
+    supported_configs: &[SupportedConfig],
+    target_rate: u32,
+    target_buf: usize,
+    target_channels: u16,
+) -> Option<ChosenConfig> {
+    supported_configs
+        .iter()
+        .filter(|conf| conf.min_sample_rate <= target_rate && conf.max_sample_rate >= target_rate)
+        .map(|conf| {
+            let buffer_size = match conf.buffer_size_range {
+                Some((min, max)) => target_buf.clamp(min as usize, max as usize),
+                None => target_buf,
+            };
+            ChosenConfig {
+                buffer_size,
+                channels: conf.channels,
+                sample_format: conf.sample_format,
+            }
+        })
+        .min_by_key(|conf| {
+            (
+                conf.sample_format != cpal::SampleFormat::F32,
+                (conf.channels as isize - target_channels as isize).abs(),
+                (conf.buffer_size as isize - target_buf as isize).abs(),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(channels: u16, rates: (u32, u32), buffer_size_range: (u32, u32)) -> SupportedConfig {
+        SupportedConfig {
+            channels,
+            min_sample_rate: rates.0,
+            max_sample_rate: rates.1,
+            buffer_size_range: Some(buffer_size_range),
+            sample_format: cpal::SampleFormat::F32,
+        }
+    }
+
+    #[test]
+    fn empty_config_list_is_none() {
+        assert_eq!(select_stream_config(&[], 48000, 512, 2), None);
+    }
+
+    #[test]
+    fn no_config_with_the_rate_is_none() {
+        let configs = [
+            config(2, (44100, 44100), (64, 4096)),
+            config(8, (96000, 192000), (64, 4096)),
+        ];
+        assert_eq!(select_stream_config(&configs, 48000, 512, 2), None);
+    }
+
+    #[test]
+    fn prefers_the_nearest_channel_count() {
+        let configs = [
+            config(2, (48000, 48000), (64, 4096)),
+            config(6, (48000, 48000), (64, 4096)),
+            config(16, (48000, 48000), (64, 4096)),
+        ];
+        let chosen = select_stream_config(&configs, 48000, 512, 8).unwrap();
+        assert_eq!(chosen.channels, 6);
+    }
+
+    #[test]
+    fn clamps_the_buffer_size_to_the_range() {
+        let configs = [config(2, (48000, 48000), (1024, 4096))];
+        let chosen = select_stream_config(&configs, 48000, 512, 2).unwrap();
+        assert_eq!(chosen.buffer_size, 1024);
+
+        let configs = [config(2, (48000, 48000), (64, 256))];
+        let chosen = select_stream_config(&configs, 48000, 512, 2).unwrap();
+        assert_eq!(chosen.buffer_size, 256);
+    }
+}
@@ -1,6 +1,74 @@
-use crate::audio_data::{AudioDataMut, AudioDataRef};
-use crate::block_convolver::BlockConvolver;
+use crate::audio_data::{
+    AudioDataMut, AudioDataRef, EngineConfig, assert_block_size, db_to_gain, gain_to_db,
+};
+use crate::biquad::Biquad;
+use crate::block_convolver::{BlockConvolver, Convolve, new_convolver};
+use crate::config::EqChannelMode;
+use crate::error::Error;
+use crate::fractional_delay::FractionalDelay;
+use realfft::RealFftPlanner;
 use std::io::Cursor;
+use std::iter;
+
+pub const NUM_SPEAKERS: usize = 8;
+/// Horizontal speakers in the channel order of a 7.1 input.
+pub const SPEAKER_LABELS: [&str; NUM_SPEAKERS] = ["FL", "FR", "FC", "LFE", "SL", "SR", "BL", "BR"];
+const FL: usize = 0;
+const FR: usize = 1;
+const FC: usize = 2;
+const LFE: usize = 3;
+const SL: usize = 4;
+const SR: usize = 5;
+const BL: usize = 6;
+const BR: usize = 7;
+
+const CENTER_GAIN: f32 = 0.5 * std::f32::consts::SQRT_2;
+const SIDE_GAIN: f32 = 0.5 * std::f32::consts::SQRT_2;
+const BACK_GAIN: f32 = 0.5 * std::f32::consts::SQRT_2;
+const LFE_GAIN: f32 = 0.25;
+
+const CH2_FRONT_GAIN: f32 = 0.9;
+const CH2_SIDE_GAIN: f32 = 0.18;
+const UPMIX_FRONT_GAIN: f32 = 0.7;
+const UPMIX_CENTER_GAIN: f32 = 0.5;
+const UPMIX_SURROUND_GAIN: f32 = 0.5;
+
+/// Speaker gains of `downmix_to_stereo`, which has no HRIRs to place the speakers.
+const PASSTHROUGH_DOWNMIX: DownmixMatrix = [
+    [1.0, 0.0],
+    [0.0, 1.0],
+    [CENTER_GAIN, CENTER_GAIN],
+    [LFE_GAIN, LFE_GAIN],
+    [SIDE_GAIN, 0.0],
+    [0.0, SIDE_GAIN],
+    [BACK_GAIN, 0.0],
+    [0.0, BACK_GAIN],
+];
+
+/// Per-speaker `[left, right]` gains applied to the binaural outputs when summing to stereo.
+pub type DownmixMatrix = [[f32; 2]; NUM_SPEAKERS];
+
+pub const DEFAULT_DOWNMIX_MATRIX: DownmixMatrix = [
+    [1.0, 1.0],
+    [1.0, 1.0],
+    [CENTER_GAIN, CENTER_GAIN],
+    [LFE_GAIN, LFE_GAIN],
+    [SIDE_GAIN, SIDE_GAIN],
+    [SIDE_GAIN, SIDE_GAIN],
+    [BACK_GAIN, BACK_GAIN],
+    [BACK_GAIN, BACK_GAIN],
+];
+
+pub const NUM_HEIGHT_CHANNELS: usize = 4;
+pub const HEIGHT_LABELS: [&str; NUM_HEIGHT_CHANNELS] = ["TFL", "TFR", "TBL", "TBR"];
+/// Index of the first height channel (TFL) in the interleaved input.
+pub const HEIGHT_CHANNELS_OFFSET: usize = 8;
+/// Horizontal input channel each height channel folds down into when its HRIR is absent:
+/// TFL -> FL, TFR -> FR, TBL -> BL, TBR -> BR.
+const HEIGHT_FOLD_DOWN: [usize; NUM_HEIGHT_CHANNELS] = [FL, FR, BL, BR];
+/// -3 dB on the height channels, whether rendered through their own HRIRs or folded down,
+/// since they add to the horizontal speakers.
+const HEIGHT_GAIN: f32 = 0.5 * std::f32::consts::SQRT_2;
 
 pub struct SurroundVirtualizerConfig<'a> {
     pub fc_wav: &'a [u8],
@@ -11,35 +79,223 @@ pub struct SurroundVirtualizerConfig<'a> {
     pub sl_wav: &'a [u8],
     pub sr_wav: &'a [u8],
     pub lfe_wav: &'a [u8],
-    pub block_size: usize,
+    pub tfl_wav: Option<&'a [u8]>,
+    pub tfr_wav: Option<&'a [u8]>,
+    pub tbl_wav: Option<&'a [u8]>,
+    pub tbr_wav: Option<&'a [u8]>,
+    pub engine: EngineConfig,
+    pub downmix: DownmixMatrix,
+    /// Overrides the LFE gain of `downmix`.
+    pub lfe_gain: Option<f32>,
+    /// Convolve the LFE with its HRIR. Otherwise it's added flat to both ears: bass isn't
+    /// localizable, and the HRIR would only color it.
+    pub lfe_hrir: bool,
+    /// Cut every HRIR to at most this many samples to bound the convolution cost.
+    pub max_hrir_len: Option<usize>,
+    /// Time-align the onsets of all speaker HRIRs to the earliest one.
+    pub align_onsets: bool,
+    /// Scale the stereo and mono renderings to the level of the 7.1 one.
+    pub mode_gain_compensation: bool,
+    /// Attenuation applied to every channel before convolution and made up at the output.
+    pub headroom_db: f32,
+    /// Subtract the mean of every HRIR so that the convolution adds no DC offset.
+    pub remove_dc: bool,
+    /// Compute the HRIR convolutions in `f64`.
+    pub double_precision: bool,
+    /// Share of each 7.1 speaker's binaural output passed through its all-pass filter before
+    /// summation, from 0 (off) to 1.
+    pub decorrelation: f32,
+    /// Distance of each 7.1 speaker from the listener in meters, in `SPEAKER_LABELS` order.
+    /// Farther speakers are delayed and attenuated relative to the nearest one.
+    pub speaker_distances: Option<[f32; NUM_SPEAKERS]>,
+}
+
+/// Fraction of the impulse peak above which a sample is considered the HRIR onset.
+const HRIR_ONSET_THRESHOLD: f32 = 0.1;
+/// Length of the fade-out window applied at the cut of a truncated HRIR.
+const HRIR_TRUNCATION_FADE_LEN: usize = 64;
+/// Center frequency of each speaker's decorrelation all-pass. Different frequencies give the
+/// speakers different group delays, so correlated content no longer sums coherently.
+const DECORRELATION_FREQS: [f32; NUM_SPEAKERS] =
+    [700.0, 1000.0, 1400.0, 200.0, 2000.0, 2800.0, 4000.0, 5600.0];
+const DECORRELATION_Q: f32 = 0.7;
+/// In air at 20 °C, in m/s.
+const SPEED_OF_SOUND: f32 = 343.0;
+/// Delay of the upmixed surround signal that decorrelates it from the fronts (12 ms at 48 kHz).
+const UPMIX_SURROUND_DELAY: usize = 576;
+
+struct BinauralIr {
+    left: Vec<f32>,
+    right: Vec<f32>,
+}
+
+impl BinauralIr {
+    /// A stereo WAV holds the left and right ear impulses, a mono one is used for both ears.
+    fn from_wav(wav_data: &[u8]) -> Result<Self, Error> {
+        let (pcm, spec) = wav_to_pcm_with_spec(wav_data)?;
+        let (left, right) = match spec.channels {
+            1 => (pcm.clone(), pcm),
+            2 => split_stereo(&pcm),
+            n => {
+                return Err(Error::Wav(format!(
+                    "HRIR must have 1 or 2 channels, found {}",
+                    n
+                )));
+            }
+        };
+        if left.is_empty() {
+            return Err(Error::Wav("HRIR is empty".to_string()));
+        }
+        Ok(Self { left, right })
+    }
+
+    /// Returns the index of the first sample of either ear that reaches the onset threshold.
+    fn onset(&self) -> usize {
+        let peak = self
+            .left
+            .iter()
+            .chain(&self.right)
+            .fold(0.0_f32, |peak, v| peak.max(v.abs()));
+        if peak == 0.0 {
+            return 0;
+        }
+
+        let threshold = peak * HRIR_ONSET_THRESHOLD;
+        let first_above = |ir: &[f32]| {
+            ir.iter()
+                .position(|v| v.abs() >= threshold)
+                .unwrap_or(ir.len())
+        };
+        first_above(&self.left).min(first_above(&self.right))
+    }
+
+    /// Removes the same number of leading samples from both ears, preserving the ITD.
+    fn advance(&mut self, num_samples: usize) {
+        self.left.drain(..num_samples.min(self.left.len()));
+        self.right.drain(..num_samples.min(self.right.len()));
+    }
+
+    /// Subtracts the mean of each ear's impulse.
+    fn remove_dc(&mut self) {
+        for ir in [&mut self.left, &mut self.right] {
+            if ir.is_empty() {
+                continue;
+            }
+            let mean = ir.iter().sum::<f32>() / ir.len() as f32;
+            ir.iter_mut().for_each(|v| *v -= mean);
+        }
+    }
+
+    /// Cuts both ears to `max_len` samples, fading out the tail to avoid a discontinuity.
+    fn truncate(&mut self, max_len: usize) {
+        for ir in [&mut self.left, &mut self.right] {
+            if ir.len() <= max_len {
+                continue;
+            }
+            ir.truncate(max_len);
+
+            let fade_len = HRIR_TRUNCATION_FADE_LEN.min(max_len);
+            for (i, v) in ir[max_len - fade_len..].iter_mut().enumerate() {
+                let t = (i + 1) as f32 / fade_len as f32;
+                *v *= 0.5 * (1.0 + (std::f32::consts::PI * t).cos());
+            }
+        }
+    }
+}
+
+/// Returns the delay in samples and the gain of each speaker relative to the nearest one:
+/// sound from a farther speaker arrives later and, by the inverse distance law, quieter.
+fn distance_compensation(
+    distances: &[f32; NUM_SPEAKERS],
+    sample_rate: u32,
+) -> [(f32, f32); NUM_SPEAKERS] {
+    let nearest = distances.iter().copied().fold(f32::INFINITY, f32::min);
+    distances.map(|d| {
+        (
+            (d - nearest) / SPEED_OF_SOUND * sample_rate as f32,
+            nearest / d,
+        )
+    })
+}
+
+/// Shifts every HRIR so that its onset lines up with the earliest onset of the set.
+fn align_onsets(irs: &mut [&mut BinauralIr]) {
+    let onsets: Vec<usize> = irs.iter().map(|ir| ir.onset()).collect();
+    let reference = onsets.iter().copied().min().unwrap_or(0);
+
+    for (ir, onset) in irs.iter_mut().zip(onsets) {
+        ir.advance(onset - reference);
+    }
 }
 
 struct BinauralConvolver {
-    left: BlockConvolver,
-    right: BlockConvolver,
+    left: Box<dyn Convolve>,
+    right: Box<dyn Convolve>,
     left_out: Vec<f32>,
     right_out: Vec<f32>,
+    /// Applied to the input before convolution.
+    input_gain: f32,
+    /// Applied to the input before convolution, see `distance_compensation`.
+    delay: Option<FractionalDelay>,
 }
 
 impl BinauralConvolver {
-    pub fn new(block_size: usize, left: Vec<f32>, right: Vec<f32>) -> Self {
-        Self {
-            left: BlockConvolver::new(block_size, &left),
-            right: BlockConvolver::new(block_size, &right),
+    pub fn new(
+        block_size: usize,
+        left: Vec<f32>,
+        right: Vec<f32>,
+        input_gain: f32,
+        double_precision: bool,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            left: new_convolver(block_size, &left, double_precision)?,
+            right: new_convolver(block_size, &right, double_precision)?,
             left_out: vec![0.0; block_size],
             right_out: vec![0.0; block_size],
-        }
+            input_gain,
+            delay: None,
+        })
     }
 
     pub fn process(&mut self, input_ch_block: impl Iterator<Item = f32>) {
         for (i, v) in input_ch_block.enumerate() {
-            self.left_out[i] = v;
+            self.left_out[i] = self.input_gain * v;
+        }
+        if let Some(delay) = &mut self.delay {
+            delay.process(&mut self.left_out);
+        }
+        self.right_out.copy_from_slice(&self.left_out);
+
+        self.left.process(&mut self.left_out);
+        self.right.process(&mut self.right_out);
+    }
+
+    /// Processes the sum of the given `(input channel, gain)` pairs.
+    pub fn process_mixed(
+        &mut self,
+        input_block: &AudioDataRef,
+        channels: impl Iterator<Item = (usize, f32)>,
+    ) {
+        self.left_out.fill(0.0);
+        for (ch, gain) in channels {
+            let gain = self.input_gain * gain;
+            for (out, v) in self.left_out.iter_mut().zip(input_block.select_channel(ch)) {
+                *out += gain * v;
+            }
+        }
+        if let Some(delay) = &mut self.delay {
+            delay.process(&mut self.left_out);
         }
         self.right_out.copy_from_slice(&self.left_out);
 
         self.left.process(&mut self.left_out);
         self.right.process(&mut self.right_out);
     }
+
+    fn prewarm(&mut self, num_blocks: usize) {
+        self.left.prewarm(num_blocks);
+        self.right.prewarm(num_blocks);
+    }
 }
 
 pub struct SurroundVirtualizer {
@@ -51,22 +307,133 @@ pub struct SurroundVirtualizer {
     br_conv: BinauralConvolver,
     sl_conv: BinauralConvolver,
     sr_conv: BinauralConvolver,
-    lfe_conv: BinauralConvolver,
+    /// `None` when the LFE is added flat to both ears from `lfe_flat`.
+    lfe_conv: Option<BinauralConvolver>,
+    lfe_flat: Vec<f32>,
+    lfe_flat_gain: f32,
+    lfe_flat_delay: Option<FractionalDelay>,
+    height_convs: [Option<BinauralConvolver>; NUM_HEIGHT_CHANNELS],
+    downmix: DownmixMatrix,
+    speaker_mask: u32,
+    center_gain: f32,
+    /// Ring buffer of the last `UPMIX_SURROUND_DELAY` surround samples, oldest at `upmix_delay_pos`.
+    upmix_delay: Vec<f32>,
+    upmix_delay_pos: usize,
+    upmix_surround: Vec<f32>,
+    /// `[left, right]` all-pass per speaker, `None` when decorrelation is off.
+    decorrelators: Option<[[Biquad; 2]; NUM_SPEAKERS]>,
+    decorrelation: f32,
+    ch8_gain: f32,
+    ch2_gain: f32,
+    ch2_upmix_gain: f32,
+    mono_gain: f32,
 }
 
 impl SurroundVirtualizer {
-    pub fn new(config: &SurroundVirtualizerConfig) -> Self {
-        let fl = wav_to_binaural_convolver(&config.fl_wav, config.block_size);
-        let fr = wav_to_binaural_convolver(&config.fr_wav, config.block_size);
-        let fc = wav_to_binaural_convolver(&config.fc_wav, config.block_size);
-        let bl = wav_to_binaural_convolver(&config.bl_wav, config.block_size);
-        let br = wav_to_binaural_convolver(&config.br_wav, config.block_size);
-        let sl = wav_to_binaural_convolver(&config.sl_wav, config.block_size);
-        let sr = wav_to_binaural_convolver(&config.sr_wav, config.block_size);
-        let lfe = wav_to_binaural_convolver(&config.lfe_wav, config.block_size);
-
-        Self {
-            block_size: config.block_size,
+    /// Fails if one of the HRIRs isn't a valid mono or stereo WAV or the block size is zero.
+    pub fn new(config: &SurroundVirtualizerConfig) -> Result<Self, Error> {
+        let wavs = [
+            config.fl_wav,
+            config.fr_wav,
+            config.fc_wav,
+            config.lfe_wav,
+            config.sl_wav,
+            config.sr_wav,
+            config.bl_wav,
+            config.br_wav,
+        ];
+        let height_wavs = [
+            config.tfl_wav,
+            config.tfr_wav,
+            config.tbl_wav,
+            config.tbr_wav,
+        ];
+        let load = |label: &str, wav: &[u8]| {
+            BinauralIr::from_wav(wav).map_err(|err| err.context(&format!("{} HRIR", label)))
+        };
+
+        let mut irs = try_collect_array(std::array::from_fn(|i| load(SPEAKER_LABELS[i], wavs[i])))?;
+        let mut height_irs = try_collect_array(std::array::from_fn(|i| {
+            height_wavs[i]
+                .map(|wav| load(HEIGHT_LABELS[i], wav))
+                .transpose()
+        }))?;
+
+        if config.align_onsets {
+            let mut all_irs: Vec<&mut BinauralIr> = irs
+                .iter_mut()
+                .chain(height_irs.iter_mut().flatten())
+                .collect();
+            align_onsets(&mut all_irs);
+        }
+
+        if let Some(max_len) = config.max_hrir_len {
+            for ir in irs.iter_mut().chain(height_irs.iter_mut().flatten()) {
+                ir.truncate(max_len);
+            }
+        }
+
+        // After truncation, which would otherwise leave a new offset behind
+        if config.remove_dc {
+            for ir in irs.iter_mut().chain(height_irs.iter_mut().flatten()) {
+                ir.remove_dc();
+            }
+        }
+
+        let pre_gain = db_to_gain(-config.headroom_db);
+        let makeup_gain = 1.0 / pre_gain;
+        let new_conv = |ir: BinauralIr| {
+            BinauralConvolver::new(
+                config.engine.block_size,
+                ir.left,
+                ir.right,
+                pre_gain,
+                config.double_precision,
+            )
+        };
+        let mut convs = try_collect_array(irs.map(new_conv))?;
+        let mut lfe_flat_gain = pre_gain;
+        let mut lfe_flat_delay = None;
+        if let Some(distances) = &config.speaker_distances {
+            let compensation = distance_compensation(distances, config.engine.sample_rate);
+            for (speaker, (delay, gain)) in compensation.into_iter().enumerate() {
+                let delay = (delay > 0.0).then(|| FractionalDelay::new(delay));
+                if speaker == LFE && !config.lfe_hrir {
+                    lfe_flat_gain *= gain;
+                    lfe_flat_delay = delay;
+                } else {
+                    convs[speaker].input_gain *= gain;
+                    convs[speaker].delay = delay;
+                }
+            }
+        }
+        let [fl, fr, fc, lfe, sl, sr, bl, br] = convs;
+        // The LFE HRIR is still loaded above, so that a broken one fails either way
+        let lfe = config.lfe_hrir.then_some(lfe);
+        let height_convs = try_collect_array(height_irs.map(|ir| ir.map(new_conv).transpose()))?;
+
+        let mut downmix = config.downmix;
+        if let Some(lfe_gain) = config.lfe_gain {
+            downmix[LFE] = [lfe_gain; 2];
+        }
+
+        let [ch2_gain, ch2_upmix_gain, mono_gain] = if config.mode_gain_compensation {
+            mode_compensation_gains(&downmix)
+        } else {
+            [1.0; 3]
+        }
+        .map(|gain| gain * makeup_gain);
+
+        let decorrelation = config.decorrelation.clamp(0.0, 1.0);
+        // Both ears share the filter, so the interaural time difference is preserved
+        let decorrelators = (decorrelation > 0.0).then(|| {
+            DECORRELATION_FREQS.map(|freq| {
+                [(); 2].map(|_| Biquad::all_pass(config.engine.sample_rate, freq, DECORRELATION_Q))
+            })
+        });
+
+        Ok(Self {
+            block_size: config.engine.block_size,
             fc_conv: fc,
             fl_conv: fl,
             fr_conv: fr,
@@ -75,48 +442,194 @@ impl SurroundVirtualizer {
             sl_conv: sl,
             sr_conv: sr,
             lfe_conv: lfe,
+            lfe_flat: vec![0.0; config.engine.block_size],
+            lfe_flat_gain,
+            lfe_flat_delay,
+            height_convs,
+            downmix,
+            speaker_mask: u32::MAX,
+            center_gain: 1.0,
+            upmix_delay: vec![0.0; UPMIX_SURROUND_DELAY],
+            upmix_delay_pos: 0,
+            upmix_surround: vec![0.0; config.engine.block_size],
+            decorrelators,
+            decorrelation,
+            ch8_gain: makeup_gain,
+            ch2_gain,
+            ch2_upmix_gain,
+            mono_gain,
+        })
+    }
+
+    /// Runs `num_blocks` silent blocks through every convolver, see `BlockConvolver::prewarm`.
+    pub fn prewarm(&mut self, num_blocks: usize) {
+        let convs = [
+            &mut self.fl_conv,
+            &mut self.fr_conv,
+            &mut self.fc_conv,
+            &mut self.sl_conv,
+            &mut self.sr_conv,
+            &mut self.bl_conv,
+            &mut self.br_conv,
+        ];
+        for conv in convs
+            .into_iter()
+            .chain(&mut self.lfe_conv)
+            .chain(self.height_convs.iter_mut().flatten())
+        {
+            conv.prewarm(num_blocks);
         }
     }
 
+    /// Selects which speakers are audible: bit `i` enables speaker `i`, and the height
+    /// channels follow from bit `HEIGHT_CHANNELS_OFFSET`.
+    pub fn set_speaker_mask(&mut self, mask: u32) {
+        self.speaker_mask = mask;
+    }
+
+    /// Boosts the center speaker, which carries most of the dialog, relative to the others.
+    pub fn set_center_boost_db(&mut self, db: f32) {
+        self.center_gain = db_to_gain(db);
+    }
+
+    /// Renders a 7.1 input (FL, FR, FC, LFE, SL, SR, BL, BR) followed by up to
+    /// four optional height channels (TFL, TFR, TBL, TBR). Height channels
+    /// without an HRIR are folded down into the nearest horizontal speaker.
+    /// Inputs with fewer channels are mapped as described in `get_speaker_channels`.
     pub fn process_ch8(&mut self, input_block: &AudioDataRef, stereo_output: &mut AudioDataMut) {
-        const CENTER_GAIN: f32 = 0.5 * std::f32::consts::SQRT_2;
-        const SIDE_GAIN: f32 = 0.5 * std::f32::consts::SQRT_2;
-        const BACK_GAIN: f32 = 0.5 * std::f32::consts::SQRT_2;
-        const LFE_GAIN: f32 = 0.25;
+        assert_block_size(input_block.num_frames(), self.block_size);
+        assert_block_size(stereo_output.num_frames(), self.block_size);
 
-        assert_eq!(stereo_output.data.len(), self.block_size * 2);
+        let num_heights = input_block
+            .num_channels()
+            .saturating_sub(HEIGHT_CHANNELS_OFFSET)
+            .min(NUM_HEIGHT_CHANNELS);
 
-        self.fl_conv.process(input_block.select_channel(0));
-        self.fr_conv.process(input_block.select_channel(1));
-        self.fc_conv.process(input_block.select_channel(2));
-        self.lfe_conv.process(input_block.select_channel(3));
-        self.sl_conv.process(input_block.select_channel(4));
-        self.sr_conv.process(input_block.select_channel(5));
-        self.bl_conv.process(input_block.select_channel(6));
-        self.br_conv.process(input_block.select_channel(7));
+        let speaker_mask = self.speaker_mask;
+        let audible = move |ch: usize| speaker_mask & (1 << ch) != 0;
+
+        // Masked channels are left out of the convolver inputs, so that a height channel
+        // folded into a speaker follows its own bit rather than the speaker's
+        let mut fold_down = [None; NUM_HEIGHT_CHANNELS];
+        for h in 0..num_heights {
+            if self.height_convs[h].is_none() && audible(HEIGHT_CHANNELS_OFFSET + h) {
+                fold_down[h] = Some(HEIGHT_FOLD_DOWN[h]);
+            }
+        }
+        let with_folded = move |speaker: usize, input_ch: usize| {
+            let gain = if audible(speaker) { 1.0 } else { 0.0 };
+            iter::once((input_ch, gain)).chain(
+                fold_down
+                    .into_iter()
+                    .enumerate()
+                    .filter(move |(_, target)| *target == Some(speaker))
+                    .map(|(h, _)| (HEIGHT_CHANNELS_OFFSET + h, HEIGHT_GAIN)),
+            )
+        };
+
+        let speaker_channels = get_speaker_channels(input_block.num_channels());
+        let convs = [
+            Some(&mut self.fl_conv),
+            Some(&mut self.fr_conv),
+            Some(&mut self.fc_conv),
+            self.lfe_conv.as_mut(),
+            Some(&mut self.sl_conv),
+            Some(&mut self.sr_conv),
+            Some(&mut self.bl_conv),
+            Some(&mut self.br_conv),
+        ];
+        for (speaker, conv) in convs.into_iter().enumerate() {
+            if let Some(input_ch) = speaker_channels[speaker]
+                && let Some(conv) = conv
+            {
+                conv.process_mixed(input_block, with_folded(speaker, input_ch));
+
+                if let Some(decorrelators) = &mut self.decorrelators {
+                    let [left_ap, right_ap] = &mut decorrelators[speaker];
+                    for (out, ap) in [
+                        (&mut conv.left_out, left_ap),
+                        (&mut conv.right_out, right_ap),
+                    ] {
+                        for v in out.iter_mut() {
+                            *v += self.decorrelation * (ap.process_sample(*v) - *v);
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.lfe_conv.is_none()
+            && let Some(input_ch) = speaker_channels[LFE]
+        {
+            for (out, v) in self
+                .lfe_flat
+                .iter_mut()
+                .zip(input_block.select_channel(input_ch))
+            {
+                *out = self.lfe_flat_gain * v;
+            }
+            if let Some(delay) = &mut self.lfe_flat_delay {
+                delay.process(&mut self.lfe_flat);
+            }
+        }
+        let (lfe_left, lfe_right) = match &self.lfe_conv {
+            Some(conv) => (&conv.left_out, &conv.right_out),
+            None => (&self.lfe_flat, &self.lfe_flat),
+        };
+
+        // Speakers missing from the input layout are silenced along with the masked ones,
+        // unless an audible height channel is folded into them
+        let mut m = self.downmix;
+        for (ch, gains) in m.iter_mut().enumerate() {
+            let folded_into = fold_down.contains(&Some(ch));
+            if !(audible(ch) || folded_into) || speaker_channels[ch].is_none() {
+                *gains = [0.0; 2];
+            } else {
+                *gains = gains.map(|g| g * self.ch8_gain);
+            }
+        }
+        m[FC] = m[FC].map(|g| g * self.center_gain);
+        let height_gain = HEIGHT_GAIN * self.ch8_gain;
 
         let left_ch = stereo_output.select_channel_mut(0);
         for (i, v) in left_ch.enumerate() {
-            *v = self.fl_conv.left_out[i]
-                + self.fr_conv.left_out[i]
-                + CENTER_GAIN * self.fc_conv.left_out[i]
-                + BACK_GAIN * self.bl_conv.left_out[i]
-                + BACK_GAIN * self.br_conv.left_out[i]
-                + SIDE_GAIN * self.sl_conv.left_out[i]
-                + SIDE_GAIN * self.sr_conv.left_out[i]
-                + LFE_GAIN * self.lfe_conv.left_out[i];
+            *v = m[FL][0] * self.fl_conv.left_out[i]
+                + m[FR][0] * self.fr_conv.left_out[i]
+                + m[FC][0] * self.fc_conv.left_out[i]
+                + m[BL][0] * self.bl_conv.left_out[i]
+                + m[BR][0] * self.br_conv.left_out[i]
+                + m[SL][0] * self.sl_conv.left_out[i]
+                + m[SR][0] * self.sr_conv.left_out[i]
+                + m[LFE][0] * lfe_left[i];
         }
 
         let right_ch = stereo_output.select_channel_mut(1);
         for (i, v) in right_ch.enumerate() {
-            *v = self.fl_conv.right_out[i]
-                + self.fr_conv.right_out[i]
-                + CENTER_GAIN * self.fc_conv.right_out[i]
-                + BACK_GAIN * self.bl_conv.right_out[i]
-                + BACK_GAIN * self.br_conv.right_out[i]
-                + SIDE_GAIN * self.sl_conv.right_out[i]
-                + SIDE_GAIN * self.sr_conv.right_out[i]
-                + LFE_GAIN * self.lfe_conv.right_out[i];
+            *v = m[FL][1] * self.fl_conv.right_out[i]
+                + m[FR][1] * self.fr_conv.right_out[i]
+                + m[FC][1] * self.fc_conv.right_out[i]
+                + m[BL][1] * self.bl_conv.right_out[i]
+                + m[BR][1] * self.br_conv.right_out[i]
+                + m[SL][1] * self.sl_conv.right_out[i]
+                + m[SR][1] * self.sr_conv.right_out[i]
+                + m[LFE][1] * lfe_right[i];
+        }
+
+        for (h, conv) in self.height_convs.iter_mut().enumerate().take(num_heights) {
+            let Some(conv) = conv else {
+                continue;
+            };
+            conv.process(input_block.select_channel(HEIGHT_CHANNELS_OFFSET + h));
+            if !audible(HEIGHT_CHANNELS_OFFSET + h) {
+                continue;
+            }
+
+            for (v, out) in stereo_output.select_channel_mut(0).zip(&conv.left_out) {
+                *v += height_gain * out;
+            }
+            for (v, out) in stereo_output.select_channel_mut(1).zip(&conv.right_out) {
+                *v += height_gain * out;
+            }
         }
     }
 
@@ -125,10 +638,8 @@ impl SurroundVirtualizer {
     /// purely through the fronts without comb coloration, while decorrelated
     /// content (ambience, wide-panned elements) is widened.
     pub fn process_ch2(&mut self, input_block: &AudioDataRef, stereo_output: &mut AudioDataMut) {
-        const FRONT_GAIN: f32 = 0.9;
-        const SIDE_GAIN: f32 = 0.18;
-
-        assert_eq!(stereo_output.data.len(), self.block_size * 2);
+        assert_block_size(input_block.num_frames(), self.block_size);
+        assert_block_size(stereo_output.num_frames(), self.block_size);
 
         let side_signal = || {
             input_block
@@ -144,77 +655,1320 @@ impl SurroundVirtualizer {
 
         let left_ch = stereo_output.select_channel_mut(0);
         for (i, v) in left_ch.enumerate() {
-            *v = FRONT_GAIN * self.fl_conv.left_out[i]
-                + FRONT_GAIN * self.fr_conv.left_out[i]
-                + SIDE_GAIN * self.sl_conv.left_out[i]
-                + SIDE_GAIN * self.sr_conv.left_out[i];
+            *v = self.ch2_gain
+                * (CH2_FRONT_GAIN * self.fl_conv.left_out[i]
+                    + CH2_FRONT_GAIN * self.fr_conv.left_out[i]
+                    + CH2_SIDE_GAIN * self.sl_conv.left_out[i]
+                    + CH2_SIDE_GAIN * self.sr_conv.left_out[i]);
+        }
+
+        let right_ch = stereo_output.select_channel_mut(1);
+        for (i, v) in right_ch.enumerate() {
+            *v = self.ch2_gain
+                * (CH2_FRONT_GAIN * self.fl_conv.right_out[i]
+                    + CH2_FRONT_GAIN * self.fr_conv.right_out[i]
+                    + CH2_SIDE_GAIN * self.sl_conv.right_out[i]
+                    + CH2_SIDE_GAIN * self.sr_conv.right_out[i]);
+        }
+    }
+
+    /// Pseudo-surround rendering of a stereo input: the correlated part (L+R) is sent to the
+    /// center speaker and a delayed difference signal (L−R) to the side speakers.
+    pub fn process_ch2_upmix(
+        &mut self,
+        input_block: &AudioDataRef,
+        stereo_output: &mut AudioDataMut,
+    ) {
+        assert_block_size(input_block.num_frames(), self.block_size);
+        assert_block_size(stereo_output.num_frames(), self.block_size);
+
+        let samples = input_block
+            .select_channel(0)
+            .zip(input_block.select_channel(1));
+        for (v, (l, r)) in self.upmix_surround.iter_mut().zip(samples) {
+            let oldest = &mut self.upmix_delay[self.upmix_delay_pos];
+            *v = std::mem::replace(oldest, 0.5 * (l - r));
+            self.upmix_delay_pos = (self.upmix_delay_pos + 1) % UPMIX_SURROUND_DELAY;
+        }
+
+        self.fl_conv.process(input_block.select_channel(0));
+        self.fr_conv.process(input_block.select_channel(1));
+        self.fc_conv.process(
+            input_block
+                .select_channel(0)
+                .zip(input_block.select_channel(1))
+                .map(|(l, r)| 0.5 * (l + r)),
+        );
+        self.sl_conv.process(self.upmix_surround.iter().copied());
+        self.sr_conv.process(self.upmix_surround.iter().map(|v| -v));
+
+        let center_gain = UPMIX_CENTER_GAIN * self.center_gain;
+
+        let left_ch = stereo_output.select_channel_mut(0);
+        for (i, v) in left_ch.enumerate() {
+            *v = self.ch2_upmix_gain
+                * (UPMIX_FRONT_GAIN * (self.fl_conv.left_out[i] + self.fr_conv.left_out[i])
+                    + center_gain * self.fc_conv.left_out[i]
+                    + UPMIX_SURROUND_GAIN * (self.sl_conv.left_out[i] + self.sr_conv.left_out[i]));
         }
 
         let right_ch = stereo_output.select_channel_mut(1);
         for (i, v) in right_ch.enumerate() {
-            *v = FRONT_GAIN * self.fl_conv.right_out[i]
-                + FRONT_GAIN * self.fr_conv.right_out[i]
-                + SIDE_GAIN * self.sl_conv.right_out[i]
-                + SIDE_GAIN * self.sr_conv.right_out[i];
+            *v = self.ch2_upmix_gain
+                * (UPMIX_FRONT_GAIN * (self.fl_conv.right_out[i] + self.fr_conv.right_out[i])
+                    + center_gain * self.fc_conv.right_out[i]
+                    + UPMIX_SURROUND_GAIN
+                        * (self.sl_conv.right_out[i] + self.sr_conv.right_out[i]));
         }
     }
 
     pub fn process_mono(&mut self, mono_input: &AudioDataRef, stereo_output: &mut AudioDataMut) {
-        assert_eq!(stereo_output.data.len(), self.block_size * 2);
+        assert_block_size(mono_input.num_frames(), self.block_size);
+        assert_block_size(stereo_output.num_frames(), self.block_size);
 
         self.fl_conv.process(mono_input.select_channel(0));
         self.fr_conv.process(mono_input.select_channel(0));
 
         let left_ch = stereo_output.select_channel_mut(0);
         for (i, v) in left_ch.enumerate() {
-            *v = self.fl_conv.left_out[i] + self.fr_conv.left_out[i];
+            *v = self.mono_gain * (self.fl_conv.left_out[i] + self.fr_conv.left_out[i]);
         }
 
         let right_ch = stereo_output.select_channel_mut(1);
         for (i, v) in right_ch.enumerate() {
-            *v = self.fl_conv.right_out[i] + self.fr_conv.right_out[i];
+            *v = self.mono_gain * (self.fl_conv.right_out[i] + self.fr_conv.right_out[i]);
+        }
+    }
+}
+
+/// Returns the input channel of each speaker (in `SPEAKER_LABELS` order) for the common
+/// layouts of the given channel count: 3.0, quad, 5.0, 5.1 and 7.1.
+fn get_speaker_channels(num_channels: usize) -> [Option<usize>; NUM_SPEAKERS] {
+    match num_channels {
+        0..=2 => [None; NUM_SPEAKERS],
+        3 => [Some(0), Some(1), Some(2), None, None, None, None, None],
+        4 => [Some(0), Some(1), None, None, None, None, Some(2), Some(3)],
+        5 => [
+            Some(0),
+            Some(1),
+            Some(2),
+            None,
+            Some(3),
+            Some(4),
+            None,
+            None,
+        ],
+        6 | 7 => [
+            Some(0),
+            Some(1),
+            Some(2),
+            Some(3),
+            Some(4),
+            Some(5),
+            None,
+            None,
+        ],
+        _ => [FL, FR, FC, LFE, SL, SR, BL, BR].map(Some),
+    }
+}
+
+/// Plain stereo downmix without virtualization, for when the HRIRs can't be loaded.
+/// Mono and stereo inputs pass through unchanged.
+pub fn downmix_to_stereo(input_block: &AudioDataRef, stereo_output: &mut AudioDataMut) {
+    let num_channels = input_block.num_channels();
+    if num_channels <= 2 {
+        let left = input_block.select_channel(0);
+        let right = input_block.select_channel(num_channels - 1);
+        for (frame, (l, r)) in stereo_output.data.chunks_exact_mut(2).zip(left.zip(right)) {
+            frame[0] = l;
+            frame[1] = r;
         }
+        return;
     }
+
+    stereo_output.data.fill(0.0);
+    for (speaker, input_ch) in get_speaker_channels(num_channels).into_iter().enumerate() {
+        let Some(input_ch) = input_ch else {
+            continue;
+        };
+        let [left_gain, right_gain] = PASSTHROUGH_DOWNMIX[speaker];
+        for (frame, v) in stereo_output
+            .data
+            .chunks_exact_mut(2)
+            .zip(input_block.select_channel(input_ch))
+        {
+            frame[0] += left_gain * v;
+            frame[1] += right_gain * v;
+        }
+    }
+}
+
+/// Gains of the stereo, upmixed stereo and mono renderings that match their output power to
+/// the 7.1 rendering, assuming uncorrelated input channels of the same total power.
+fn mode_compensation_gains(downmix: &DownmixMatrix) -> [f32; 3] {
+    // averaged over both ears, as a custom matrix may weigh a speaker differently in each
+    let ch8_power = downmix
+        .iter()
+        .map(|[l, r]| (l * l + r * r) / 2.0)
+        .sum::<f32>()
+        / NUM_SPEAKERS as f32;
+    // the side signal L-R carries the power of both channels
+    let ch2_power = CH2_FRONT_GAIN.powi(2) + 2.0 * CH2_SIDE_GAIN.powi(2);
+    // (L+R)/2 and (L-R)/2 each carry a quarter of the total power
+    let ch2_upmix_power = UPMIX_FRONT_GAIN.powi(2)
+        + UPMIX_CENTER_GAIN.powi(2) / 4.0
+        + UPMIX_SURROUND_GAIN.powi(2) / 2.0;
+    // both front convolvers receive the full mono signal
+    let mono_power = 2.0;
+
+    [ch2_power, ch2_upmix_power, mono_power].map(|power| (ch8_power / power).sqrt())
 }
 
 pub struct Equalizer {
     left: BlockConvolver,
     right: BlockConvolver,
+    /// Convolvers of `process_channels`, one per channel, see `with_channels`.
+    channel_convs: Vec<BlockConvolver>,
     scratch: Vec<f32>,
+    preamp: f32,
+    /// The curves apply to the mid and side signals instead of the left and right ears.
+    mid_side: bool,
 }
 
 impl Equalizer {
-    pub fn new(block_size: usize, eqir: Vec<f32>) -> Self {
-        Self {
-            left: BlockConvolver::new(block_size, &eqir),
-            right: BlockConvolver::new(block_size, &eqir),
-            scratch: vec![0.0; block_size],
-        }
+    pub fn new(engine: EngineConfig, eqir: Vec<f32>) -> Result<Self, Error> {
+        Self::new_stereo(engine, eqir.clone(), eqir)
+    }
+
+    /// The preamp defaults to the attenuation that keeps the loudest band of either curve at 0 dB.
+    pub fn new_stereo(
+        engine: EngineConfig,
+        left_ir: Vec<f32>,
+        right_ir: Vec<f32>,
+    ) -> Result<Self, Error> {
+        let peak = peak_magnitude_response(&left_ir).max(peak_magnitude_response(&right_ir));
+        Ok(Self {
+            left: BlockConvolver::new(engine.block_size, &left_ir)?,
+            right: BlockConvolver::new(engine.block_size, &right_ir)?,
+            channel_convs: Vec::new(),
+            scratch: vec![0.0; engine.block_size],
+            preamp: (1.0 / peak).min(1.0),
+            mid_side: false,
+        })
+    }
+
+    /// Applies `mid_ir` to the mid signal (L+R)/2 and `side_ir` to the side signal (L-R)/2.
+    /// Identical curves are the same as a stereo equalizer with these curves, and the side
+    /// curve leaves mono content alone.
+    pub fn new_mid_side(
+        engine: EngineConfig,
+        mid_ir: Vec<f32>,
+        side_ir: Vec<f32>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            mid_side: true,
+            ..Self::new_stereo(engine, mid_ir, side_ir)?
+        })
+    }
+
+    pub fn preamp_db(&self) -> f32 {
+        gain_to_db(self.preamp)
+    }
+
+    /// Sets the gain applied after the correction curve.
+    pub fn set_preamp_db(&mut self, db: f32) {
+        self.preamp = db_to_gain(db);
     }
 
     pub fn process(&mut self, stereo_data: &mut AudioDataMut<'_>) {
+        assert_block_size(stereo_data.num_frames(), self.scratch.len());
+
+        if self.mid_side {
+            for frame in stereo_data.data.chunks_exact_mut(2) {
+                let [l, r] = [frame[0], frame[1]];
+                frame.copy_from_slice(&[0.5 * (l + r), 0.5 * (l - r)]);
+            }
+        }
+
         stereo_data.copy_channel_to_slice(0, &mut self.scratch);
         self.left.process(&mut self.scratch);
+        self.scratch.iter_mut().for_each(|v| *v *= self.preamp);
         stereo_data.copy_channel_from_slice(0, &self.scratch);
 
         stereo_data.copy_channel_to_slice(1, &mut self.scratch);
         self.right.process(&mut self.scratch);
+        self.scratch.iter_mut().for_each(|v| *v *= self.preamp);
         stereo_data.copy_channel_from_slice(1, &self.scratch);
+
+        if self.mid_side {
+            for frame in stereo_data.data.chunks_exact_mut(2) {
+                let [mid, side] = [frame[0], frame[1]];
+                frame.copy_from_slice(&[mid + side, mid - side]);
+            }
+        }
+    }
+
+    /// Prepares `process_channels` for blocks of up to `num_channels` channels.
+    /// Must be called before the equalizer processes any audio.
+    pub fn with_channels(mut self, num_channels: usize) -> Self {
+        self.channel_convs = vec![self.left.clone(); num_channels];
+        self
+    }
+
+    /// Runs `num_blocks` silent blocks through the convolvers, see `BlockConvolver::prewarm`.
+    pub fn prewarm(&mut self, num_blocks: usize) {
+        self.left.prewarm(num_blocks);
+        self.right.prewarm(num_blocks);
+        for conv in &mut self.channel_convs {
+            conv.prewarm(num_blocks);
+        }
+    }
+
+    /// Applies the curve to each channel of a multichannel block, before virtualization.
+    /// There is no ear to pick a curve for, so per-ear profiles use their left curve
+    /// and mid/side ones their mid curve. Channels beyond those given to `with_channels`
+    /// are left as they are.
+    pub fn process_channels(&mut self, data: &mut AudioDataMut<'_>) {
+        assert_block_size(data.num_frames(), self.scratch.len());
+
+        let num_channels = data.num_channels();
+        debug_assert!(num_channels <= self.channel_convs.len());
+
+        for (ch, conv) in self.channel_convs.iter_mut().take(num_channels).enumerate() {
+            data.copy_channel_to_slice(ch, &mut self.scratch);
+            conv.process(&mut self.scratch);
+            self.scratch.iter_mut().for_each(|v| *v *= self.preamp);
+            data.copy_channel_from_slice(ch, &self.scratch);
+        }
     }
 }
 
-pub fn wav_to_pcm(wav_data: &[u8]) -> Vec<f32> {
-    let mut reader = hound::WavReader::new(Cursor::new(wav_data)).unwrap();
+/// Returns the largest linear gain of the impulse's frequency response.
+fn peak_magnitude_response(ir: &[f32]) -> f32 {
+    const MIN_FFT_SIZE: usize = 4096;
+
+    let fft_size = ir.len().max(MIN_FFT_SIZE).next_power_of_two();
+    let fft = RealFftPlanner::<f32>::new().plan_fft_forward(fft_size);
+
+    let mut padded = vec![0.0; fft_size];
+    padded[..ir.len()].copy_from_slice(ir);
+    let mut spectrum = fft.make_output_vec();
+    fft.process(&mut padded, &mut spectrum).unwrap();
+
+    spectrum.iter().map(|c| c.norm()).fold(0.0, f32::max)
+}
+
+/// Returns the interleaved samples together with the WAV header.
+fn wav_to_pcm_with_spec(wav_data: &[u8]) -> Result<(Vec<f32>, hound::WavSpec), Error> {
+    let mut reader = hound::WavReader::new(Cursor::new(wav_data))?;
+    let spec = reader.spec();
     let pcm = reader
         .samples::<f32>()
         .map(|s| s.unwrap_or_default())
         .collect::<Vec<f32>>();
-    pcm
+    Ok((pcm, spec))
+}
+
+/// Collects the results of a per-speaker array, failing with the first error.
+fn try_collect_array<T, const N: usize>(results: [Result<T, Error>; N]) -> Result<[T; N], Error> {
+    let items = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+    let Ok(items) = items.try_into() else {
+        unreachable!("the array length is preserved");
+    };
+    Ok(items)
+}
+
+/// Splits interleaved stereo samples into the left (even) and right (odd) channels.
+fn split_stereo(pcm: &[f32]) -> (Vec<f32>, Vec<f32>) {
+    let left = pcm.iter().step_by(2).cloned().collect();
+    let right = pcm.iter().skip(1).step_by(2).cloned().collect();
+    (left, right)
+}
+
+/// Resamples an impulse response through the frequency domain, which keeps its frequency
+/// response up to the lower of the two Nyquist frequencies.
+fn resample_ir(ir: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || ir.is_empty() {
+        return ir.to_vec();
+    }
+    let scaled_len = |len: usize| (len as u64 * to_rate as u64).div_ceil(from_rate as u64) as usize;
+
+    // The padding keeps the interpolated tail from wrapping around onto the onset
+    let in_len = (ir.len() * 2).next_power_of_two();
+    let out_len = scaled_len(in_len);
+    let mut planner = RealFftPlanner::<f32>::new();
+    let forward = planner.plan_fft_forward(in_len);
+    let inverse = planner.plan_fft_inverse(out_len);
+
+    let mut padded = vec![0.0; in_len];
+    padded[..ir.len()].copy_from_slice(ir);
+    let mut spectrum = forward.make_output_vec();
+    forward.process(&mut padded, &mut spectrum).unwrap();
+
+    let mut out_spectrum = inverse.make_input_vec();
+    let num_bins = spectrum.len().min(out_spectrum.len());
+    out_spectrum[..num_bins].copy_from_slice(&spectrum[..num_bins]);
+    // The inverse transform requires real DC and Nyquist bins
+    out_spectrum[0].im = 0.0;
+    if out_len % 2 == 0 {
+        out_spectrum.last_mut().unwrap().im = 0.0;
+    }
+    let mut out = inverse.make_output_vec();
+    inverse.process(&mut out_spectrum, &mut out).unwrap();
+
+    out.truncate(scaled_len(ir.len()));
+    out.iter_mut().for_each(|v| *v /= out_len as f32);
+    out
+}
+
+/// Builds an equalizer from a correction WAV, resampled to the engine rate if it differs.
+/// A stereo WAV holds per-ear curves, or mid and side ones with `EqChannelMode::MidSide`.
+/// Of any other layout, the first channel is shared by both ears.
+pub fn wav_to_equalizer(
+    wav_data: &[u8],
+    engine: EngineConfig,
+    channel_mode: EqChannelMode,
+) -> Result<Equalizer, Error> {
+    let (pcm, spec) = wav_to_pcm_with_spec(wav_data)?;
+    let resample = |ir: Vec<f32>| resample_ir(&ir, spec.sample_rate, engine.sample_rate);
+    if spec.channels == 2 {
+        let (first, second) = split_stereo(&pcm);
+        match channel_mode {
+            EqChannelMode::LeftRight => {
+                Equalizer::new_stereo(engine, resample(first), resample(second))
+            }
+            EqChannelMode::MidSide => {
+                Equalizer::new_mid_side(engine, resample(first), resample(second))
+            }
+        }
+    } else {
+        let first_channel = pcm.iter().step_by(spec.channels.max(1) as usize).copied();
+        Equalizer::new(engine, resample(first_channel.collect()))
+    }
 }
 
-fn wav_to_binaural_convolver(wav_data: &[u8], block_size: usize) -> BinauralConvolver {
-    let pcm = wav_to_pcm(wav_data);
-    let left_pcm = pcm.iter().step_by(2).cloned().collect::<Vec<_>>();
-    let right_pcm = pcm.iter().skip(1).step_by(2).cloned().collect::<Vec<_>>();
-    BinauralConvolver::new(block_size, left_pcm, right_pcm)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audio_data::white_noise, runtime_params::RuntimeParams};
+
+    const ENGINE: EngineConfig = EngineConfig {
+        block_size: 256,
+        sample_rate: 48000,
+    };
+
+    fn wav_bytes(num_channels: u16, sample_rate: u32, samples: &[f32]) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: num_channels,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        for &v in samples {
+            writer.write_sample(v).unwrap();
+        }
+        writer.finalize().unwrap();
+        cursor.into_inner()
+    }
+
+    /// A unit impulse, with which every convolver passes its input through unchanged.
+    fn impulse_wav() -> Vec<u8> {
+        wav_bytes(1, ENGINE.sample_rate, &[1.0])
+    }
+
+    /// Uses `wav` for every speaker, with no gain, delay or HRIR processing of its own.
+    fn test_config(wav: &[u8]) -> SurroundVirtualizerConfig<'_> {
+        SurroundVirtualizerConfig {
+            fc_wav: wav,
+            bl_wav: wav,
+            br_wav: wav,
+            fl_wav: wav,
+            fr_wav: wav,
+            sl_wav: wav,
+            sr_wav: wav,
+            lfe_wav: wav,
+            tfl_wav: None,
+            tfr_wav: None,
+            tbl_wav: None,
+            tbr_wav: None,
+            engine: ENGINE,
+            downmix: DEFAULT_DOWNMIX_MATRIX,
+            lfe_gain: None,
+            lfe_hrir: false,
+            max_hrir_len: None,
+            align_onsets: false,
+            mode_gain_compensation: false,
+            headroom_db: 0.0,
+            remove_dc: false,
+            double_precision: false,
+            decorrelation: 0.0,
+            speaker_distances: None,
+        }
+    }
+
+    fn energy(block: &[f32]) -> f32 {
+        block.iter().map(|v| v * v).sum()
+    }
+
+    /// Interleaves `left` and `right` sample by sample.
+    fn stereo_block(left: impl Fn(usize) -> f32, right: impl Fn(usize) -> f32) -> Vec<f32> {
+        (0..ENGINE.block_size)
+            .flat_map(|i| [left(i), right(i)])
+            .collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!((a - e).abs() < 1e-4, "sample {}: {} != {}", i, a, e);
+        }
+    }
+
+    #[test]
+    fn process_channels_equalizes_every_channel() {
+        let num_channels = 3;
+        let mut eq = Equalizer::new(ENGINE, vec![0.5])
+            .unwrap()
+            .with_channels(num_channels);
+        let input: Vec<f32> = (0..ENGINE.block_size * num_channels)
+            .map(|i| (i % 7) as f32 * 0.1)
+            .collect();
+
+        let mut data = input.clone();
+        eq.process_channels(&mut AudioDataMut::new(&mut data, num_channels));
+
+        let expected: Vec<f32> = input.iter().map(|v| v * 0.5).collect();
+        assert_close(&data, &expected);
+    }
+
+    /// Runs `blocks` blocks of `input` through `process_ch2_upmix` and returns the energy
+    /// of the center and of the left surround speaker in the last block.
+    fn upmix_energies(input: &[f32], blocks: usize) -> (f32, f32) {
+        let wav = impulse_wav();
+        let mut sv = SurroundVirtualizer::new(&test_config(&wav)).unwrap();
+        let mut output = vec![0.0; ENGINE.block_size * 2];
+        for _ in 0..blocks {
+            sv.process_ch2_upmix(
+                &AudioDataRef::new(input, 2),
+                &mut AudioDataMut::new(&mut output, 2),
+            );
+        }
+        (energy(&sv.fc_conv.left_out), energy(&sv.sl_conv.left_out))
+    }
+
+    #[test]
+    fn upmix_renders_correlated_input_through_the_center() {
+        let sine = |i: usize| (i as f32 * 0.05).sin();
+        let input = stereo_block(sine, sine);
+        let blocks = UPMIX_SURROUND_DELAY.div_ceil(ENGINE.block_size) + 1;
+
+        let (center, surround) = upmix_energies(&input, blocks);
+        assert!(center > 0.0);
+        assert!(surround < center * 1e-6, "{} vs {}", surround, center);
+    }
+
+    #[test]
+    fn upmix_spreads_anti_correlated_input_to_the_surrounds() {
+        let sine = |i: usize| (i as f32 * 0.05).sin();
+        let input = stereo_block(sine, |i| -sine(i));
+        let blocks = UPMIX_SURROUND_DELAY.div_ceil(ENGINE.block_size) + 1;
+
+        let (center, surround) = upmix_energies(&input, blocks);
+        assert!(surround > 0.0);
+        assert!(center < surround * 1e-6, "{} vs {}", center, surround);
+    }
+
+    #[test]
+    fn upmix_delays_the_surround_signal() {
+        let wav = impulse_wav();
+        let mut sv = SurroundVirtualizer::new(&test_config(&wav)).unwrap();
+        let mut output = vec![0.0; ENGINE.block_size * 2];
+        let impulse_at = 10;
+
+        let mut surround = Vec::new();
+        for block in 0..UPMIX_SURROUND_DELAY.div_ceil(ENGINE.block_size) + 1 {
+            let input = stereo_block(
+                |i| {
+                    if block == 0 && i == impulse_at {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                },
+                |_| 0.0,
+            );
+            sv.process_ch2_upmix(
+                &AudioDataRef::new(&input, 2),
+                &mut AudioDataMut::new(&mut output, 2),
+            );
+            surround.extend_from_slice(&sv.upmix_surround);
+        }
+
+        let mut expected = vec![0.0; surround.len()];
+        expected[impulse_at + UPMIX_SURROUND_DELAY] = 0.5;
+        assert_eq!(surround, expected);
+    }
+
+    /// RMS of `signal` after a few blocks of it ran through `eq`, once the filter settled.
+    fn settled_rms(eq: &mut Equalizer, signal: impl Fn(usize) -> f32) -> f32 {
+        let mut data = Vec::new();
+        for block in 0..4 {
+            let offset = block * ENGINE.block_size;
+            data = stereo_block(|i| signal(offset + i), |i| signal(offset + i));
+            eq.process(&mut AudioDataMut::new(&mut data, 2));
+        }
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        (energy(&left) / left.len() as f32).sqrt()
+    }
+
+    /// A one-pole low pass with a 0.2 ms time constant (800 Hz cutoff) at `sample_rate`.
+    fn low_pass_ir(sample_rate: u32) -> Vec<f32> {
+        let tau = 0.0002 * sample_rate as f32;
+        (0..(10.0 * tau) as usize)
+            .map(|i| (-(i as f32) / tau).exp() / tau)
+            .collect()
+    }
+
+    #[test]
+    fn resampled_44k_mono_eq_matches_the_48k_curve() {
+        let wav_44k = wav_bytes(1, 44100, &low_pass_ir(44100));
+        let wav_48k = wav_bytes(1, 48000, &low_pass_ir(48000));
+        let mut eq_44k = wav_to_equalizer(&wav_44k, ENGINE, EqChannelMode::LeftRight).unwrap();
+        let mut eq_48k = wav_to_equalizer(&wav_48k, ENGINE, EqChannelMode::LeftRight).unwrap();
+
+        for freq in [100.0, 1000.0, 4000.0] {
+            let sine = |i: usize| {
+                (2.0 * std::f32::consts::PI * freq * i as f32 / ENGINE.sample_rate as f32).sin()
+            };
+            let rms_44k = settled_rms(&mut eq_44k, sine);
+            let rms_48k = settled_rms(&mut eq_48k, sine);
+            let diff_db = gain_to_db(rms_44k / rms_48k).abs();
+            assert!(diff_db < 0.2, "{} Hz differs by {:.2} dB", freq, diff_db);
+        }
+    }
+
+    #[test]
+    fn stereo_48k_eq_applies_a_curve_per_ear() {
+        let wav = wav_bytes(2, 48000, &[1.0, 0.5]);
+        let mut eq = wav_to_equalizer(&wav, ENGINE, EqChannelMode::LeftRight).unwrap();
+        let input = stereo_block(|i| (i as f32 * 0.1).sin(), |i| (i as f32 * 0.1).sin());
+
+        let mut data = input.clone();
+        eq.process(&mut AudioDataMut::new(&mut data, 2));
+
+        let expected = stereo_block(|i| (i as f32 * 0.1).sin(), |i| 0.5 * (i as f32 * 0.1).sin());
+        assert_close(&data, &expected);
+    }
+
+    #[test]
+    fn malformed_eq_wav_is_an_error() {
+        let result = wav_to_equalizer(b"RIFF", ENGINE, EqChannelMode::LeftRight);
+        assert!(result.is_err());
+    }
+
+    fn delayed_impulse(delay: usize) -> Vec<f32> {
+        let mut ir = vec![0.0; delay + 1];
+        ir[delay] = 1.0;
+        ir
+    }
+
+    fn first_nonzero(block: &[f32]) -> Option<usize> {
+        block.iter().position(|v| v.abs() > 1e-6)
+    }
+
+    /// An 8-channel block with a unit impulse on `channels` at frame 0.
+    fn ch8_impulse(channels: &[usize]) -> Vec<f32> {
+        let mut input = vec![0.0; ENGINE.block_size * NUM_SPEAKERS];
+        for &ch in channels {
+            input[ch] = 1.0;
+        }
+        input
+    }
+
+    /// Runs a 7.1 block with a unit impulse on `channels` through a new virtualizer.
+    fn render_ch8_impulse(config: &SurroundVirtualizerConfig, channels: &[usize]) -> Vec<f32> {
+        let mut sv = SurroundVirtualizer::new(config).unwrap();
+        let input = ch8_impulse(channels);
+        let mut output = vec![0.0; ENGINE.block_size * 2];
+        sv.process_ch8(
+            &AudioDataRef::new(&input, NUM_SPEAKERS),
+            &mut AudioDataMut::new(&mut output, 2),
+        );
+        output
+    }
+
+    /// Normalized correlation of the left and right channel of interleaved stereo frames.
+    fn stereo_correlation(frames: &[f32]) -> f32 {
+        let (mut lr, mut ll, mut rr) = (0.0, 0.0, 0.0);
+        for frame in frames.chunks_exact(2) {
+            lr += frame[0] * frame[1];
+            ll += frame[0] * frame[0];
+            rr += frame[1] * frame[1];
+        }
+        lr / (ll * rr).sqrt()
+    }
+
+    #[test]
+    fn decorrelation_lowers_the_correlation_of_shared_content() {
+        let wav = impulse_wav();
+        let left_only = wav_bytes(2, ENGINE.sample_rate, &[1.0, 0.0]);
+        let right_only = wav_bytes(2, ENGINE.sample_rate, &[0.0, 1.0]);
+        // The same noise on FL and FR, which reach only the left and only the right ear
+        let input: Vec<Vec<f32>> = (0..8)
+            .map(|block| {
+                let mut input = vec![0.0; ENGINE.block_size * NUM_SPEAKERS];
+                let shared = noise(ENGINE.block_size, 0.25, block + 1);
+                for (frame, v) in input.chunks_exact_mut(NUM_SPEAKERS).zip(shared) {
+                    frame[FL] = v;
+                    frame[FR] = v;
+                }
+                input
+            })
+            .collect();
+        let render = |decorrelation: f32| {
+            let config = SurroundVirtualizerConfig {
+                fl_wav: &left_only,
+                fr_wav: &right_only,
+                decorrelation,
+                ..test_config(&wav)
+            };
+            let mut sv = SurroundVirtualizer::new(&config).unwrap();
+            let mut output = Vec::new();
+            for block in &input {
+                let mut out_block = vec![0.0; ENGINE.block_size * 2];
+                sv.process_ch8(
+                    &AudioDataRef::new(block, NUM_SPEAKERS),
+                    &mut AudioDataMut::new(&mut out_block, 2),
+                );
+                output.extend(out_block);
+            }
+            output
+        };
+
+        // Off, both ears get the input unchanged
+        let dry = render(0.0);
+        let expected: Vec<f32> = input
+            .iter()
+            .flat_map(|block| block.chunks_exact(NUM_SPEAKERS))
+            .flat_map(|frame| [frame[FL], frame[FR]])
+            .collect();
+        assert_close(&dry, &expected);
+        assert!(stereo_correlation(&dry) > 0.9999);
+
+        let half = stereo_correlation(&render(0.5));
+        let full = stereo_correlation(&render(1.0));
+        assert!(half < 0.999, "{}", half);
+        assert!(full < half && full < 0.99, "{} {}", full, half);
+    }
+
+    #[test]
+    fn invalid_config_is_an_error() {
+        let wav = impulse_wav();
+        let three_channels = wav_bytes(3, ENGINE.sample_rate, &[1.0, 1.0, 1.0]);
+        let empty = wav_bytes(1, ENGINE.sample_rate, &[]);
+        let error_of = |config: &SurroundVirtualizerConfig| match SurroundVirtualizer::new(config) {
+            Ok(_) => panic!("an invalid config was accepted"),
+            Err(err) => err.to_string(),
+        };
+
+        let broken = SurroundVirtualizerConfig {
+            fc_wav: b"RIFF",
+            ..test_config(&wav)
+        };
+        assert!(error_of(&broken).starts_with("FC HRIR"));
+        let too_many_channels = SurroundVirtualizerConfig {
+            sl_wav: &three_channels,
+            ..test_config(&wav)
+        };
+        assert!(error_of(&too_many_channels).starts_with("SL HRIR"));
+        // Fails even though it isn't rendered through its HRIR
+        let empty_lfe = SurroundVirtualizerConfig {
+            lfe_wav: &empty,
+            ..test_config(&wav)
+        };
+        assert!(error_of(&empty_lfe).starts_with("LFE HRIR"));
+        let no_block_size = SurroundVirtualizerConfig {
+            engine: EngineConfig {
+                block_size: 0,
+                ..ENGINE
+            },
+            ..test_config(&wav)
+        };
+        error_of(&no_block_size);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "audio block of 128 frames passed to a processor built for blocks of 256 frames"
+    )]
+    fn block_of_another_engine_size_is_rejected() {
+        let wav = impulse_wav();
+        let mut sv = SurroundVirtualizer::new(&test_config(&wav)).unwrap();
+        let input = vec![0.0; 128 * NUM_SPEAKERS];
+        let mut output = vec![0.0; 128 * 2];
+        sv.process_ch8(
+            &AudioDataRef::new(&input, NUM_SPEAKERS),
+            &mut AudioDataMut::new(&mut output, 2),
+        );
+    }
+
+    #[test]
+    fn ch8_output_is_the_gain_weighted_sum_of_the_impulses() {
+        let wav = impulse_wav();
+        let left_only = wav_bytes(2, ENGINE.sample_rate, &[1.0, 0.0]);
+        let right_only = wav_bytes(2, ENGINE.sample_rate, &[0.0, 1.0]);
+        let config = SurroundVirtualizerConfig {
+            fl_wav: &left_only,
+            fr_wav: &right_only,
+            lfe_hrir: true,
+            ..test_config(&wav)
+        };
+
+        let expected_frames = [
+            (FL, [1.0, 0.0]),
+            (FR, [0.0, 1.0]),
+            (FC, [CENTER_GAIN, CENTER_GAIN]),
+            (LFE, [LFE_GAIN, LFE_GAIN]),
+            (SL, [SIDE_GAIN, SIDE_GAIN]),
+            (BR, [BACK_GAIN, BACK_GAIN]),
+        ];
+        for (speaker, expected_frame) in expected_frames {
+            let output = render_ch8_impulse(&config, &[speaker]);
+            let mut expected = vec![0.0; output.len()];
+            expected[..2].copy_from_slice(&expected_frame);
+            assert_close(&output, &expected);
+        }
+
+        // Every speaker at once adds up their contributions
+        let output = render_ch8_impulse(&config, &[FL, FR, FC, LFE]);
+        let center = CENTER_GAIN + LFE_GAIN;
+        assert_close(&output[..2], &[1.0 + center, 1.0 + center]);
+        assert_eq!(first_nonzero(&output[2..]), None);
+    }
+
+    #[test]
+    fn lfe_is_added_flat_or_through_its_hrir() {
+        let wav = impulse_wav();
+        // One frame late and uneven between the ears, unlike the flat path
+        let lfe_wav = wav_bytes(2, ENGINE.sample_rate, &[0.0, 0.0, 0.5, 0.25]);
+        let flat = SurroundVirtualizerConfig {
+            lfe_wav: &lfe_wav,
+            ..test_config(&wav)
+        };
+        let through_hrir = SurroundVirtualizerConfig {
+            lfe_hrir: true,
+            ..flat
+        };
+
+        let output = render_ch8_impulse(&flat, &[LFE]);
+        let mut expected = vec![0.0; output.len()];
+        expected[..2].copy_from_slice(&[LFE_GAIN, LFE_GAIN]);
+        assert_close(&output, &expected);
+
+        let output = render_ch8_impulse(&through_hrir, &[LFE]);
+        let mut expected = vec![0.0; output.len()];
+        expected[2..4].copy_from_slice(&[0.5 * LFE_GAIN, 0.25 * LFE_GAIN]);
+        assert_close(&output, &expected);
+
+        // The gain override applies to either path
+        for config in [flat, through_hrir] {
+            let boosted = SurroundVirtualizerConfig {
+                lfe_gain: Some(2.0),
+                ..config
+            };
+            let ratio = energy(&render_ch8_impulse(&boosted, &[LFE]))
+                / energy(&render_ch8_impulse(&config, &[LFE]));
+            assert!((ratio - (2.0 / LFE_GAIN).powi(2)).abs() < 1e-3, "{}", ratio);
+        }
+    }
+
+    #[test]
+    fn farther_speaker_arrives_later_and_quieter() {
+        let wav = impulse_wav();
+        let left_only = wav_bytes(2, ENGINE.sample_rate, &[1.0, 0.0]);
+        let right_only = wav_bytes(2, ENGINE.sample_rate, &[0.0, 1.0]);
+        let mut distances = [1.0; NUM_SPEAKERS];
+        distances[FR] = 3.0;
+        let config = SurroundVirtualizerConfig {
+            fl_wav: &left_only,
+            fr_wav: &right_only,
+            speaker_distances: Some(distances),
+            ..test_config(&wav)
+        };
+
+        // The delay is longer than a block
+        let mut sv = SurroundVirtualizer::new(&config).unwrap();
+        let silence = vec![0.0; ENGINE.block_size * NUM_SPEAKERS];
+        let mut output = Vec::new();
+        for input in [ch8_impulse(&[FL, FR]), silence] {
+            let mut block = vec![0.0; ENGINE.block_size * 2];
+            sv.process_ch8(
+                &AudioDataRef::new(&input, NUM_SPEAKERS),
+                &mut AudioDataMut::new(&mut block, 2),
+            );
+            output.extend(block);
+        }
+        let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = output.iter().skip(1).step_by(2).copied().collect();
+
+        // The nearest speaker is the reference, neither delayed nor attenuated
+        assert_close(&left[..1], &[1.0]);
+        assert_eq!(first_nonzero(&left[1..]), None);
+
+        // 2 m farther: 2 / 343 s later, at a third of the level
+        let expected_delay = 2.0 / SPEED_OF_SOUND * ENGINE.sample_rate as f32;
+        let level: f32 = right.iter().sum();
+        let centroid = right
+            .iter()
+            .enumerate()
+            .map(|(i, v)| i as f32 * v)
+            .sum::<f32>()
+            / level;
+        assert!((level - 1.0 / 3.0).abs() < 1e-3, "{}", level);
+        assert!(
+            (centroid - expected_delay).abs() < 0.1,
+            "{} != {}",
+            centroid,
+            expected_delay
+        );
+    }
+
+    #[test]
+    fn hrir_channel_count_is_read_from_the_header() {
+        let mono = BinauralIr::from_wav(&wav_bytes(1, ENGINE.sample_rate, &[1.0, 0.5, 0.25, 0.0]))
+            .unwrap();
+        assert_eq!(mono.left, [1.0, 0.5, 0.25, 0.0]);
+        assert_eq!(mono.right, mono.left);
+
+        let stereo =
+            BinauralIr::from_wav(&wav_bytes(2, ENGINE.sample_rate, &[1.0, 0.5, 0.25, 0.0]))
+                .unwrap();
+        assert_eq!(stereo.left, [1.0, 0.25]);
+        assert_eq!(stereo.right, [0.5, 0.0]);
+
+        let surround = wav_bytes(3, ENGINE.sample_rate, &[1.0, 0.5, 0.25]);
+        assert!(BinauralIr::from_wav(&surround).is_err());
+    }
+
+    /// `white_noise` scaled to `rms`.
+    fn noise(len: usize, rms: f32, seed: u32) -> Vec<f32> {
+        // The RMS of the uniform distribution in [-1, 1] is 1/sqrt(3)
+        let scale = rms * 3.0_f32.sqrt();
+        white_noise(len, seed).map(|v| scale * v).collect()
+    }
+
+    #[test]
+    fn modes_play_at_the_same_level() {
+        // Mono impulses of distinct delays, so that the speaker contributions of white noise
+        // are uncorrelated like those of real HRIRs
+        let wavs: Vec<Vec<u8>> = (0..NUM_SPEAKERS)
+            .map(|speaker| wav_bytes(1, ENGINE.sample_rate, &delayed_impulse(speaker * 7)))
+            .collect();
+        let config = |mode_gain_compensation| SurroundVirtualizerConfig {
+            fl_wav: &wavs[FL],
+            fr_wav: &wavs[FR],
+            fc_wav: &wavs[FC],
+            lfe_wav: &wavs[LFE],
+            sl_wav: &wavs[SL],
+            sr_wav: &wavs[SR],
+            bl_wav: &wavs[BL],
+            br_wav: &wavs[BR],
+            mode_gain_compensation,
+            ..test_config(&wavs[FL])
+        };
+        type Render = fn(&mut SurroundVirtualizer, &AudioDataRef, &mut AudioDataMut);
+        // The same total input power of 1 spread over the channels of each mode
+        let modes: [(usize, Render); 4] = [
+            (NUM_SPEAKERS, SurroundVirtualizer::process_ch8),
+            (2, SurroundVirtualizer::process_ch2),
+            (2, SurroundVirtualizer::process_ch2_upmix),
+            (1, SurroundVirtualizer::process_mono),
+        ];
+        let output_db = |mode_gain_compensation, (num_channels, render): (usize, Render)| {
+            let mut sv = SurroundVirtualizer::new(&config(mode_gain_compensation)).unwrap();
+            let rms = (1.0 / num_channels as f32).sqrt();
+            let mut output_energy = 0.0;
+            for block in 0..64 {
+                let input = noise(ENGINE.block_size * num_channels, rms, block + 1);
+                let mut output = vec![0.0; ENGINE.block_size * 2];
+                render(
+                    &mut sv,
+                    &AudioDataRef::new(&input, num_channels),
+                    &mut AudioDataMut::new(&mut output, 2),
+                );
+                // Once the upmix delay line has filled up
+                if block >= 8 {
+                    output_energy += energy(&output);
+                }
+            }
+            10.0 * output_energy.log10()
+        };
+
+        let compensated = modes.map(|mode| output_db(true, mode));
+        for db in compensated {
+            assert!((db - compensated[0]).abs() < 1.0, "{:?}", compensated);
+        }
+        // Mono would be far louder otherwise
+        let uncompensated = modes.map(|mode| output_db(false, mode));
+        assert!(
+            uncompensated[3] - uncompensated[0] > 3.0,
+            "{:?}",
+            uncompensated
+        );
+    }
+
+    #[test]
+    fn headroom_is_made_up_after_the_convolution() {
+        let wav = impulse_wav();
+        let input = noise(ENGINE.block_size * NUM_SPEAKERS, 0.25, 1);
+        let render = |headroom_db, lfe_hrir| {
+            let config = SurroundVirtualizerConfig {
+                headroom_db,
+                lfe_hrir,
+                ..test_config(&wav)
+            };
+            let mut sv = SurroundVirtualizer::new(&config).unwrap();
+            let mut output = vec![0.0; ENGINE.block_size * 2];
+            sv.process_ch8(
+                &AudioDataRef::new(&input, NUM_SPEAKERS),
+                &mut AudioDataMut::new(&mut output, 2),
+            );
+            output
+        };
+
+        for lfe_hrir in [false, true] {
+            let reference = render(0.0, lfe_hrir);
+            assert!(energy(&reference) > 0.0);
+            for headroom_db in [6.0, 20.0] {
+                assert_close(&render(headroom_db, lfe_hrir), &reference);
+            }
+        }
+    }
+
+    #[test]
+    fn center_boost_only_raises_the_center() {
+        let wav = impulse_wav();
+        let config = test_config(&wav);
+        let input = noise(ENGINE.block_size * NUM_SPEAKERS, 0.25, 1);
+        let render = |center_boost_db, speakers: &[usize]| {
+            let mut input = input.clone();
+            for frame in input.chunks_exact_mut(NUM_SPEAKERS) {
+                for (speaker, v) in frame.iter_mut().enumerate() {
+                    if !speakers.contains(&speaker) {
+                        *v = 0.0;
+                    }
+                }
+            }
+            let mut sv = SurroundVirtualizer::new(&config).unwrap();
+            sv.set_center_boost_db(center_boost_db);
+            let mut output = vec![0.0; ENGINE.block_size * 2];
+            sv.process_ch8(
+                &AudioDataRef::new(&input, NUM_SPEAKERS),
+                &mut AudioDataMut::new(&mut output, 2),
+            );
+            output
+        };
+
+        let others = [FL, FR, LFE, SL, SR, BL, BR];
+        assert_close(&render(6.0, &others), &render(0.0, &others));
+
+        let center = render(0.0, &[FC]);
+        let boosted: Vec<f32> = center.iter().map(|v| db_to_gain(6.0) * v).collect();
+        assert_close(&render(6.0, &[FC]), &boosted);
+
+        // All speakers at once: only the center's share grows
+        let all: Vec<usize> = (0..NUM_SPEAKERS).collect();
+        let expected: Vec<f32> = render(0.0, &others)
+            .iter()
+            .zip(&boosted)
+            .map(|(others, center)| others + center)
+            .collect();
+        assert_close(&render(6.0, &all), &expected);
+    }
+
+    #[test]
+    fn dc_removal_zeroes_the_dc_response() {
+        // An impulse riding on an offset
+        let mut dc_ir = vec![0.1; 64];
+        dc_ir[0] = 1.0;
+        let dc_wav = wav_bytes(1, ENGINE.sample_rate, &dc_ir);
+        let render_dc = |remove_dc| {
+            let config = SurroundVirtualizerConfig {
+                remove_dc,
+                ..test_config(&dc_wav)
+            };
+            let mut sv = SurroundVirtualizer::new(&config).unwrap();
+            let mut input = vec![0.0; ENGINE.block_size * NUM_SPEAKERS];
+            for frame in input.chunks_exact_mut(NUM_SPEAKERS) {
+                frame[FL] = 1.0;
+            }
+            let mut output = vec![0.0; ENGINE.block_size * 2];
+            for _ in 0..2 {
+                sv.process_ch8(
+                    &AudioDataRef::new(&input, NUM_SPEAKERS),
+                    &mut AudioDataMut::new(&mut output, 2),
+                );
+            }
+            output
+        };
+
+        // A constant input settles to the sum of the impulse
+        let kept = render_dc(false);
+        assert!(
+            kept.iter()
+                .all(|v| (v - dc_ir.iter().sum::<f32>()).abs() < 1e-3)
+        );
+        let removed = render_dc(true);
+        assert_close(&removed, &vec![0.0; removed.len()]);
+
+        let mut zero_mean = BinauralIr {
+            left: vec![0.5, -0.25, -0.25],
+            right: vec![-1.0, 1.0],
+        };
+        zero_mean.remove_dc();
+        assert_eq!(zero_mean.left, [0.5, -0.25, -0.25]);
+        assert_eq!(zero_mean.right, [-1.0, 1.0]);
+    }
+
+    #[test]
+    fn truncation_drops_partitions_and_fades_the_tail() {
+        let long_len = ENGINE.block_size * 8;
+        let max_len = ENGINE.block_size * 2 + 10;
+        let mut ir = BinauralIr {
+            left: vec![1.0; long_len],
+            right: vec![1.0; long_len],
+        };
+        let partitions = |ir: &[f32]| {
+            BlockConvolver::<f32>::new(ENGINE.block_size, ir)
+                .unwrap()
+                .num_partitions()
+        };
+        assert_eq!(partitions(&ir.left), 8);
+
+        ir.truncate(max_len);
+        assert_eq!(partitions(&ir.left), 3);
+        for ear in [&ir.left, &ir.right] {
+            assert_eq!(ear.len(), max_len);
+            // Untouched up to the fade, which falls steadily to silence at the cut
+            let fade_start = max_len - HRIR_TRUNCATION_FADE_LEN;
+            assert!(ear[..fade_start].iter().all(|&v| v == 1.0));
+            assert!(ear[fade_start..].windows(2).all(|w| w[1] < w[0]));
+            assert!(ear[max_len - 1].abs() < 1e-6, "{}", ear[max_len - 1]);
+        }
+
+        // Shorter impulses are left alone
+        let mut short = BinauralIr {
+            left: vec![1.0; 10],
+            right: vec![1.0; 10],
+        };
+        short.truncate(max_len);
+        assert_eq!(short.left, [1.0; 10]);
+    }
+
+    #[test]
+    fn align_onsets_keeps_the_interaural_delay() {
+        let mut near = BinauralIr {
+            left: delayed_impulse(5),
+            right: delayed_impulse(5),
+        };
+        let mut far = BinauralIr {
+            left: delayed_impulse(12),
+            right: delayed_impulse(15),
+        };
+        align_onsets(&mut [&mut near, &mut far]);
+
+        assert_eq!(near.onset(), 5);
+        assert_eq!(far.onset(), 5);
+        assert_eq!(first_nonzero(&far.right), Some(8));
+    }
+
+    #[test]
+    fn aligned_hrirs_render_simultaneous_onsets() {
+        let early = wav_bytes(1, ENGINE.sample_rate, &delayed_impulse(5));
+        let late = wav_bytes(1, ENGINE.sample_rate, &delayed_impulse(12));
+        let input = ch8_impulse(&[FL, FR]);
+        let mut output = vec![0.0; ENGINE.block_size * 2];
+
+        for align_onsets in [false, true] {
+            let config = SurroundVirtualizerConfig {
+                fr_wav: &late,
+                align_onsets,
+                ..test_config(&early)
+            };
+            let mut sv = SurroundVirtualizer::new(&config).unwrap();
+            sv.process_ch8(
+                &AudioDataRef::new(&input, NUM_SPEAKERS),
+                &mut AudioDataMut::new(&mut output, 2),
+            );
+
+            let fl_onset = first_nonzero(&sv.fl_conv.left_out).unwrap();
+            let fr_onset = first_nonzero(&sv.fr_conv.left_out).unwrap();
+            if align_onsets {
+                assert_eq!(fl_onset, fr_onset);
+            } else {
+                assert_eq!(fr_onset - fl_onset, 7);
+            }
+        }
+    }
+
+    #[test]
+    fn left_only_boost_leaves_the_right_ear_alone() {
+        let mut eq = Equalizer::new_stereo(ENGINE, vec![2.0], vec![1.0]).unwrap();
+        eq.set_preamp_db(0.0);
+        let input = stereo_block(|i| (i as f32 * 0.1).sin(), |i| (i as f32 * 0.2).cos());
+
+        let mut data = input.clone();
+        eq.process(&mut AudioDataMut::new(&mut data, 2));
+
+        let expected = stereo_block(|i| 2.0 * (i as f32 * 0.1).sin(), |i| (i as f32 * 0.2).cos());
+        assert_close(&data, &expected);
+    }
+
+    #[test]
+    fn identity_mid_side_eq_is_transparent() {
+        let mut eq = Equalizer::new_mid_side(ENGINE, vec![1.0], vec![1.0]).unwrap();
+        let input = stereo_block(|i| (i as f32 * 0.1).sin(), |i| (i as f32 * 0.2).cos());
+
+        let mut data = input.clone();
+        eq.process(&mut AudioDataMut::new(&mut data, 2));
+        assert_close(&data, &input);
+    }
+
+    #[test]
+    fn side_boost_widens_stereo_and_leaves_mono_alone() {
+        let mut eq = Equalizer::new_mid_side(ENGINE, vec![1.0], vec![2.0]).unwrap();
+        eq.set_preamp_db(0.0);
+
+        let mono = stereo_block(|i| (i as f32 * 0.1).sin(), |i| (i as f32 * 0.1).sin());
+        let mut data = mono.clone();
+        eq.process(&mut AudioDataMut::new(&mut data, 2));
+        assert_close(&data, &mono);
+
+        // L = M + S and R = M - S with the side doubled
+        let stereo = stereo_block(|_| 0.75, |_| 0.25);
+        let mut data = stereo.clone();
+        eq.process(&mut AudioDataMut::new(&mut data, 2));
+        assert_close(&data, &stereo_block(|_| 1.0, |_| 0.0));
+    }
+
+    #[test]
+    fn eq_wav_with_more_channels_uses_the_first_for_both_ears() {
+        let wav = wav_bytes(3, ENGINE.sample_rate, &[0.5, 1.0, 1.0]);
+        let mut eq = wav_to_equalizer(&wav, ENGINE, EqChannelMode::LeftRight).unwrap();
+        let input = stereo_block(|i| (i as f32 * 0.1).sin(), |i| (i as f32 * 0.2).cos());
+
+        let mut data = input.clone();
+        eq.process(&mut AudioDataMut::new(&mut data, 2));
+
+        let expected: Vec<f32> = input.iter().map(|v| 0.5 * v).collect();
+        assert_close(&data, &expected);
+    }
+
+    /// A 10-channel (7.1 + TFL/TFR) block with a unit impulse on TFL at frame 0.
+    fn ch10_tfl_impulse() -> Vec<f32> {
+        let num_channels = HEIGHT_CHANNELS_OFFSET + 2;
+        let mut input = vec![0.0; ENGINE.block_size * num_channels];
+        input[HEIGHT_CHANNELS_OFFSET] = 1.0;
+        input
+    }
+
+    #[test]
+    fn height_channel_without_hrir_folds_into_its_horizontal_speaker() {
+        let wav = impulse_wav();
+        let mut sv = SurroundVirtualizer::new(&test_config(&wav)).unwrap();
+        let input = ch10_tfl_impulse();
+        let mut output = vec![0.0; ENGINE.block_size * 2];
+
+        sv.process_ch8(
+            &AudioDataRef::new(&input, HEIGHT_CHANNELS_OFFSET + 2),
+            &mut AudioDataMut::new(&mut output, 2),
+        );
+
+        assert_close(&sv.fl_conv.left_out[..1], &[HEIGHT_GAIN]);
+        assert_eq!(first_nonzero(&sv.fr_conv.left_out), None);
+        assert_eq!(first_nonzero(&sv.bl_conv.left_out), None);
+    }
+
+    #[test]
+    fn height_channel_with_hrir_is_rendered_on_its_own() {
+        let wav = impulse_wav();
+        let config = SurroundVirtualizerConfig {
+            tfl_wav: Some(&wav),
+            ..test_config(&wav)
+        };
+        let mut sv = SurroundVirtualizer::new(&config).unwrap();
+        let input = ch10_tfl_impulse();
+        let mut output = vec![0.0; ENGINE.block_size * 2];
+
+        sv.process_ch8(
+            &AudioDataRef::new(&input, HEIGHT_CHANNELS_OFFSET + 2),
+            &mut AudioDataMut::new(&mut output, 2),
+        );
+
+        assert_eq!(first_nonzero(&sv.fl_conv.left_out), None);
+        assert_close(&output[..2], &[HEIGHT_GAIN; 2]);
+        assert_eq!(first_nonzero(&output[2..]), None);
+    }
+
+    #[test]
+    fn solo_with_height_channels_outputs_only_the_soloed_channel() {
+        let wav = impulse_wav();
+        // TFL has its own HRIR, while TBL folds down into BL
+        let config = SurroundVirtualizerConfig {
+            tfl_wav: Some(&wav),
+            ..test_config(&wav)
+        };
+        let num_channels = HEIGHT_CHANNELS_OFFSET + NUM_HEIGHT_CHANNELS;
+        // A distinct impulse on each of the `channels` at frame 0
+        let impulses = |channels: &dyn Fn(usize) -> bool| {
+            let mut input = vec![0.0; ENGINE.block_size * num_channels];
+            for ch in (0..num_channels).filter(|&ch| channels(ch)) {
+                input[ch] = 0.1 * (ch + 1) as f32;
+            }
+            input
+        };
+        let render = |mask: u32, input: &[f32]| {
+            let mut sv = SurroundVirtualizer::new(&config).unwrap();
+            sv.set_speaker_mask(mask);
+            let mut output = vec![0.0; ENGINE.block_size * 2];
+            sv.process_ch8(
+                &AudioDataRef::new(input, num_channels),
+                &mut AudioDataMut::new(&mut output, 2),
+            );
+            output
+        };
+
+        for solo in [BL, HEIGHT_CHANNELS_OFFSET, HEIGHT_CHANNELS_OFFSET + 2] {
+            let params = RuntimeParams {
+                channel_solo: Some(solo),
+                ..RuntimeParams::DEFAULT
+            };
+            let soloed = render(params.active_speaker_mask(), &impulses(&|_| true));
+            let alone = render(u32::MAX, &impulses(&|ch| ch == solo));
+
+            assert_eq!(first_nonzero(&alone), Some(0));
+            assert_close(&soloed, &alone);
+        }
+    }
+
+    #[test]
+    fn default_preamp_keeps_a_boosted_band_at_unity() {
+        // A two-tap average scaled by 2: +6 dB at DC, falling to nothing at Nyquist
+        let mut eq = Equalizer::new(ENGINE, vec![1.0, 1.0]).unwrap();
+        assert!((eq.preamp_db() + 6.02).abs() < 0.01, "{}", eq.preamp_db());
+
+        let dc_rms = settled_rms(&mut eq, |_| 0.5);
+        assert!((dc_rms - 0.5).abs() < 1e-4, "{}", dc_rms);
+    }
+
+    #[test]
+    fn preamp_override_replaces_the_default() {
+        let mut eq = Equalizer::new(ENGINE, vec![1.0, 1.0]).unwrap();
+        eq.set_preamp_db(0.0);
+
+        let dc_rms = settled_rms(&mut eq, |_| 0.25);
+        assert!((dc_rms - 0.5).abs() < 1e-4, "{}", dc_rms);
+    }
 }
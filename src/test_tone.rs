@@ -0,0 +1,101 @@
+use crate::surround_virtualizer::NUM_SPEAKERS;
+
+const BURST_MS: usize = 1000;
+const GAP_MS: usize = 300;
+/// Roughly -20 dBFS RMS.
+const NOISE_GAIN: f32 = 0.1;
+
+/// Plays a pink noise burst in each selected speaker channel in turn,
+/// replacing the device input while active.
+pub struct TestTone {
+    burst_frames: usize,
+    gap_frames: usize,
+    speakers_left: u32,
+    current_speaker: Option<usize>,
+    frames_left: usize,
+    rng_state: u32,
+    pink_state: [f32; 7],
+}
+
+impl TestTone {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            burst_frames: sample_rate as usize * BURST_MS / 1000,
+            gap_frames: sample_rate as usize * GAP_MS / 1000,
+            speakers_left: 0,
+            current_speaker: None,
+            frames_left: 0,
+            rng_state: 0x2545_F491,
+            pink_state: [0.0; 7],
+        }
+    }
+
+    /// Starts over with the speakers whose bits are set in `speaker_mask`, in `SPEAKER_LABELS` order.
+    pub fn start(&mut self, speaker_mask: u32) {
+        self.speakers_left = speaker_mask & ((1 << NUM_SPEAKERS) - 1);
+        self.current_speaker = None;
+        self.frames_left = 0;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.speakers_left != 0 || self.frames_left > 0
+    }
+
+    /// Fills interleaved `data` with `num_channels` channels, the current speaker
+    /// carrying noise and the others silence.
+    pub fn fill(&mut self, data: &mut [f32], num_channels: usize) {
+        for frame in data.chunks_exact_mut(num_channels) {
+            frame.fill(0.0);
+
+            if self.frames_left == 0 {
+                self.next_speaker();
+            }
+            if self.frames_left == 0 {
+                continue;
+            }
+            self.frames_left -= 1;
+
+            // The trailing gap keeps consecutive bursts apart
+            if let Some(speaker) = self.current_speaker
+                && self.frames_left >= self.gap_frames
+                && speaker < num_channels
+            {
+                frame[speaker] = self.next_pink() * NOISE_GAIN;
+            }
+        }
+    }
+
+    fn next_speaker(&mut self) {
+        if self.speakers_left == 0 {
+            self.current_speaker = None;
+            return;
+        }
+        let speaker = self.speakers_left.trailing_zeros() as usize;
+        self.speakers_left &= !(1 << speaker);
+        self.current_speaker = Some(speaker);
+        self.frames_left = self.burst_frames + self.gap_frames;
+    }
+
+    /// Xorshift32 white noise in (-1, 1).
+    fn next_white(&mut self) -> f32 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 17;
+        self.rng_state ^= self.rng_state << 5;
+        (self.rng_state >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+
+    /// Paul Kellet's refined pink noise filter, scaled to about unit peak.
+    fn next_pink(&mut self) -> f32 {
+        let white = self.next_white();
+        let b = &mut self.pink_state;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink * 0.11
+    }
+}